@@ -0,0 +1,95 @@
+// Baseline timings for the crypto primitives and whole-folder lock/unlock.
+// Run with `cargo bench`; fixtures are generated the same way every run, so
+// numbers are comparable between branches on the same machine.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use securelock::crypto;
+use securelock::folder::{self, LockOptions, UnlockOptions, VaultNames};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const PASSWORD: &str = "benchmark password";
+const SALT: [u8; 32] = [7; 32];
+
+// Deterministic, distinct contents per file, so deduplication doesn't kick in.
+fn contents(seed: usize, len: usize) -> Vec<u8> {
+    let mut state = seed as u64 ^ 0x9e37_79b9_7f4a_7c15;
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect()
+}
+
+// `count` files of `size` bytes, spread over subfolders of 100.
+fn build_folder(count: usize, size: usize) -> TempDir {
+    let dir = tempfile::tempdir().expect("temp dir");
+    for i in 0..count {
+        let sub = dir.path().join(format!("d{:03}", i / 100));
+        fs::create_dir_all(&sub).expect("create subfolder");
+        fs::write(sub.join(format!("f{:05}.bin", i)), contents(i, size)).expect("write fixture");
+    }
+    dir
+}
+
+fn path_str(dir: &Path) -> &str {
+    dir.to_str().expect("utf-8 temp path")
+}
+
+fn derive_key(c: &mut Criterion) {
+    let mut group = c.benchmark_group("derive_key");
+    group.sample_size(10);
+    group.bench_function("argon2id", |b| b.iter(|| crypto::derive_key(PASSWORD, &SALT).unwrap()));
+    group.finish();
+}
+
+fn encrypt_decrypt(c: &mut Criterion) {
+    let key = crypto::derive_key(PASSWORD, &SALT).unwrap();
+    let mut group = c.benchmark_group("aead");
+    for size in [1 << 10, 64 << 10, 1 << 20, 16 << 20] {
+        let plaintext = contents(size, size);
+        let ciphertext = crypto::encrypt(&key, &plaintext).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("encrypt", size), &plaintext, |b, pt| b.iter(|| crypto::encrypt(&key, pt).unwrap()));
+        group.bench_with_input(BenchmarkId::new("decrypt", size), &ciphertext, |b, ct| b.iter(|| crypto::decrypt(&key, ct).unwrap()));
+    }
+    group.finish();
+}
+
+fn lock_unlock(c: &mut Criterion) {
+    let names = VaultNames::default();
+    let mut group = c.benchmark_group("folder");
+    group.sample_size(10);
+    for (label, count, size) in [("1000x1KiB", 1000, 1 << 10), ("10x10MiB", 10, 10 << 20)] {
+        group.throughput(Throughput::Bytes((count * size) as u64));
+        group.bench_function(BenchmarkId::new("lock", label), |b| {
+            b.iter_batched(
+                || build_folder(count, size),
+                |dir| {
+                    folder::lock_folder(path_str(dir.path()), &names, PASSWORD, None, &LockOptions::default()).unwrap();
+                    dir
+                },
+                BatchSize::PerIteration,
+            )
+        });
+        group.bench_function(BenchmarkId::new("unlock", label), |b| {
+            b.iter_batched(
+                || {
+                    let dir = build_folder(count, size);
+                    folder::lock_folder(path_str(dir.path()), &names, PASSWORD, None, &LockOptions::default()).unwrap();
+                    dir
+                },
+                |dir| {
+                    folder::unlock_folder(path_str(dir.path()), &names, PASSWORD, &UnlockOptions::default(), &mut |_, _| {}).unwrap();
+                    dir
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, derive_key, encrypt_decrypt, lock_unlock);
+criterion_main!(benches);
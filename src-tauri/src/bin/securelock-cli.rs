@@ -0,0 +1,365 @@
+use securelock::breach::{self, BreachFilter};
+use securelock::crypto::{self, CipherKind, KdfKind};
+use securelock::error::Error;
+use securelock::folder::{self, FolderState, HiddenPolicy, LockOptions, UnlockOptions, UnreadablePolicy, VaultNames};
+use securelock::recipient::Identity;
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage:
+  securelock-cli lock <path> [--keyfile <file>] [--cipher gcm|gcm-siv] [--bind-machine] [--allow-system-path] [--skip-unreadable]
+      [--hidden unix|windows|none] [--exclude <glob>]... [--split-metadata]
+      [--stream-threshold <bytes>] [--kdf argon2id|pbkdf2] [--recipient <age1...|ssh public key>]...
+      [--reserve <bytes>] [--kdf-fallback] [--quick-lock <bytes>] [--show-vault-files] [--retries <n>]
+      [--ignore-limits]
+  securelock-cli finish <path> [--keyfile <file>]
+  securelock-cli decoy <path> --decoy-dir <dir> [--reserve <bytes>]
+  securelock-cli unlock <path> [--keyfile <file> | --identity <file>] [--output-dir <dir>] [--retries <n>]
+  securelock-cli cat <path> --file <file> [--keyfile <file>] [--retries <n>]
+  securelock-cli archive <path> --output <file.slk>
+  securelock-cli extract <file.slk> --output-dir <dir>
+  securelock-cli import <file.zip> --output-dir <dir>
+  securelock-cli status <path>
+  securelock-cli verify <path>
+  securelock-cli compare <path> --with <other-path>
+  securelock-cli rebuild <path> [--keyfile <file>]
+  securelock-cli upgrade-kdf <path> [--keyfile <file>] [--kdf argon2id|pbkdf2]
+  securelock-cli passwd <path>
+  securelock-cli breach-filter <hash-list> --output <file>
+
+The password is read from the first line of stdin unless --keyfile is given.
+It is never accepted on the command line. With --output-dir, decrypted copies
+are written there and the folder itself stays locked. cat decrypts one file
+(a path relative to the folder) to stdout, also leaving the folder locked.
+rebuild recreates
+damaged metadata from the locked files that decrypt with the password.
+verify checks, without the password, that no locked file was added, removed
+or altered since the folder was locked. compare checks that two copies of a
+locked folder (a backup, say) match, also without the password.
+archive writes an encrypted copy of the folder to one .slk file and leaves
+the folder as it is; extract unpacks one into a new or empty directory.
+import moves a password-protected ZIP from another locker into a new or empty
+directory and locks it; it reads the ZIP's password and then the new one
+from stdin, one per line.
+Files starting with a dot are not locked unless --hidden says otherwise;
+--exclude leaves out files matching the glob instead. --split-metadata keeps
+the file list in a separate .securelock.manifest next to a small header that
+can be backed up on its own. Files over --stream-threshold bytes (default
+64 MiB) are encrypted in chunks instead of in memory. --kdf pbkdf2 derives
+the key with PBKDF2-HMAC-SHA256 instead of Argon2id, for setups that need a
+FIPS-approved KDF. --kdf-fallback lets Argon2id drop to lighter memory
+settings, recorded in the metadata, on devices that can't spare 64 MiB.
+--recipient (repeatable) also wraps the folder key for an age or SSH public
+key; unlock --identity recovers with the matching private key file instead
+of the password. decoy hides the folder's files in a reserve
+of random-looking bytes and locks copies of --decoy-dir's files in their place;
+it reads the real password and then the decoy password from stdin, one per
+line, and unlock opens whichever set matches the password given. --reserve
+keeps such a reserve on an ordinary lock too, so vaults look alike; it can be
+at most 1 GiB.
+--quick-lock leaves files over the given size unencrypted for now, so the
+rest is secured without waiting on large media; finish encrypts them later.
+On Windows the metadata and .locked files get the hidden attribute unless
+--show-vault-files is given. A read or write that times out or drops its
+connection, as network drives sometimes do, is tried again up to --retries
+times (default 3) with a growing pause in between.
+A folder with more than 100,000 files or 100 GiB to encrypt is refused, in
+case it was picked by mistake; --ignore-limits locks it anyway.
+upgrade-kdf re-encrypts a folder locked with older or lighter key derivation
+settings for the current defaults; run it again to resume if interrupted.
+Folders with a master password recovery key are upgraded from the app.
+passwd reads the current password and then the new one from stdin, one per
+line, and re-encrypts the folder under the new one without unlocking it.
+Like upgrade-kdf, folders with a recovery key are changed from the app.
+The app's master_only setting doesn't apply here: unlock and passwd accept
+the folder password whatever config.json says.
+breach-filter builds a breached-password filter from a list of SHA-1 hashes,
+one per line (the Pwned Passwords `HASH:count` format works as is).";
+
+struct Args {
+    command: String,
+    path: String,
+    keyfile: Option<String>,
+    identity: Option<String>,
+    options: LockOptions,
+    unlock_options: UnlockOptions,
+    output: Option<String>,
+    decoy_dir: Option<String>,
+    other: Option<String>,
+    file: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let command = args.next().ok_or("Missing command")?;
+    let path = args.next().ok_or("Missing path")?;
+    let mut keyfile = None;
+    let mut identity = None;
+    let mut options = LockOptions::default();
+    let mut unlock_options = UnlockOptions::default();
+    let mut output = None;
+    let mut decoy_dir = None;
+    let mut file = None;
+    let mut other = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--password-stdin" => keyfile = None,
+            "--keyfile" => keyfile = Some(args.next().ok_or("--keyfile needs a file")?),
+            "--identity" => identity = Some(args.next().ok_or("--identity needs a private key file")?),
+            "--recipient" => options.recipients.push(args.next().ok_or("--recipient needs a public key")?),
+            "--bind-machine" => options.bind_to_machine = true,
+            "--allow-system-path" => options.allow_system_paths = true,
+            "--skip-unreadable" => options.on_unreadable = UnreadablePolicy::Skip,
+            "--split-metadata" => options.split_metadata = true,
+            "--show-vault-files" => options.show_vault_files = true,
+            "--ignore-limits" => options.ignore_limits = true,
+            "--kdf-fallback" => options.kdf_fallback = true,
+            "--stream-threshold" => {
+                let bytes = args.next().ok_or("--stream-threshold needs a size in bytes")?;
+                options.stream_threshold = Some(bytes.parse().map_err(|_| format!("Invalid size '{}'", bytes))?);
+            }
+            "--kdf" => {
+                options.kdf = match args.next().as_deref() {
+                    Some("argon2id") => KdfKind::Argon2id,
+                    Some("pbkdf2") => KdfKind::Pbkdf2HmacSha256,
+                    _ => return Err("--kdf must be 'argon2id' or 'pbkdf2'".into()),
+                }
+            }
+            "--hidden" => {
+                options.hidden = Some(match args.next().as_deref() {
+                    Some("unix") => HiddenPolicy::Unix,
+                    Some("windows") => HiddenPolicy::WindowsAttribute,
+                    Some("none") => HiddenPolicy::None,
+                    _ => return Err("--hidden must be 'unix', 'windows' or 'none'".into()),
+                })
+            }
+            "--exclude" => {
+                let pattern = args.next().ok_or("--exclude needs a glob")?;
+                match &mut options.hidden {
+                    Some(HiddenPolicy::Exclude(patterns)) => patterns.push(pattern),
+                    _ => options.hidden = Some(HiddenPolicy::Exclude(vec![pattern])),
+                }
+            }
+            "--with" => other = Some(args.next().ok_or("--with needs a folder")?),
+            "--decoy-dir" => decoy_dir = Some(args.next().ok_or("--decoy-dir needs a directory")?),
+            "--quick-lock" => {
+                let bytes = args.next().ok_or("--quick-lock needs a size in bytes")?;
+                options.quick_lock_limit = Some(bytes.parse().map_err(|_| format!("Invalid size '{}'", bytes))?);
+            }
+            "--retries" => {
+                let count = args.next().ok_or("--retries needs a number")?;
+                options.retry.retries = count.parse().map_err(|_| format!("Invalid retry count '{}'", count))?;
+                unlock_options.retry = options.retry;
+            }
+            "--reserve" => {
+                let bytes = args.next().ok_or("--reserve needs a size in bytes")?;
+                options.reserve = Some(bytes.parse().map_err(|_| format!("Invalid size '{}'", bytes))?);
+            }
+            "--output" => output = Some(args.next().ok_or("--output needs a file")?),
+            "--file" => file = Some(args.next().ok_or("--file needs a path inside the folder")?),
+            "--output-dir" => unlock_options.output_dir = Some(args.next().ok_or("--output-dir needs a directory")?),
+            "--cipher" => {
+                options.cipher = match args.next().as_deref() {
+                    Some("gcm") => CipherKind::Aes256Gcm,
+                    Some("gcm-siv") => CipherKind::Aes256GcmSiv,
+                    _ => return Err("--cipher must be 'gcm' or 'gcm-siv'".into()),
+                }
+            }
+            "--password" => return Err("Passwords are not accepted on the command line; use stdin or --keyfile".into()),
+            other => return Err(format!("Unknown option '{}'", other)),
+        }
+    }
+    Ok(Args { command, path, keyfile, identity, options, unlock_options, output, decoy_dir, other, file })
+}
+
+fn read_password(keyfile: Option<&str>) -> Result<String, Error> {
+    let mut password = match keyfile {
+        Some(file) => std::fs::read_to_string(file).map_err(|e| format!("Failed to read keyfile '{}': {}", file, e))?,
+        None => {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line).map_err(|e| format!("Failed to read password from stdin: {}", e))?;
+            line
+        }
+    };
+    while password.ends_with(['\n', '\r']) {
+        password.pop();
+    }
+    if password.is_empty() {
+        return Err("Password is empty".into());
+    }
+    Ok(password)
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    let names = VaultNames::default();
+    match args.command.as_str() {
+        "lock" => {
+            let password = read_password(args.keyfile.as_deref())?;
+            let result = folder::lock_folder(&args.path, &names, &password, None, &args.options)?;
+            println!("Locked {} ({} files)", result.path, result.file_count);
+            for file in &result.skipped {
+                println!("  skipped {} (unreadable or not a regular file)", file);
+            }
+            for file in &result.deferred {
+                println!("  deferred {} (over the quick-lock limit; run finish)", file);
+            }
+        }
+        "finish" => {
+            let password = read_password(args.keyfile.as_deref())?;
+            let key = folder::folder_key(&args.path, &names, &password)?;
+            let result = folder::finish_lock(&args.path, &names, &key, &args.options)?;
+            println!("Locked {} ({} files)", result.path, result.file_count);
+            for file in &result.skipped {
+                println!("  skipped {} (gone or unreadable)", file);
+            }
+        }
+        "decoy" => {
+            let decoy_dir = args.decoy_dir.as_deref().ok_or("decoy needs --decoy-dir <dir>")?;
+            let password = read_password(None)?;
+            let decoy_password = read_password(None)?;
+            let result = folder::lock_with_decoy(&args.path, &names, decoy_dir, &decoy_password, &password, None, &args.options)?;
+            println!("Locked {} ({} decoy files)", result.path, result.file_count);
+        }
+        "unlock" => {
+            let result = match &args.identity {
+                Some(file) => folder::unlock_folder_with_identity(&args.path, &names, &Identity::from_file(file)?, &args.unlock_options, &mut |_, _| {})?,
+                None => {
+                    let password = read_password(args.keyfile.as_deref())?;
+                    folder::unlock_folder(&args.path, &names, &password, &args.unlock_options, &mut |_, _| {})?
+                }
+            };
+            match &args.unlock_options.output_dir {
+                Some(dir) => println!("Decrypted {} files from {} into {}", result.file_count, result.path, dir),
+                None => println!("Unlocked {} ({} files)", result.path, result.file_count),
+            }
+            for file in &result.missing {
+                println!("  missing {} (no ciphertext found)", file);
+            }
+            for failure in &result.failed {
+                println!("  failed {}: {}", failure.path, failure.error);
+            }
+            if !result.failed.is_empty() {
+                return Err(format!("{} files could not be restored and are still locked", result.failed.len()).into());
+            }
+        }
+        "archive" => {
+            let output = args.output.as_deref().ok_or("archive needs --output <file.slk>")?;
+            let password = read_password(args.keyfile.as_deref())?;
+            let result = folder::lock_to_archive(&args.path, &names, output, &password, None, &args.options)?;
+            println!("Archived {} files into {}", result.file_count, result.path);
+            for file in &result.skipped {
+                println!("  skipped {} (unreadable or not a regular file)", file);
+            }
+        }
+        "extract" => {
+            let dest = args.unlock_options.output_dir.as_deref().ok_or("extract needs --output-dir <dir>")?;
+            let password = read_password(args.keyfile.as_deref())?;
+            let result = folder::unlock_from_archive(&args.path, dest, &password, &mut |_, _| {})?;
+            println!("Extracted {} files into {}", result.file_count, result.path);
+        }
+        "import" => {
+            let dest = args.unlock_options.output_dir.as_deref().ok_or("import needs --output-dir <dir>")?;
+            let zip_password = read_password(None)?;
+            let password = read_password(None)?;
+            let result = folder::import_zip(&args.path, dest, &names, &zip_password, &password, None, &args.options)?;
+            println!("Imported {} files into {} and locked it", result.file_count, result.path);
+        }
+        "cat" => {
+            let file = args.file.as_deref().ok_or("cat needs --file <path inside the folder>")?;
+            let password = read_password(args.keyfile.as_deref())?;
+            let key = folder::folder_key(&args.path, &names, &password)?;
+            let plaintext = folder::decrypt_file_to_bytes(&args.path, &names, file, &key, args.unlock_options.retry)?;
+            io::stdout().write_all(&plaintext).map_err(|e| format!("Failed to write to stdout: {}", e))?;
+        }
+        "status" => {
+            let diagnosis = folder::diagnose_folder(&args.path, &names)?;
+            let state = match diagnosis.state {
+                FolderState::CleanLocked => "locked",
+                FolderState::CleanPlaintext => "unlocked",
+                FolderState::PartiallyLocked => "partially locked",
+                FolderState::OrphanedCiphertext => "orphaned ciphertext (metadata missing)",
+            };
+            println!("{}: {}", diagnosis.path, state);
+            for file in &diagnosis.inconsistent_files {
+                println!("  {}", file);
+            }
+            for file in &diagnosis.leftover_temp_files {
+                println!("  {} (left by an interrupted lock; removed by the next lock)", file);
+            }
+        }
+        "verify" => {
+            if !folder::verify_vault_root(&args.path, &names)? {
+                return Err(format!("{}: locked files were added, removed or altered since it was locked", args.path).into());
+            }
+            println!("{}: intact", args.path);
+        }
+        "compare" => {
+            let other = args.other.as_deref().ok_or("compare needs --with <other-path>")?;
+            let diff = folder::compare_vaults(&args.path, &names, other, &names)?;
+            if diff.identical {
+                println!("{} and {} match", args.path, other);
+                return Ok(());
+            }
+            if diff.metadata_differs {
+                println!("  metadata differs");
+            }
+            for (label, files) in [("only in first", &diff.only_in_a), ("only in second", &diff.only_in_b), ("differs", &diff.differing)] {
+                for file in files {
+                    println!("  {} ({})", file, label);
+                }
+            }
+            return Err(format!("{} and {} do not match", args.path, other).into());
+        }
+        "rebuild" => {
+            let password = read_password(args.keyfile.as_deref())?;
+            let result = folder::rebuild_metadata(&args.path, &names, &password)?;
+            println!("Rebuilt metadata for {} ({} files)", result.path, result.file_count);
+            for file in &result.skipped {
+                println!("  skipped {} (does not decrypt with this password)", file);
+            }
+        }
+        "upgrade-kdf" => {
+            let password = read_password(args.keyfile.as_deref())?;
+            let kdf = crypto::default_kdf_params(args.options.kdf);
+            let result = folder::upgrade_folder_kdf(&args.path, &names, &password, &kdf, None, &mut |_, _| {})?;
+            println!("Upgraded {} ({} files)", result.path, result.file_count);
+        }
+        "passwd" => {
+            let password = read_password(None)?;
+            let new_password = read_password(None)?;
+            let result = folder::rekey_folder(&args.path, &names, &password, &new_password, None)?;
+            println!("Changed the password of {} ({} files)", result.path, result.file_count);
+        }
+        "breach-filter" => {
+            let output = args.output.as_deref().ok_or("breach-filter needs --output <file>")?;
+            let list = std::fs::read_to_string(&args.path).map_err(|e| format!("Failed to read '{}': {}", args.path, e))?;
+            let lines: Vec<&str> = list.lines().filter(|l| !l.trim().is_empty()).collect();
+            let mut filter = BreachFilter::new(lines.len(), breach::DEFAULT_FALSE_POSITIVE_RATE);
+            for line in &lines {
+                filter.insert_hex(line)?;
+            }
+            std::fs::write(output, filter.to_bytes()).map_err(|e| format!("Failed to write '{}': {}", output, e))?;
+            println!("Wrote {} ({} hashes)", output, lines.len());
+        }
+        other => return Err(format!("Unknown command '{}'", other).into()),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use crate::error::{Error, ErrorCode};
+use sha1::{Digest, Sha1};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"SLBF";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 4 + 1 + 1 + 8;
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.001;
+
+// Bloom filter over SHA-1 digests of breached passwords, the form Have I Been
+// Pwned publishes. A match may be a false positive; a miss is certain.
+pub struct BreachFilter {
+    bits: Vec<u8>,
+    bit_count: u64,
+    hashes: u8,
+}
+
+impl BreachFilter {
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let n = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bit_count = ((-n * false_positive_rate.ln()) / (ln2 * ln2)).ceil().max(8.0) as u64;
+        let hashes = ((bit_count as f64 / n) * ln2).round().clamp(1.0, 32.0) as u8;
+        BreachFilter { bits: vec![0; bit_count.div_ceil(8) as usize], bit_count, hashes }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+            return Err("Not a breached-password filter".into());
+        }
+        if data[4] != VERSION {
+            return Err(format!("Unsupported breached-password filter version {}", data[4]).into());
+        }
+        let hashes = data[5];
+        let bit_count = u64::from_le_bytes(data[6..14].try_into().unwrap());
+        let bits = data[HEADER_LEN..].to_vec();
+        if hashes == 0 || bit_count == 0 || bits.len() as u64 != bit_count.div_ceil(8) {
+            return Err(Error::new(ErrorCode::Corrupted, "Breached-password filter is corrupted"));
+        }
+        Ok(BreachFilter { bits, bit_count, hashes })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let data = std::fs::read(path).map_err(|e| Error::io(&e, format!("Failed to read breached-password filter '{}': {}", path.display(), e)))?;
+        Self::from_bytes(&data)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.bits.len());
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.push(self.hashes);
+        out.extend_from_slice(&self.bit_count.to_le_bytes());
+        out.extend_from_slice(&self.bits);
+        out
+    }
+
+    pub fn insert_sha1(&mut self, digest: &[u8; 20]) {
+        for bit in self.positions(digest) {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    // One line of a hash list; a trailing `:count` is ignored.
+    pub fn insert_hex(&mut self, line: &str) -> Result<(), Error> {
+        let hex = line.split(':').next().unwrap_or_default().trim();
+        let digest = parse_sha1_hex(hex).ok_or_else(|| format!("Not a SHA-1 hash: '{}'", hex))?;
+        self.insert_sha1(&digest);
+        Ok(())
+    }
+
+    pub fn contains_sha1(&self, digest: &[u8; 20]) -> bool {
+        self.positions(digest).all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn contains_password(&self, password: &str) -> bool {
+        self.contains_sha1(&Sha1::digest(password.as_bytes()).into())
+    }
+
+    // Double hashing over the digest, which is already uniformly distributed.
+    fn positions(&self, digest: &[u8; 20]) -> impl Iterator<Item = u64> {
+        let h1 = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap()) | 1;
+        let bit_count = self.bit_count;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count)
+    }
+}
+
+fn parse_sha1_hex(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0u8; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundled() -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/breached-passwords.bloom")
+    }
+
+    #[test]
+    fn bundled_filter_flags_breached_passwords() {
+        let filter = BreachFilter::load(&bundled()).unwrap();
+        for password in ["password", "123456", "qwerty123", "P@ssw0rd", "letmein"] {
+            assert!(filter.contains_password(password), "{}", password);
+        }
+        assert!(!filter.contains_password("correct horse battery staple 9!Zq"));
+        assert!(BreachFilter::from_bytes(b"nope").is_err());
+    }
+
+    #[test]
+    fn filter_round_trips_through_bytes() {
+        let mut filter = BreachFilter::new(3, 0.001);
+        filter.insert_hex("5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8:3730471").unwrap();
+        assert!(filter.insert_hex("xyz").is_err());
+        let filter = BreachFilter::from_bytes(&filter.to_bytes()).unwrap();
+        assert!(filter.contains_password("password"));
+        assert!(!filter.contains_password("password1"));
+    }
+}
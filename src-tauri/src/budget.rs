@@ -0,0 +1,103 @@
+use std::sync::{Condvar, Mutex};
+
+const FALLBACK_BUDGET: u64 = 512 << 20;
+const MIN_BUDGET: u64 = 64 << 20;
+
+// Caps the file bytes held in memory at once by the threads encrypting a
+// folder. A file bigger than the whole budget waits until it can run alone.
+pub struct ByteBudget {
+    capacity: u64,
+    usage: Mutex<Usage>,
+    freed: Condvar,
+}
+
+#[derive(Default)]
+struct Usage {
+    in_flight: u64,
+    peak: u64,
+}
+
+pub struct BudgetPermit<'a> {
+    budget: &'a ByteBudget,
+    bytes: u64,
+}
+
+impl ByteBudget {
+    pub fn new(capacity: u64) -> Self {
+        ByteBudget { capacity: capacity.max(1), usage: Mutex::new(Usage::default()), freed: Condvar::new() }
+    }
+
+    pub fn acquire(&self, bytes: u64) -> BudgetPermit<'_> {
+        let bytes = bytes.min(self.capacity);
+        let mut usage = self.usage.lock().unwrap();
+        while usage.in_flight + bytes > self.capacity {
+            usage = self.freed.wait(usage).unwrap();
+        }
+        usage.in_flight += bytes;
+        usage.peak = usage.peak.max(usage.in_flight);
+        BudgetPermit { budget: self, bytes }
+    }
+
+    // Most bytes ever held at once, for diagnostics.
+    pub fn peak(&self) -> u64 {
+        self.usage.lock().unwrap().peak
+    }
+}
+
+impl Drop for BudgetPermit<'_> {
+    fn drop(&mut self) {
+        self.budget.usage.lock().unwrap().in_flight -= self.bytes;
+        self.budget.freed.notify_all();
+    }
+}
+
+// A quarter of the memory currently available, where the OS reports it.
+pub fn default_budget() -> u64 {
+    available_memory().map(|bytes| (bytes / 4).max(MIN_BUDGET)).unwrap_or(FALLBACK_BUDGET)
+}
+
+#[cfg(target_os = "linux")]
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn in_flight_bytes_stay_under_the_budget() {
+        let budget = ByteBudget::new(1000);
+        let in_flight = AtomicU64::new(0);
+        let max_seen = AtomicU64::new(0);
+        std::thread::scope(|s| {
+            for i in 0..8u64 {
+                let (budget, in_flight, max_seen) = (&budget, &in_flight, &max_seen);
+                s.spawn(move || {
+                    for _ in 0..20 {
+                        let size = 150 + i * 40;
+                        let _permit = budget.acquire(size);
+                        let now = in_flight.fetch_add(size, Ordering::SeqCst) + size;
+                        max_seen.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(std::time::Duration::from_micros(300));
+                        in_flight.fetch_sub(size, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert!(max_seen.load(Ordering::SeqCst) <= 1000);
+        assert!(budget.peak() <= 1000 && budget.peak() > 400);
+        // Larger than the whole budget: runs alone instead of deadlocking.
+        drop(budget.acquire(5000));
+        assert_eq!(budget.peak(), 1000);
+    }
+}
@@ -1,172 +1,3028 @@
-use crate::crypto;
-use crate::folder::{self, ProtectedFolder};
+use crate::events::{self, Action, StateChange};
+use securelock::breach::BreachFilter;
+use securelock::crypto;
+use securelock::error::{Error, ErrorCode};
+use securelock::fido::{HardwareKey, HmacSecretToken, UsbToken};
+use securelock::folder::{self, FolderDiagnosis, FolderInfo, HiddenPolicy, LockLimits, LockOptions, LockedFile, RebuildResult, ProtectedFolder, UnlockOptions, VaultComparison, VaultNames};
+use securelock::keychain::{KeyStore, OsKeychain};
+use securelock::recipient::Identity;
+use securelock::secret::SecretKey;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::sync::Mutex;
-use tauri::State;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Runtime, State};
+use zeroize::Zeroize;
+
+// What a failed command hands the frontend: the message as before, plus a
+// stable code and catalogue key to branch on.
+#[derive(Debug, Serialize)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message_key: &'static str,
+    pub message: String,
+}
+
+impl From<Error> for CommandError {
+    fn from(error: Error) -> Self {
+        CommandError { code: error.code, message_key: error.code.message_key(), message: error.message }
+    }
+}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        Error::from(message).into()
+    }
+}
+
+impl From<&str> for CommandError {
+    fn from(message: &str) -> Self {
+        Error::from(message).into()
+    }
+}
+
+fn master_locked() -> Error {
+    Error::new(ErrorCode::MasterLocked, "Master password not unlocked for this session")
+}
+
+fn incorrect_master_password() -> Error {
+    Error::new(ErrorCode::WrongPassword, "Incorrect master password")
+}
 
 pub struct AppState {
     pub folders: Mutex<Vec<String>>,
+    // Encrypted folder list while the master password is locked; the paths
+    // only appear in `folders` once it is unlocked.
+    pub sealed_folders: Mutex<Option<Vec<u8>>>,
     pub master_salt: Mutex<Option<Vec<u8>>>,
     pub master_verify_token: Mutex<Option<Vec<u8>>>,
-    pub master_key: Mutex<Option<[u8; 32]>>,
+    pub master_key: Mutex<Option<SecretKey>>,
+    // Keys to re-lock folders unlocked this session, by folder path; only kept
+    // while lock_on_hide is on.
+    pub session_keys: Mutex<HashMap<String, folder::RelockKey>>,
+    // Files opened with unlock_file while lock_on_hide is on. There is no way
+    // to re-lock one without its password, so lock_on_hide reports them.
+    pub unlocked_files: Mutex<HashSet<String>>,
+    // Folders unlocked for a fixed window, with what's needed to re-lock them.
+    pub timed_unlocks: Mutex<HashMap<String, TimedUnlock>>,
+    // Decrypted copies from unlock_to_tmpfs, by folder path.
+    pub temp_unlocks: Mutex<HashMap<String, PathBuf>>,
+    pub folder_settings: Mutex<HashMap<String, FolderSettings>>,
+    pub in_progress: Mutex<HashSet<String>>,
+    // Set while lock_all runs; raising the flag stops it after the current folder.
+    pub lock_all_cancel: Mutex<Option<Arc<AtomicBool>>>,
+    pub keychain: Box<dyn KeyStore>,
+    pub keychain_enabled: Mutex<bool>,
+    // When set, the master key is wrapped and unlocking also needs the token.
+    pub hardware_key: Mutex<Option<HardwareKey>>,
+    pub hardware_token: Box<dyn HmacSecretToken>,
+    // A new master password from prepare_master_password, held until it is
+    // committed or aborted; nothing is saved meanwhile.
+    pub pending_master: Mutex<Option<PendingMaster>>,
+    // Folders under this directory are stored relative to it, for portable
+    // setups where the drive letter or mount point changes.
+    pub base_dir: Mutex<Option<String>>,
+    // Files above this size are encrypted in chunks; None uses the library default.
+    pub stream_threshold: Mutex<Option<u64>>,
+    // Folder locks over these fail unless confirmed; None uses the library defaults.
+    pub lock_limits: Mutex<Option<LockLimits>>,
+    // Tray menu actions wait for the master password; see tray_gate.
+    pub tray_requires_master: Mutex<bool>,
+    // Hiding the window to the tray re-locks folders; see lock_on_hide.
+    pub lock_on_hide: Mutex<bool>,
+    // Checks that unlock_to_tmpfs copies really were overwritten when they are
+    // shredded; see folder::shred_file_checked.
+    pub verify_shred: Mutex<bool>,
+    // Set by hand in the config, for managed deployments: folder passwords no
+    // longer unlock anything, and every unlock goes through recover_folder.
+    pub master_only: bool,
+    // Set by hand in the config; checked against at master password setup.
+    pub breach_policy: BreachPolicy,
+    pub breach_filter: Option<String>,
+    // The filter shipped with the app, used unless the config names another.
+    pub bundled_breach_filter: Option<PathBuf>,
     pub config_path: String,
+    // Why the config couldn't be loaded, if it was set aside at startup.
+    pub config_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrayGate {
+    Allowed,
+    // The window is shown and asks for the master password first.
+    NeedsMaster,
+}
+
+pub struct PendingMaster {
+    salt: Vec<u8>,
+    key: SecretKey,
+    verify_token: Vec<u8>,
+}
+
+pub struct TimedUnlock {
+    pub relock: folder::RelockKey,
+    pub deadline: Instant,
+}
+
+// Marks a path as busy for as long as it is alive, so quitting can be refused
+// while files are half-written.
+pub struct OperationGuard<'a> {
+    state: &'a AppState,
+    path: String,
+}
+
+impl Drop for OperationGuard<'_> {
+    fn drop(&mut self) {
+        self.state.in_progress.lock().unwrap().remove(&self.path);
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FolderSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub names: Option<VaultNames>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hidden: Option<HiddenPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin: Option<FolderPin>,
+}
+
+// A PIN the app asks for before showing a folder's contents. It is a speed
+// bump against someone at an unlocked screen, not a security boundary: it sits
+// in the config as a fast salted hash and has nothing to do with the folder's
+// keys, so anyone with the files or the config can skip it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderPin {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl FolderPin {
+    fn new(pin: &str) -> Self {
+        let salt = crypto::generate_salt().to_vec();
+        FolderPin { hash: crypto::pin_hash(pin, &salt), salt }
+    }
+
+    fn matches(&self, pin: &str) -> bool {
+        crypto::pin_hash(pin, &self.salt) == self.hash
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct FolderList {
+    folders: Vec<String>,
+    #[serde(default)]
+    folder_settings: HashMap<String, FolderSettings>,
+}
+
+impl FolderList {
+    fn map_paths(self, f: impl Fn(&str) -> String) -> Self {
+        FolderList {
+            folders: self.folders.iter().map(|p| f(p)).collect(),
+            folder_settings: self.folder_settings.into_iter().map(|(p, s)| (f(&p), s)).collect(),
+        }
+    }
+
+    fn relative_to(self, base: Option<&Path>) -> Self {
+        let Some(base) = base else { return self };
+        self.map_paths(|p| match Path::new(p).strip_prefix(base) {
+            Ok(rel) if !rel.as_os_str().is_empty() => rel.to_string_lossy().to_string(),
+            _ => p.to_string(),
+        })
+    }
+
+    // Absolute entries are kept as they are, so mixed lists work.
+    fn resolved_against(self, base: &Path) -> Self {
+        self.map_paths(|p| if Path::new(p).is_absolute() { p.to_string() } else { base.join(p).to_string_lossy().to_string() })
+    }
+}
+
+// Relative entries resolve against the base directory, or the config file's
+// directory if none is set.
+fn resolve_base(base_dir: Option<&str>, config_path: &str) -> PathBuf {
+    match base_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(config_path).parent().map(Path::to_path_buf).unwrap_or_default(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreachPolicy {
+    #[default]
+    Off,
+    Warn,
+    Reject,
+}
+
+impl BreachPolicy {
+    fn is_off(&self) -> bool {
+        *self == BreachPolicy::Off
+    }
 }
 
+// Bump when a change to `Config` can't be read by older versions, and migrate
+// in `load_config`. 0 is a config written before the field existed.
+const CONFIG_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize, Default)]
 struct Config {
+    #[serde(default)]
+    version: u32,
     folders: Vec<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed_folders: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     master_salt: Option<Vec<u8>>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     master_verify_token: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    folder_settings: HashMap<String, FolderSettings>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    keychain_enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hardware_key: Option<HardwareKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stream_threshold: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lock_limits: Option<LockLimits>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    tray_requires_master: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    lock_on_hide: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    verify_shred: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    master_only: bool,
+    #[serde(default, skip_serializing_if = "BreachPolicy::is_off")]
+    breach_policy: BreachPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    breach_filter: Option<String>,
+}
+
+// A config that can't be read is copied aside before the app starts with an
+// empty one, so the next save can't destroy the user's folder list.
+fn load_config(config_path: &str) -> Result<Config, Error> {
+    let data = match fs::read_to_string(config_path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(Error::io(&e, format!("Failed to read config '{}': {}", config_path, e))),
+    };
+    let problem = match serde_json::from_str::<Config>(&data) {
+        Ok(config) if config.version <= CONFIG_VERSION => return Ok(config),
+        Ok(config) => format!("it was written by a newer version of SecureLock (config version {})", config.version),
+        Err(e) => format!("it could not be parsed ({})", e),
+    };
+    let backup = backup_config(config_path)?;
+    Err(format!("The config file was set aside because {}. The original is saved at '{}'.", problem, backup).into())
+}
+
+fn backup_config(config_path: &str) -> Result<String, Error> {
+    let stamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut backup = format!("{}.bak-{}", config_path, stamp);
+    let mut n = 1;
+    while Path::new(&backup).exists() {
+        backup = format!("{}.bak-{}-{}", config_path, stamp, n);
+        n += 1;
+    }
+    fs::copy(config_path, &backup).map_err(|e| Error::io(&e, format!("Failed to back up unreadable config '{}': {}", config_path, e)))?;
+    Ok(backup)
 }
 
 impl AppState {
     pub fn new(config_path: String) -> Self {
-        let (folders, master_salt, master_verify_token) =
-            if let Ok(data) = fs::read_to_string(&config_path) {
-                if let Ok(config) = serde_json::from_str::<Config>(&data) {
-                    (config.folders, config.master_salt, config.master_verify_token)
-                } else {
-                    (Vec::new(), None, None)
-                }
-            } else {
-                (Vec::new(), None, None)
-            };
+        Self::with_keychain(config_path, Box::new(OsKeychain))
+    }
+
+    pub fn with_keychain(config_path: String, keychain: Box<dyn KeyStore>) -> Self {
+        let (config, config_error) = match load_config(&config_path) {
+            Ok(config) => (config, None),
+            Err(e) => (Config::default(), Some(e.message)),
+        };
+        let list = FolderList { folders: config.folders, folder_settings: config.folder_settings }
+            .resolved_against(&resolve_base(config.base_dir.as_deref(), &config_path));
         AppState {
-            folders: Mutex::new(folders),
-            master_salt: Mutex::new(master_salt),
-            master_verify_token: Mutex::new(master_verify_token),
+            folders: Mutex::new(list.folders),
+            sealed_folders: Mutex::new(config.sealed_folders),
+            master_salt: Mutex::new(config.master_salt),
+            master_verify_token: Mutex::new(config.master_verify_token),
             master_key: Mutex::new(None),
+            session_keys: Mutex::new(HashMap::new()),
+            unlocked_files: Mutex::new(HashSet::new()),
+            timed_unlocks: Mutex::new(HashMap::new()),
+            temp_unlocks: Mutex::new(HashMap::new()),
+            folder_settings: Mutex::new(list.folder_settings),
+            in_progress: Mutex::new(HashSet::new()),
+            lock_all_cancel: Mutex::new(None),
+            keychain,
+            keychain_enabled: Mutex::new(config.keychain_enabled),
+            hardware_key: Mutex::new(config.hardware_key),
+            hardware_token: Box::new(UsbToken),
+            pending_master: Mutex::new(None),
+            base_dir: Mutex::new(config.base_dir),
+            stream_threshold: Mutex::new(config.stream_threshold),
+            lock_limits: Mutex::new(config.lock_limits),
+            tray_requires_master: Mutex::new(config.tray_requires_master),
+            lock_on_hide: Mutex::new(config.lock_on_hide),
+            verify_shred: Mutex::new(config.verify_shred),
+            master_only: config.master_only,
+            breach_policy: config.breach_policy,
+            breach_filter: config.breach_filter,
+            bundled_breach_filter: None,
             config_path,
+            config_error,
         }
     }
 
+    // With the master key in memory the folder list is written encrypted only.
+    // Without it, a previously sealed list is kept as is and anything added in
+    // the meantime is written in plaintext until the next unlock merges it in.
     fn save(&self) {
-        let folders = self.folders.lock().unwrap();
+        let master_key = self.master_key.lock().unwrap().clone();
+        let sealed = match &master_key {
+            Some(key) => self.seal_folders(key).ok(),
+            None => self.sealed_folders.lock().unwrap().clone(),
+        };
+        let plaintext = master_key.is_none() || sealed.is_none();
+        let list = if plaintext { self.stored_list() } else { FolderList::default() };
         let master_salt = self.master_salt.lock().unwrap();
         let master_verify_token = self.master_verify_token.lock().unwrap();
+        let keychain_enabled = self.keychain_enabled.lock().unwrap();
         let config = Config {
-            folders: folders.clone(),
+            version: CONFIG_VERSION,
+            folders: list.folders,
+            sealed_folders: sealed,
             master_salt: master_salt.clone(),
             master_verify_token: master_verify_token.clone(),
+            folder_settings: list.folder_settings,
+            keychain_enabled: *keychain_enabled,
+            hardware_key: self.hardware_key.lock().unwrap().clone(),
+            base_dir: self.base_dir.lock().unwrap().clone(),
+            stream_threshold: *self.stream_threshold.lock().unwrap(),
+            lock_limits: *self.lock_limits.lock().unwrap(),
+            tray_requires_master: *self.tray_requires_master.lock().unwrap(),
+            lock_on_hide: *self.lock_on_hide.lock().unwrap(),
+            verify_shred: *self.verify_shred.lock().unwrap(),
+            master_only: self.master_only,
+            breach_policy: self.breach_policy,
+            breach_filter: self.breach_filter.clone(),
         };
         if let Ok(json) = serde_json::to_string_pretty(&config) {
-            let _ = fs::write(&self.config_path, json);
+            let _ = folder::write_atomic(Path::new(&self.config_path), json.as_bytes());
+        }
+    }
+
+    // The folder list as written to disk, relative to the base directory.
+    fn stored_list(&self) -> FolderList {
+        let list = FolderList {
+            folders: self.folders.lock().unwrap().clone(),
+            folder_settings: self.folder_settings.lock().unwrap().clone(),
+        };
+        let base_dir = self.base_dir.lock().unwrap().clone();
+        list.relative_to(base_dir.as_deref().map(Path::new))
+    }
+
+    fn resolve_list(&self, list: FolderList) -> FolderList {
+        let base_dir = self.base_dir.lock().unwrap().clone();
+        list.resolved_against(&resolve_base(base_dir.as_deref(), &self.config_path))
+    }
+
+    fn seal_folders(&self, master_key: &[u8; 32]) -> Result<Vec<u8>, Error> {
+        let json = serde_json::to_vec(&self.stored_list()).map_err(|e| format!("Config serialization error: {}", e))?;
+        let config_key = crypto::config_key(master_key)?;
+        crypto::encrypt(&config_key, &json)
+    }
+
+    // Makes `key` the session's master key and decrypts the folder list with it.
+    fn unlock_master(&self, key: SecretKey) -> Result<(), Error> {
+        let sealed = self.sealed_folders.lock().unwrap().clone();
+        if let Some(sealed) = sealed {
+            let config_key = crypto::config_key(&key)?;
+            let json = crypto::decrypt(&config_key, &sealed)?;
+            let list: FolderList = serde_json::from_slice(&json).map_err(|e| Error::new(ErrorCode::Corrupted, format!("Invalid folder list: {}", e)))?;
+            let list = self.resolve_list(list);
+            let mut folders = self.folders.lock().unwrap();
+            let added_while_locked = std::mem::replace(&mut *folders, list.folders);
+            for path in added_while_locked {
+                if !folders.contains(&path) { folders.push(path); }
+            }
+            let mut settings = self.folder_settings.lock().unwrap();
+            let changed_while_locked = std::mem::replace(&mut *settings, list.folder_settings);
+            settings.extend(changed_while_locked);
+        }
+        *self.sealed_folders.lock().unwrap() = None;
+        *self.master_key.lock().unwrap() = Some(key);
+        self.save();
+        Ok(())
+    }
+
+    // Seals the folder list and forgets the master key, hiding the paths again.
+    fn lock_master(&self) {
+        let Some(key) = self.master_key.lock().unwrap().take() else { return };
+        if let Ok(sealed) = self.seal_folders(&key) {
+            *self.sealed_folders.lock().unwrap() = Some(sealed);
+            self.folders.lock().unwrap().clear();
+            self.folder_settings.lock().unwrap().clear();
+        }
+        self.save();
+    }
+
+    // Always false with the policy off. A filter that can't be loaded is an
+    // error rather than a pass, since the check was asked for.
+    fn password_breached(&self, password: &str) -> Result<bool, Error> {
+        if self.breach_policy == BreachPolicy::Off {
+            return Ok(false);
+        }
+        let path = self.breach_filter.as_ref().map(PathBuf::from).or_else(|| self.bundled_breach_filter.clone())
+            .ok_or("No breached-password filter is available")?;
+        Ok(BreachFilter::load(&path)?.contains_password(password))
+    }
+
+    // Checked by every command that opens a folder without the master key: its
+    // own password, an archive's, or an age identity. A dry run and a password
+    // change count too.
+    fn check_password_unlock(&self) -> Result<(), Error> {
+        if self.master_only {
+            return Err("Folder passwords are turned off by policy; unlock with the master password instead".into());
+        }
+        Ok(())
+    }
+
+    // For lock_on_hide: what re-locks `path` after an unlock, taken before the
+    // unlock removes the metadata. None while the option is off.
+    fn relock_key(&self, path: &str, names: &VaultNames, key: impl FnOnce() -> Result<SecretKey, Error>) -> Option<folder::RelockKey> {
+        if !*self.lock_on_hide.lock().unwrap() {
+            return None;
+        }
+        key().and_then(|key| folder::RelockKey::new(path, names, key)).ok()
+    }
+
+    // Keeps `relock` if the unlock it was taken for left the folder open.
+    fn keep_relock_key(&self, result: &Result<ProtectedFolder, Error>, relock: Option<folder::RelockKey>) {
+        if let (Ok(folder), Some(relock)) = (result, relock) {
+            if !folder.is_locked {
+                self.session_keys.lock().unwrap().insert(folder.path.clone(), relock);
+            }
+        }
+    }
+
+    pub fn begin_operation(&self, path: &str) -> Result<OperationGuard<'_>, Error> {
+        if !self.in_progress.lock().unwrap().insert(path.to_string()) {
+            return Err(Error::new(ErrorCode::Busy, format!("Another operation is already running on '{}'", path)));
+        }
+        Ok(OperationGuard { state: self, path: path.to_string() })
+    }
+
+    // Returns false when no lock_all is running.
+    pub fn cancel_lock_all(&self) -> bool {
+        match &*self.lock_all_cancel.lock().unwrap() {
+            Some(cancel) => {
+                cancel.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Whether a tray menu action may run now. The tray works without the window
+    // in front, so with the setting on it waits for the master password rather
+    // than acting for whoever is at the keyboard.
+    pub fn tray_gate(&self) -> TrayGate {
+        if *self.tray_requires_master.lock().unwrap() && self.master_key.lock().unwrap().is_none() {
+            TrayGate::NeedsMaster
+        } else {
+            TrayGate::Allowed
+        }
+    }
+
+    // Locking while the master password exists but isn't unlocked leaves the
+    // folder without a recovery key, which is easy to miss until it's needed.
+    fn warn_if_unrecoverable<R: Runtime>(&self, app: &AppHandle<R>, locked: Option<&ProtectedFolder>) {
+        if let Some(folder) = locked {
+            if !folder.has_recovery && self.master_verify_token.lock().unwrap().is_some() {
+                events::emit_no_recovery(app, &folder.path);
+            }
+        }
+    }
+
+    // Shreds and removes a folder's unlock_to_tmpfs copy, if it has one.
+    // Returns the files whose shredding couldn't be verified, with verify_shred on.
+    pub fn close_temp_unlock(&self, path: &str) -> Result<Vec<String>, Error> {
+        let dir = self.temp_unlocks.lock().unwrap().remove(path);
+        let verify = *self.verify_shred.lock().unwrap();
+        dir.map_or(Ok(Vec::new()), |dir| folder::remove_secure_temp_dir(&dir, verify))
+    }
+
+    // Every unlock_to_tmpfs copy, at the end of the session.
+    pub fn close_temp_unlocks(&self) {
+        let dirs: Vec<PathBuf> = self.temp_unlocks.lock().unwrap().drain().map(|(_, dir)| dir).collect();
+        for dir in dirs {
+            let _ = folder::remove_secure_temp_dir(&dir, false);
+        }
+    }
+
+    pub fn operations_in_progress(&self) -> Vec<String> {
+        self.in_progress.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn names_for(&self, path: &str) -> VaultNames {
+        let settings = self.folder_settings.lock().unwrap();
+        settings.get(path).and_then(|s| s.names.clone()).unwrap_or_default()
+    }
+
+    fn hidden_for(&self, path: &str) -> HiddenPolicy {
+        let settings = self.folder_settings.lock().unwrap();
+        settings.get(path).and_then(|s| s.hidden.clone()).unwrap_or_default()
+    }
+
+    // A hidden policy, stream threshold or lock limits passed with the call win
+    // over the folder's and app's settings.
+    fn lock_options_for(&self, path: &str, options: Option<LockOptions>) -> LockOptions {
+        let mut options = options.unwrap_or_default();
+        if options.hidden.is_none() {
+            options.hidden = Some(self.hidden_for(path));
+        }
+        if options.stream_threshold.is_none() {
+            options.stream_threshold = *self.stream_threshold.lock().unwrap();
+        }
+        if options.limits.is_none() {
+            options.limits = *self.lock_limits.lock().unwrap();
+        }
+        options
+    }
+
+    fn folder_status(&self, path: &str) -> ProtectedFolder {
+        let (label, note) = self.folder_settings.lock().unwrap().get(path).map(|s| (s.label.clone(), s.note.clone())).unwrap_or_default();
+        if let Err(problem) = folder::check_folder_path(path) {
+            return ProtectedFolder { path: path.to_string(), is_locked: false, file_count: 0, has_recovery: false, label, note, problem: Some(problem.message), ..Default::default() };
         }
+        let names = self.names_for(path);
+        let is_locked = folder::is_locked(path, &names);
+        let file_count = if is_locked { folder::get_locked_file_count(path, &names) } else { folder::count_files(path, &names, &self.hidden_for(path)) };
+        let has_recovery = if is_locked { folder::has_recovery_key(path, &names) } else { false };
+        ProtectedFolder { path: path.to_string(), is_locked, file_count, has_recovery, label, note, ..Default::default() }
     }
 }
 
 #[tauri::command]
 pub fn get_folders(state: State<'_, AppState>) -> Vec<ProtectedFolder> {
     let folders = state.folders.lock().unwrap();
-    folders.iter().map(|path| {
-        let is_locked = folder::is_locked(path);
-        let file_count = if is_locked { folder::get_locked_file_count(path) } else { folder::count_files(path) };
-        let has_recovery = if is_locked { folder::has_recovery_key(path) } else { false };
-        ProtectedFolder { path: path.clone(), is_locked, file_count, has_recovery }
-    }).collect()
+    folders.iter().map(|path| state.folder_status(path)).collect()
 }
 
 #[tauri::command]
-pub fn add_folder(path: String, state: State<'_, AppState>) -> Result<ProtectedFolder, String> {
+pub fn add_folder(path: String, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let path = folder::normalize_path(&path);
     let mut folders = state.folders.lock().unwrap();
     if folders.contains(&path) { return Err("Folder is already in the list".into()); }
-    if !std::path::Path::new(&path).is_dir() { return Err("Path is not a valid directory".into()); }
+    folder::check_folder_path(&path)?;
+    if let Some(other) = folder::overlapping_folder(&path, &folders) {
+        return Err(format!("Folder overlaps '{}', which is already in the list; locking one would encrypt the other's files", other).into());
+    }
     folders.push(path.clone());
     drop(folders);
     state.save();
-    let is_locked = folder::is_locked(&path);
-    let file_count = if is_locked { folder::get_locked_file_count(&path) } else { folder::count_files(&path) };
-    let has_recovery = if is_locked { folder::has_recovery_key(&path) } else { false };
-    Ok(ProtectedFolder { path, is_locked, file_count, has_recovery })
+    Ok(state.folder_status(&path))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddFolderStatus {
+    Added,
+    AlreadyPresent,
+    Invalid,
+    // Contains or is inside a folder already in the list.
+    Overlaps,
+}
+
+#[derive(Serialize)]
+pub struct AddFolderResult {
+    pub path: String,
+    pub status: AddFolderStatus,
+}
+
+#[tauri::command]
+pub fn add_folders(paths: Vec<String>, state: State<'_, AppState>) -> Vec<AddFolderResult> {
+    let mut folders = state.folders.lock().unwrap();
+    let mut results = Vec::with_capacity(paths.len());
+    for path in paths {
+        let path = folder::normalize_path(&path);
+        let status = if folders.contains(&path) {
+            AddFolderStatus::AlreadyPresent
+        } else if folder::check_folder_path(&path).is_err() {
+            AddFolderStatus::Invalid
+        } else if folder::overlapping_folder(&path, &folders).is_some() {
+            AddFolderStatus::Overlaps
+        } else {
+            folders.push(path.clone());
+            AddFolderStatus::Added
+        };
+        results.push(AddFolderResult { path, status });
+    }
+    let any_added = results.iter().any(|r| matches!(r.status, AddFolderStatus::Added));
+    drop(folders);
+    if any_added { state.save(); }
+    results
 }
 
 #[tauri::command]
-pub fn remove_folder(path: String, state: State<'_, AppState>) -> Result<(), String> {
+pub fn remove_folder(path: String, state: State<'_, AppState>) -> Result<(), CommandError> {
     let mut folders = state.folders.lock().unwrap();
     folders.retain(|f| f != &path);
     drop(folders);
+    state.folder_settings.lock().unwrap().remove(&path);
     state.save();
     Ok(())
 }
 
 #[tauri::command]
-pub fn lock_folder(path: String, password: String, state: State<'_, AppState>) -> Result<ProtectedFolder, String> {
+pub fn lock_folder<R: Runtime>(path: String, password: String, other_passwords: Option<Vec<String>>, options: Option<LockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    state.close_temp_unlock(&path)?;
     let master_key = state.master_key.lock().unwrap();
-    folder::lock_folder(&path, &password, master_key.as_ref())
+    let other_passwords: Vec<&str> = other_passwords.iter().flatten().map(String::as_str).collect();
+    let result = folder::lock_folder_with_passwords(&path, &state.names_for(&path), &password, &other_passwords, master_key.as_ref(), &state.lock_options_for(&path, options));
+    events::emit_folder(&app, Action::Lock, &result);
+    state.warn_if_unrecoverable(&app, result.as_ref().ok());
+    Ok(result?)
+}
+
+// Hides the folder's files in its reserve under `password` and locks copies of
+// `decoy_source` in their place under `decoy_password`.
+#[tauri::command(async)]
+pub fn lock_with_decoy<R: Runtime>(path: String, decoy_source: String, decoy_password: String, password: String, options: Option<LockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    let master_key = state.master_key.lock().unwrap().clone();
+    let result = folder::lock_with_decoy(&path, &state.names_for(&path), &decoy_source, &decoy_password, &password, master_key.as_ref(), &state.lock_options_for(&path, options));
+    events::emit_folder(&app, Action::Lock, &result);
+    state.warn_if_unrecoverable(&app, result.as_ref().ok());
+    Ok(result?)
 }
 
+// Password slots let several people share a folder, each with their own
+// password. Any password that unlocks the folder can add or remove slots.
 #[tauri::command]
-pub fn unlock_folder(path: String, password: String) -> Result<ProtectedFolder, String> {
-    folder::unlock_folder(&path, &password)
+pub fn add_password_slot(path: String, password: String, new_password: String, state: State<'_, AppState>) -> Result<usize, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    Ok(folder::add_password_slot(&path, &state.names_for(&path), &password, &new_password)?)
 }
 
 #[tauri::command]
-pub fn lock_all(password: String, state: State<'_, AppState>) -> Result<Vec<ProtectedFolder>, String> {
+pub fn remove_password_slot(path: String, password: String, slot: usize, state: State<'_, AppState>) -> Result<usize, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    Ok(folder::remove_password_slot(&path, &state.names_for(&path), &password, slot)?)
+}
+
+#[tauri::command]
+pub fn lock_matching<R: Runtime>(path: String, pattern: String, password: String, options: Option<LockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    let master_key = state.master_key.lock().unwrap();
+    let result = folder::lock_matching(&path, &state.names_for(&path), &pattern, &password, master_key.as_ref(), &state.lock_options_for(&path, options));
+    events::emit_folder(&app, Action::Lock, &result);
+    state.warn_if_unrecoverable(&app, result.as_ref().ok());
+    Ok(result?)
+}
+
+// Writes an encrypted `.slk` copy of the folder, by default next to it; the
+// folder itself stays as it is.
+#[tauri::command(async)]
+pub fn lock_to_archive(path: String, password: String, archive: Option<String>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let archive = archive.unwrap_or_else(|| format!("{}.slk", path.trim_end_matches(['/', '\\'])));
+    let _op = state.begin_operation(&path)?;
     let master_key = state.master_key.lock().unwrap().clone();
-    let folders = state.folders.lock().unwrap();
-    let mut results = Vec::new();
-    for path in folders.iter() {
-        if !folder::is_locked(path) {
-            match folder::lock_folder(path, &password, master_key.as_ref()) {
-                Ok(pf) => results.push(pf),
-                Err(e) => return Err(format!("Failed to lock '{}': {}", path, e)),
-            }
+    Ok(folder::lock_to_archive(&path, &state.names_for(&path), &archive, &password, master_key.as_ref(), &state.lock_options_for(&path, None))?)
+}
+
+#[tauri::command(async)]
+pub fn unlock_from_archive<R: Runtime>(archive: String, dest: String, password: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    state.check_password_unlock()?;
+    let mut on_progress = |done, total| events::emit_progress(&app, Action::Unlock, &dest, done, total);
+    Ok(folder::unlock_from_archive(&archive, &dest, &password, &mut on_progress)?)
+}
+
+// Moves a password-protected ZIP from another locker into a new SecureLock
+// folder at `dest`, locked under `password`, and adds it to the list.
+#[tauri::command(async)]
+pub fn import_external_locker<R: Runtime>(archive: String, dest: String, archive_password: String, password: String, options: Option<LockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let dest = folder::normalize_path(&dest);
+    {
+        let folders = state.folders.lock().unwrap();
+        if let Some(other) = folder::overlapping_folder(&dest, &folders) {
+            return Err(format!("Folder overlaps '{}', which is already in the list; locking one would encrypt the other's files", other).into());
         }
     }
-    Ok(results)
+    let _op = state.begin_operation(&dest)?;
+    let master_key = state.master_key.lock().unwrap().clone();
+    let result = folder::import_zip(&archive, &dest, &state.names_for(&dest), &archive_password, &password, master_key.as_ref(), &state.lock_options_for(&dest, options));
+    if result.is_ok() {
+        state.folders.lock().unwrap().push(dest.clone());
+        state.save();
+    }
+    events::emit_folder(&app, Action::Lock, &result);
+    state.warn_if_unrecoverable(&app, result.as_ref().ok());
+    Ok(result?)
+}
+
+// Async so the key derivation doesn't block the main thread and freeze the UI.
+#[tauri::command(async)]
+pub fn unlock_folder<R: Runtime>(path: String, password: String, options: Option<UnlockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    state.check_password_unlock()?;
+    let _op = state.begin_operation(&path)?;
+    let names = state.names_for(&path);
+    let mut on_progress = |done, total| events::emit_progress(&app, Action::Unlock, &path, done, total);
+    let options = options.unwrap_or_default();
+    // Taken before the unlock removes the metadata, for lock_on_hide.
+    let mut relock = None;
+    let result = events::derive_key_on_worker(&app, Some(&path), || folder::folder_key(&path, &names, &password))
+        .and_then(|key| {
+            relock = state.relock_key(&path, &names, || Ok(key.clone()));
+            folder::unlock_folder_with_key(&path, &names, &key, &options, &mut on_progress)
+        });
+    // A password the vault rejects may open hidden data in its reserve instead.
+    let result = match result {
+        Err(e) if folder::reserve_may_open(&e, &path, &names) => {
+            events::derive_key_on_worker(&app, Some(&path), || folder::unlock_hidden(&path, &names, &password, &options)).and_then(|r| r.ok_or(e))
+        }
+        result => result,
+    };
+    events::emit_folder(&app, Action::Unlock, &result);
+    state.keep_relock_key(&result, relock);
+    Ok(result?)
+}
+
+// Without a password the folder key comes from the master key, as in recovery.
+#[tauri::command(async)]
+pub fn add_file_to_locked<R: Runtime>(path: String, file: String, password: Option<String>, options: Option<LockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    let names = state.names_for(&path);
+    let key = match password {
+        Some(password) => events::derive_key_on_worker(&app, Some(&path), || folder::folder_key(&path, &names, &password))?,
+        None => {
+            let master_key = state.master_key.lock().unwrap().clone().ok_or_else(master_locked)?;
+            folder::folder_key_with_master(&path, &names, &master_key)?
+        }
+    };
+    let result = folder::add_file_to_locked(&path, &names, &file, &key, &state.lock_options_for(&path, options));
+    events::emit_folder(&app, Action::Lock, &result);
+    Ok(result?)
+}
+
+// Encrypts the files a quick lock (LockOptions::quick_lock_limit) deferred.
+// Without a password the folder key comes from the master key.
+#[tauri::command(async)]
+pub fn finish_lock<R: Runtime>(path: String, password: Option<String>, options: Option<LockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    let names = state.names_for(&path);
+    let key = match password {
+        Some(password) => events::derive_key_on_worker(&app, Some(&path), || folder::folder_key(&path, &names, &password))?,
+        None => {
+            let master_key = state.master_key.lock().unwrap().clone().ok_or_else(master_locked)?;
+            folder::folder_key_with_master(&path, &names, &master_key)?
+        }
+    };
+    let result = folder::finish_lock(&path, &names, &key, &state.lock_options_for(&path, options));
+    events::emit_folder(&app, Action::Lock, &result);
+    Ok(result?)
+}
+
+#[tauri::command(async)]
+pub fn unlock_dry_run<R: Runtime>(path: String, password: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    state.check_password_unlock()?;
+    let names = state.names_for(&path);
+    Ok(events::derive_key_on_worker(&app, Some(&path), || folder::unlock_dry_run(&path, &names, &password))?)
+}
+
+#[derive(Serialize)]
+pub struct TmpfsUnlock {
+    pub folder: ProtectedFolder,
+    // Where the decrypted copies are.
+    pub output_dir: String,
+}
+
+// Decrypts copies into memory-backed temporary storage and leaves the folder
+// locked. The copies are shredded by relock_tmpfs, when the folder is locked
+// again, or when the app exits; a second call replaces the first copy.
+#[tauri::command(async)]
+pub fn unlock_to_tmpfs<R: Runtime>(path: String, password: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<TmpfsUnlock, CommandError> {
+    state.check_password_unlock()?;
+    let _op = state.begin_operation(&path)?;
+    let names = state.names_for(&path);
+    let mut on_progress = |done, total| events::emit_progress(&app, Action::Unlock, &path, done, total);
+    let result = events::derive_key_on_worker(&app, Some(&path), || folder::folder_key(&path, &names, &password))
+        .and_then(|key| folder::unlock_to_tmpfs(&path, &names, &key, &mut on_progress));
+    let (folder, dir) = result?;
+    events::emit_folder(&app, Action::Unlock, &Ok(folder.clone()));
+    state.close_temp_unlock(&path)?;
+    let output_dir = dir.to_string_lossy().to_string();
+    state.temp_unlocks.lock().unwrap().insert(path, dir);
+    Ok(TmpfsUnlock { folder, output_dir })
 }
 
+// Returns the copies whose shredding couldn't be verified, with verify_shred on.
 #[tauri::command]
-pub fn setup_master_password(password: String, state: State<'_, AppState>) -> Result<(), String> {
-    if password.len() < 4 {
-        return Err("Master password must be at least 4 characters".into());
-    }
-    let salt = crypto::generate_salt();
-    let key = crypto::derive_key(&password, &salt)?;
-    let verify_token = crypto::create_verify_token(&key)?;
-    *state.master_salt.lock().unwrap() = Some(salt.to_vec());
-    *state.master_verify_token.lock().unwrap() = Some(verify_token);
-    *state.master_key.lock().unwrap() = Some(key);
-    state.save();
-    Ok(())
+pub fn relock_tmpfs(path: String, state: State<'_, AppState>) -> Result<Vec<String>, CommandError> {
+    Ok(state.close_temp_unlock(&path)?)
 }
 
 #[tauri::command]
-pub fn verify_master_password(password: String, state: State<'_, AppState>) -> Result<(), String> {
-    let salt_opt = state.master_salt.lock().unwrap().clone();
-    let token_opt = state.master_verify_token.lock().unwrap().clone();
-    let salt_vec = salt_opt.ok_or("No master password configured")?;
-    let token = token_opt.ok_or("No master password configured")?;
-    let salt: [u8; 32] = salt_vec.try_into().map_err(|_| "Invalid master salt")?;
-    let key = crypto::derive_key(&password, &salt)?;
-    if !crypto::verify_password(&key, &token) {
-        return Err("Incorrect master password".into());
+pub fn get_verify_shred(state: State<'_, AppState>) -> bool {
+    *state.verify_shred.lock().unwrap()
+}
+
+#[tauri::command]
+pub fn set_verify_shred(enabled: bool, state: State<'_, AppState>) {
+    *state.verify_shred.lock().unwrap() = enabled;
+    state.save();
+}
+
+// Unlocks like `unlock_folder`, then re-locks the folder under the same
+// password once `duration_secs` have passed; see `tick_timed_unlocks`.
+#[tauri::command(async)]
+pub fn unlock_folder_timed<R: Runtime>(path: String, password: String, duration_secs: u64, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    if duration_secs == 0 {
+        return Err("The unlock window must be at least a second".into());
     }
-    *state.master_key.lock().unwrap() = Some(key);
-    Ok(())
+    state.check_password_unlock()?;
+    let _op = state.begin_operation(&path)?;
+    let names = state.names_for(&path);
+    let relock = events::derive_key_on_worker(&app, Some(&path), || folder::folder_key(&path, &names, &password))
+        .and_then(|key| folder::RelockKey::new(&path, &names, key))?;
+    let mut on_progress = |done, total| events::emit_progress(&app, Action::Unlock, &path, done, total);
+    let result = folder::unlock_folder_with_key(&path, &names, relock.key(), &UnlockOptions::default(), &mut on_progress);
+    events::emit_folder(&app, Action::Unlock, &result);
+    let result = result?;
+    let deadline = Instant::now() + Duration::from_secs(duration_secs);
+    state.timed_unlocks.lock().unwrap().insert(path, TimedUnlock { relock, deadline });
+    Ok(result)
+}
+
+// Run about once a second by the app: reports the time left on each timed
+// unlock and re-locks the folders whose window has closed. A folder that was
+// locked by hand in the meantime is just forgotten; one that is busy is tried
+// again on the next tick.
+pub fn tick_timed_unlocks<R: Runtime>(app: &AppHandle<R>, state: &AppState) {
+    let now = Instant::now();
+    let expired: Vec<(String, TimedUnlock)> = {
+        let mut timers = state.timed_unlocks.lock().unwrap();
+        for (path, timer) in timers.iter().filter(|(_, t)| t.deadline > now) {
+            events::emit_countdown(app, path, timer.deadline.duration_since(now).as_secs_f64().ceil() as u64);
+        }
+        let due: Vec<String> = timers.iter().filter(|(_, t)| t.deadline <= now).map(|(p, _)| p.clone()).collect();
+        due.into_iter().filter_map(|p| timers.remove_entry(&p)).collect()
+    };
+    for (path, timer) in expired {
+        let names = state.names_for(&path);
+        if folder::is_locked(&path, &names) {
+            continue;
+        }
+        let Ok(_op) = state.begin_operation(&path) else {
+            state.timed_unlocks.lock().unwrap().insert(path, timer);
+            continue;
+        };
+        events::emit_countdown(app, &path, 0);
+        let result = folder::relock_folder(&path, &names, &timer.relock, &state.lock_options_for(&path, None));
+        events::emit_folder(app, Action::Lock, &result);
+    }
+}
+
+// Run by the app when its window is hidden to the tray. With lock_on_hide on,
+// re-locks every folder a key was kept for (by unlock_folder,
+// unlock_folder_timed, recover_folder or unlock_all_with_master), shreds
+// unlock_to_tmpfs copies and forgets the master key. Folders open without a
+// kept key (unlocked before the option was on, or with an age identity) and
+// files from unlock_file stay as they are; they are reported as LeftUnlocked
+// and sent in a LEFT_UNLOCKED event so the app can warn. Does nothing at all
+// while any operation is running; returns None when it didn't run.
+pub fn lock_on_hide<R: Runtime>(app: &AppHandle<R>, state: &AppState) -> Option<Vec<LockAllResult>> {
+    if !*state.lock_on_hide.lock().unwrap() || !state.operations_in_progress().is_empty() {
+        return None;
+    }
+    let mut keys: Vec<(String, folder::RelockKey)> = state.session_keys.lock().unwrap().drain().collect();
+    keys.extend(state.timed_unlocks.lock().unwrap().drain().map(|(path, timer)| (path, timer.relock)));
+    let mut results = Vec::new();
+    for (path, relock) in keys {
+        let names = state.names_for(&path);
+        if folder::is_locked(&path, &names) {
+            continue;
+        }
+        // Something started since the check; the key is kept for next time.
+        let Ok(_op) = state.begin_operation(&path) else {
+            results.push(LockAllResult { path: path.clone(), status: LockAllStatus::Failed, message: Some(format!("Another operation is already running on '{}'", path)) });
+            state.session_keys.lock().unwrap().insert(path, relock);
+            continue;
+        };
+        let result = folder::relock_folder(&path, &names, &relock, &state.lock_options_for(&path, None));
+        events::emit_folder(app, Action::Lock, &result);
+        results.push(match result {
+            Ok(_) => LockAllResult { path, status: LockAllStatus::Locked, message: None },
+            Err(e) => LockAllResult { path, status: LockAllStatus::Failed, message: Some(e.message) },
+        });
+    }
+    let mut left_unlocked: Vec<String> = state.folders.lock().unwrap().iter()
+        .filter(|path| !results.iter().any(|r| &r.path == *path))
+        .map(|path| state.folder_status(path))
+        .filter(|f| !f.is_locked && f.problem.is_none() && f.file_count > 0)
+        .map(|f| f.path)
+        .collect();
+    left_unlocked.extend(state.unlocked_files.lock().unwrap().drain().filter(|path| Path::new(path).is_file()));
+    if !left_unlocked.is_empty() {
+        events::emit_left_unlocked(app, &left_unlocked);
+    }
+    results.extend(left_unlocked.into_iter().map(|path| LockAllResult { path, status: LockAllStatus::LeftUnlocked, message: Some("No key was kept to lock it again; lock it with its password".into()) }));
+    state.close_temp_unlocks();
+    if state.master_key.lock().unwrap().is_some() {
+        state.lock_master();
+        events::emit(app, StateChange::master(Action::MasterLock));
+    }
+    Some(results)
 }
 
 #[tauri::command]
-pub fn has_master_password(state: State<'_, AppState>) -> bool {
-    state.master_salt.lock().unwrap().is_some()
+pub fn get_lock_on_hide(state: State<'_, AppState>) -> bool {
+    *state.lock_on_hide.lock().unwrap()
 }
 
+// Only folders unlocked after this is turned on have their keys kept; turning
+// it off forgets them again.
 #[tauri::command]
-pub fn is_master_unlocked(state: State<'_, AppState>) -> bool {
-    state.master_key.lock().unwrap().is_some()
+pub fn set_lock_on_hide(enabled: bool, state: State<'_, AppState>) {
+    *state.lock_on_hide.lock().unwrap() = enabled;
+    if !enabled {
+        state.session_keys.lock().unwrap().clear();
+        state.unlocked_files.lock().unwrap().clear();
+    }
+    state.save();
+}
+
+#[tauri::command(async)]
+pub fn check_folder_password<R: Runtime>(path: String, password: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<bool, CommandError> {
+    let names = state.names_for(&path);
+    Ok(events::derive_key_on_worker(&app, Some(&path), || folder::check_folder_password(&path, &names, &password))?)
+}
+
+#[tauri::command(async)]
+pub fn rekey_folder<R: Runtime>(path: String, old_password: String, new_password: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    state.check_password_unlock()?;
+    let _op = state.begin_operation(&path)?;
+    let master_key = state.master_key.lock().unwrap().clone();
+    let names = state.names_for(&path);
+    Ok(events::derive_key_on_worker(&app, Some(&path), || folder::rekey_folder(&path, &names, &old_password, &new_password, master_key.as_ref()))?)
+}
+
+// rekey_folder for the app's password change: a key kept for the session under
+// the old password is dropped, and the folder list is told.
+#[tauri::command(async)]
+pub fn change_folder_password<R: Runtime>(path: String, old_password: String, new_password: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    state.check_password_unlock()?;
+    let folder = rekey_folder(path.clone(), old_password, new_password, app.clone(), state.clone())?;
+    state.session_keys.lock().unwrap().remove(&path);
+    events::emit_folder(&app, Action::Lock, &Ok(folder.clone()));
+    Ok(folder)
+}
+
+// Re-encrypts the folder for stronger key derivation, by default the current
+// Argon2id defaults; measure_kdf_cost helps pick them. Run it again to resume
+// an interrupted upgrade.
+#[tauri::command(async)]
+pub fn upgrade_folder_kdf<R: Runtime>(path: String, password: String, params: Option<crypto::KdfParams>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    let master_key = state.master_key.lock().unwrap().clone();
+    let params = params.unwrap_or_else(crypto::kdf_params);
+    let mut on_progress = |done, total| events::emit_progress(&app, Action::Lock, &path, done, total);
+    let result = folder::upgrade_folder_kdf(&path, &state.names_for(&path), &password, &params, master_key.as_ref(), &mut on_progress);
+    events::emit_folder(&app, Action::Lock, &result);
+    Ok(result?)
 }
 
 #[tauri::command]
-pub fn check_recovery_key(path: String) -> bool {
-    folder::has_recovery_key(&path)
+pub fn lock_file<R: Runtime>(path: String, password: String, options: Option<LockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    let master_key = state.master_key.lock().unwrap();
+    let result = folder::lock_file(&path, &password, master_key.as_ref(), &state.lock_options_for(&path, options));
+    events::emit_folder(&app, Action::Lock, &result);
+    state.warn_if_unrecoverable(&app, result.as_ref().ok());
+    Ok(result?)
+}
+
+#[tauri::command]
+pub fn unlock_file<R: Runtime>(path: String, password: String, options: Option<UnlockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    state.check_password_unlock()?;
+    let _op = state.begin_operation(&path)?;
+    let result = folder::unlock_file(&path, &password, &options.unwrap_or_default());
+    events::emit_folder(&app, Action::Unlock, &result);
+    if result.is_ok() && *state.lock_on_hide.lock().unwrap() {
+        state.unlocked_files.lock().unwrap().insert(path);
+    }
+    Ok(result?)
 }
 
 #[tauri::command]
-pub fn recover_folder(path: String, state: State<'_, AppState>) -> Result<ProtectedFolder, String> {
+pub fn recover_file<R: Runtime>(path: String, options: Option<UnlockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let _op = state.begin_operation(&path)?;
     let master_key = state.master_key.lock().unwrap();
-    let key = master_key.as_ref().ok_or("Master password not unlocked for this session")?;
-    folder::unlock_folder_with_master_key(&path, key)
+    let key = master_key.as_ref().ok_or_else(master_locked)?;
+    let result = folder::unlock_file_with_master_key(&path, key, &options.unwrap_or_default());
+    events::emit_folder(&app, Action::Recover, &result);
+    Ok(result?)
+}
+
+#[tauri::command]
+pub fn preview_lock_all(state: State<'_, AppState>) -> Vec<ProtectedFolder> {
+    let folders = state.folders.lock().unwrap();
+    folders.iter().map(|path| state.folder_status(path)).filter(|f| !f.is_locked && f.file_count > 0).collect()
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockAllStatus {
+    Locked,
+    Failed,
+    // Not reached before cancel_lock_all.
+    Cancelled,
+    // Open with no key kept to re-lock it; only from lock_on_hide.
+    LeftUnlocked,
+}
+
+#[derive(Serialize)]
+pub struct LockAllResult {
+    pub path: String,
+    pub status: LockAllStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+// Locks every unlocked, non-empty folder, carrying on past failures. Async so
+// cancel_lock_all can get through while it runs; a cancelled run finishes the
+// folder in hand and reports the rest as cancelled.
+#[tauri::command(async)]
+pub fn lock_all<R: Runtime>(password: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<Vec<LockAllResult>, CommandError> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    {
+        let mut running = state.lock_all_cancel.lock().unwrap();
+        if running.is_some() {
+            return Err(Error::new(ErrorCode::Busy, "Another operation is already running on all folders").into());
+        }
+        *running = Some(cancel.clone());
+    }
+    let master_key = state.master_key.lock().unwrap().clone();
+    let folders = state.folders.lock().unwrap().clone();
+    let mut results = Vec::new();
+    for path in folders {
+        let names = state.names_for(&path);
+        let hidden = state.hidden_for(&path);
+        // Empty folders have nothing to protect and are left out of the report.
+        if folder::is_locked(&path, &names) || folder::count_files(&path, &names, &hidden) == 0 {
+            continue;
+        }
+        if cancel.load(Ordering::Relaxed) {
+            results.push(LockAllResult { path, status: LockAllStatus::Cancelled, message: None });
+            continue;
+        }
+        let options = state.lock_options_for(&path, None);
+        let result = state.begin_operation(&path)
+            .and_then(|_op| folder::lock_folder(&path, &names, &password, master_key.as_ref(), &options));
+        match result {
+            Ok(pf) => {
+                events::emit(&app, StateChange::folder(Action::LockAll, &pf));
+                state.warn_if_unrecoverable(&app, Some(&pf));
+                results.push(LockAllResult { path, status: LockAllStatus::Locked, message: None });
+            }
+            Err(e) => results.push(LockAllResult { path, status: LockAllStatus::Failed, message: Some(e.message) }),
+        }
+    }
+    *state.lock_all_cancel.lock().unwrap() = None;
+    Ok(results)
+}
+
+#[tauri::command]
+pub fn cancel_lock_all(state: State<'_, AppState>) -> bool {
+    state.cancel_lock_all()
+}
+
+#[derive(Serialize)]
+pub struct MasterSetup {
+    // The password is on the breached-password list; only ever true when the
+    // policy is to warn, since rejecting fails the setup instead.
+    pub breached: bool,
+}
+
+// Checks a new master password and derives its key, without saving anything.
+fn new_master(state: &AppState, password: &str) -> Result<(PendingMaster, bool), Error> {
+    if password.len() < 4 {
+        return Err("Master password must be at least 4 characters".into());
+    }
+    let breached = state.password_breached(password)?;
+    if breached && state.breach_policy == BreachPolicy::Reject {
+        return Err("This password appears in a list of breached passwords; choose another".into());
+    }
+    if state.sealed_folders.lock().unwrap().is_some() {
+        return Err(Error::new(ErrorCode::MasterLocked, "Unlock the current master password before replacing it"));
+    }
+    let salt = crypto::generate_salt();
+    let key = crypto::derive_key(password, &salt)?;
+    let verify_token = crypto::create_verify_token(&key)?;
+    Ok((PendingMaster { salt: salt.to_vec(), key, verify_token }, breached))
+}
+
+fn save_master(state: &AppState, pending: PendingMaster) -> Result<(), Error> {
+    if state.sealed_folders.lock().unwrap().is_some() {
+        return Err(Error::new(ErrorCode::MasterLocked, "Unlock the current master password before replacing it"));
+    }
+    if *state.keychain_enabled.lock().unwrap() {
+        state.keychain.store(&pending.key[..])?;
+    }
+    *state.master_salt.lock().unwrap() = Some(pending.salt);
+    *state.master_verify_token.lock().unwrap() = Some(pending.verify_token);
+    *state.hardware_key.lock().unwrap() = None;
+    state.unlock_master(pending.key)
+}
+
+// Sets up the master password in one step, for callers that have already had
+// it confirmed; the app goes through prepare_master_password instead.
+#[tauri::command]
+pub fn setup_master_password<R: Runtime>(password: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<MasterSetup, CommandError> {
+    let (pending, breached) = new_master(&state, &password)?;
+    save_master(&state, pending)?;
+    events::emit(&app, StateChange::master(Action::MasterSetup));
+    Ok(MasterSetup { breached })
+}
+
+// First half of setting up the master password: checks it and reports whether
+// it is breached, but saves nothing until commit_master_password. Replaces any
+// earlier prepared password.
+#[tauri::command]
+pub fn prepare_master_password(password: String, state: State<'_, AppState>) -> Result<MasterSetup, CommandError> {
+    let (pending, breached) = new_master(&state, &password)?;
+    *state.pending_master.lock().unwrap() = Some(pending);
+    Ok(MasterSetup { breached })
+}
+
+// Saves the prepared master password once `confirmation` matches it. A
+// mismatch keeps it prepared, so the confirmation can be typed again.
+#[tauri::command]
+pub fn commit_master_password<R: Runtime>(confirmation: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    let pending = state.pending_master.lock().unwrap().take().ok_or("No master password is waiting to be saved")?;
+    let confirmed = crypto::derive_key(&confirmation, &pending.salt).is_ok_and(|key| crypto::verify_password(&key, &pending.verify_token));
+    if !confirmed {
+        *state.pending_master.lock().unwrap() = Some(pending);
+        return Err("Passwords do not match".into());
+    }
+    save_master(&state, pending)?;
+    events::emit(&app, StateChange::master(Action::MasterSetup));
+    Ok(())
+}
+
+#[tauri::command]
+pub fn abort_master_password(state: State<'_, AppState>) {
+    *state.pending_master.lock().unwrap() = None;
+}
+
+#[tauri::command(async)]
+pub fn verify_master_password<R: Runtime>(password: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    let salt_opt = state.master_salt.lock().unwrap().clone();
+    let token_opt = state.master_verify_token.lock().unwrap().clone();
+    let salt = salt_opt.ok_or("No master password configured")?;
+    let token = token_opt.ok_or("No master password configured")?;
+    let mut key = events::derive_key_on_worker(&app, None, || crypto::derive_key(&password, &salt))?;
+    let hardware_key = state.hardware_key.lock().unwrap().clone();
+    if let Some(hardware_key) = hardware_key {
+        let secret = state.hardware_token.hmac_secret(&hardware_key.credential_id, &hardware_key.salt)?;
+        let wrapping_key = crypto::hardware_bound_key(&key, &secret)?;
+        key = crypto::unwrap_key(&wrapping_key, &hardware_key.wrapped_master, &salt)
+            .map_err(|_| incorrect_master_password())?;
+    }
+    if !crypto::verify_password(&key, &token) {
+        return Err(incorrect_master_password().into());
+    }
+    state.unlock_master(key)?;
+    events::emit(&app, StateChange::master(Action::MasterVerify));
+    Ok(())
+}
+
+// Puts the master key behind a FIDO2 security key as well as the password. The
+// master key itself is unchanged, so folders keep their recovery; it is wrapped
+// under a key mixed from a fresh password key and the token's hmac-secret
+// output, and the old salt is dropped so the password alone no longer gives it.
+// The keychain copy would bypass the token, so it is cleared.
+#[tauri::command(async)]
+pub fn enroll_hardware_key<R: Runtime>(password: String, app: AppHandle<R>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    if state.hardware_key.lock().unwrap().is_some() {
+        return Err("A security key is already enrolled".into());
+    }
+    let master_key = state.master_key.lock().unwrap().clone().ok_or_else(|| Error::new(ErrorCode::MasterLocked, "Unlock the master password first"))?;
+    let salt = state.master_salt.lock().unwrap().clone().ok_or("No master password configured")?;
+    let token = state.master_verify_token.lock().unwrap().clone().ok_or("No master password configured")?;
+    let check = events::derive_key_on_worker(&app, None, || crypto::derive_key(&password, &salt))?;
+    if !crypto::verify_password(&check, &token) {
+        return Err(incorrect_master_password().into());
+    }
+    let credential_id = state.hardware_token.enroll()?;
+    let token_salt = crypto::generate_salt();
+    let secret = state.hardware_token.hmac_secret(&credential_id, &token_salt)?;
+    let new_salt = crypto::generate_salt();
+    let password_key = events::derive_key_on_worker(&app, None, || crypto::derive_key(&password, &new_salt))?;
+    let wrapping_key = crypto::hardware_bound_key(&password_key, &secret)?;
+    let wrapped_master = crypto::wrap_key(&wrapping_key, &master_key, &new_salt)?;
+    let mut keychain_enabled = state.keychain_enabled.lock().unwrap();
+    if *keychain_enabled {
+        state.keychain.clear()?;
+        *keychain_enabled = false;
+    }
+    drop(keychain_enabled);
+    *state.master_salt.lock().unwrap() = Some(new_salt.to_vec());
+    *state.hardware_key.lock().unwrap() = Some(HardwareKey { credential_id, salt: token_salt.to_vec(), wrapped_master });
+    state.save();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn has_hardware_key(state: State<'_, AppState>) -> bool {
+    state.hardware_key.lock().unwrap().is_some()
+}
+
+// Storing the master key in the OS keychain is opt-in: it lets the app unlock
+// recovery at launch, but so can anyone signed in to this OS account.
+#[tauri::command]
+pub fn store_master_in_keychain(state: State<'_, AppState>) -> Result<(), CommandError> {
+    let master_key = state.master_key.lock().unwrap();
+    let key = master_key.as_ref().ok_or_else(master_locked)?;
+    if state.hardware_key.lock().unwrap().is_some() {
+        return Err("The master key can't be kept in the keychain while a security key is required".into());
+    }
+    state.keychain.store(&key[..])?;
+    drop(master_key);
+    *state.keychain_enabled.lock().unwrap() = true;
+    state.save();
+    Ok(())
+}
+
+// Returns false when the keychain isn't in use or holds nothing, so the
+// frontend can call it unconditionally at launch.
+#[tauri::command]
+pub fn load_master_from_keychain<R: Runtime>(app: AppHandle<R>, state: State<'_, AppState>) -> Result<bool, CommandError> {
+    if !*state.keychain_enabled.lock().unwrap() {
+        return Ok(false);
+    }
+    let token = state.master_verify_token.lock().unwrap().clone().ok_or("No master password configured")?;
+    let Some(mut bytes) = state.keychain.load()? else { return Ok(false) };
+    let mut key = SecretKey::zeroed();
+    let valid_len = bytes.len() == key.len();
+    if valid_len {
+        key.as_mut_bytes().copy_from_slice(&bytes);
+    }
+    bytes.zeroize();
+    if !valid_len || !crypto::verify_password(&key, &token) {
+        return Err("The keychain entry does not match the current master password".into());
+    }
+    state.unlock_master(key)?;
+    events::emit(&app, StateChange::master(Action::MasterVerify));
+    Ok(true)
+}
+
+// Forgets the master key for this session and removes it from the keychain,
+// which also turns keychain storage back off.
+#[tauri::command]
+pub fn lock_master<R: Runtime>(app: AppHandle<R>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    let mut keychain_enabled = state.keychain_enabled.lock().unwrap();
+    if *keychain_enabled {
+        state.keychain.clear()?;
+        *keychain_enabled = false;
+    }
+    drop(keychain_enabled);
+    state.lock_master();
+    events::emit(&app, StateChange::master(Action::MasterLock));
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct SessionKeys {
+    pub master: bool,
+    pub folders: Vec<String>,
+}
+
+#[tauri::command]
+pub fn list_session_keys(state: State<'_, AppState>) -> SessionKeys {
+    let mut folders: Vec<String> = state.session_keys.lock().unwrap().keys().cloned().collect();
+    folders.extend(state.timed_unlocks.lock().unwrap().keys().cloned());
+    folders.sort();
+    folders.dedup();
+    SessionKeys { master: state.master_key.lock().unwrap().is_some(), folders }
+}
+
+// Drops every key held in memory, master included; SecretKey zeroizes on drop.
+// The keychain copy, if any, is left alone. Timed unlocks lose their key too,
+// so those folders stay unlocked until locked by hand.
+#[tauri::command]
+pub fn clear_session_keys<R: Runtime>(app: AppHandle<R>, state: State<'_, AppState>) {
+    state.session_keys.lock().unwrap().clear();
+    state.timed_unlocks.lock().unwrap().clear();
+    state.lock_master();
+    events::emit(&app, StateChange::master(Action::MasterLock));
+}
+
+pub const PANIC_WIPE_CONFIRMATION: &str = "WIPE EVERYTHING";
+
+#[derive(Debug, Serialize)]
+pub struct WipeResult {
+    pub metadata_shredded: usize,
+    pub failed: Vec<String>,
+}
+
+// Destroys every key this app holds: session keys in memory, the keychain copy,
+// and the master salt, verify token and sealed folder list on disk, with the
+// old config and its backups shredded rather than just replaced. With
+// `shred_metadata` each tracked vault's metadata goes too, leaving its files
+// undecryptable by anyone. Only the folders known right now are reached, so
+// with the master password locked a sealed folder list can't be walked. Keeps
+// going past failures and reports them; it never waits on running operations.
+#[tauri::command]
+pub fn panic_wipe<R: Runtime>(confirmation: String, shred_metadata: bool, app: AppHandle<R>, state: State<'_, AppState>) -> Result<WipeResult, CommandError> {
+    if confirmation != PANIC_WIPE_CONFIRMATION {
+        return Err(format!("Type '{}' to confirm the wipe", PANIC_WIPE_CONFIRMATION).into());
+    }
+    let folders = std::mem::take(&mut *state.folders.lock().unwrap());
+    let settings = std::mem::take(&mut *state.folder_settings.lock().unwrap());
+    state.session_keys.lock().unwrap().clear();
+    state.timed_unlocks.lock().unwrap().clear();
+    state.close_temp_unlocks();
+    *state.pending_master.lock().unwrap() = None;
+    *state.master_key.lock().unwrap() = None;
+    *state.master_salt.lock().unwrap() = None;
+    *state.master_verify_token.lock().unwrap() = None;
+    *state.hardware_key.lock().unwrap() = None;
+    *state.sealed_folders.lock().unwrap() = None;
+
+    let mut failed = Vec::new();
+    let mut keychain_enabled = state.keychain_enabled.lock().unwrap();
+    if let Err(e) = state.keychain.clear() {
+        if *keychain_enabled { failed.push(e.message); }
+    }
+    *keychain_enabled = false;
+    drop(keychain_enabled);
+
+    let config_path = Path::new(&state.config_path);
+    let config_name = config_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let config_dir = config_path.parent().map(Path::to_path_buf).unwrap_or_default();
+    for entry in fs::read_dir(&config_dir).into_iter().flatten().flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(&config_name) {
+            if let Err(e) = folder::shred_file(&entry.path()) {
+                failed.push(format!("Failed to shred '{}': {}", entry.path().display(), e));
+            }
+        }
+    }
+    state.save();
+
+    let mut metadata_shredded = 0;
+    if shred_metadata {
+        for path in &folders {
+            let names = settings.get(path).and_then(|s| s.names.clone()).unwrap_or_default();
+            match folder::shred_metadata(path, &names) {
+                Ok(n) => metadata_shredded += n,
+                Err(e) => failed.push(e.message),
+            }
+        }
+    }
+    events::emit(&app, StateChange::master(Action::PanicWipe));
+    Ok(WipeResult { metadata_shredded, failed })
+}
+
+#[tauri::command]
+pub fn crypto_self_test() -> Vec<crypto::SelfTestResult> {
+    crypto::self_test()
+}
+
+// Milliseconds one key derivation takes here, with the given parameters or the
+// Argon2id defaults. Async, so the seconds it can take don't freeze the window.
+#[tauri::command(async)]
+pub fn measure_kdf_cost(params: Option<crypto::KdfParams>) -> Result<u64, CommandError> {
+    let elapsed = crypto::measure_kdf_cost(&params.unwrap_or_else(crypto::kdf_params))?;
+    Ok(elapsed.as_millis() as u64)
+}
+
+#[tauri::command]
+pub fn get_base_dir(state: State<'_, AppState>) -> Option<String> {
+    state.base_dir.lock().unwrap().clone()
+}
+
+#[tauri::command]
+pub fn get_stream_threshold(state: State<'_, AppState>) -> u64 {
+    state.stream_threshold.lock().unwrap().unwrap_or(folder::DEFAULT_STREAM_THRESHOLD)
+}
+
+// Lower saves memory on small machines; higher is a little faster. None
+// restores the default.
+#[tauri::command]
+pub fn set_stream_threshold(bytes: Option<u64>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    if bytes.is_some_and(|b| b < crypto::STREAM_CHUNK_SIZE as u64) {
+        return Err(format!("The streaming threshold can't be below the {} MiB chunk size", crypto::STREAM_CHUNK_SIZE >> 20).into());
+    }
+    *state.stream_threshold.lock().unwrap() = bytes;
+    state.save();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_lock_limits(state: State<'_, AppState>) -> LockLimits {
+    state.lock_limits.lock().unwrap().unwrap_or_default()
+}
+
+// A limit of None turns it off; passing no limits at all restores the defaults.
+#[tauri::command]
+pub fn set_lock_limits(limits: Option<LockLimits>, state: State<'_, AppState>) {
+    *state.lock_limits.lock().unwrap() = limits;
+    state.save();
+}
+
+// Turning the requirement on needs a master password to exist; turning it off
+// needs it unlocked, so it can't be switched off by the people it keeps out.
+#[tauri::command]
+pub fn set_tray_requires_master(enabled: bool, state: State<'_, AppState>) -> Result<(), CommandError> {
+    if enabled && state.master_verify_token.lock().unwrap().is_none() {
+        return Err("Set up a master password first".into());
+    }
+    if !enabled && state.master_key.lock().unwrap().is_none() {
+        return Err(Error::new(ErrorCode::MasterLocked, "Unlock the master password first").into());
+    }
+    *state.tray_requires_master.lock().unwrap() = enabled;
+    state.save();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_tray_requires_master(state: State<'_, AppState>) -> bool {
+    *state.tray_requires_master.lock().unwrap()
+}
+
+// Relative entries follow the base directory to its new location.
+#[tauri::command]
+pub fn set_base_dir(path: Option<String>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    if let Some(dir) = &path {
+        if !Path::new(dir).is_absolute() || !Path::new(dir).is_dir() {
+            return Err("Base directory must be an existing absolute path".into());
+        }
+    }
+    let stored = state.stored_list();
+    *state.base_dir.lock().unwrap() = path.map(|p| folder::normalize_path(&p));
+    let list = state.resolve_list(stored);
+    *state.folders.lock().unwrap() = list.folders;
+    *state.folder_settings.lock().unwrap() = list.folder_settings;
+    state.save();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_config_error(state: State<'_, AppState>) -> Option<String> {
+    state.config_error.clone()
+}
+
+#[tauri::command]
+pub fn has_master_password(state: State<'_, AppState>) -> bool {
+    state.master_salt.lock().unwrap().is_some()
+}
+
+#[tauri::command]
+pub fn is_master_unlocked(state: State<'_, AppState>) -> bool {
+    state.master_key.lock().unwrap().is_some()
+}
+
+#[tauri::command]
+pub fn is_master_only(state: State<'_, AppState>) -> bool {
+    state.master_only
+}
+
+#[tauri::command]
+pub fn check_recovery_key(path: String, state: State<'_, AppState>) -> bool {
+    folder::has_recovery_key(&path, &state.names_for(&path))
+}
+
+// Whether recover_folder can work on `path` right now: the folder has a
+// recovery key and the master password is unlocked.
+#[tauri::command]
+pub fn recovery_available(path: String, state: State<'_, AppState>) -> bool {
+    state.master_key.lock().unwrap().is_some() && folder::has_recovery_key(&path, &state.names_for(&path))
+}
+
+#[tauri::command]
+pub fn recover_folder<R: Runtime>(path: String, options: Option<UnlockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    let master_key = state.master_key.lock().unwrap();
+    let key = master_key.as_ref().ok_or_else(master_locked)?;
+    let names = state.names_for(&path);
+    let relock = state.relock_key(&path, &names, || folder::folder_key_with_master(&path, &names, key));
+    let mut on_progress = |done, total| events::emit_progress(&app, Action::Recover, &path, done, total);
+    let result = folder::unlock_folder_with_master_key(&path, &names, key, &options.unwrap_or_default(), &mut on_progress);
+    events::emit_folder(&app, Action::Recover, &result);
+    state.keep_relock_key(&result, relock);
+    Ok(result?)
+}
+
+// Recovery with an age identity file or SSH private key matching one of the
+// folder's recipients; no master password involved.
+#[tauri::command]
+pub fn recover_folder_with_identity<R: Runtime>(path: String, identity: String, options: Option<UnlockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<ProtectedFolder, CommandError> {
+    state.check_password_unlock()?;
+    let _op = state.begin_operation(&path)?;
+    let identity = Identity::from_file(&identity)?;
+    let mut on_progress = |done, total| events::emit_progress(&app, Action::Recover, &path, done, total);
+    let result = folder::unlock_folder_with_identity(&path, &state.names_for(&path), &identity, &options.unwrap_or_default(), &mut on_progress);
+    events::emit_folder(&app, Action::Recover, &result);
+    Ok(result?)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoverStatus {
+    Recovered,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize)]
+pub struct RecoverResult {
+    pub path: String,
+    pub status: RecoverStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+// Recovers one folder for recover_all and unlock_all_with_master, turning
+// anything that stops it into a Skipped or Failed result.
+fn recover_one<R: Runtime>(app: &AppHandle<R>, state: &AppState, master_key: &SecretKey, path: String) -> RecoverResult {
+    let names = state.names_for(&path);
+    let skipped = if !folder::is_locked(&path, &names) {
+        Some("Folder is not locked")
+    } else if !folder::has_recovery_key(&path, &names) {
+        Some("No recovery key found for this folder")
+    } else {
+        None
+    };
+    if let Some(reason) = skipped {
+        return RecoverResult { path, status: RecoverStatus::Skipped, message: Some(reason.into()) };
+    }
+    let result = state.begin_operation(&path)
+        .and_then(|_op| {
+            let relock = state.relock_key(&path, &names, || folder::folder_key_with_master(&path, &names, master_key));
+            let mut on_progress = |done, total| events::emit_progress(app, Action::Recover, &path, done, total);
+            let result = folder::unlock_folder_with_master_key(&path, &names, master_key, &UnlockOptions::default(), &mut on_progress);
+            state.keep_relock_key(&result, relock);
+            result
+        });
+    events::emit_folder(app, Action::Recover, &result);
+    match result {
+        Ok(folder) if !folder.failed.is_empty() => {
+            let message = format!("{} files could not be restored and are still locked", folder.failed.len());
+            RecoverResult { path, status: RecoverStatus::Failed, message: Some(message) }
+        }
+        Ok(_) => RecoverResult { path, status: RecoverStatus::Recovered, message: None },
+        Err(e) => RecoverResult { path, status: RecoverStatus::Failed, message: Some(e.message) },
+    }
+}
+
+// Recovers every tracked folder that has a recovery key, carrying on past
+// failures so one damaged vault doesn't block the rest.
+#[tauri::command]
+pub fn recover_all<R: Runtime>(app: AppHandle<R>, state: State<'_, AppState>) -> Result<Vec<RecoverResult>, CommandError> {
+    let master_key = state.master_key.lock().unwrap().clone().ok_or_else(master_locked)?;
+    let folders = state.folders.lock().unwrap().clone();
+    Ok(folders.into_iter().map(|path| recover_one(&app, &state, &master_key, path)).collect())
+}
+
+// Like recover_all for the given folders, but recovers several at once on a
+// few threads. Results come back in the order of `paths`; a path listed twice
+// is caught by the operation guard or found already unlocked.
+#[tauri::command(async)]
+pub fn unlock_all_with_master<R: Runtime>(paths: Vec<String>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<Vec<RecoverResult>, CommandError> {
+    let master_key = state.master_key.lock().unwrap().clone().ok_or_else(master_locked)?;
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1).min(paths.len()).max(1);
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..paths.len()).map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(i) else { break };
+                let result = recover_one(&app, &state, &master_key, path.clone());
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    Ok(results.into_inner().unwrap().into_iter().flatten().collect())
+}
+
+#[tauri::command]
+pub fn rebuild_metadata(path: String, password: String, state: State<'_, AppState>) -> Result<RebuildResult, CommandError> {
+    let _op = state.begin_operation(&path)?;
+    Ok(folder::rebuild_metadata(&path, &state.names_for(&path), &password)?)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpgradeStatus {
+    Upgraded,
+    Current,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize)]
+pub struct UpgradeResult {
+    pub path: String,
+    pub status: UpgradeStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+// Moves every tracked folder's recovery key to the current wrap format. Safe to
+// run repeatedly; folders already upgraded report `current`.
+#[tauri::command]
+pub fn upgrade_recovery_blobs(state: State<'_, AppState>) -> Result<Vec<UpgradeResult>, CommandError> {
+    let master_key = state.master_key.lock().unwrap().clone().ok_or_else(master_locked)?;
+    let folders = state.folders.lock().unwrap().clone();
+    let results = folders.into_iter().map(|path| {
+        let names = state.names_for(&path);
+        if !folder::has_recovery_key(&path, &names) {
+            return UpgradeResult { path, status: UpgradeStatus::Skipped, message: Some("No recovery key found for this folder".into()) };
+        }
+        let result = state.begin_operation(&path)
+            .and_then(|_op| folder::upgrade_recovery_blob(&path, &names, &master_key));
+        match result {
+            Ok(true) => UpgradeResult { path, status: UpgradeStatus::Upgraded, message: None },
+            Ok(false) => UpgradeResult { path, status: UpgradeStatus::Current, message: None },
+            Err(e) => UpgradeResult { path, status: UpgradeStatus::Failed, message: Some(e.message) },
+        }
+    }).collect();
+    Ok(results)
+}
+
+#[tauri::command]
+pub fn export_recovery_blob(path: String, state: State<'_, AppState>) -> Result<String, CommandError> {
+    Ok(folder::export_recovery_blob(&path, &state.names_for(&path))?)
+}
+
+// Checked against the folder when the master password is unlocked.
+#[tauri::command]
+pub fn import_recovery_blob(path: String, blob: String, state: State<'_, AppState>) -> Result<(), CommandError> {
+    let _op = state.begin_operation(&path)?;
+    let master_key = state.master_key.lock().unwrap();
+    Ok(folder::import_recovery_blob(&path, &state.names_for(&path), &blob, master_key.as_ref())?)
+}
+
+#[tauri::command]
+pub fn diagnose_folder(path: String, state: State<'_, AppState>) -> Result<FolderDiagnosis, CommandError> {
+    Ok(folder::diagnose_folder(&path, &state.names_for(&path))?)
+}
+
+// Run once at startup: removes partly written ciphertext that a lock killed
+// mid-file left in any protected folder. A folder busy with another operation
+// is left for the next lock, which cleans up before it starts. Decrypted
+// copies a crashed session left in temporary storage are shredded too.
+pub fn clean_interrupted_locks(state: &AppState) {
+    folder::remove_stale_secure_temp_dirs();
+    let folders = state.folders.lock().unwrap().clone();
+    for path in folders {
+        let Ok(_op) = state.begin_operation(&path) else { continue };
+        let _ = folder::clean_interrupted_lock(&path, &state.names_for(&path));
+    }
+}
+
+#[tauri::command(async)]
+pub fn verify_vault_root(path: String, state: State<'_, AppState>) -> Result<bool, CommandError> {
+    Ok(folder::verify_vault_root(&path, &state.names_for(&path))?)
+}
+
+#[tauri::command(async)]
+pub fn compare_vaults(path_a: String, path_b: String, state: State<'_, AppState>) -> Result<VaultComparison, CommandError> {
+    Ok(folder::compare_vaults(&path_a, &state.names_for(&path_a), &path_b, &state.names_for(&path_b))?)
+}
+
+#[tauri::command]
+pub fn folder_info(path: String, state: State<'_, AppState>) -> Result<FolderInfo, CommandError> {
+    Ok(folder::folder_info(&path, &state.names_for(&path))?)
+}
+
+#[tauri::command]
+pub fn list_locked_files(path: String, state: State<'_, AppState>) -> Result<Vec<LockedFile>, CommandError> {
+    Ok(folder::list_locked_files(&path, &state.names_for(&path))?)
+}
+
+// A single file's contents for an in-app preview, decrypted in memory; the
+// folder stays locked and nothing is written to disk. Of the unlock options only
+// `retry` applies.
+#[tauri::command(async)]
+pub fn decrypt_file_to_bytes<R: Runtime>(path: String, file: String, password: String, options: Option<UnlockOptions>, app: AppHandle<R>, state: State<'_, AppState>) -> Result<Vec<u8>, CommandError> {
+    state.check_password_unlock()?;
+    let names = state.names_for(&path);
+    let key = events::derive_key_on_worker(&app, Some(&path), || folder::folder_key(&path, &names, &password))?;
+    Ok(folder::decrypt_file_to_bytes(&path, &names, &file, &key, options.unwrap_or_default().retry)?)
+}
+
+#[tauri::command]
+pub fn set_folder_names(path: String, names: Option<VaultNames>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    if !state.folders.lock().unwrap().contains(&path) {
+        return Err("Folder is not in the list".into());
+    }
+    if folder::is_locked(&path, &state.names_for(&path)) {
+        return Err("Unlock the folder before changing its file names".into());
+    }
+    if let Some(names) = &names {
+        names.validate()?;
+    }
+    state.folder_settings.lock().unwrap().entry(path).or_default().names = names;
+    state.save();
+    Ok(())
+}
+
+const MAX_LABEL_LEN: usize = 100;
+const MAX_NOTE_LEN: usize = 2000;
+const MIN_PIN_LEN: usize = 4;
+const MAX_PIN_LEN: usize = 32;
+
+// Purely cosmetic; the path stays the folder's key. A blank label clears it.
+#[tauri::command]
+pub fn set_folder_label(path: String, label: Option<String>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    if !state.folders.lock().unwrap().contains(&path) {
+        return Err("Folder is not in the list".into());
+    }
+    let label = label.map(|l| l.trim().to_string()).filter(|l| !l.is_empty());
+    if label.as_ref().is_some_and(|l| l.chars().count() > MAX_LABEL_LEN || l.chars().any(char::is_control)) {
+        return Err(format!("Labels must be at most {} characters on one line", MAX_LABEL_LEN).into());
+    }
+    state.folder_settings.lock().unwrap().entry(path).or_default().label = label;
+    state.save();
+    Ok(())
+}
+
+// A note for the user's own bookkeeping ("client NDA files, delete after
+// 2025"). It is kept in the config as plain text, not in the vault, and may
+// span lines. A blank note clears it.
+#[tauri::command]
+pub fn set_folder_note(path: String, note: Option<String>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    if !state.folders.lock().unwrap().contains(&path) {
+        return Err("Folder is not in the list".into());
+    }
+    let note = note.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    if note.as_ref().is_some_and(|n| n.chars().count() > MAX_NOTE_LEN || n.chars().any(|c| c.is_control() && c != '\n')) {
+        return Err(format!("Notes must be at most {} characters", MAX_NOTE_LEN).into());
+    }
+    state.folder_settings.lock().unwrap().entry(path).or_default().note = note;
+    state.save();
+    Ok(())
+}
+
+// Sets, changes or (with None or a blank PIN) removes a folder's PIN; see
+// FolderPin. Replacing an existing PIN needs it as `current_pin`.
+#[tauri::command]
+pub fn set_folder_pin(path: String, pin: Option<String>, current_pin: Option<String>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    if !state.folders.lock().unwrap().contains(&path) {
+        return Err("Folder is not in the list".into());
+    }
+    let pin = pin.filter(|p| !p.trim().is_empty());
+    if pin.as_ref().is_some_and(|p| !(MIN_PIN_LEN..=MAX_PIN_LEN).contains(&p.chars().count()) || p.chars().any(char::is_control)) {
+        return Err(format!("PINs must be {} to {} characters", MIN_PIN_LEN, MAX_PIN_LEN).into());
+    }
+    let mut settings = state.folder_settings.lock().unwrap();
+    let entry = settings.entry(path).or_default();
+    if entry.pin.as_ref().is_some_and(|old| !current_pin.as_deref().is_some_and(|p| old.matches(p))) {
+        return Err("Incorrect PIN".into());
+    }
+    entry.pin = pin.as_deref().map(FolderPin::new);
+    drop(settings);
+    state.save();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn has_folder_pin(path: String, state: State<'_, AppState>) -> bool {
+    state.folder_settings.lock().unwrap().get(&path).is_some_and(|s| s.pin.is_some())
+}
+
+// True for the right PIN, and for any PIN when the folder has none.
+#[tauri::command]
+pub fn verify_folder_pin(path: String, pin: String, state: State<'_, AppState>) -> Result<bool, CommandError> {
+    if !state.folders.lock().unwrap().contains(&path) {
+        return Err("Folder is not in the list".into());
+    }
+    let settings = state.folder_settings.lock().unwrap();
+    Ok(settings.get(&path).and_then(|s| s.pin.as_ref()).is_none_or(|p| p.matches(&pin)))
+}
+
+// None goes back to skipping dot-files. Takes effect at the next lock.
+#[tauri::command]
+pub fn set_hidden_policy(path: String, policy: Option<HiddenPolicy>, state: State<'_, AppState>) -> Result<(), CommandError> {
+    if !state.folders.lock().unwrap().contains(&path) {
+        return Err("Folder is not in the list".into());
+    }
+    if let Some(policy) = &policy {
+        policy.validate()?;
+    }
+    state.folder_settings.lock().unwrap().entry(path).or_default().hidden = policy;
+    state.save();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use securelock::vfs::OsFileSystem;
+    use tauri::test::{mock_app, MockRuntime};
+    use std::path::Path;
+    use std::sync::Arc;
+    use tauri::{App, Manager};
+
+    fn tmp() -> tempfile::TempDir {
+        tempfile::tempdir().unwrap()
+    }
+
+    // A keychain entry that lives as long as its clones.
+    #[derive(Default, Clone)]
+    struct MockKeychain(Arc<Mutex<Option<Vec<u8>>>>);
+
+    impl KeyStore for MockKeychain {
+        fn store(&self, secret: &[u8]) -> Result<(), Error> {
+            *self.0.lock().unwrap() = Some(secret.to_vec());
+            Ok(())
+        }
+
+        fn load(&self) -> Result<Option<Vec<u8>>, Error> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn clear(&self) -> Result<(), Error> {
+            *self.0.lock().unwrap() = None;
+            Ok(())
+        }
+    }
+
+    // An app managing the state a launch with `config_path` would load.
+    fn app_with(config_path: &Path, keychain: MockKeychain) -> App<MockRuntime> {
+        let app = mock_app();
+        app.manage(AppState::with_keychain(config_path.to_string_lossy().to_string(), Box::new(keychain)));
+        app
+    }
+
+    fn app_in(dir: &tempfile::TempDir) -> App<MockRuntime> {
+        app_with(&dir.path().join("config.json"), MockKeychain::default())
+    }
+
+    #[test]
+    fn add_folders_reports_each_path() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let (a, b) = (tmp(), tmp());
+        let a_path = a.path().to_str().unwrap().to_string();
+        let b_path = b.path().to_str().unwrap().to_string();
+        let missing = a.path().join("missing").to_str().unwrap().to_string();
+        let results = add_folders(vec![a_path.clone(), b_path.clone(), a_path.clone(), missing], app.state());
+        let statuses: Vec<_> = results.iter().map(|r| &r.status).collect();
+        assert!(matches!(statuses[..], [AddFolderStatus::Added, AddFolderStatus::Added, AddFolderStatus::AlreadyPresent, AddFolderStatus::Invalid]));
+        assert_eq!(*app.state::<AppState>().folders.lock().unwrap(), vec![a_path, b_path]);
+    }
+
+    #[test]
+    fn config_lives_at_the_given_path() {
+        let cfg = tmp();
+        let config_path = cfg.path().join("portable").join("data").join("config.json");
+        fs::create_dir_all(config_path.parent().unwrap()).unwrap();
+        let folder = tmp();
+        let folder_path = folder.path().to_str().unwrap().to_string();
+        let app = app_with(&config_path, MockKeychain::default());
+        add_folders(vec![folder_path.clone()], app.state());
+        assert!(config_path.exists());
+        assert_eq!(fs::read_dir(cfg.path()).unwrap().count(), 1);
+
+        let reloaded = app_with(&config_path, MockKeychain::default());
+        assert_eq!(*reloaded.state::<AppState>().folders.lock().unwrap(), vec![folder_path]);
+    }
+
+    #[test]
+    fn folder_commands_emit_their_transition() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let folder = tmp();
+        let path = folder.path().to_str().unwrap().to_string();
+        fs::write(folder.path().join("a.txt"), b"hello").unwrap();
+        lock_folder(path.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        assert!(unlock_folder(path.clone(), "wrong".into(), None, app.handle(), app.state()).is_err());
+        unlock_folder(path.clone(), "pw".into(), None, app.handle(), app.state()).unwrap();
+        lock_folder(path.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        let sent: Vec<_> = events::emitted(events::STATE_CHANGED).into_iter().filter(|c| c["path"] == path.as_str()).map(|c| (c["action"].clone(), c["is_locked"].clone())).collect();
+        assert_eq!(sent, vec![("lock".into(), true.into()), ("unlock".into(), false.into()), ("lock".into(), true.into())]);
+    }
+
+    #[test]
+    fn preview_lists_only_unlocked_folders() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let (locked, open, empty) = (tmp(), tmp(), tmp());
+        fs::write(locked.path().join("a.txt"), b"a").unwrap();
+        fs::write(open.path().join("b.txt"), b"b").unwrap();
+        fs::write(open.path().join("c.txt"), b"c").unwrap();
+        let paths: Vec<String> = [&locked, &open, &empty].iter().map(|d| d.path().to_str().unwrap().to_string()).collect();
+        add_folders(paths.clone(), app.state());
+        lock_folder(paths[0].clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        let preview = preview_lock_all(app.state());
+        assert_eq!(preview.len(), 1);
+        assert_eq!(preview[0].path, paths[1]);
+        assert_eq!(preview[0].file_count, 2);
+    }
+
+    #[test]
+    fn master_salt_of_any_supported_length_verifies() {
+        let cfg = tmp();
+        let config_path = cfg.path().join("config.json");
+        let salt = vec![7u8; 16];
+        let token = crypto::create_verify_token(&crypto::derive_key("master", &salt).unwrap()).unwrap();
+        fs::write(&config_path, serde_json::json!({ "folders": [], "master_salt": salt, "master_verify_token": token }).to_string()).unwrap();
+        let app = app_with(&config_path, MockKeychain::default());
+        assert!(verify_master_password("wrong".into(), app.handle(), app.state()).is_err());
+        verify_master_password("master".into(), app.handle(), app.state()).unwrap();
+        assert!(is_master_unlocked(app.state()));
+
+        fs::write(&config_path, serde_json::json!({ "folders": [], "master_salt": [1, 2, 3], "master_verify_token": [] }).to_string()).unwrap();
+        let app = app_with(&config_path, MockKeychain::default());
+        assert!(verify_master_password("master".into(), app.handle(), app.state()).is_err());
+    }
+
+    #[test]
+    fn operations_in_progress_hold_off_exit() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let state = app.state::<AppState>();
+        assert!(state.operations_in_progress().is_empty());
+        {
+            let _op = state.begin_operation("/vault").unwrap();
+            assert_eq!(state.operations_in_progress(), vec!["/vault".to_string()]);
+            assert!(state.begin_operation("/vault").is_err());
+            let err = unlock_folder("/vault".into(), "pw".into(), None, app.handle(), app.state()).unwrap_err();
+            assert!(err.message.contains("already running"), "{}", err.message);
+            // Hiding the window doesn't lock underneath it either.
+            set_lock_on_hide(true, app.state());
+            assert!(lock_on_hide(&app.handle(), &state).is_none());
+        }
+        assert!(state.operations_in_progress().is_empty());
+    }
+
+    #[test]
+    fn recover_all_reports_each_folder() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let state = app.state::<AppState>();
+        let master_key = crypto::derive_key("master", &crypto::generate_salt()).unwrap();
+        let names = VaultNames::default();
+        let dirs: Vec<_> = (0..4).map(|_| tmp()).collect();
+        for (i, dir) in dirs.iter().enumerate() {
+            let path = dir.path().to_str().unwrap();
+            fs::write(dir.path().join("a.txt"), b"data").unwrap();
+            match i {
+                0 => {
+                    folder::lock_folder(path, &names, "pw", Some(&master_key), &LockOptions::default()).unwrap();
+                }
+                1 => {
+                    folder::lock_folder(path, &names, "pw", None, &LockOptions::default()).unwrap();
+                }
+                2 => {}
+                _ => {
+                    folder::lock_folder(path, &names, "pw", Some(&master_key), &LockOptions::default()).unwrap();
+                    fs::write(dir.path().join("a.txt.locked"), b"garbage-garbage-garbage").unwrap();
+                }
+            }
+            state.folders.lock().unwrap().push(path.to_string());
+        }
+        assert!(recover_all(app.handle(), app.state()).is_err());
+        *state.master_key.lock().unwrap() = Some(master_key);
+        let results = recover_all(app.handle(), app.state()).unwrap();
+        let statuses: Vec<_> = results.iter().map(|r| &r.status).collect();
+        assert!(matches!(statuses[..], [RecoverStatus::Recovered, RecoverStatus::Skipped, RecoverStatus::Skipped, RecoverStatus::Failed]));
+        assert!(results[3].message.is_some());
+        assert_eq!(fs::read(dirs[0].path().join("a.txt")).unwrap(), b"data");
+        assert!(folder::is_locked(dirs[1].path().to_str().unwrap(), &names));
+    }
+
+    #[test]
+    fn recovery_available_needs_a_recovery_key_and_the_master_key() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let state = app.state::<AppState>();
+        let master_key = crypto::derive_key("master", &crypto::generate_salt()).unwrap();
+        let names = VaultNames::default();
+        let (with, without) = (tmp(), tmp());
+        for (dir, master) in [(&with, Some(&master_key)), (&without, None)] {
+            fs::write(dir.path().join("a.txt"), b"data").unwrap();
+            folder::lock_folder(dir.path().to_str().unwrap(), &names, "pw", master, &LockOptions::default()).unwrap();
+        }
+        let available = |dir: &tempfile::TempDir| recovery_available(dir.path().to_str().unwrap().into(), app.state());
+        assert!(!available(&with));
+        assert!(!available(&without));
+        *state.master_key.lock().unwrap() = Some(master_key);
+        assert!(available(&with));
+        assert!(!available(&without));
+    }
+
+    #[test]
+    fn unlock_all_with_master_recovers_several_folders_at_once() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let state = app.state::<AppState>();
+        let master_key = crypto::derive_key("master", &crypto::generate_salt()).unwrap();
+        let names = VaultNames::default();
+        let dirs: Vec<_> = (0..5).map(|_| tmp()).collect();
+        let paths: Vec<String> = dirs.iter().map(|d| d.path().to_str().unwrap().to_string()).collect();
+        for (i, (dir, path)) in dirs.iter().zip(&paths).enumerate() {
+            fs::write(dir.path().join("a.txt"), format!("data {}", i)).unwrap();
+            let master = if i == 3 { None } else { Some(&master_key) };
+            folder::lock_folder(path, &names, "pw", master, &LockOptions::default()).unwrap();
+        }
+        assert_eq!(unlock_all_with_master(paths.clone(), app.handle(), app.state()).err().unwrap().code, ErrorCode::MasterLocked);
+        *state.master_key.lock().unwrap() = Some(master_key);
+        let busy = state.begin_operation(&paths[4]).unwrap();
+        let results = unlock_all_with_master(paths.clone(), app.handle(), app.state()).unwrap();
+        drop(busy);
+        let statuses: Vec<_> = results.iter().map(|r| &r.status).collect();
+        assert!(matches!(statuses[..], [RecoverStatus::Recovered, RecoverStatus::Recovered, RecoverStatus::Recovered, RecoverStatus::Skipped, RecoverStatus::Failed]));
+        assert!(results[4].message.as_ref().unwrap().contains("already running"));
+        assert_eq!(results.iter().map(|r| r.path.as_str()).collect::<Vec<_>>(), paths);
+        for (i, dir) in dirs.iter().enumerate().take(3) {
+            assert_eq!(fs::read_to_string(dir.path().join("a.txt")).unwrap(), format!("data {}", i));
+        }
+        assert!(folder::is_locked(&paths[3], &names) && folder::is_locked(&paths[4], &names));
+        assert!(state.operations_in_progress().is_empty());
+    }
+
+    #[test]
+    fn master_key_round_trips_through_the_keychain() {
+        let cfg = tmp();
+        let config_path = cfg.path().join("config.json");
+        let keychain = MockKeychain::default();
+        let app = app_with(&config_path, keychain.clone());
+        assert!(store_master_in_keychain(app.state()).is_err());
+        setup_master_password("master".into(), app.handle(), app.state()).unwrap();
+        assert!(keychain.0.lock().unwrap().is_none());
+        assert!(!load_master_from_keychain(app.handle(), app.state()).unwrap());
+        store_master_in_keychain(app.state()).unwrap();
+        assert!(keychain.0.lock().unwrap().is_some());
+
+        // Next launch: the opt-in is kept and the key comes back from the keychain.
+        let app = app_with(&config_path, keychain.clone());
+        assert!(!is_master_unlocked(app.state()));
+        assert!(load_master_from_keychain(app.handle(), app.state()).unwrap());
+        assert!(is_master_unlocked(app.state()));
+
+        // A stale entry is rejected.
+        *keychain.0.lock().unwrap() = Some(vec![7u8; 32]);
+        *app.state::<AppState>().master_key.lock().unwrap() = None;
+        assert!(load_master_from_keychain(app.handle(), app.state()).is_err());
+        assert!(!is_master_unlocked(app.state()));
+
+        // An explicit lock clears it.
+        lock_master(app.handle(), app.state()).unwrap();
+        assert!(keychain.0.lock().unwrap().is_none());
+        assert!(!load_master_from_keychain(app.handle(), app.state()).unwrap());
+    }
+
+    #[test]
+    fn clearing_session_keys_leaves_none_resident() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        setup_master_password("master".into(), app.handle(), app.state()).unwrap();
+        set_lock_on_hide(true, app.state());
+        let (a, b) = (tmp(), tmp());
+        for dir in [&a, &b] {
+            let path = dir.path().to_str().unwrap().to_string();
+            fs::write(dir.path().join("f.txt"), b"x").unwrap();
+            folder::lock_folder(&path, &VaultNames::default(), "pw", None, &LockOptions::default()).unwrap();
+            unlock_folder(path, "pw".into(), None, app.handle(), app.state()).unwrap();
+        }
+        let listed = list_session_keys(app.state());
+        assert!(listed.master);
+        let mut expected: Vec<String> = [&a, &b].iter().map(|d| d.path().to_str().unwrap().to_string()).collect();
+        expected.sort();
+        assert_eq!(listed.folders, expected);
+
+        clear_session_keys(app.handle(), app.state());
+        let listed = list_session_keys(app.state());
+        assert!(!listed.master && listed.folders.is_empty());
+        let state = app.state::<AppState>();
+        assert!(state.master_key.lock().unwrap().is_none() && state.session_keys.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn folder_list_is_sealed_at_rest() {
+        let cfg = tmp();
+        let config_path = cfg.path().join("config.json");
+        let vault = tmp();
+        let vault_path = folder::normalize_path(vault.path().to_str().unwrap());
+        let app = app_in(&cfg);
+        setup_master_password("master".into(), app.handle(), app.state()).unwrap();
+        add_folder(vault_path.clone(), app.state()).unwrap();
+        assert!(!fs::read_to_string(&config_path).unwrap().contains(&vault_path));
+        lock_master(app.handle(), app.state()).unwrap();
+        assert!(get_folders(app.state()).is_empty());
+
+        let app = app_in(&cfg);
+        assert!(get_folders(app.state()).is_empty());
+        assert!(setup_master_password("other".into(), app.handle(), app.state()).is_err());
+        // Added while locked: kept in plaintext, merged on unlock.
+        let other = tmp();
+        let other_path = folder::normalize_path(other.path().to_str().unwrap());
+        add_folder(other_path.clone(), app.state()).unwrap();
+        verify_master_password("master".into(), app.handle(), app.state()).unwrap();
+        let listed: Vec<String> = get_folders(app.state()).into_iter().map(|f| f.path).collect();
+        assert_eq!(listed, vec![vault_path.clone(), other_path.clone()]);
+        let text = fs::read_to_string(&config_path).unwrap();
+        assert!(!text.contains(&vault_path) && !text.contains(&other_path));
+    }
+
+    #[test]
+    fn unlock_brackets_key_derivation_with_events() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let folder = tmp();
+        let path = folder.path().to_str().unwrap().to_string();
+        fs::write(folder.path().join("a"), b"a").unwrap();
+        folder::lock_folder(&path, &VaultNames::default(), "pw", None, &LockOptions::default()).unwrap();
+        assert!(unlock_folder(path.clone(), "bad".into(), None, app.handle(), app.state()).is_err());
+        unlock_folder(path.clone(), "pw".into(), None, app.handle(), app.state()).unwrap();
+        let stages: Vec<_> = events::emitted(events::KEY_DERIVATION).into_iter().filter(|d| d["path"] == path.as_str()).map(|d| d["done"].clone()).collect();
+        assert_eq!(stages, vec![false, true, false, true]);
+    }
+
+    #[test]
+    fn unparseable_config_is_backed_up() {
+        let cfg = tmp();
+        let config_path = cfg.path().join("config.json");
+        assert!(get_config_error(app_in(&cfg).state()).is_none());
+        fs::write(&config_path, b"{\"folders\": [\"/a\", ").unwrap();
+        let app = app_in(&cfg);
+        let error = get_config_error(app.state()).unwrap();
+        assert!(error.contains("could not be parsed"), "{}", error);
+        let vault = tmp();
+        add_folder(vault.path().to_str().unwrap().into(), app.state()).unwrap();
+        let backups: Vec<_> = fs::read_dir(cfg.path()).unwrap().filter_map(|e| e.ok()).filter(|e| e.file_name().to_string_lossy().contains(".bak-")).collect();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(fs::read(backups[0].path()).unwrap(), b"{\"folders\": [\"/a\", ");
+        assert!(fs::read_to_string(&config_path).unwrap().contains("\"version\": 1"));
+
+        // A config from a newer version is set aside too.
+        fs::write(&config_path, b"{\"version\": 99, \"folders\": []}").unwrap();
+        assert!(get_config_error(app_in(&cfg).state()).unwrap().contains("newer version"));
+        assert_eq!(fs::read_dir(cfg.path()).unwrap().filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().contains(".bak-")).count(), 2);
+    }
+
+    #[test]
+    fn relative_paths_follow_the_base_dir() {
+        let cfg = tmp();
+        let config_path = cfg.path().join("config.json");
+        let (old_base, new_base, elsewhere) = (tmp(), tmp(), tmp());
+        for base in [&old_base, &new_base] {
+            fs::create_dir(base.path().join("vault")).unwrap();
+        }
+        let path_of = |d: &tempfile::TempDir| d.path().to_str().unwrap().to_string();
+        let app = app_in(&cfg);
+        assert!(set_base_dir(Some("relative".into()), app.state()).is_err());
+        set_base_dir(Some(path_of(&old_base)), app.state()).unwrap();
+        add_folder(format!("{}/vault", path_of(&old_base)), app.state()).unwrap();
+        add_folder(path_of(&elsewhere), app.state()).unwrap();
+        let text = fs::read_to_string(&config_path).unwrap();
+        assert!(text.contains("\"vault\"") && text.contains(&path_of(&elsewhere)), "{}", text);
+
+        // The vault moved: point the base at its new location.
+        set_base_dir(Some(path_of(&new_base)), app.state()).unwrap();
+        let expected = vec![format!("{}/vault", path_of(&new_base)), path_of(&elsewhere)];
+        let listed = |app: &App<MockRuntime>| get_folders(app.state()).into_iter().map(|f| f.path).collect::<Vec<_>>();
+        assert_eq!(listed(&app), expected);
+        assert_eq!(listed(&app_in(&cfg)), expected);
+    }
+
+    #[test]
+    fn upgrade_recovery_blobs_rewraps_old_ones_once() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        setup_master_password("master".into(), app.handle(), app.state()).unwrap();
+        let master_key = app.state::<AppState>().master_key.lock().unwrap().clone().unwrap();
+        // A vault written before recovery keys were bound to the salt.
+        let vault = tmp();
+        let path = vault.path().to_str().unwrap().to_string();
+        let salt = crypto::generate_salt();
+        let key = crypto::derive_key("pw", &salt).unwrap();
+        fs::write(vault.path().join("a.txt.locked"), crypto::encrypt(&key, b"old").unwrap()).unwrap();
+        let legacy = serde_json::json!({
+            "salt": salt.to_vec(), "verify_token": crypto::create_verify_token(&key).unwrap(),
+            "recovery_key": crypto::wrap_key(&master_key, &key, b"").unwrap(),
+            "files": [{"original_name": "a.txt", "locked_name": "a.txt.locked", "relative_path": "a.txt"}],
+        });
+        let meta_path = vault.path().join(VaultNames::default().meta_file);
+        fs::write(&meta_path, legacy.to_string()).unwrap();
+        let plain = tmp();
+        add_folder(path.clone(), app.state()).unwrap();
+        add_folder(plain.path().to_str().unwrap().into(), app.state()).unwrap();
+
+        let statuses = |results: Vec<UpgradeResult>| results.into_iter().map(|r| serde_json::to_value(&r.status).unwrap()).collect::<Vec<_>>();
+        assert_eq!(statuses(upgrade_recovery_blobs(app.state()).unwrap()), vec!["upgraded", "skipped"]);
+        assert_eq!(statuses(upgrade_recovery_blobs(app.state()).unwrap()), vec!["current", "skipped"]);
+        let meta: folder::FolderMeta = serde_json::from_str(&fs::read_to_string(&meta_path).unwrap()).unwrap();
+        assert!(crypto::unwrap_key(&master_key, meta.recovery_key.as_ref().unwrap(), &salt).is_ok());
+        assert!(crypto::unwrap_key(&master_key, meta.recovery_key.as_ref().unwrap(), b"").is_err());
+        folder::unlock_folder_with_master_key(&path, &VaultNames::default(), &master_key, &UnlockOptions::default(), &mut |_, _| {}).unwrap();
+        assert_eq!(fs::read(vault.path().join("a.txt")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn command_errors_carry_a_code() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let vault = tmp();
+        fs::write(vault.path().join("a"), b"a").unwrap();
+        folder::lock_folder(vault.path().to_str().unwrap(), &VaultNames::default(), "pw", None, &LockOptions::default()).unwrap();
+        let err = unlock_folder(vault.path().to_str().unwrap().into(), "bad".into(), None, app.handle(), app.state()).unwrap_err();
+        assert_eq!((err.code, err.message_key), (ErrorCode::WrongPassword, "error.wrong_password"));
+        let json = serde_json::to_value(&err).unwrap();
+        assert_eq!(json["code"], "wrong_password");
+        assert_eq!(json["message"], "Incorrect password");
+        assert_eq!(recover_all(app.handle(), app.state()).err().unwrap().code, ErrorCode::MasterLocked);
+    }
+
+    #[test]
+    fn panic_wipe_destroys_master_material() {
+        let cfg = tmp();
+        let config_path = cfg.path().join("config.json");
+        let app = app_in(&cfg);
+        setup_master_password("master".into(), app.handle(), app.state()).unwrap();
+        store_master_in_keychain(app.state()).unwrap();
+        let vault = tmp();
+        let path = vault.path().to_str().unwrap().to_string();
+        fs::write(vault.path().join("a"), b"secret").unwrap();
+        add_folder(path.clone(), app.state()).unwrap();
+        lock_folder(path.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        fs::write(vault.path().join(".securelock.conflict-1"), b"{\"salt\":[1]}").unwrap();
+        fs::write(cfg.path().join("config.json.bak-1"), b"old").unwrap();
+
+        let err = panic_wipe("yes".into(), true, app.handle(), app.state()).unwrap_err();
+        assert!(err.message.contains(PANIC_WIPE_CONFIRMATION));
+        assert!(vault.path().join(".securelock").exists());
+
+        let wiped = panic_wipe(PANIC_WIPE_CONFIRMATION.into(), true, app.handle(), app.state()).unwrap();
+        assert!(wiped.failed.is_empty(), "{:?}", wiped.failed);
+        assert_eq!(wiped.metadata_shredded, 2);
+        assert!(!vault.path().join(".securelock").exists());
+        assert!(!vault.path().join(".securelock.conflict-1").exists());
+        assert!(vault.path().join("a.locked").exists());
+        assert!(!cfg.path().join("config.json.bak-1").exists());
+
+        let config: serde_json::Value = serde_json::from_str(&fs::read_to_string(&config_path).unwrap()).unwrap();
+        for key in ["master_salt", "master_verify_token", "sealed_folders", "keychain_enabled"] {
+            assert!(config.get(key).is_none(), "{} survived: {}", key, config);
+        }
+        assert_eq!(config["folders"], serde_json::json!([]));
+        assert!(!has_master_password(app.state()) && !is_master_unlocked(app.state()));
+        assert!(verify_master_password("master".into(), app.handle(), app.state()).is_err());
+        assert!(unlock_folder(path.clone(), "pw".into(), None, app.handle(), app.state()).is_err());
+        assert!(!has_master_password(app_in(&cfg).state()));
+    }
+
+    #[test]
+    fn hidden_policy_is_kept_per_folder() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let vault = tmp();
+        let path = vault.path().to_str().unwrap().to_string();
+        for name in ["a.txt", ".env"] {
+            fs::write(vault.path().join(name), name).unwrap();
+        }
+        add_folder(path.clone(), app.state()).unwrap();
+        assert_eq!(get_folders(app.state())[0].file_count, 1);
+        assert!(set_hidden_policy(path.clone(), Some(HiddenPolicy::Exclude(vec!["[".into()])), app.state()).is_err());
+        set_hidden_policy(path.clone(), Some(HiddenPolicy::None), app.state()).unwrap();
+        assert_eq!(get_folders(app.state())[0].file_count, 2);
+        assert_eq!(lock_folder(path.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap().file_count, 2);
+        assert!(vault.path().join(".env.locked").exists());
+
+        let app = app_in(&cfg);
+        unlock_folder(path.clone(), "pw".into(), None, app.handle(), app.state()).unwrap();
+        assert_eq!(lock_folder(path, "pw".into(), None, None, app.handle(), app.state()).unwrap().file_count, 2);
+    }
+
+    #[test]
+    fn cancel_halts_lock_all_after_the_current_folder() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let state = app.state::<AppState>();
+        let dirs: Vec<_> = (0..6).map(|i| {
+            let dir = tmp();
+            fs::write(dir.path().join("f"), format!("{}", i)).unwrap();
+            add_folder(dir.path().to_str().unwrap().into(), app.state()).unwrap();
+            dir
+        }).collect();
+        let empty = tmp();
+        add_folder(empty.path().to_str().unwrap().into(), app.state()).unwrap();
+        assert!(!state.cancel_lock_all());
+
+        let handle = app.handle();
+        let results = std::thread::scope(|s| {
+            let run = s.spawn(|| lock_all("pw".into(), handle.clone(), handle.state()).ok().unwrap());
+            while !dirs[0].path().join(".securelock").exists() {
+                std::thread::yield_now();
+            }
+            assert!(state.cancel_lock_all());
+            run.join().unwrap()
+        });
+        let status = |r: &LockAllResult| serde_json::to_value(&r.status).unwrap();
+        assert_eq!(results.len(), 6);
+        let locked = results.iter().take_while(|r| status(r) == "locked").count();
+        assert!((1..=2).contains(&locked), "{}", locked);
+        assert!(results[locked..].iter().all(|r| status(r) == "cancelled"));
+        for (dir, result) in dirs.iter().zip(&results) {
+            assert_eq!(dir.path().join(".securelock").exists(), status(result) == "locked");
+        }
+        assert!(state.lock_all_cancel.lock().unwrap().is_none());
+
+        // A later run starts fresh and picks up the rest, carrying on past failures.
+        let busy = state.begin_operation(dirs[4].path().to_str().unwrap()).unwrap();
+        let results = lock_all("pw".into(), app.handle(), app.state()).ok().unwrap();
+        drop(busy);
+        assert_eq!(results.len(), 6 - locked);
+        assert_eq!(status(&results[4 - locked]), "failed");
+        assert!(results[4 - locked].message.as_ref().unwrap().contains("already running"));
+        assert_eq!(results.iter().filter(|r| status(r) == "locked").count(), 5 - locked);
+        assert!(dirs[5].path().join(".securelock").exists());
+    }
+
+    #[test]
+    fn folder_label_round_trips_through_config() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let vault = tmp();
+        let path = vault.path().to_str().unwrap().to_string();
+        fs::write(vault.path().join("f"), b"x").unwrap();
+        assert!(set_folder_label(path.clone(), Some("Tax".into()), app.state()).is_err());
+        assert_eq!(add_folder(path.clone(), app.state()).unwrap().label, None);
+        set_folder_label(path.clone(), Some("  Tax Documents ".into()), app.state()).unwrap();
+        assert!(set_folder_label(path.clone(), Some("a\nb".into()), app.state()).is_err());
+        assert!(set_folder_label(path.clone(), Some("x".repeat(101)), app.state()).is_err());
+
+        let app = app_in(&cfg);
+        let folders = get_folders(app.state());
+        assert_eq!((folders[0].path.as_str(), folders[0].label.as_deref()), (path.as_str(), Some("Tax Documents")));
+        assert_eq!(serde_json::to_value(&folders[0]).unwrap()["label"], "Tax Documents");
+        lock_folder(path.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        assert_eq!(get_folders(app.state())[0].label.as_deref(), Some("Tax Documents"));
+
+        // A blank label clears it.
+        set_folder_label(path, Some(" ".into()), app.state()).unwrap();
+        let json = serde_json::to_value(&get_folders(app_in(&cfg).state())[0]).unwrap();
+        assert!(json.get("label").is_none());
+    }
+
+    #[test]
+    fn master_only_policy_refuses_folder_passwords() {
+        let cfg = tmp();
+        let config_path = cfg.path().join("config.json");
+        let vault = tmp();
+        let path = vault.path().to_str().unwrap().to_string();
+        fs::write(vault.path().join("a.txt"), b"data").unwrap();
+        let app = app_in(&cfg);
+        setup_master_password("master".into(), app.handle(), app.state()).unwrap();
+        add_folder(path.clone(), app.state()).unwrap();
+        lock_folder(path.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        let mut config: serde_json::Value = serde_json::from_slice(&fs::read(&config_path).unwrap()).unwrap();
+        config["master_only"] = true.into();
+        fs::write(&config_path, config.to_string()).unwrap();
+
+        let app = app_in(&cfg);
+        assert!(is_master_only(app.state()));
+        let err = unlock_folder(path.clone(), "pw".into(), None, app.handle(), app.state()).err().unwrap();
+        assert!(err.message.contains("turned off by policy"));
+        assert!(unlock_folder_timed(path.clone(), "pw".into(), 60, app.handle(), app.state()).is_err());
+        assert!(unlock_to_tmpfs(path.clone(), "pw".into(), app.handle(), app.state()).is_err());
+        assert!(decrypt_file_to_bytes(path.clone(), "a.txt".into(), "pw".into(), None, app.handle(), app.state()).is_err());
+        assert!(unlock_dry_run(path.clone(), "pw".into(), app.handle(), app.state()).is_err());
+        assert!(rekey_folder(path.clone(), "pw".into(), "pw2".into(), app.handle(), app.state()).is_err());
+        assert!(change_folder_password(path.clone(), "pw".into(), "pw2".into(), app.handle(), app.state()).is_err());
+        // Refused before the archive or identity file is even read.
+        let out = tmp();
+        let archive = out.path().join("a.slk").to_str().unwrap().to_string();
+        let dest = out.path().join("dest").to_str().unwrap().to_string();
+        let err = unlock_from_archive(archive, dest, "pw".into(), app.handle(), app.state()).err().unwrap();
+        assert!(err.message.contains("turned off by policy"));
+        let identity = out.path().join("identity.txt").to_str().unwrap().to_string();
+        let err = recover_folder_with_identity(path.clone(), identity, None, app.handle(), app.state()).err().unwrap();
+        assert!(err.message.contains("turned off by policy"));
+        assert!(folder::is_locked(&path, &VaultNames::default()));
+        verify_master_password("master".into(), app.handle(), app.state()).unwrap();
+        recover_folder(path.clone(), None, app.handle(), app.state()).unwrap();
+        assert_eq!(fs::read(vault.path().join("a.txt")).unwrap(), b"data");
+        assert!(fs::read_to_string(&config_path).unwrap().contains("\"master_only\": true"));
+    }
+
+    #[test]
+    fn breach_policy_governs_master_setup() {
+        let cfg = tmp();
+        let config_path = cfg.path().join("config.json");
+        let bundled = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/breached-passwords.bloom");
+        let app_with_policy = |policy: &str, filter: Option<&Path>| {
+            fs::write(&config_path, format!("{{\"folders\": [], \"breach_policy\": \"{}\"}}", policy)).unwrap();
+            let mut state = AppState::with_keychain(config_path.to_string_lossy().to_string(), Box::new(MockKeychain::default()));
+            state.bundled_breach_filter = filter.map(Path::to_path_buf);
+            let app = mock_app();
+            app.manage(state);
+            app
+        };
+        let app = app_with_policy("reject", Some(&bundled));
+        let err = setup_master_password("password1".into(), app.handle(), app.state()).err().unwrap();
+        assert!(err.message.contains("breached"));
+        assert!(!has_master_password(app.state()));
+        assert!(!setup_master_password("Gx7-unlisted-Horse".into(), app.handle(), app.state()).ok().unwrap().breached);
+        assert!(fs::read_to_string(&config_path).unwrap().contains("\"breach_policy\": \"reject\""));
+
+        let app = app_with_policy("warn", Some(&bundled));
+        assert!(setup_master_password("password1".into(), app.handle(), app.state()).ok().unwrap().breached);
+        let app = app_with_policy("off", Some(&bundled));
+        assert!(!setup_master_password("password1".into(), app.handle(), app.state()).ok().unwrap().breached);
+        // Checking is on but there is no filter to check against.
+        let app = app_with_policy("warn", None);
+        assert!(setup_master_password("password1".into(), app.handle(), app.state()).is_err());
+    }
+
+    #[test]
+    fn add_file_to_a_locked_folder() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        setup_master_password("master".into(), app.handle(), app.state()).ok().unwrap();
+        let vault = tmp();
+        let path = vault.path().to_str().unwrap().to_string();
+        let in_vault = |name: &str| vault.path().join(name).to_str().unwrap().to_string();
+        fs::write(vault.path().join("a"), b"a").unwrap();
+        add_folder(path.clone(), app.state()).unwrap();
+        lock_folder(path.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        let a_locked = fs::read(vault.path().join("a.locked")).unwrap();
+
+        fs::create_dir(vault.path().join("sub")).unwrap();
+        fs::write(vault.path().join("sub/new.txt"), b"new").unwrap();
+        assert!(add_file_to_locked(path.clone(), in_vault("sub/new.txt"), Some("bad".into()), None, app.handle(), app.state()).is_err());
+        let added = add_file_to_locked(path.clone(), in_vault("sub/new.txt"), Some("pw".into()), None, app.handle(), app.state()).unwrap();
+        assert_eq!(added.file_count, 2);
+        assert!(!vault.path().join("sub/new.txt").exists() && vault.path().join("sub/new.txt.locked").exists());
+        assert_eq!(fs::read(vault.path().join("a.locked")).unwrap(), a_locked);
+
+        // With the master key instead of the password.
+        fs::write(vault.path().join("b"), b"b").unwrap();
+        assert_eq!(add_file_to_locked(path.clone(), in_vault("b"), None, None, app.handle(), app.state()).unwrap().file_count, 3);
+
+        let names = VaultNames::default();
+        let key = folder::folder_key(&path, &names, "pw").unwrap();
+        let outside = tmp();
+        fs::write(outside.path().join("x"), b"x").unwrap();
+        assert!(folder::add_file_to_locked(&path, &names, outside.path().join("x").to_str().unwrap(), &key, &LockOptions::default()).unwrap_err().message.contains("not inside"));
+        assert!(folder::add_file_to_locked(&path, &names, &in_vault("a.locked"), &key, &LockOptions::default()).unwrap_err().message.contains("part of the vault"));
+        assert!(folder::add_file_to_locked(&path, &names, &in_vault(".securelock"), &key, &LockOptions::default()).is_err());
+        fs::write(vault.path().join("a"), b"again").unwrap();
+        assert!(folder::add_file_to_locked(&path, &names, &in_vault("a"), &key, &LockOptions::default()).unwrap_err().message.contains("already in the vault"));
+        fs::remove_file(vault.path().join("a")).unwrap();
+
+        unlock_folder(path, "pw".into(), None, app.handle(), app.state()).unwrap();
+        assert_eq!(fs::read(vault.path().join("sub/new.txt")).unwrap(), b"new");
+        assert_eq!(fs::read(vault.path().join("b")).unwrap(), b"b");
+        assert_eq!(fs::read(vault.path().join("a")).unwrap(), b"a");
+    }
+
+    #[test]
+    fn check_folder_password_leaves_the_folder_untouched() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let vault = tmp();
+        let path = vault.path().to_str().unwrap().to_string();
+        fs::write(vault.path().join("a"), b"a").unwrap();
+        assert!(check_folder_password(path.clone(), "pw".into(), app.handle(), app.state()).is_err());
+        folder::lock_folder(&path, &VaultNames::default(), "pw", None, &LockOptions::default()).unwrap();
+        let snapshot = || {
+            let mut files: Vec<_> = fs::read_dir(vault.path()).unwrap().flatten()
+                .map(|e| (e.path(), fs::read(e.path()).unwrap(), e.metadata().unwrap().modified().unwrap()))
+                .collect();
+            files.sort();
+            files
+        };
+        let before = snapshot();
+        assert!(check_folder_password(path.clone(), "pw".into(), app.handle(), app.state()).unwrap());
+        assert!(!check_folder_password(path.clone(), "nope".into(), app.handle(), app.state()).unwrap());
+        assert_eq!(snapshot(), before);
+        assert!(folder::is_locked(&path, &VaultNames::default()));
+    }
+
+    #[test]
+    fn timed_unlock_relocks_after_the_window() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let state = app.state::<AppState>();
+        let names = VaultNames::default();
+        setup_master_password("master".into(), app.handle(), app.state()).ok().unwrap();
+        let vault = tmp();
+        let path = vault.path().to_str().unwrap().to_string();
+        fs::write(vault.path().join("a"), b"a").unwrap();
+        add_folder(path.clone(), app.state()).unwrap();
+        lock_folder(path.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        let salt = || serde_json::from_str::<serde_json::Value>(&fs::read_to_string(vault.path().join(".securelock")).unwrap()).unwrap()["salt"].clone();
+        let original_salt = salt();
+
+        assert!(unlock_folder_timed(path.clone(), "bad".into(), 1, app.handle(), app.state()).is_err());
+        assert!(unlock_folder_timed(path.clone(), "pw".into(), 0, app.handle(), app.state()).is_err());
+        assert!(!unlock_folder_timed(path.clone(), "pw".into(), 1, app.handle(), app.state()).unwrap().is_locked);
+        assert_eq!(fs::read(vault.path().join("a")).unwrap(), b"a");
+        assert_eq!(list_session_keys(app.state()).folders, vec![path.clone()]);
+
+        tick_timed_unlocks(&app.handle(), &state);
+        assert!(!folder::is_locked(&path, &names));
+        let countdown: Vec<_> = events::emitted(events::RELOCK_COUNTDOWN).into_iter().filter(|c| c["path"] == path.as_str()).collect();
+        assert_eq!(countdown, vec![serde_json::json!({ "path": path, "remaining_secs": 1 })]);
+
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        tick_timed_unlocks(&app.handle(), &state);
+        assert!(folder::is_locked(&path, &names));
+        assert!(!vault.path().join("a").exists());
+        assert!(state.timed_unlocks.lock().unwrap().is_empty());
+        // Same salt and key as before, so the password and recovery still work.
+        assert_eq!(salt(), original_salt);
+        assert!(folder::has_recovery_key(&path, &names));
+        recover_folder(path.clone(), None, app.handle(), app.state()).unwrap();
+        assert_eq!(fs::read(vault.path().join("a")).unwrap(), b"a");
+
+        // Locked by hand before the window closed: the timer is dropped.
+        lock_folder(path.clone(), "pw2".into(), None, None, app.handle(), app.state()).unwrap();
+        unlock_folder_timed(path.clone(), "pw2".into(), 1, app.handle(), app.state()).unwrap();
+        lock_folder(path.clone(), "pw3".into(), None, None, app.handle(), app.state()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        tick_timed_unlocks(&app.handle(), &state);
+        assert!(state.timed_unlocks.lock().unwrap().is_empty());
+        assert!(folder::check_folder_password(&path, &names, "pw3").unwrap());
+    }
+
+    #[test]
+    fn nested_folders_are_flagged() {
+        let d = tmp();
+        let app = app_in(&d);
+        fs::create_dir_all(d.path().join("data/sub")).unwrap();
+        fs::create_dir_all(d.path().join("data2")).unwrap();
+        let data = d.path().join("data").to_str().unwrap().to_string();
+        add_folder(data, app.state()).unwrap();
+        let err = add_folder(d.path().join("data/sub").to_str().unwrap().into(), app.state()).err().unwrap();
+        assert!(err.message.contains("overlaps"), "{}", err.message);
+        // Another spelling of the same tree, and its parent, are caught too.
+        let dotted = d.path().join("data2/../data/sub").to_str().unwrap().to_string();
+        assert!(add_folder(dotted, app.state()).is_err());
+        assert!(add_folder(d.path().to_str().unwrap().into(), app.state()).is_err());
+        // A sibling sharing the name prefix is fine.
+        add_folder(d.path().join("data2").to_str().unwrap().into(), app.state()).unwrap();
+        let results = add_folders(vec![d.path().join("data/sub").to_str().unwrap().into()], app.state());
+        assert_eq!(serde_json::to_value(&results[0]).unwrap()["status"], "overlaps");
+        assert_eq!(app.state::<AppState>().folders.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn stream_threshold_setting_persists() {
+        let d = tmp();
+        let app = app_in(&d);
+        assert_eq!(get_stream_threshold(app.state()), folder::DEFAULT_STREAM_THRESHOLD);
+        assert!(set_stream_threshold(Some(10), app.state()).is_err());
+        set_stream_threshold(Some(8 << 20), app.state()).unwrap();
+        assert_eq!(get_stream_threshold(app_in(&d).state()), 8 << 20);
+    }
+
+    #[test]
+    fn file_paths_are_not_folders() {
+        let d = tmp();
+        let app = app_in(&d);
+        let file = d.path().join("notes.txt");
+        fs::write(&file, b"not a folder").unwrap();
+        let fp = file.to_str().unwrap().to_string();
+        let err = add_folder(fp.clone(), app.state()).err().unwrap();
+        assert!(err.message.contains("is a file, not a folder"), "{}", err.message);
+        let err = add_folder(d.path().join("missing").to_str().unwrap().into(), app.state()).err().unwrap();
+        assert!(err.message.contains("does not exist"), "{}", err.message);
+        assert_eq!(err.message_key, "error.not_found");
+        let results = add_folders(vec![fp], app.state());
+        assert_eq!(serde_json::to_value(&results[0]).unwrap()["status"], "invalid");
+        assert!(get_folders(app.state()).is_empty());
+
+        // A tracked folder replaced by a file is reported, not miscounted.
+        let dir = d.path().join("vault");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a.txt"), b"a").unwrap();
+        let dp = dir.to_str().unwrap().to_string();
+        assert_eq!(add_folder(dp.clone(), app.state()).unwrap().file_count, 1);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::write(&dir, b"now a file").unwrap();
+        let folders = get_folders(app.state());
+        assert_eq!(folders.len(), 1);
+        assert!(!folders[0].is_locked && folders[0].file_count == 0);
+        assert!(folders[0].problem.as_ref().unwrap().contains("is a file"));
+        let names = folder::VaultNames::default();
+        assert_eq!(folder::count_files(&dp, &names, &Default::default()), 0);
+        assert!(!folder::is_locked(&dp, &names));
+        for err in [
+            folder::lock_folder(&dp, &names, "pw", None, &folder::LockOptions { allow_system_paths: true, ..Default::default() }).err().unwrap(),
+            folder::unlock_folder(&dp, &names, "pw", &Default::default(), &mut |_, _| {}).err().unwrap(),
+            folder::folder_info(&dp, &names).err().unwrap(),
+            folder::diagnose_folder(&dp, &names).err().unwrap(),
+        ] {
+            assert!(err.message.contains("is a file, not a folder"), "{}", err);
+        }
+        fs::remove_file(&dir).unwrap();
+        assert!(get_folders(app.state())[0].problem.as_ref().unwrap().contains("does not exist"));
+    }
+
+    #[test]
+    fn tray_lock_all_can_require_the_master_password() {
+        let d = tmp();
+        let keychain = MockKeychain::default();
+        let app = app_with(&d.path().join("config.json"), keychain.clone());
+        let state = app.state::<AppState>();
+        assert_eq!(state.tray_gate(), TrayGate::Allowed);
+        assert!(set_tray_requires_master(true, app.state()).is_err());
+        setup_master_password("master".into(), app.handle(), app.state()).ok().unwrap();
+        set_tray_requires_master(true, app.state()).unwrap();
+        assert!(get_tray_requires_master(app.state()));
+        // Unlocked: the tray acts straight away.
+        assert_eq!(state.tray_gate(), TrayGate::Allowed);
+        lock_master(app.handle(), app.state()).unwrap();
+        assert_eq!(state.tray_gate(), TrayGate::NeedsMaster);
+        assert_eq!(set_tray_requires_master(false, app.state()).unwrap_err().code, ErrorCode::MasterLocked);
+
+        // The setting survives a restart, and the gate opens with the master password.
+        let app = app_with(&d.path().join("config.json"), keychain);
+        let state = app.state::<AppState>();
+        assert_eq!(state.tray_gate(), TrayGate::NeedsMaster);
+        assert!(verify_master_password("wrong".into(), app.handle(), app.state()).is_err());
+        assert_eq!(state.tray_gate(), TrayGate::NeedsMaster);
+        verify_master_password("master".into(), app.handle(), app.state()).unwrap();
+        assert_eq!(state.tray_gate(), TrayGate::Allowed);
+        set_tray_requires_master(false, app.state()).unwrap();
+        lock_master(app.handle(), app.state()).unwrap();
+        assert_eq!(state.tray_gate(), TrayGate::Allowed);
+    }
+
+    #[test]
+    fn kdf_cost_is_measured_in_milliseconds() {
+        let ms = measure_kdf_cost(None).unwrap();
+        assert!(ms > 0 && ms < 60_000, "{}", ms);
+        let silly = crypto::KdfParams { iterations: 1000, ..crypto::kdf_params() };
+        assert!(measure_kdf_cost(Some(silly)).is_err());
+    }
+
+    #[test]
+    fn lock_without_recovery_key_warns() {
+        let warned = |path: &str| events::emitted(events::NO_RECOVERY_WARNING).into_iter().filter(|w| w["path"] == path).count();
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let dirs: Vec<_> = (0..4).map(|i| {
+            let d = tmp();
+            fs::write(d.path().join("f"), format!("{}", i)).unwrap();
+            d
+        }).collect();
+        let path = |i: usize| dirs[i].path().to_str().unwrap().to_string();
+
+        // No master password at all, or an unlocked one: nothing to warn about.
+        lock_folder(path(0), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        assert_eq!(warned(&path(0)), 0);
+        setup_master_password("master".into(), app.handle(), app.state()).ok().unwrap();
+        lock_folder(path(1), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        assert_eq!(warned(&path(1)), 0);
+
+        // Set up but locked: the folder gets no recovery key, and that's flagged.
+        lock_master(app.handle(), app.state()).unwrap();
+        assert!(!lock_folder(path(2), "pw".into(), None, None, app.handle(), app.state()).unwrap().has_recovery);
+        assert_eq!(warned(&path(2)), 1);
+        add_folder(path(3), app.state()).unwrap();
+        lock_all("pw".into(), app.handle(), app.state()).ok().unwrap();
+        assert!(folder::is_locked(&path(3), &Default::default()));
+        assert_eq!(warned(&path(3)), 1);
+        // A failed lock says nothing.
+        assert!(lock_folder(path(2), "pw".into(), None, None, app.handle(), app.state()).is_err());
+        assert_eq!(warned(&path(2)), 1);
+    }
+
+    // A software authenticator: hmac-secret is a keyed hash under the
+    // credential id, and it can be "unplugged".
+    #[derive(Default, Clone)]
+    struct SoftToken(Arc<Mutex<(bool, Vec<Vec<u8>>)>>);
+
+    impl HmacSecretToken for SoftToken {
+        fn enroll(&self) -> Result<Vec<u8>, Error> {
+            let mut token = self.0.lock().unwrap();
+            if token.0 { return Err(securelock::fido::NO_TOKEN.into()); }
+            let id = crypto::generate_salt().to_vec();
+            token.1.push(id.clone());
+            Ok(id)
+        }
+
+        fn hmac_secret(&self, credential_id: &[u8], salt: &[u8]) -> Result<SecretKey, Error> {
+            let token = self.0.lock().unwrap();
+            if token.0 || !token.1.iter().any(|c| c == credential_id) { return Err(securelock::fido::NO_TOKEN.into()); }
+            let mut key = SecretKey::zeroed();
+            key.as_mut_bytes().copy_from_slice(blake3::keyed_hash(&credential_id.try_into().unwrap(), salt).as_bytes());
+            Ok(key)
+        }
+    }
+
+    fn app_with_token(dir: &tempfile::TempDir, keychain: MockKeychain, token: SoftToken) -> App<MockRuntime> {
+        let mut state = AppState::with_keychain(dir.path().join("config.json").to_string_lossy().to_string(), Box::new(keychain));
+        state.hardware_token = Box::new(token);
+        let app = mock_app();
+        app.manage(state);
+        app
+    }
+
+    #[test]
+    fn hardware_key_wraps_the_master_key() {
+        let cfg = tmp();
+        let keychain = MockKeychain::default();
+        let token = SoftToken::default();
+        let app = app_with_token(&cfg, keychain.clone(), token.clone());
+        setup_master_password("master".into(), app.handle(), app.state()).ok().unwrap();
+        let master = app.state::<AppState>().master_key.lock().unwrap().clone().unwrap();
+        store_master_in_keychain(app.state()).unwrap();
+        assert!(enroll_hardware_key("wrong".into(), app.handle(), app.state()).is_err());
+        enroll_hardware_key("master".into(), app.handle(), app.state()).unwrap();
+        assert!(has_hardware_key(app.state()));
+        // Enrolling takes the master key out of the keychain and keeps it out.
+        assert!(keychain.0.lock().unwrap().is_none());
+        assert!(store_master_in_keychain(app.state()).is_err());
+        assert!(enroll_hardware_key("master".into(), app.handle(), app.state()).is_err());
+
+        let app = app_with_token(&cfg, keychain.clone(), token.clone());
+        assert!(has_hardware_key(app.state()));
+        // The password alone is not enough.
+        token.0.lock().unwrap().0 = true;
+        let err = verify_master_password("master".into(), app.handle(), app.state()).unwrap_err();
+        assert!(err.message.contains("security key"), "{}", err.message);
+        token.0.lock().unwrap().0 = false;
+        assert!(verify_master_password("nope".into(), app.handle(), app.state()).is_err());
+        assert!(!is_master_unlocked(app.state()));
+        verify_master_password("master".into(), app.handle(), app.state()).unwrap();
+        assert_eq!(app.state::<AppState>().master_key.lock().unwrap().as_ref().unwrap()[..], master[..]);
+
+        // Another token with its own credentials can't stand in.
+        let other = SoftToken::default();
+        other.0.lock().unwrap().1.push(vec![1u8; 32]);
+        let app = app_with_token(&cfg, keychain, other);
+        assert!(verify_master_password("master".into(), app.handle(), app.state()).is_err());
+    }
+
+    #[test]
+    fn folder_pin_round_trips_through_config() {
+        let cfg = tmp();
+        let dir = tmp();
+        let app = app_in(&cfg);
+        let path = dir.path().to_str().unwrap().to_string();
+        assert!(set_folder_pin(path.clone(), Some("1234".into()), None, app.state()).is_err());
+        add_folder(path.clone(), app.state()).unwrap();
+        assert!(verify_folder_pin(path.clone(), "anything".into(), app.state()).unwrap());
+        assert!(set_folder_pin(path.clone(), Some("12".into()), None, app.state()).is_err());
+        set_folder_pin(path.clone(), Some("1234".into()), None, app.state()).unwrap();
+        assert!(has_folder_pin(path.clone(), app.state()));
+        assert!(verify_folder_pin(path.clone(), "1234".into(), app.state()).unwrap());
+        assert!(!verify_folder_pin(path.clone(), "4321".into(), app.state()).unwrap());
+        // Changing or clearing needs the current PIN.
+        assert!(set_folder_pin(path.clone(), Some("5555".into()), None, app.state()).is_err());
+        assert!(set_folder_pin(path.clone(), None, Some("0000".into()), app.state()).is_err());
+        set_folder_pin(path.clone(), Some("5555".into()), Some("1234".into()), app.state()).unwrap();
+
+        let app = app_in(&cfg);
+        assert!(verify_folder_pin(path.clone(), "5555".into(), app.state()).unwrap());
+        assert!(!verify_folder_pin(path.clone(), "1234".into(), app.state()).unwrap());
+        set_folder_pin(path.clone(), Some(" ".into()), Some("5555".into()), app.state()).unwrap();
+        assert!(!has_folder_pin(path, app.state()));
+    }
+
+    #[test]
+    fn unlock_to_tmpfs_cleans_up_on_relock() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap().to_string();
+        fs::write(d.path().join("secret.txt"), b"top secret").unwrap();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("sub/b.txt"), b"b").unwrap();
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let state = app.state::<AppState>();
+        lock_folder(p.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        assert!(unlock_to_tmpfs(p.clone(), "bad".into(), app.handle(), app.state()).is_err());
+        assert!(state.temp_unlocks.lock().unwrap().is_empty());
+        let view = unlock_to_tmpfs(p.clone(), "pw".into(), app.handle(), app.state()).unwrap();
+        assert!(view.folder.is_locked);
+        let out = PathBuf::from(&view.output_dir);
+        #[cfg(target_os = "linux")]
+        assert!(out.starts_with("/dev/shm"));
+        assert_eq!(fs::read(out.join("secret.txt")).unwrap(), b"top secret");
+        assert_eq!(fs::read(out.join("sub/b.txt")).unwrap(), b"b");
+        assert!(d.path().join("secret.txt.locked").exists() && !d.path().join("secret.txt").exists());
+
+        // A second view replaces the first.
+        let again = unlock_to_tmpfs(p.clone(), "pw".into(), app.handle(), app.state()).unwrap();
+        assert!(!out.exists());
+        let out = PathBuf::from(&again.output_dir);
+        set_verify_shred(true, app.state());
+        let unverified = relock_tmpfs(p.clone(), app.state()).unwrap();
+        #[cfg(target_os = "linux")]
+        assert!(unverified.is_empty(), "{:?}", unverified);
+        assert!(!out.exists());
+        assert!(get_verify_shred(app_in(&cfg).state()));
+        set_verify_shred(false, app.state());
+        relock_tmpfs(p.clone(), app.state()).unwrap();
+
+        // Closed by locking the folder again, and at exit.
+        let view = unlock_to_tmpfs(p.clone(), "pw".into(), app.handle(), app.state()).unwrap();
+        unlock_folder(p.clone(), "pw".into(), None, app.handle(), app.state()).unwrap();
+        assert!(Path::new(&view.output_dir).exists());
+        lock_folder(p.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        assert!(!Path::new(&view.output_dir).exists());
+        let view = unlock_to_tmpfs(p.clone(), "pw".into(), app.handle(), app.state()).unwrap();
+        state.close_temp_unlocks();
+        assert!(!Path::new(&view.output_dir).exists());
+        // Only directories it made itself are removed.
+        assert!(folder::remove_secure_temp_dir(d.path(), false).is_err());
+        assert!(d.path().exists());
+    }
+
+    #[test]
+    fn master_setup_can_be_tried_before_it_is_saved() {
+        let cfg = tmp();
+        let config = cfg.path().join("config.json");
+        let app = app_in(&cfg);
+        let before = fs::read(&config).ok();
+        assert!(prepare_master_password("abc".into(), app.state()).is_err());
+        prepare_master_password("master".into(), app.state()).unwrap();
+        abort_master_password(app.state());
+        assert_eq!(fs::read(&config).ok(), before);
+        assert!(!has_master_password(app.state()));
+        assert!(commit_master_password("master".into(), app.handle(), app.state()).is_err());
+
+        // Nothing is saved until the password is typed again correctly.
+        prepare_master_password("master".into(), app.state()).unwrap();
+        assert!(commit_master_password("mastr".into(), app.handle(), app.state()).unwrap_err().message.contains("match"));
+        assert_eq!(fs::read(&config).ok(), before);
+        assert!(!is_master_unlocked(app.state()));
+        commit_master_password("master".into(), app.handle(), app.state()).unwrap();
+        assert!(has_master_password(app.state()));
+        assert!(is_master_unlocked(app.state()));
+        assert_ne!(fs::read(&config).ok(), before);
+        let reloaded = app_in(&cfg);
+        verify_master_password("master".into(), reloaded.handle(), reloaded.state()).unwrap();
+    }
+
+    #[test]
+    fn change_password_keeps_the_recovery_key() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        setup_master_password("master".into(), app.handle(), app.state()).ok().unwrap();
+        let d = tmp();
+        let p = d.path().to_str().unwrap().to_string();
+        let names = VaultNames::default();
+        let meta_path = d.path().join(&names.meta_file);
+        fs::write(d.path().join("a.txt"), b"alpha").unwrap();
+        fs::write(d.path().join("b.txt"), b"beta").unwrap();
+        add_folder(p.clone(), app.state()).unwrap();
+        lock_folder(p.clone(), "old".into(), None, None, app.handle(), app.state()).unwrap();
+        let salt = folder::load_meta(&OsFileSystem, &meta_path).unwrap().salt;
+        assert!(change_folder_password(p.clone(), "wrong".into(), "new".into(), app.handle(), app.state()).is_err());
+        change_folder_password(p.clone(), "old".into(), "new".into(), app.handle(), app.state()).unwrap();
+        assert!(!d.path().join("a.txt").exists());
+        assert_ne!(folder::load_meta(&OsFileSystem, &meta_path).unwrap().salt, salt);
+        assert!(!folder::check_folder_password(&p, &names, "old").unwrap());
+        assert!(folder::check_folder_password(&p, &names, "new").unwrap());
+
+        // The recovery key follows the new password.
+        recover_folder(p.clone(), None, app.handle(), app.state()).unwrap();
+        assert_eq!(fs::read(d.path().join("b.txt")).unwrap(), b"beta");
+    }
+
+    #[test]
+    fn lock_limits_come_from_config() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap().to_string();
+        for i in 0..3 {
+            fs::write(d.path().join(format!("{}.txt", i)), b"x").unwrap();
+        }
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        assert_eq!(get_lock_limits(app.state()), LockLimits::default());
+        set_lock_limits(Some(LockLimits { max_files: Some(2), max_bytes: None }), app.state());
+        let err = lock_folder(p.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap_err();
+        assert_eq!(err.code, ErrorCode::OverLimit);
+        // Overridden per call.
+        let ignore = LockOptions { ignore_limits: true, ..Default::default() };
+        lock_folder(p.clone(), "pw".into(), None, Some(ignore), app.handle(), app.state()).unwrap();
+        assert!(folder::is_locked(&p, &VaultNames::default()));
+        assert_eq!(get_lock_limits(app_in(&cfg).state()).max_files, Some(2));
+    }
+
+    #[test]
+    fn folder_note_round_trips_through_config() {
+        let cfg = tmp();
+        let d = tmp();
+        let p = d.path().to_str().unwrap().to_string();
+        let app = app_in(&cfg);
+        assert!(set_folder_note(p.clone(), Some("x".into()), app.state()).is_err());
+        add_folder(p.clone(), app.state()).unwrap();
+        set_folder_note(p.clone(), Some("  client NDA files\ndelete after 2025 ".into()), app.state()).unwrap();
+        assert!(set_folder_note(p.clone(), Some("a\u{7}b".into()), app.state()).is_err());
+
+        let app = app_in(&cfg);
+        assert_eq!(get_folders(app.state())[0].note.as_deref(), Some("client NDA files\ndelete after 2025"));
+        set_folder_note(p, Some("   ".into()), app.state()).unwrap();
+        assert!(get_folders(app_in(&cfg).state())[0].note.is_none());
+    }
+
+    #[test]
+    fn decrypt_file_to_bytes_checks_the_password() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap().to_string();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("sub/img.png"), b"\x89PNG fake").unwrap();
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        lock_folder(p.clone(), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        assert_eq!(decrypt_file_to_bytes(p.clone(), "sub/img.png".into(), "pw".into(), None, app.handle(), app.state()).unwrap(), b"\x89PNG fake");
+        let err = decrypt_file_to_bytes(p.clone(), "sub/img.png".into(), "bad".into(), None, app.handle(), app.state()).unwrap_err();
+        assert_eq!(err.code, ErrorCode::WrongPassword);
+        assert!(folder::is_locked(&p, &VaultNames::default()));
+    }
+
+    #[test]
+    fn hiding_the_window_relocks_what_it_can() {
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let state = app.state::<AppState>();
+        setup_master_password("master".into(), app.handle(), app.state()).ok().unwrap();
+        let (a, b, c, d) = (tmp(), tmp(), tmp(), tmp());
+        let path = |d: &tempfile::TempDir| -> String { d.path().to_str().unwrap().into() };
+        let names = VaultNames::default();
+        for d in [&a, &b, &c, &d] {
+            fs::write(d.path().join("f.txt"), b"secret").unwrap();
+            add_folder(path(d), app.state()).unwrap();
+            lock_folder(path(d), "pw".into(), None, None, app.handle(), app.state()).unwrap();
+        }
+        // Off by default: nothing happens and no keys are kept.
+        unlock_folder(path(&c), "pw".into(), None, app.handle(), app.state()).unwrap();
+        assert!(lock_on_hide(&app.handle(), &state).is_none());
+        assert!(state.master_key.lock().unwrap().is_some());
+        set_lock_on_hide(true, app.state());
+        unlock_folder(path(&a), "pw".into(), None, app.handle(), app.state()).unwrap();
+        unlock_folder_timed(path(&b), "pw".into(), 3600, app.handle(), app.state()).unwrap();
+        recover_folder(path(&d), None, app.handle(), app.state()).unwrap();
+        let file = tmp();
+        let single = file.path().join("single.txt");
+        fs::write(&single, b"single").unwrap();
+        folder::lock_file(single.to_str().unwrap(), "pw", None, &LockOptions::default()).unwrap();
+        unlock_file(single.to_str().unwrap().into(), "pw".into(), None, app.handle(), app.state()).unwrap();
+        // Not while anything is running.
+        let op = state.begin_operation("/elsewhere").unwrap();
+        assert!(lock_on_hide(&app.handle(), &state).is_none());
+        assert!(!folder::is_locked(&path(&a), &names));
+        drop(op);
+        let results = lock_on_hide(&app.handle(), &state).unwrap();
+        let status = |p: &str| results.iter().find(|r| r.path == p).map(|r| serde_json::to_value(&r.status).unwrap());
+        assert_eq!(results.len(), 5);
+        for d in [&a, &b, &d] {
+            assert_eq!(status(&path(d)), Some(serde_json::json!("locked")));
+            assert!(folder::is_locked(&path(d), &names));
+        }
+        // Unlocked before the option was on, or a single file: no key, left
+        // alone and reported.
+        let left = [path(&c), single.to_str().unwrap().to_string()];
+        for p in &left {
+            assert_eq!(status(p), Some(serde_json::json!("left_unlocked")));
+        }
+        assert!(!folder::is_locked(&path(&c), &names));
+        assert!(events::emitted(events::LEFT_UNLOCKED).contains(&serde_json::json!({ "paths": left })));
+        assert!(state.master_key.lock().unwrap().is_none());
+        assert!(state.session_keys.lock().unwrap().is_empty() && state.timed_unlocks.lock().unwrap().is_empty());
+        // Still opens with the same password afterwards.
+        folder::unlock_folder(&path(&a), &names, "pw", &Default::default(), &mut |_, _| {}).unwrap();
+        assert_eq!(fs::read(a.path().join("f.txt")).unwrap(), b"secret");
+        assert!(get_lock_on_hide(app_in(&cfg).state()));
+    }
+
+    #[test]
+    fn imported_zip_joins_the_folder_list() {
+        use std::io::Write;
+        let dir = tmp();
+        let zip_path = dir.path().join("old.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+        zip.start_file("a.txt", zip::write::SimpleFileOptions::default().with_aes_encryption(zip::AesMode::Aes256, "zpw")).unwrap();
+        zip.write_all(b"alpha").unwrap();
+        zip.finish().unwrap();
+        let cfg = tmp();
+        let app = app_in(&cfg);
+        let dest = dir.path().join("new").to_str().unwrap().to_string();
+        let r = import_external_locker(zip_path.to_str().unwrap().into(), dest.clone(), "zpw".into(), "pw".into(), None, app.handle(), app.state()).unwrap();
+        assert_eq!(r.file_count, 1);
+        assert!(app.state::<AppState>().folders.lock().unwrap().contains(&folder::normalize_path(&dest)));
+    }
 }
@@ -1,14 +1,86 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit},
-    Aes256Gcm, Nonce,
+    aead::{consts::U12, generic_array::GenericArray, Aead, AeadCore, KeyInit, Payload},
+    Aes256Gcm,
 };
-use argon2::{Argon2, Algorithm, Version, Params};
+use aes_gcm_siv::Aes256GcmSiv;
+use crate::error::{Error, ErrorCode};
+use crate::secret::SecretKey;
+use argon2::{Argon2, Algorithm, Block, Version, Params};
+use hkdf::Hkdf;
 use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
 use zeroize::Zeroize;
 
 const SALT_LEN: usize = 32;
+const MIN_SALT_LEN: usize = 8;
 const NONCE_LEN: usize = 12;
 const KEY_LEN: usize = 32;
+const VERIFY_TOKEN_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+// Nonce plus authentication tag added to every sealed message.
+const CIPHERTEXT_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+const STREAM_PREFIX_LEN: usize = 7;
+// Plaintext bytes per chunk of a streamed file.
+pub const STREAM_CHUNK_SIZE: usize = 1 << 20;
+const ARGON2_MEMORY_KIB: u32 = 65536;
+const ARGON2_ITERATIONS: u32 = 3;
+const ARGON2_PARALLELISM: u32 = 1;
+// OWASP's 2023 recommendation for PBKDF2-HMAC-SHA256.
+const PBKDF2_ITERATIONS: u32 = 600_000;
+// Bounds on parameters read from metadata, so a crafted file can't make key
+// derivation eat all memory or run for hours.
+const MAX_ARGON2_MEMORY_KIB: u32 = 4 << 20;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_PBKDF2_ITERATIONS: u32 = 100_000_000;
+// Lower-memory Argon2id settings (KiB, passes) tried in turn when the default
+// can't be allocated; the extra passes win back some of the lost cost.
+const ARGON2_FALLBACKS: &[(u32, u32)] = &[(32768, 4), (16384, 5), (8192, 6)];
+// Start of the error returned when Argon2's working memory can't be allocated.
+pub const KDF_OUT_OF_MEMORY: &str = "Not enough memory for key derivation";
+
+// AEAD for file contents. GCM-SIV survives a repeated random nonce, at a small
+// speed cost, for very large vaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CipherKind {
+    #[default]
+    Aes256Gcm,
+    Aes256GcmSiv,
+}
+
+// Password-based key derivation. PBKDF2 is for FIPS environments that can't
+// use Argon2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KdfKind {
+    #[default]
+    Argon2id,
+    Pbkdf2HmacSha256,
+}
+
+// `memory_kib` and `parallelism` only apply to Argon2id and are 0 for PBKDF2.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub algorithm: KdfKind,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+// The Argon2id parameters `derive_key` uses, and that vaults without stored
+// parameters were locked with.
+pub fn kdf_params() -> KdfParams {
+    default_kdf_params(KdfKind::Argon2id)
+}
+
+pub fn default_kdf_params(kind: KdfKind) -> KdfParams {
+    match kind {
+        KdfKind::Argon2id => KdfParams { algorithm: kind, memory_kib: ARGON2_MEMORY_KIB, iterations: ARGON2_ITERATIONS, parallelism: ARGON2_PARALLELISM },
+        KdfKind::Pbkdf2HmacSha256 => KdfParams { algorithm: kind, memory_kib: 0, iterations: PBKDF2_ITERATIONS, parallelism: 0 },
+    }
+}
 
 pub fn generate_salt() -> [u8; SALT_LEN] {
     let mut salt = [0u8; SALT_LEN];
@@ -16,65 +88,598 @@ pub fn generate_salt() -> [u8; SALT_LEN] {
     salt
 }
 
-pub fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; KEY_LEN], String> {
-    let params = Params::new(65536, 3, 1, Some(KEY_LEN))
-        .map_err(|e| format!("Argon2 params error: {}", e))?;
-    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
-    let mut key = [0u8; KEY_LEN];
-    argon2
-        .hash_password_into(password.as_bytes(), salt, &mut key)
-        .map_err(|e| format!("Key derivation error: {}", e))?;
+// Salts are generated at SALT_LEN but accepted at any length Argon2 allows, so
+// stored salts from other versions still derive.
+pub fn derive_key(password: &str, salt: &[u8]) -> Result<SecretKey, Error> {
+    derive_key_with(&kdf_params(), password, salt)
+}
+
+pub fn derive_key_with(params: &KdfParams, password: &str, salt: &[u8]) -> Result<SecretKey, Error> {
+    if salt.len() < MIN_SALT_LEN {
+        return Err(format!("Salt is too short ({} bytes, need at least {})", salt.len(), MIN_SALT_LEN).into());
+    }
+    let mut key = SecretKey::zeroed();
+    match params.algorithm {
+        KdfKind::Argon2id => {
+            if params.memory_kib > MAX_ARGON2_MEMORY_KIB || params.iterations > MAX_ARGON2_ITERATIONS {
+                return Err("Unsupported key derivation parameters".into());
+            }
+            let argon_params = Params::new(params.memory_kib, params.iterations, params.parallelism, Some(KEY_LEN))
+                .map_err(|e| format!("Argon2 params error: {}", e))?;
+            let mut blocks = argon2_memory(argon_params.block_count())?;
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params)
+                .hash_password_into_with_memory(password.as_bytes(), salt, key.as_mut_bytes(), &mut blocks)
+                .map_err(|e| format!("Key derivation error: {}", e))?;
+        }
+        KdfKind::Pbkdf2HmacSha256 => {
+            if params.iterations == 0 || params.iterations > MAX_PBKDF2_ITERATIONS {
+                return Err("Unsupported key derivation parameters".into());
+            }
+            pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, params.iterations, key.as_mut_bytes());
+        }
+    }
     Ok(key)
 }
 
-pub fn encrypt(key: &[u8; KEY_LEN], plaintext: &[u8]) -> Result<Vec<u8>, String> {
-    let cipher = Aes256Gcm::new_from_slice(key)
-        .map_err(|e| format!("Cipher init error: {}", e))?;
+// How long one derivation with `params` takes on this machine, which is what
+// every unlock pays before any file is touched.
+pub fn measure_kdf_cost(params: &KdfParams) -> Result<std::time::Duration, Error> {
+    let salt = generate_salt();
+    let start = std::time::Instant::now();
+    derive_key_with(params, "securelock-kdf-benchmark", &salt)?;
+    Ok(start.elapsed())
+}
+
+// Argon2's `hash_password_into` allocates with `vec!`, which aborts the process
+// when the memory isn't there; reserving it here turns that into an error.
+fn argon2_memory(block_count: usize) -> Result<Vec<Block>, Error> {
+    let mut blocks = Vec::new();
+    blocks.try_reserve_exact(block_count)
+        .map_err(|_| Error::new(ErrorCode::OutOfMemory, format!("{} ({} MiB needed)", KDF_OUT_OF_MEMORY, (block_count * Block::SIZE) >> 20)))?;
+    blocks.resize(block_count, Block::default());
+    Ok(blocks)
+}
+
+// Derives with `params`, and if Argon2id runs out of memory, with each lighter
+// fallback in turn. Returns the parameters that worked so they can be stored
+// with the vault and used again to unlock it.
+pub fn derive_key_with_fallback(params: KdfParams, password: &str, salt: &[u8]) -> Result<(KdfParams, SecretKey), Error> {
+    let first_error = match derive_key_with(&params, password, salt) {
+        Ok(key) => return Ok((params, key)),
+        Err(e) if params.algorithm == KdfKind::Argon2id && e.code == ErrorCode::OutOfMemory => e,
+        Err(e) => return Err(e),
+    };
+    for &(memory_kib, iterations) in ARGON2_FALLBACKS.iter().filter(|(m, _)| *m < params.memory_kib) {
+        let lighter = KdfParams { memory_kib, iterations: iterations.max(params.iterations), ..params.clone() };
+        match derive_key_with(&lighter, password, salt) {
+            Ok(key) => return Ok((lighter, key)),
+            Err(e) if e.code == ErrorCode::OutOfMemory => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(first_error)
+}
+
+pub fn encrypt(key: &[u8; KEY_LEN], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    seal::<Aes256Gcm>(key, plaintext, b"")
+}
+
+pub fn decrypt(key: &[u8; KEY_LEN], data: &[u8]) -> Result<Vec<u8>, Error> {
+    open::<Aes256Gcm>(key, data, b"")
+}
+
+pub fn encrypt_with(cipher: CipherKind, key: &[u8; KEY_LEN], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    match cipher {
+        CipherKind::Aes256Gcm => seal::<Aes256Gcm>(key, plaintext, b""),
+        CipherKind::Aes256GcmSiv => seal::<Aes256GcmSiv>(key, plaintext, b""),
+    }
+}
+
+pub fn decrypt_with(cipher: CipherKind, key: &[u8; KEY_LEN], data: &[u8]) -> Result<Vec<u8>, Error> {
+    match cipher {
+        CipherKind::Aes256Gcm => open::<Aes256Gcm>(key, data, b""),
+        CipherKind::Aes256GcmSiv => open::<Aes256GcmSiv>(key, data, b""),
+    }
+}
+
+fn seal<C: KeyInit + Aead + AeadCore<NonceSize = U12>>(key: &[u8; KEY_LEN], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
     let mut nonce_bytes = [0u8; NONCE_LEN];
     rand::rngs::OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+    seal_with_nonce::<C>(key, &nonce_bytes, plaintext, aad)
+}
+
+fn seal_with_nonce<C: KeyInit + Aead + AeadCore<NonceSize = U12>>(key: &[u8; KEY_LEN], nonce_bytes: &[u8; NONCE_LEN], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = C::new_from_slice(key)
+        .map_err(|e| format!("Cipher init error: {}", e))?;
+    let nonce = GenericArray::from_slice(nonce_bytes);
     let ciphertext = cipher
-        .encrypt(nonce, plaintext)
+        .encrypt(nonce, Payload { msg: plaintext, aad })
         .map_err(|e| format!("Encryption error: {}", e))?;
     let mut result = Vec::with_capacity(NONCE_LEN + ciphertext.len());
-    result.extend_from_slice(&nonce_bytes);
+    result.extend_from_slice(nonce_bytes);
     result.extend_from_slice(&ciphertext);
     Ok(result)
 }
 
-pub fn decrypt(key: &[u8; KEY_LEN], data: &[u8]) -> Result<Vec<u8>, String> {
+fn open<C: KeyInit + Aead + AeadCore<NonceSize = U12>>(key: &[u8; KEY_LEN], data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
     if data.len() < NONCE_LEN {
         return Err("Data too short to contain nonce".into());
     }
     let (nonce_bytes, ciphertext) = data.split_at(NONCE_LEN);
-    let nonce = Nonce::from_slice(nonce_bytes);
-    let cipher = Aes256Gcm::new_from_slice(key)
+    let nonce = GenericArray::from_slice(nonce_bytes);
+    let cipher = C::new_from_slice(key)
         .map_err(|e| format!("Cipher init error: {}", e))?;
     cipher
-        .decrypt(nonce, ciphertext)
-        .map_err(|_| "Decryption failed — wrong password or corrupted data".into())
+        .decrypt(nonce, Payload { msg: ciphertext, aad })
+        .map_err(|_| decryption_failed())
+}
+
+fn decryption_failed() -> Error {
+    Error::new(ErrorCode::Corrupted, "Decryption failed — wrong password or corrupted data")
+}
+
+// Chunked encryption for files too big to hold in memory, after the STREAM
+// construction: a random 7-byte nonce prefix, then each chunk sealed under the
+// prefix, a big-endian chunk counter and a byte that is 1 only for the last
+// chunk, so reordered, dropped or truncated chunks fail to open. `on_chunk`
+// sees each plaintext chunk, for hashing. Returns the plaintext length.
+pub fn encrypt_stream(cipher: CipherKind, key: &[u8; KEY_LEN], chunk_size: usize, reader: &mut dyn Read, writer: &mut dyn Write, on_chunk: &mut dyn FnMut(&[u8])) -> Result<u64, Error> {
+    match cipher {
+        CipherKind::Aes256Gcm => seal_stream::<Aes256Gcm>(key, chunk_size, reader, writer, on_chunk),
+        CipherKind::Aes256GcmSiv => seal_stream::<Aes256GcmSiv>(key, chunk_size, reader, writer, on_chunk),
+    }
+}
+
+pub fn decrypt_stream(cipher: CipherKind, key: &[u8; KEY_LEN], chunk_size: usize, reader: &mut dyn Read, writer: &mut dyn Write, on_chunk: &mut dyn FnMut(&[u8])) -> Result<u64, Error> {
+    match cipher {
+        CipherKind::Aes256Gcm => open_stream::<Aes256Gcm>(key, chunk_size, reader, writer, on_chunk),
+        CipherKind::Aes256GcmSiv => open_stream::<Aes256GcmSiv>(key, chunk_size, reader, writer, on_chunk),
+    }
+}
+
+// Plaintext size behind a ciphertext of `len` bytes, sealed in one piece or,
+// with `chunk_size`, as a stream.
+pub fn plaintext_len(len: u64, chunk_size: Option<usize>) -> u64 {
+    match chunk_size {
+        None => len.saturating_sub(CIPHERTEXT_OVERHEAD as u64),
+        Some(chunk_size) => {
+            let body = len.saturating_sub(STREAM_PREFIX_LEN as u64);
+            let chunks = body.div_ceil((chunk_size + TAG_LEN) as u64).max(1);
+            body.saturating_sub(chunks * TAG_LEN as u64)
+        }
+    }
 }
 
-pub fn create_verify_token(key: &[u8; KEY_LEN]) -> Result<Vec<u8>, String> {
-    encrypt(key, b"SECURELOCK_VERIFY_TOKEN_V1")
+fn stream_nonce(prefix: &[u8; STREAM_PREFIX_LEN], counter: u32, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..STREAM_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[STREAM_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    nonce
+}
+
+// Fills `buf` as far as the reader allows; short only at end of input.
+fn read_full(reader: &mut dyn Read, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::io(&e, format!("Read error: {}", e))),
+        }
+    }
+    Ok(filled)
+}
+
+// Both directions read one block ahead, since a chunk can only be sealed or
+// opened once it is known whether it is the last.
+fn seal_stream<C: KeyInit + Aead + AeadCore<NonceSize = U12>>(key: &[u8; KEY_LEN], chunk_size: usize, reader: &mut dyn Read, writer: &mut dyn Write, on_chunk: &mut dyn FnMut(&[u8])) -> Result<u64, Error> {
+    let cipher = C::new_from_slice(key).map_err(|e| format!("Cipher init error: {}", e))?;
+    let mut prefix = [0u8; STREAM_PREFIX_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut prefix);
+    writer.write_all(&prefix).map_err(|e| Error::io(&e, format!("Write error: {}", e)))?;
+    let mut current = vec![0u8; chunk_size];
+    let mut next = vec![0u8; chunk_size];
+    let mut current_len = read_full(reader, &mut current)?;
+    let mut total = 0u64;
+    for counter in 0u32.. {
+        let next_len = if current_len == chunk_size { read_full(reader, &mut next)? } else { 0 };
+        let last = next_len == 0;
+        let chunk = &current[..current_len];
+        on_chunk(chunk);
+        let sealed = cipher
+            .encrypt(GenericArray::from_slice(&stream_nonce(&prefix, counter, last)), chunk)
+            .map_err(|e| format!("Encryption error: {}", e))?;
+        writer.write_all(&sealed).map_err(|e| Error::io(&e, format!("Write error: {}", e)))?;
+        total += current_len as u64;
+        if last {
+            current.zeroize();
+            next.zeroize();
+            return Ok(total);
+        }
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+    }
+    Err("File is too large to encrypt in chunks".into())
+}
+
+fn open_stream<C: KeyInit + Aead + AeadCore<NonceSize = U12>>(key: &[u8; KEY_LEN], chunk_size: usize, reader: &mut dyn Read, writer: &mut dyn Write, on_chunk: &mut dyn FnMut(&[u8])) -> Result<u64, Error> {
+    let cipher = C::new_from_slice(key).map_err(|e| format!("Cipher init error: {}", e))?;
+    let mut prefix = [0u8; STREAM_PREFIX_LEN];
+    if read_full(reader, &mut prefix)? < STREAM_PREFIX_LEN {
+        return Err("Data too short to contain nonce".into());
+    }
+    let block = chunk_size + TAG_LEN;
+    let mut current = vec![0u8; block];
+    let mut next = vec![0u8; block];
+    let mut current_len = read_full(reader, &mut current)?;
+    let mut total = 0u64;
+    for counter in 0u32.. {
+        let next_len = if current_len == block { read_full(reader, &mut next)? } else { 0 };
+        let last = next_len == 0;
+        let mut chunk = cipher
+            .decrypt(GenericArray::from_slice(&stream_nonce(&prefix, counter, last)), &current[..current_len])
+            .map_err(|_| decryption_failed())?;
+        on_chunk(&chunk);
+        let written = writer.write_all(&chunk).map_err(|e| Error::io(&e, format!("Write error: {}", e)));
+        total += chunk.len() as u64;
+        chunk.zeroize();
+        written?;
+        if last {
+            return Ok(total);
+        }
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+    }
+    Err(decryption_failed())
+}
+
+// The token's plaintext is random per vault, so there is no universally known
+// plaintext under every key; a password checks out if the GCM tag verifies.
+// Tokens from older versions (a fixed string) verify the same way.
+pub fn create_verify_token(key: &[u8; KEY_LEN]) -> Result<Vec<u8>, Error> {
+    let mut token = [0u8; VERIFY_TOKEN_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut token);
+    encrypt(key, &token)
 }
 
 pub fn verify_password(key: &[u8; KEY_LEN], encrypted_token: &[u8]) -> bool {
     match decrypt(key, encrypted_token) {
-        Ok(plaintext) => plaintext == b"SECURELOCK_VERIFY_TOKEN_V1",
+        Ok(mut plaintext) => {
+            plaintext.zeroize();
+            true
+        }
         Err(_) => false,
     }
 }
 
-pub fn wrap_key(master_key: &[u8; KEY_LEN], folder_key: &[u8; KEY_LEN]) -> Result<Vec<u8>, String> {
-    encrypt(master_key, folder_key)
+// `context` identifies the folder the key belongs to (its salt) and is bound in
+// as associated data, so a wrapped key copied into another folder's metadata
+// won't unwrap there. An empty context matches keys wrapped before this existed.
+pub const WRAPPED_KEY_LEN: usize = KEY_LEN + CIPHERTEXT_OVERHEAD;
+
+pub fn wrap_key(master_key: &[u8; KEY_LEN], folder_key: &[u8; KEY_LEN], context: &[u8]) -> Result<Vec<u8>, Error> {
+    seal::<Aes256Gcm>(master_key, folder_key, context)
+}
+
+pub fn unwrap_key(master_key: &[u8; KEY_LEN], wrapped: &[u8], context: &[u8]) -> Result<SecretKey, Error> {
+    let mut key_bytes = open::<Aes256Gcm>(master_key, wrapped, context)?;
+    if key_bytes.len() != KEY_LEN {
+        key_bytes.zeroize();
+        return Err(Error::new(ErrorCode::Corrupted, "Invalid wrapped key length"));
+    }
+    let mut key = SecretKey::zeroed();
+    key.as_mut_bytes().copy_from_slice(&key_bytes);
+    key_bytes.zeroize();
+    Ok(key)
 }
 
-pub fn unwrap_key(master_key: &[u8; KEY_LEN], wrapped: &[u8]) -> Result<[u8; KEY_LEN], String> {
-    let key_bytes = decrypt(master_key, wrapped)?;
-    key_bytes.try_into().map_err(|_| "Invalid wrapped key length".to_string())
+pub fn bind_key_to_machine(key: &[u8; KEY_LEN], machine_id: &str) -> Result<SecretKey, Error> {
+    let hk = Hkdf::<Sha256>::new(Some(machine_id.as_bytes()), key);
+    let mut bound = SecretKey::zeroed();
+    hk.expand(b"SECURELOCK_MACHINE_BINDING_V1", bound.as_mut_bytes())
+        .map_err(|e| format!("Key binding error: {}", e))?;
+    Ok(bound)
 }
 
-pub fn zeroize_key(key: &mut [u8; KEY_LEN]) {
-    key.zeroize();
+// Subkey for one file's contents, bound to its path within the folder.
+pub fn derive_file_key(folder_key: &[u8; KEY_LEN], relative_path: &[u8]) -> Result<SecretKey, Error> {
+    let hk = Hkdf::<Sha256>::new(None, folder_key);
+    let mut key = SecretKey::zeroed();
+    hk.expand_multi_info(&[b"SECURELOCK_FILE_KEY_V1", relative_path], key.as_mut_bytes())
+        .map_err(|e| format!("Key derivation error: {}", e))?;
+    Ok(key)
+}
+
+// Separate key for encrypting the app's own config, so the master key itself
+// is only ever used to wrap folder keys.
+pub fn config_key(master_key: &[u8; KEY_LEN]) -> Result<SecretKey, Error> {
+    let hk = Hkdf::<Sha256>::new(None, master_key);
+    let mut key = SecretKey::zeroed();
+    hk.expand(b"SECURELOCK_CONFIG_KEY_V1", key.as_mut_bytes())
+        .map_err(|e| format!("Key derivation error: {}", e))?;
+    Ok(key)
+}
+
+// Key that wraps the master key when a security key is enrolled: neither the
+// password key nor the token's hmac-secret output opens it alone.
+pub fn hardware_bound_key(password_key: &[u8; KEY_LEN], token_secret: &[u8; KEY_LEN]) -> Result<SecretKey, Error> {
+    let hk = Hkdf::<Sha256>::new(Some(token_secret), password_key);
+    let mut key = SecretKey::zeroed();
+    hk.expand(b"SECURELOCK_HARDWARE_KEY_V1", key.as_mut_bytes())
+        .map_err(|e| format!("Key derivation error: {}", e))?;
+    Ok(key)
+}
+
+pub fn machine_fingerprint(salt: &[u8], machine_id: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(machine_id.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// Keyed BLAKE3 over a file's plaintext, checked after decryption as an
+// end-to-end guard on top of the AEAD tag. Keyed so the stored value can't be
+// used to confirm guesses about file contents.
+pub fn plaintext_digest(key: &[u8; KEY_LEN], plaintext: &[u8]) -> Vec<u8> {
+    let mut hasher = PlaintextHasher::new(key);
+    hasher.update(plaintext);
+    hasher.finalize()
+}
+
+// Salted hash of a folder's UI PIN. Deliberately fast: the PIN only gates the
+// app's own views and protects nothing cryptographically.
+pub fn pin_hash(pin: &str, salt: &[u8]) -> Vec<u8> {
+    let mut hasher = blake3::Hasher::new_derive_key("SECURELOCK_FOLDER_PIN_V1");
+    hasher.update(salt);
+    hasher.update(pin.as_bytes());
+    hasher.finalize().as_bytes().to_vec()
+}
+
+// `plaintext_digest` fed a piece at a time, for streamed files.
+pub struct PlaintextHasher(blake3::Hasher);
+
+impl PlaintextHasher {
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        let mut subkey = blake3::derive_key("SECURELOCK_PLAINTEXT_DIGEST_V1", key);
+        let hasher = blake3::Hasher::new_keyed(&subkey);
+        subkey.zeroize();
+        PlaintextHasher(hasher)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finalize(&self) -> Vec<u8> {
+        self.0.finalize().as_bytes().to_vec()
+    }
+}
+
+// Unkeyed BLAKE3 of a ciphertext file, for the vault's Merkle root.
+pub fn ciphertext_hash(reader: &mut dyn Read) -> std::io::Result<[u8; 32]> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(reader, &mut hasher)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+// A file's path in the vault and the hash of its ciphertext.
+pub type MerkleLeaf = (Vec<u8>, [u8; 32]);
+
+// Merkle root over (path, ciphertext hash) leaves, sorted by path so the order
+// files were found in doesn't matter. Leaves and inner nodes hash under
+// different contexts, so one can't stand in for the other.
+pub fn merkle_root(mut leaves: Vec<MerkleLeaf>) -> Vec<u8> {
+    leaves.sort();
+    let mut level: Vec<[u8; 32]> = leaves.iter()
+        .map(|(path, hash)| {
+            let mut hasher = blake3::Hasher::new_derive_key("SECURELOCK_MERKLE_LEAF_V1");
+            hasher.update(&(path.len() as u64).to_le_bytes());
+            hasher.update(path);
+            hasher.update(hash);
+            *hasher.finalize().as_bytes()
+        })
+        .collect();
+    if level.is_empty() {
+        return blake3::derive_key("SECURELOCK_MERKLE_EMPTY_V1", b"").to_vec();
+    }
+    while level.len() > 1 {
+        level = level.chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = blake3::Hasher::new_derive_key("SECURELOCK_MERKLE_NODE_V1");
+                    hasher.update(left);
+                    hasher.update(right);
+                    *hasher.finalize().as_bytes()
+                }
+                _ => pair[0],
+            })
+            .collect();
+    }
+    level[0].to_vec()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestResult {
+    pub primitive: String,
+    pub passed: bool,
+    pub detail: Option<String>,
+}
+
+type SelfTest = fn() -> Result<(), Error>;
+
+// Known-answer tests against published vectors, to catch a miscompiled build or
+// a bad dependency upgrade before it touches user data.
+pub fn self_test() -> Vec<SelfTestResult> {
+    let tests: [(&str, SelfTest); 7] = [
+        ("argon2id", kat_argon2id),
+        ("pbkdf2-hmac-sha256", kat_pbkdf2),
+        ("aes-256-gcm", kat_aes_gcm),
+        ("aes-256-gcm-siv", kat_aes_gcm_siv),
+        ("hkdf-sha256", kat_hkdf),
+        ("blake3", kat_blake3),
+        ("key-wrap", check_key_wrap),
+    ];
+    tests.iter()
+        .map(|(primitive, test)| {
+            let result = test();
+            SelfTestResult { primitive: primitive.to_string(), passed: result.is_ok(), detail: result.err().map(|e| e.message) }
+        })
+        .collect()
+}
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(0)).collect()
+}
+
+fn expect_eq(actual: &[u8], expected_hex: &str) -> Result<(), Error> {
+    if actual == unhex(expected_hex).as_slice() { Ok(()) } else { Err("Output does not match the known answer".into()) }
+}
+
+// RFC 9106, section 5.3.
+fn kat_argon2id() -> Result<(), Error> {
+    let params = argon2::ParamsBuilder::new()
+        .m_cost(32)
+        .t_cost(3)
+        .p_cost(4)
+        .data(argon2::AssociatedData::new(&[4; 12]).map_err(|e| e.to_string())?)
+        .output_len(32)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let argon2 = Argon2::new_with_secret(&[3; 8], Algorithm::Argon2id, Version::V0x13, params).map_err(|e| e.to_string())?;
+    let mut out = [0u8; 32];
+    argon2.hash_password_into(&[1; 32], &[2; 16], &mut out).map_err(|e| e.to_string())?;
+    expect_eq(&out, "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659")
+}
+
+// RFC 7914, section 11, first PBKDF2-HMAC-SHA256 vector.
+fn kat_pbkdf2() -> Result<(), Error> {
+    let mut out = [0u8; 64];
+    pbkdf2::pbkdf2_hmac::<Sha256>(b"passwd", b"salt", 1, &mut out);
+    expect_eq(&out, "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783")
+}
+
+// McGrew & Viega GCM test case 14 (256-bit zero key, zero IV, one zero block).
+fn kat_aes_gcm() -> Result<(), Error> {
+    let key = [0u8; KEY_LEN];
+    let sealed = seal_with_nonce::<Aes256Gcm>(&key, &[0; NONCE_LEN], &[0; 16], b"")?;
+    expect_eq(&sealed[NONCE_LEN..], "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919")?;
+    expect_eq(&open::<Aes256Gcm>(&key, &sealed, b"")?, "00000000000000000000000000000000")
+}
+
+// RFC 8452, appendix C.2, first AEAD_AES_256_GCM_SIV vector.
+fn kat_aes_gcm_siv() -> Result<(), Error> {
+    let mut key = [0u8; KEY_LEN];
+    key[0] = 1;
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[0] = 3;
+    let sealed = seal_with_nonce::<Aes256GcmSiv>(&key, &nonce, b"", b"")?;
+    expect_eq(&sealed[NONCE_LEN..], "07f5f4169bbf55a8400cd47ea6fd400f")
+}
+
+// RFC 5869, test case 1.
+fn kat_hkdf() -> Result<(), Error> {
+    let hk = Hkdf::<Sha256>::new(Some(&unhex("000102030405060708090a0b0c")), &[0x0b; 22]);
+    let mut okm = [0u8; 42];
+    hk.expand(&unhex("f0f1f2f3f4f5f6f7f8f9"), &mut okm).map_err(|e| e.to_string())?;
+    expect_eq(&okm, "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865")
+}
+
+// Hash of the empty input, from the BLAKE3 reference test vectors.
+fn kat_blake3() -> Result<(), Error> {
+    expect_eq(blake3::hash(b"").as_bytes(), "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262")
+}
+
+fn check_key_wrap() -> Result<(), Error> {
+    let master = [0x11u8; KEY_LEN];
+    let folder = [0x22u8; KEY_LEN];
+    let wrapped = wrap_key(&master, &folder, b"context")?;
+    if *unwrap_key(&master, &wrapped, b"context")? != folder {
+        return Err("Unwrapped key differs from the original".into());
+    }
+    if unwrap_key(&master, &wrapped, b"other").is_ok() {
+        return Err("Key unwrapped under the wrong context".into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gcm_siv_round_trip() {
+        let key = [7u8; KEY_LEN];
+        let sealed = encrypt_with(CipherKind::Aes256GcmSiv, &key, b"secret data").unwrap();
+        assert_eq!(decrypt_with(CipherKind::Aes256GcmSiv, &key, &sealed).unwrap(), b"secret data");
+        assert!(decrypt_with(CipherKind::Aes256Gcm, &key, &sealed).is_err());
+        assert!(decrypt_with(CipherKind::Aes256GcmSiv, &[8u8; KEY_LEN], &sealed).is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt_with(CipherKind::Aes256GcmSiv, &key, &tampered).is_err());
+
+        let data = vec![0x5Au8; 3 * 1000 + 17];
+        let mut streamed = Vec::new();
+        encrypt_stream(CipherKind::Aes256GcmSiv, &key, 1000, &mut &data[..], &mut streamed, &mut |_| {}).unwrap();
+        let mut opened = Vec::new();
+        decrypt_stream(CipherKind::Aes256GcmSiv, &key, 1000, &mut &streamed[..], &mut opened, &mut |_| {}).unwrap();
+        assert_eq!(opened, data);
+    }
+
+    #[test]
+    fn verify_token_is_random_and_checks_the_key() {
+        let right = derive_key("a", &generate_salt()).unwrap();
+        let wrong = derive_key("b", &generate_salt()).unwrap();
+        let token = create_verify_token(&right).unwrap();
+        assert_ne!(decrypt(&right, &token).unwrap(), decrypt(&right, &create_verify_token(&right).unwrap()).unwrap());
+        assert!(verify_password(&right, &token));
+        assert!(!verify_password(&wrong, &token));
+        // Tokens from before the random ones still verify.
+        let legacy = encrypt(&right, b"SECURELOCK_VERIFY_TOKEN_V1").unwrap();
+        assert!(verify_password(&right, &legacy));
+        assert!(!verify_password(&wrong, &legacy));
+    }
+
+    #[test]
+    fn wrapped_key_is_bound_to_its_context() {
+        let master_key = derive_key("master", &generate_salt()).unwrap();
+        let folder_key = derive_key("folder", &generate_salt()).unwrap();
+        let wrapped = wrap_key(&master_key, &folder_key, b"salt-a").unwrap();
+        assert_eq!(*unwrap_key(&master_key, &wrapped, b"salt-a").unwrap(), *folder_key);
+        assert!(unwrap_key(&master_key, &wrapped, b"salt-b").is_err());
+        assert!(unwrap_key(&master_key, &wrapped, b"").is_err());
+    }
+
+    #[test]
+    fn self_test_passes() {
+        let results = self_test();
+        assert_eq!(results.len(), 7);
+        for result in &results {
+            assert!(result.passed, "{}: {:?}", result.primitive, result.detail);
+        }
+        assert!(expect_eq(b"\x00\x01", "0002").is_err());
+    }
+
+    #[test]
+    fn each_kdf_derives_its_own_key() {
+        let salt = [3u8; 32];
+        let pbkdf2 = default_kdf_params(KdfKind::Pbkdf2HmacSha256);
+        let a = derive_key_with(&pbkdf2, "pw", &salt).unwrap();
+        assert_eq!(*a, *derive_key_with(&pbkdf2, "pw", &salt).unwrap());
+        assert_ne!(*a, *derive_key_with(&default_kdf_params(KdfKind::Argon2id), "pw", &salt).unwrap());
+        assert_ne!(*a, *derive_key_with(&pbkdf2, "pw2", &salt).unwrap());
+        // Out-of-range parameters are refused rather than run.
+        let absurd = KdfParams { iterations: u32::MAX, ..pbkdf2 };
+        assert!(derive_key_with(&absurd, "pw", &salt).is_err());
+        assert!(derive_key_with(&pbkdf2, "pw", &[0; 4]).is_err());
+    }
+
+    #[test]
+    fn kdf_cost_tracks_the_parameters() {
+        let light = KdfParams { memory_kib: 8192, iterations: 1, ..kdf_params() };
+        let full = measure_kdf_cost(&kdf_params()).unwrap();
+        assert!(measure_kdf_cost(&light).unwrap() < full);
+        assert!(full.as_millis() > 0 && full.as_secs() < 60, "{:?}", full);
+    }
 }
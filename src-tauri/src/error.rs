@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+// What kind of failure a message is, so the frontend can pick an icon and a fix
+// without parsing English.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    DiskFull,
+    PermissionDenied,
+    NotFound,
+    WrongPassword,
+    Corrupted,
+    Busy,
+    MasterLocked,
+    OutOfMemory,
+    OverLimit,
+    #[default]
+    Other,
+}
+
+impl ErrorCode {
+    // Key into the frontend's message catalogue.
+    pub fn message_key(self) -> &'static str {
+        match self {
+            ErrorCode::DiskFull => "error.disk_full",
+            ErrorCode::PermissionDenied => "error.permission_denied",
+            ErrorCode::NotFound => "error.not_found",
+            ErrorCode::WrongPassword => "error.wrong_password",
+            ErrorCode::Corrupted => "error.corrupted",
+            ErrorCode::Busy => "error.busy",
+            ErrorCode::MasterLocked => "error.master_locked",
+            ErrorCode::OutOfMemory => "error.out_of_memory",
+            ErrorCode::OverLimit => "error.over_limit",
+            ErrorCode::Other => "error.other",
+        }
+    }
+}
+
+// A failure and its kind, decided where it happens rather than read back out
+// of the wording. The message is for people.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Error { code, message: message.into() }
+    }
+
+    // An I/O failure; the code comes from the OS error, so a full disk while
+    // reading metadata is still a full disk.
+    pub fn io(err: &io::Error, message: impl Into<String>) -> Self {
+        Error::new(io_code(err), message)
+    }
+
+    // The same failure with more said about where it happened.
+    pub fn context(self, message: impl FnOnce(&str) -> String) -> Self {
+        Error { message: message(&self.message), ..self }
+    }
+}
+
+pub fn io_code(err: &io::Error) -> ErrorCode {
+    match err.kind() {
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded => ErrorCode::DiskFull,
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => ErrorCode::PermissionDenied,
+        ErrorKind::NotFound => ErrorCode::NotFound,
+        ErrorKind::ResourceBusy => ErrorCode::Busy,
+        ErrorKind::OutOfMemory => ErrorCode::OutOfMemory,
+        // ERROR_SHARING_VIOLATION: another program has the file open.
+        _ if cfg!(windows) && err.raw_os_error() == Some(32) => ErrorCode::Busy,
+        _ => ErrorCode::Other,
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Plain messages are failures no one needs to branch on.
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::new(ErrorCode::Other, message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        message.to_string().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io_error(code: i32) -> Error {
+        let err = io::Error::from_raw_os_error(code);
+        Error::io(&err, format!("Failed to write 'x': {}", err))
+    }
+
+    #[test]
+    fn each_kind_gets_its_code() {
+        assert_eq!(io_error(28).code, ErrorCode::DiskFull);
+        assert_eq!(io_error(13).code, ErrorCode::PermissionDenied);
+        assert_eq!(io_error(1).code, ErrorCode::PermissionDenied);
+        assert_eq!(io_error(2).code, ErrorCode::NotFound);
+        assert_eq!(Error::io(&ErrorKind::QuotaExceeded.into(), "x").code, ErrorCode::DiskFull);
+        assert_eq!(Error::io(&ErrorKind::ResourceBusy.into(), "x").code, ErrorCode::Busy);
+        assert_eq!(Error::io(&ErrorKind::InvalidData.into(), "x").code, ErrorCode::Other);
+        // Wording doesn't decide the code; where the error came from does.
+        assert_eq!(Error::from("Incorrect password").code, ErrorCode::Other);
+        let disk = io_error(28).context(|m| format!("Invalid metadata: {}", m));
+        assert_eq!(disk.code, ErrorCode::DiskFull);
+        assert_eq!(disk.to_string(), format!("Invalid metadata: {}", io_error(28)));
+        assert_eq!(ErrorCode::DiskFull.message_key(), "error.disk_full");
+        assert_eq!(serde_json::to_value(ErrorCode::WrongPassword).unwrap(), "wrong_password");
+    }
+}
@@ -0,0 +1,152 @@
+use securelock::error::Error;
+use securelock::folder::ProtectedFolder;
+use serde::Serialize;
+use tauri::{AppHandle, Manager, Runtime};
+
+pub const STATE_CHANGED: &str = "state-changed";
+pub const PROGRESS: &str = "progress";
+pub const KEY_DERIVATION: &str = "key-derivation";
+pub const RELOCK_COUNTDOWN: &str = "relock-countdown";
+pub const NO_RECOVERY_WARNING: &str = "no-recovery-warning";
+pub const LEFT_UNLOCKED: &str = "left-unlocked";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Lock,
+    Unlock,
+    LockAll,
+    Recover,
+    MasterSetup,
+    MasterVerify,
+    MasterLock,
+    PanicWipe,
+}
+
+// `path` is None for master password transitions, where `is_locked` refers to
+// the master key rather than a folder.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StateChange {
+    pub action: Action,
+    pub path: Option<String>,
+    pub is_locked: bool,
+}
+
+impl StateChange {
+    pub fn folder(action: Action, folder: &ProtectedFolder) -> Self {
+        StateChange { action, path: Some(folder.path.clone()), is_locked: folder.is_locked }
+    }
+
+    pub fn master(action: Action) -> Self {
+        StateChange { action, path: None, is_locked: matches!(action, Action::MasterLock | Action::PanicWipe) }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Progress {
+    pub action: Action,
+    pub path: String,
+    pub done: usize,
+    pub total: usize,
+}
+
+// Brackets a password-to-key derivation. `path` is None for the master password.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyDerivation {
+    pub path: Option<String>,
+    pub done: bool,
+}
+
+// Time left before a timed unlock re-locks its folder.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RelockCountdown {
+    pub path: String,
+    pub remaining_secs: u64,
+}
+
+// A folder was locked without a recovery key although a master password is set
+// up, because it wasn't unlocked at the time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NoRecoveryWarning {
+    pub path: String,
+}
+
+// Folders and files lock_on_hide couldn't re-lock, having no key for them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LeftUnlocked {
+    pub paths: Vec<String>,
+}
+
+fn send<R: Runtime, S: Serialize + Clone>(app: &AppHandle<R>, event: &str, payload: S) {
+    #[cfg(test)]
+    EMITTED.lock().unwrap().push((event.to_string(), serde_json::to_value(&payload).unwrap()));
+    let _ = app.emit_all(event, payload);
+}
+
+// Tauri only delivers events to webviews, so tests read what was sent here.
+#[cfg(test)]
+static EMITTED: std::sync::Mutex<Vec<(String, serde_json::Value)>> = std::sync::Mutex::new(Vec::new());
+
+// Payloads of every `event` sent so far by any test; filter them by path.
+#[cfg(test)]
+pub fn emitted(event: &str) -> Vec<serde_json::Value> {
+    EMITTED.lock().unwrap().iter().filter(|(e, _)| e == event).map(|(_, payload)| payload.clone()).collect()
+}
+
+pub fn emit<R: Runtime>(app: &AppHandle<R>, change: StateChange) {
+    send(app, STATE_CHANGED, change);
+}
+
+pub fn emit_folder<R: Runtime>(app: &AppHandle<R>, action: Action, result: &Result<ProtectedFolder, Error>) {
+    if let Ok(folder) = result {
+        emit(app, StateChange::folder(action, folder));
+    }
+}
+
+pub fn emit_no_recovery<R: Runtime>(app: &AppHandle<R>, path: &str) {
+    send(app, NO_RECOVERY_WARNING, NoRecoveryWarning { path: path.to_string() });
+}
+
+pub fn emit_left_unlocked<R: Runtime>(app: &AppHandle<R>, paths: &[String]) {
+    send(app, LEFT_UNLOCKED, LeftUnlocked { paths: paths.to_vec() });
+}
+
+pub fn emit_countdown<R: Runtime>(app: &AppHandle<R>, path: &str, remaining_secs: u64) {
+    send(app, RELOCK_COUNTDOWN, RelockCountdown { path: path.to_string(), remaining_secs });
+}
+
+pub fn emit_progress<R: Runtime>(app: &AppHandle<R>, action: Action, path: &str, done: usize, total: usize) {
+    send(app, PROGRESS, Progress { action, path: path.to_string(), done, total });
+}
+
+// Argon2 can take seconds on slow hardware and can't report progress, so the
+// frontend is told when it starts and ends. Callers are async commands, which
+// already run off the main thread.
+pub fn derive_key_on_worker<R: Runtime, T>(app: &AppHandle<R>, path: Option<&str>, derive: impl FnOnce() -> T) -> T {
+    let path = path.map(str::to_string);
+    send(app, KEY_DERIVATION, KeyDerivation { path: path.clone(), done: false });
+    let result = derive();
+    send(app, KEY_DERIVATION, KeyDerivation { path, done: true });
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folder_transitions_carry_path_and_state() {
+        let app = tauri::test::mock_app();
+        let folder = ProtectedFolder { path: "/events/folder".into(), is_locked: true, file_count: 1, has_recovery: false, ..Default::default() };
+        emit_folder(&app.handle(), Action::Lock, &Ok(folder));
+        emit_folder(&app.handle(), Action::Unlock, &Err("Incorrect password".into()));
+        let sent: Vec<_> = emitted(STATE_CHANGED).into_iter().filter(|c| c["path"] == "/events/folder").collect();
+        assert_eq!(sent, vec![serde_json::json!({ "action": "lock", "path": "/events/folder", "is_locked": true })]);
+    }
+
+    #[test]
+    fn master_transitions_have_no_path() {
+        assert_eq!(StateChange::master(Action::MasterVerify), StateChange { action: Action::MasterVerify, path: None, is_locked: false });
+        assert_eq!(serde_json::to_value(Action::LockAll).unwrap(), "lock_all");
+    }
+}
@@ -0,0 +1,80 @@
+use crate::crypto;
+use crate::error::Error;
+use crate::secret::SecretKey;
+use ctap_hid_fido2::fidokey::{GetAssertionArgsBuilder, GetAssertionExtension, MakeCredentialArgsBuilder, MakeCredentialExtension};
+use ctap_hid_fido2::{Cfg, FidoKeyHid, FidoKeyHidFactory};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+// Credentials are scoped to this relying party; nothing is ever sent to it.
+const RP_ID: &str = "securelock.local";
+
+pub const NO_TOKEN: &str = "No security key found; insert it and try again";
+
+// A FIDO2 credential guarding the master key: the token's hmac-secret for
+// `salt` is mixed into the password key, so both are needed to unwrap it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HardwareKey {
+    pub credential_id: Vec<u8>,
+    pub salt: Vec<u8>,
+    // The master key, wrapped under crypto::hardware_bound_key.
+    pub wrapped_master: Vec<u8>,
+}
+
+// A security key with the hmac-secret extension.
+pub trait HmacSecretToken: Send + Sync {
+    // Makes a new credential on the token and returns its id.
+    fn enroll(&self) -> Result<Vec<u8>, Error>;
+    // The same credential and salt always give the same 32 bytes.
+    fn hmac_secret(&self, credential_id: &[u8], salt: &[u8]) -> Result<SecretKey, Error>;
+}
+
+// The first FIDO2 token on USB; every call waits for a touch.
+pub struct UsbToken;
+
+impl UsbToken {
+    fn device() -> Result<FidoKeyHid, Error> {
+        FidoKeyHidFactory::create(&Cfg::init()).map_err(|_| NO_TOKEN.into())
+    }
+}
+
+impl HmacSecretToken for UsbToken {
+    fn enroll(&self) -> Result<Vec<u8>, Error> {
+        let challenge = crypto::generate_salt();
+        let args = MakeCredentialArgsBuilder::new(RP_ID, &challenge)
+            .extensions(&[MakeCredentialExtension::HmacSecret(Some(true))])
+            .without_pin_and_uv()
+            .build();
+        let attestation = Self::device()?
+            .make_credential_with_args(&args)
+            .map_err(|e| format!("Security key enrollment failed: {}", e))?;
+        if !attestation.extensions.iter().any(|e| matches!(e, MakeCredentialExtension::HmacSecret(Some(true)))) {
+            return Err("This security key does not support hmac-secret".into());
+        }
+        Ok(attestation.credential_descriptor.id)
+    }
+
+    fn hmac_secret(&self, credential_id: &[u8], salt: &[u8]) -> Result<SecretKey, Error> {
+        let salt: [u8; 32] = salt.try_into().map_err(|_| "Invalid security key salt".to_string())?;
+        let challenge = crypto::generate_salt();
+        let args = GetAssertionArgsBuilder::new(RP_ID, &challenge)
+            .credential_id(credential_id)
+            .extensions(&[GetAssertionExtension::HmacSecret(Some(salt))])
+            .without_pin_and_uv()
+            .build();
+        let assertions = Self::device()?
+            .get_assertion_with_args(&args)
+            .map_err(|e| format!("Security key check failed: {}", e))?;
+        let mut output = assertions.iter()
+            .flat_map(|a| a.extensions.iter())
+            .find_map(|e| match e {
+                GetAssertionExtension::HmacSecret(Some(output)) => Some(*output),
+                _ => None,
+            })
+            .ok_or("The security key returned no hmac-secret")?;
+        let mut secret = SecretKey::zeroed();
+        secret.as_mut_bytes().copy_from_slice(&output);
+        output.zeroize();
+        Ok(secret)
+    }
+}
@@ -1,19 +1,214 @@
-use crate::crypto;
-use serde::{Deserialize, Serialize};
+use crate::budget::{self, ByteBudget};
+use crate::crypto::{self, CipherKind, KdfKind, KdfParams};
+use crate::error::{self, Error, ErrorCode};
+use crate::recipient::{self, Identity, RecipientKey};
+use crate::secret::SecretKey;
+use crate::vfs::{self, FileSystem, OsFileSystem, RetryPolicy, Retrying};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use unicode_normalization::UnicodeNormalization;
 use walkdir::WalkDir;
+use zeroize::Zeroize;
 
 const LOCKED_EXT: &str = ".locked";
 const META_FILE: &str = ".securelock";
+const REKEY_EXT: &str = ".rekey";
+const MANIFEST_EXT: &str = ".manifest";
+const RESERVE_EXT: &str = ".reserve";
+// lock_with_decoy sizes the reserve to the hidden data rounded up to a power of
+// two, and at least this, unless LockOptions::reserve says otherwise.
+const MIN_RESERVE: u64 = 1 << 20;
+// Reserves are built whole in memory, so LockOptions::reserve can't go over this.
+const MAX_RESERVE: u64 = 1 << 30;
+// A salt, then crypto::encrypt's nonce and tag around the padded contents.
+const RESERVE_SALT_LEN: usize = 32;
+const RESERVE_OVERHEAD: u64 = RESERVE_SALT_LEN as u64 + 12 + 16;
+const FORMAT_VERSION: u32 = 4;
+// The first field of every vault's metadata, checked before the rest is trusted.
+const META_MAGIC: &str = "securelock-vault";
+pub const NOT_A_VAULT: &str = "is not a SecureLock vault";
+// Recorded in new vaults, to tell which release wrote a folder that won't open.
+pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+// Recovery keys are wrapped with the folder salt as associated data from this version on.
+const BOUND_RECOVERY_VERSION: u32 = 2;
+// Each file is encrypted under its own subkey from this version on.
+const PER_FILE_KEY_VERSION: u32 = 3;
+// Metadata without META_MAGIC is refused from this version on.
+const MAGIC_VERSION: u32 = 4;
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+const CHANGED_FILE_RETRIES: usize = 2;
+const ARCHIVE_MAGIC: &[u8; 4] = b"SLK1";
+// Index length (u64, little-endian) and the magic again, at the very end.
+const ARCHIVE_TRAILER_LEN: u64 = 8 + 4;
+// Files larger than this are encrypted in chunks as they are read rather than
+// held in memory whole, unless LockOptions says otherwise.
+pub const DEFAULT_STREAM_THRESHOLD: u64 = 64 << 20;
+// Timestamps up to this far ahead of the clock are put down to machines
+// disagreeing slightly, and shown as "now".
+const CLOCK_SKEW_TOLERANCE: u64 = 5 * 60;
+
+// Always written as META_MAGIC, so older metadata gains it on its next save.
+// Reading any other value fails.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetaMagic;
+
+impl Serialize for MetaMagic {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(META_MAGIC)
+    }
+}
+
+impl<'de> Deserialize<'de> for MetaMagic {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let magic = String::deserialize(deserializer)?;
+        if magic != META_MAGIC {
+            return Err(serde::de::Error::invalid_value(serde::de::Unexpected::Str(&magic), &META_MAGIC));
+        }
+        Ok(MetaMagic)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FolderMeta {
+    #[serde(default)]
+    pub magic: MetaMagic,
+    // 0 for metadata written before the format was versioned.
+    #[serde(default)]
+    pub format_version: u32,
     pub salt: Vec<u8>,
+    // How the password key is derived from the password and salt. None is
+    // crypto::kdf_params(), which vaults from before this was stored used.
+    // Kept next to the salt so rebuild can salvage both from a truncated file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfParams>,
+    // The SecureLock version that created the vault. Absent from older metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_version: Option<String>,
     pub verify_token: Vec<u8>,
+    // Absent from the header of a split vault; filled in from the manifest.
+    #[serde(default)]
     pub files: Vec<FileMeta>,
+    // Merkle root over every ciphertext file in the folder as it was locked;
+    // see verify_vault_root. Absent from older metadata and while an
+    // interrupted unlock is part way through.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ciphertext_root: Option<Vec<u8>>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub recovery_key: Option<Vec<u8>>,
+    // The same key wrapped for age recipients / SSH public keys, so any one of
+    // the matching private keys can recover the folder.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<RecipientKey>,
+    // More passwords that unlock the folder, one slot each.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub password_slots: Vec<PasswordSlot>,
+    #[serde(default)]
+    pub cipher: CipherKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_binding: Option<Vec<u8>>,
+    // Set when only files matching this glob were locked; everything else in
+    // the folder was deliberately left as plaintext.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    // Independently locked subfolders and files that were left alone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nested: Vec<String>,
+    // Files a quick lock left plaintext for being too large, relative to the
+    // folder, until finish_lock encrypts them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deferred: Vec<String>,
+    // LockOptions::stream_threshold as the folder was locked, for files
+    // encrypted into it later. None is DEFAULT_STREAM_THRESHOLD.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_threshold: Option<u64>,
+    // When the folder was last locked, in seconds since the Unix epoch (UTC, so
+    // it reads the same in any timezone). Absent from older metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked_at: Option<u64>,
+    // The file list (and nested vaults) live in `<metadata>.manifest` instead,
+    // leaving the metadata file a small header that can be backed up on its own.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub split_manifest: bool,
+    // An upgrade_folder_kdf that hasn't finished; running it again resumes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf_upgrade: Option<KdfUpgrade>,
+    // The metadata a rekey_folder is swapping files in for, written before the
+    // first one is renamed into place. If the rekey stops part way the next
+    // change to the folder finishes it; see read_meta_to_change.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_rekey: Option<Box<FolderMeta>>,
+}
+
+// The new key derivation an upgrade re-encrypts to. Until `files` is set the
+// re-encrypted files are only staged beside the old ones, which still open
+// with the current metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KdfUpgrade {
+    pub kdf: KdfParams,
+    pub salt: Vec<u8>,
+    // The file list under the new key, once every file is staged. From then on
+    // the staged files are being swapped in and the upgrade has to finish
+    // before the folder opens again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileMeta>>,
+}
+
+// The folder's password key wrapped under a key derived from another password
+// and the slot's own salt, so each person sharing a folder can use their own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordSlot {
+    pub salt: Vec<u8>,
+    pub wrapped_key: Vec<u8>,
+}
+
+impl PasswordSlot {
+    fn new(kdf: &KdfParams, password: &str, password_key: &[u8; 32]) -> Result<Self, Error> {
+        let salt = crypto::generate_salt();
+        let slot_key = crypto::derive_key_with(kdf, password, &salt)?;
+        Ok(PasswordSlot { wrapped_key: crypto::wrap_key(&slot_key, password_key, &salt)?, salt: salt.to_vec() })
+    }
+}
+
+impl FolderMeta {
+    fn kdf(&self) -> KdfParams {
+        self.kdf.clone().unwrap_or_else(crypto::kdf_params)
+    }
+
+    fn password_key(&self, password: &str) -> Result<SecretKey, Error> {
+        crypto::derive_key_with(&self.kdf(), password, &self.salt)
+    }
+
+    fn upgrade_swapping(&self) -> bool {
+        self.kdf_upgrade.as_ref().is_some_and(|u| u.files.is_some())
+    }
+
+    // The metadata once an interrupted rekey is finished, for read-only
+    // callers that check a password or key against it without finishing it.
+    fn settled(self) -> FolderMeta {
+        match self.pending_rekey {
+            Some(pending) => *pending,
+            None => self,
+        }
+    }
+}
+
+// The part of a split vault's metadata that can be recreated from the locked
+// files with `rebuild_metadata`.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    files: Vec<FileMeta>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    nested: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deferred: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,140 +216,4946 @@ pub struct FileMeta {
     pub original_name: String,
     pub locked_name: String,
     pub relative_path: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub percent_encoded: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plaintext_hash: Option<Vec<u8>>,
+    // Relative path of the entry this file was a hard link to. Links have no
+    // ciphertext of their own and are re-linked to that file on unlock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hard_link_to: Option<String>,
+    // Relative path of an earlier entry with identical contents. Only that
+    // entry keeps ciphertext; this one is restored as a copy of it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    // Set when the contents were encrypted as a stream of chunks of this many
+    // bytes (see crypto::encrypt_stream) instead of in one piece.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_size: Option<u32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// Finds `path` under `root` even if its name, or a parent's, is now in another
+// Unicode normalization form. Components that can't be found are kept as given.
+fn resolve_normalized(root: &Path, path: &Path) -> PathBuf {
+    if path.exists() {
+        return path.to_path_buf();
+    }
+    let Ok(relative) = path.strip_prefix(root) else { return path.to_path_buf() };
+    let mut resolved = root.to_path_buf();
+    let mut components = relative.components();
+    for component in components.by_ref() {
+        let name = component.as_os_str();
+        if resolved.join(name).exists() {
+            resolved.push(name);
+            continue;
+        }
+        let Some(wanted) = name.to_str().map(|n| n.nfc().collect::<String>()) else {
+            resolved.push(name);
+            break;
+        };
+        let found = fs::read_dir(&resolved).ok().and_then(|entries| {
+            entries.flatten().find(|e| e.file_name().to_str().is_some_and(|n| n.nfc().eq(wanted.chars())))
+        });
+        match found {
+            Some(entry) => resolved.push(entry.file_name()),
+            None => {
+                resolved.push(name);
+                break;
+            }
+        }
+    }
+    resolved.extend(components);
+    resolved
+}
+
+impl FileMeta {
+    fn decode(&self, name: &str) -> OsString {
+        if self.percent_encoded { bytes_to_os(percent_decode(name)) } else { OsString::from(name) }
+    }
+
+    pub fn locked_path(&self, folder: &Path) -> PathBuf {
+        folder.join(self.decode(&self.relative_path)).with_file_name(self.decode(&self.locked_name))
+    }
+
+    pub fn original_path(&self, folder: &Path) -> PathBuf {
+        self.locked_path(folder).with_file_name(self.decode(&self.original_name))
+    }
+
+    // The `_on_disk` lookups tolerate names whose Unicode normalization was
+    // changed after locking, as sync services do between macOS (NFD) and
+    // everything else (NFC). New files are still created under the stored name.
+    fn locked_path_on_disk(&self, folder: &Path) -> PathBuf {
+        resolve_normalized(folder, &self.locked_path(folder))
+    }
+
+    fn original_path_on_disk(&self, folder: &Path) -> PathBuf {
+        let original = self.original_path(folder);
+        match (original.parent(), original.file_name()) {
+            (Some(parent), Some(name)) => resolve_normalized(folder, parent).join(name),
+            _ => original,
+        }
+    }
+
+    fn link_target(&self, folder: &Path) -> Option<PathBuf> {
+        self.hard_link_to.as_ref().map(|target| resolve_normalized(folder, &folder.join(self.decode(target))))
+    }
+
+    fn copy_source(&self, folder: &Path) -> Option<PathBuf> {
+        self.duplicate_of.as_ref().map(|source| resolve_normalized(folder, &folder.join(self.decode(source))))
+    }
+
+    // Metadata and archive indexes may have been tampered with, so every path
+    // an entry names has to stay inside the folder once decoded: no `..`, no
+    // root or drive prefix, and bare file names where one is expected.
+    fn is_contained(&self) -> bool {
+        let relative = |path: &str| {
+            let path = PathBuf::from(self.decode(path));
+            path.components().next().is_some() && path.components().all(|c| matches!(c, Component::Normal(_)))
+        };
+        let single = |name: &str| {
+            let name = PathBuf::from(self.decode(name));
+            matches!(name.components().collect::<Vec<_>>().as_slice(), [Component::Normal(_)])
+        };
+        relative(&self.relative_path)
+            && single(&self.original_name)
+            && single(&self.locked_name)
+            && self.hard_link_to.as_deref().is_none_or(relative)
+            && self.duplicate_of.as_deref().is_none_or(relative)
+    }
+
+    fn has_ciphertext(&self) -> bool {
+        self.hard_link_to.is_none() && self.duplicate_of.is_none()
+    }
+
+    // Files locked before checksums were recorded have no hash and are trusted
+    // on the AEAD tag alone.
+    fn verify_plaintext(&self, key: &[u8; 32], plaintext: &[u8]) -> Result<(), Error> {
+        self.verify_digest(&crypto::plaintext_digest(key, plaintext))
+    }
+
+    fn verify_digest(&self, digest: &[u8]) -> Result<(), Error> {
+        match &self.plaintext_hash {
+            Some(expected) if digest != expected.as_slice() => {
+                Err(Error::new(ErrorCode::Corrupted, format!("Integrity check failed for '{}': decrypted contents do not match the recorded checksum", self.relative_path)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// The folder key and how file contents are encrypted under it. Since
+// PER_FILE_KEY_VERSION every file gets a subkey derived from the folder key and
+// its relative path, so nothing needs storing and no two files share a key.
+#[derive(Clone, Copy)]
+struct ContentKey<'a> {
+    key: &'a [u8; 32],
+    cipher: CipherKind,
+    per_file: bool,
+}
+
+impl<'a> ContentKey<'a> {
+    fn new(meta: &FolderMeta, key: &'a [u8; 32]) -> Self {
+        ContentKey { key, cipher: meta.cipher, per_file: meta.format_version >= PER_FILE_KEY_VERSION }
+    }
+
+    fn file_key(&self, file_meta: &FileMeta) -> Result<Option<SecretKey>, Error> {
+        if !self.per_file { return Ok(None) }
+        crypto::derive_file_key(self.key, file_meta.relative_path.as_bytes()).map(Some)
+    }
+
+    // Streamed files can go through these too, whole in memory, for code paths
+    // that don't care how big the file is.
+    fn encrypt(&self, file_meta: &FileMeta, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        if file_meta.chunk_size.is_some() {
+            let mut out = Vec::with_capacity(plaintext.len() + plaintext.len() / crypto::STREAM_CHUNK_SIZE * 16 + 64);
+            self.encrypt_stream(file_meta, &mut &plaintext[..], &mut out, &mut |_| {})?;
+            return Ok(out);
+        }
+        let file_key = self.file_key(file_meta)?;
+        crypto::encrypt_with(self.cipher, file_key.as_deref().unwrap_or(self.key), plaintext)
+    }
+
+    fn decrypt(&self, file_meta: &FileMeta, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if file_meta.chunk_size.is_some() {
+            let mut out = Vec::with_capacity(data.len());
+            self.decrypt_stream(file_meta, &mut &data[..], &mut out, &mut |_| {})?;
+            return Ok(out);
+        }
+        let file_key = self.file_key(file_meta)?;
+        crypto::decrypt_with(self.cipher, file_key.as_deref().unwrap_or(self.key), data)
+    }
+
+    fn encrypt_stream(&self, file_meta: &FileMeta, reader: &mut dyn std::io::Read, writer: &mut dyn Write, on_chunk: &mut dyn FnMut(&[u8])) -> Result<u64, Error> {
+        let chunk_size = file_meta.chunk_size.ok_or("File is not set up for chunked encryption")? as usize;
+        let file_key = self.file_key(file_meta)?;
+        crypto::encrypt_stream(self.cipher, file_key.as_deref().unwrap_or(self.key), chunk_size, reader, writer, on_chunk)
+    }
+
+    fn decrypt_stream(&self, file_meta: &FileMeta, reader: &mut dyn std::io::Read, writer: &mut dyn Write, on_chunk: &mut dyn FnMut(&[u8])) -> Result<u64, Error> {
+        let chunk_size = file_meta.chunk_size.ok_or("File was not encrypted in chunks")? as usize;
+        let file_key = self.file_key(file_meta)?;
+        crypto::decrypt_stream(self.cipher, file_key.as_deref().unwrap_or(self.key), chunk_size, reader, writer, on_chunk)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VaultNames {
+    pub locked_ext: String,
+    pub meta_file: String,
+}
+
+impl Default for VaultNames {
+    fn default() -> Self {
+        VaultNames { locked_ext: LOCKED_EXT.into(), meta_file: META_FILE.into() }
+    }
+}
+
+impl VaultNames {
+    pub fn validate(&self) -> Result<(), Error> {
+        for (label, value) in [("Locked extension", &self.locked_ext), ("Metadata filename", &self.meta_file)] {
+            if value.is_empty() || value == "." || value.contains(['/', '\\']) {
+                return Err(format!("{} '{}' is not a valid file name", label, value).into());
+            }
+        }
+        if self.locked_ext == self.meta_file {
+            return Err("Locked extension and metadata filename must differ".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LockOptions {
+    pub cipher: CipherKind,
+    pub bind_to_machine: bool,
+    // Skips the check that refuses filesystem roots, home and OS directories.
+    pub allow_system_paths: bool,
+    pub on_unreadable: UnreadablePolicy,
+    // Bytes of file contents held in memory at once while encrypting; defaults
+    // to a quarter of the available memory.
+    pub memory_budget: Option<u64>,
+    // Which files are left out as hidden; None means HiddenPolicy::Unix.
+    pub hidden: Option<HiddenPolicy>,
+    // Files bigger than this many bytes are streamed; None means DEFAULT_STREAM_THRESHOLD.
+    pub stream_threshold: Option<u64>,
+    // Key derivation for new vaults, at its default parameters.
+    pub kdf: KdfKind,
+    // Drops to lighter Argon2id settings, recorded in the metadata, when the
+    // default 64 MiB can't be allocated; otherwise locking fails.
+    pub kdf_fallback: bool,
+    // age recipients (`age1...`) or SSH public keys that can recover the vault.
+    pub recipients: Vec<String>,
+    // Writes the file list to a separate manifest; see FolderMeta::split_manifest.
+    pub split_metadata: bool,
+    // Keeps `<metadata>.reserve`, this many bytes of random data that a hidden
+    // dataset may or may not live in; see lock_with_decoy.
+    pub reserve: Option<u64>,
+    // Quick lock: files over this many bytes are left as they are and recorded
+    // in FolderMeta::deferred, for finish_lock to encrypt later.
+    pub quick_lock_limit: Option<u64>,
+    // Leaves the metadata and `.locked` files visible on Windows, where the
+    // dot prefix doesn't hide them; by default they get the hidden attribute.
+    pub show_vault_files: bool,
+    // Retries for transient read and write errors on network mounts.
+    pub retry: RetryPolicy,
+    // Checked before a folder lock starts; None means LockLimits::default().
+    pub limits: Option<LockLimits>,
+    // Locks a folder over its limits anyway, once the user has confirmed.
+    pub ignore_limits: bool,
+}
+
+pub const OVER_LIMIT: &str = "exceeds the lock limit";
+
+// Soft limits so a huge directory picked by mistake fails up front instead of
+// locking for hours. None turns a limit off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockLimits {
+    pub max_files: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Default for LockLimits {
+    fn default() -> Self {
+        LockLimits { max_files: Some(100_000), max_bytes: Some(100 << 30) }
+    }
+}
+
+impl LockLimits {
+    fn check(&self, files: &[PathBuf]) -> Result<(), Error> {
+        let total = || files.iter().filter_map(|f| fs::metadata(f).ok()).map(|m| m.len()).sum();
+        self.check_totals(files.len() as u64, total)
+    }
+
+    // The byte total is only worked out if there is a byte limit.
+    fn check_totals(&self, count: u64, total: impl FnOnce() -> u64) -> Result<(), Error> {
+        if let Some(max) = self.max_files.filter(|&max| count > max) {
+            return Err(Error::new(ErrorCode::OverLimit, format!("The folder {} of {} files ({} found); confirm to lock it anyway", OVER_LIMIT, max, count)));
+        }
+        if let Some(max) = self.max_bytes {
+            let total = total();
+            if total > max {
+                return Err(Error::new(ErrorCode::OverLimit, format!("The folder {} of {} MiB ({} MiB found); confirm to lock it anyway", OVER_LIMIT, max >> 20, total >> 20)));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Which files a folder lock leaves alone as "hidden". Vault metadata is always
+// left alone, whatever the policy.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HiddenPolicy {
+    // Names starting with a dot.
+    #[default]
+    Unix,
+    // Files with the Windows hidden attribute; nothing elsewhere.
+    WindowsAttribute,
+    // Files whose name or path relative to the folder matches one of these globs.
+    Exclude(Vec<String>),
+    None,
+}
+
+impl HiddenPolicy {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let HiddenPolicy::Exclude(patterns) = self {
+            for pattern in patterns {
+                glob::Pattern::new(pattern).map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_hidden(&self, path: &Path, relative: &Path) -> bool {
+        let name = path.file_name().unwrap_or_default();
+        match self {
+            HiddenPolicy::Unix => name.to_string_lossy().starts_with('.'),
+            HiddenPolicy::WindowsAttribute => has_hidden_attribute(path),
+            HiddenPolicy::Exclude(patterns) => patterns.iter()
+                .filter_map(|p| glob::Pattern::new(p).ok())
+                .any(|p| p.matches_path(Path::new(name)) || p.matches_path(relative)),
+            HiddenPolicy::None => false,
+        }
+    }
+}
+
+#[cfg(windows)]
+fn has_hidden_attribute(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
+    fs::symlink_metadata(path).is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_path: &Path) -> bool {
+    false
+}
+
+// Purely cosmetic, so a file that can't be hidden is left as it is.
+#[cfg(windows)]
+fn set_hidden_attribute(path: &Path) {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::{SetFileAttributesW, FILE_ATTRIBUTE_HIDDEN};
+    let Ok(metadata) = fs::symlink_metadata(path) else { return };
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    unsafe { SetFileAttributesW(wide.as_ptr(), metadata.file_attributes() | FILE_ATTRIBUTE_HIDDEN) };
+}
+
+#[cfg(not(windows))]
+fn set_hidden_attribute(_path: &Path) {}
+
+// Hides a vault's bookkeeping on Windows: the metadata and manifest, and every
+// `.locked` file. Elsewhere their names already do.
+fn hide_vault_files(folder: &Path, meta_path: &Path, meta: &FolderMeta) {
+    if !cfg!(windows) {
+        return;
+    }
+    set_hidden_attribute(meta_path);
+    if meta.split_manifest {
+        set_hidden_attribute(&manifest_path(meta_path));
+    }
+    for file_meta in meta.files.iter().filter(|f| f.has_ciphertext()) {
+        set_hidden_attribute(&file_meta.locked_path(folder));
+    }
+}
+
+// Files a folder lock would encrypt: everything the hidden policy doesn't
+// exclude, minus vault metadata and anything inside nested vaults. FIFOs,
+// sockets and device nodes go in `special` instead; reading one can block
+// forever or never reach the end.
+fn lockable_files(folder: &Path, names: &VaultNames, hidden: &HiddenPolicy, nested: &mut Vec<PathBuf>, special: &mut Vec<PathBuf>) -> Vec<PathBuf> {
+    let entries = WalkDir::new(folder)
+        .into_iter()
+        .filter_entry(|e| {
+            let is_vault = e.depth() > 0 && if e.file_type().is_dir() { is_vault_dir(e.path(), names) } else { is_locked_single_file(e.path()) };
+            if is_vault {
+                nested.push(e.path().to_path_buf());
+            }
+            !is_vault
+        })
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.file_type().is_dir() && !e.file_type().is_symlink())
+        .filter(|e| !hidden.is_hidden(e.path(), e.path().strip_prefix(folder).unwrap_or(e.path())));
+    let mut files = Vec::new();
+    for entry in entries {
+        if !entry.file_type().is_file() {
+            special.push(entry.into_path());
+        } else if !is_vault_metadata(entry.path(), names) {
+            files.push(entry.into_path());
+        }
+    }
+    files
+}
+
+// The metadata, its manifest and reserve, and the temp files written in their
+// place; a single file's `.<name>.securelock` counts too. Only whole names
+// match, so a user's `.securelock-notes.txt` is locked like anything else.
+fn is_vault_metadata(path: &Path, names: &VaultNames) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let name = name.strip_suffix(".tmp").unwrap_or(&name);
+    let name = name.strip_suffix(MANIFEST_EXT).or_else(|| name.strip_suffix(RESERVE_EXT)).unwrap_or(name);
+    name == names.meta_file || name == META_FILE
+        || name.strip_suffix(META_FILE).is_some_and(|rest| rest.len() > 1 && rest.starts_with('.'))
+}
+
+// What to do with a file that can't be read while locking (no permission,
+// held open by another process).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnreadablePolicy {
+    #[default]
+    Abort,
+    // Leave the file as plaintext and report it in `ProtectedFolder::skipped`.
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    #[default]
+    Quarantine,
+    Error,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UnlockOptions {
+    pub on_conflict: CollisionPolicy,
+    // Decrypt copies into this directory instead of in place, leaving the
+    // vault itself locked.
+    pub output_dir: Option<String>,
+    // Retries for transient read and write errors, as for LockOptions.
+    pub retry: RetryPolicy,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ProtectedFolder {
     pub path: String,
     pub is_locked: bool,
     pub file_count: usize,
     pub has_recovery: bool,
+    // Files left unencrypted because they couldn't be read, or aren't regular
+    // files (FIFOs, sockets, device nodes).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<String>,
+    // Display name from the app's config; the library never sets it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    // Free-text note from the app's config, also never set by the library.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    // Why a tracked path can't be used as a folder right now (gone, or a file
+    // in its place); the counts are zero while it is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub problem: Option<String>,
+    // Absolute paths an unlock wrote (or found already restored by an earlier,
+    // interrupted one).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub restored: Vec<String>,
+    // Files an unlock skipped because their ciphertext was missing and nothing
+    // had been restored in its place, relative to the folder.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+    // Files an unlock couldn't restore; while there are any the folder stays
+    // locked with just those files left in its metadata.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<FileFailure>,
+    // Large files a quick lock left for finish_lock, relative to the folder.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deferred: Vec<String>,
 }
 
-pub fn lock_folder(folder_path: &str, password: &str, master_key: Option<&[u8; 32]>) -> Result<ProtectedFolder, String> {
-    let folder = Path::new(folder_path);
-    if !folder.is_dir() {
-        return Err(format!("'{}' is not a valid directory", folder_path));
+// Everything here is safe to show without the password; no key material.
+#[derive(Debug, Clone, Serialize)]
+pub struct FolderInfo {
+    pub path: String,
+    pub format_version: u32,
+    pub cipher: CipherKind,
+    pub kdf: KdfParams,
+    pub salt_len: usize,
+    pub file_count: usize,
+    pub has_recovery: bool,
+    // Public keys whose private keys can recover the folder.
+    pub recipients: Vec<String>,
+    // Passwords besides the folder's own that unlock it.
+    pub password_slots: usize,
+    pub machine_bound: bool,
+    pub pattern: Option<String>,
+    pub nested: Vec<String>,
+    // Unix seconds. None when unknown, or when the stored time is in the future
+    // (a wrong clock here or where it was locked), which locked_in_future flags.
+    pub locked_at: Option<u64>,
+    pub locked_in_future: bool,
+    // The SecureLock version that created the vault, if it was recorded.
+    pub app_version: Option<String>,
+}
+
+// A file as recorded in a vault's metadata. Names are stored in the clear, so
+// listing needs no password.
+#[derive(Debug, Clone, Serialize)]
+pub struct LockedFile {
+    pub path: String,
+    // Plaintext size, worked out from the ciphertext; None if that is missing.
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FolderState {
+    CleanLocked,
+    CleanPlaintext,
+    PartiallyLocked,
+    OrphanedCiphertext,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FolderDiagnosis {
+    pub path: String,
+    pub state: FolderState,
+    pub inconsistent_files: Vec<String>,
+    // Partly written ciphertext from an interrupted lock, relative to the
+    // folder; clean_interrupted_lock removes it.
+    pub leftover_temp_files: Vec<String>,
+}
+
+pub fn lock_folder(folder_path: &str, names: &VaultNames, password: &str, master_key: Option<&SecretKey>, options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    lock_folder_with_passwords(folder_path, names, password, &[], master_key, options)
+}
+
+// Like lock_folder, with a password slot for each of `other_passwords` so any
+// one of the passwords unlocks the folder.
+pub fn lock_folder_with_passwords(folder_path: &str, names: &VaultNames, password: &str, other_passwords: &[&str], master_key: Option<&SecretKey>, options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    lock_selected(folder_path, names, None, || new_vault(password, other_passwords, master_key, options), options)
+}
+
+// Locks only the files whose path relative to the folder matches `pattern`
+// (e.g. `*.pem`); the rest of the folder stays plaintext.
+pub fn lock_matching(folder_path: &str, names: &VaultNames, pattern: &str, password: &str, master_key: Option<&SecretKey>, options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    let pattern = glob::Pattern::new(pattern).map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))?;
+    lock_selected(folder_path, names, Some(&pattern), || new_vault(password, &[], master_key, options), options)
+}
+
+// What re-locking a folder needs without its password: the folder key and the
+// salt, verify token and wrapped recovery key it was locked with. Taken from
+// the metadata before an unlock removes it.
+pub struct RelockKey {
+    meta: FolderMeta,
+    key: SecretKey,
+}
+
+impl RelockKey {
+    pub fn new(folder_path: &str, names: &VaultNames, key: SecretKey) -> Result<Self, Error> {
+        let (mut meta, _) = read_meta_to_change(folder_path, names)?;
+        if !crypto::verify_password(&key, &meta.verify_token) {
+            return Err(incorrect_password());
+        }
+        meta.files.clear();
+        meta.nested.clear();
+        meta.deferred.clear();
+        Ok(RelockKey { meta, key })
+    }
+
+    pub fn key(&self) -> &SecretKey {
+        &self.key
     }
-    let meta_path = folder.join(META_FILE);
+}
+
+// Locks the folder again under the key and parameters it had before being
+// unlocked, so its password and recovery key keep working.
+pub fn relock_folder(folder_path: &str, names: &VaultNames, relock: &RelockKey, options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    let pattern = relock.meta.pattern.as_deref()
+        .map(|p| glob::Pattern::new(p).map_err(|e| format!("Invalid pattern '{}': {}", p, e)))
+        .transpose()?;
+    lock_selected(folder_path, names, pattern.as_ref(), || Ok((relock.meta.clone(), relock.key.clone())), options)
+}
+
+fn lock_selected(folder_path: &str, names: &VaultNames, pattern: Option<&glob::Pattern>, vault: impl FnOnce() -> Result<(FolderMeta, SecretKey), Error>, options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    let folder = check_folder_path(folder_path)?;
+    if !options.allow_system_paths {
+        check_lock_target(folder)?;
+    }
+    let meta_path = folder.join(&names.meta_file);
     if meta_path.exists() {
-        return Err("Folder is already locked".into());
+        return Err(already_locked(&meta_path, "Folder").into());
     }
-    let salt = crypto::generate_salt();
-    let key = crypto::derive_key(password, &salt)?;
-    let verify_token = crypto::create_verify_token(&key)?;
-    let recovery_key = match master_key {
-        Some(mk) => Some(crypto::wrap_key(mk, &key)?),
-        None => None,
-    };
-    let files: Vec<PathBuf> = WalkDir::new(folder)
+    let hidden = options.hidden.clone().unwrap_or_default();
+    hidden.validate()?;
+    if let Some(capacity) = options.reserve {
+        check_reserve_size(capacity)?;
+    }
+    clean_interrupted_lock(folder_path, names)?;
+    // Vaults already inside this folder are skipped whole; encrypting their
+    // ciphertext and metadata again would make them unrecoverable.
+    let mut nested: Vec<PathBuf> = Vec::new();
+    let mut special: Vec<PathBuf> = Vec::new();
+    let selected = |p: &PathBuf| pattern.is_none_or(|pat| p.strip_prefix(folder).is_ok_and(|rel| pat.matches_path(rel)));
+    let (deferred, files): (Vec<PathBuf>, Vec<PathBuf>) = lockable_files(folder, names, &hidden, &mut nested, &mut special)
         .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| {
-            e.file_type().is_file()
-                && !e.file_name().to_str().map(|n| n.starts_with('.')).unwrap_or(false)
-        })
-        .map(|e| e.into_path())
-        .collect();
-    let mut file_metas = Vec::new();
+        .filter(selected)
+        .partition(|f| options.quick_lock_limit.is_some_and(|limit| fs::metadata(f).is_ok_and(|m| m.len() > limit)));
+    special.retain(selected);
+    if files.is_empty() {
+        return Err(match pattern {
+            _ if !deferred.is_empty() => "Nothing to lock: every file is over the quick-lock size limit".into(),
+            Some(pattern) => format!("No files match '{}'", pattern).into(),
+            None if hidden == HiddenPolicy::None => "Nothing to lock: the folder has no files to encrypt".into(),
+            None => "Nothing to lock: the folder has no files to encrypt (hidden files are skipped)".into(),
+        });
+    }
+    if !options.ignore_limits {
+        options.limits.unwrap_or_default().check(&files)?;
+    }
+    let (mut meta, key) = vault()?;
+    if let Some(capacity) = options.reserve {
+        ensure_reserve(folder, names, capacity)?;
+    }
+    meta.locked_at = Some(unix_now());
+    meta.pattern = pattern.map(|p| p.to_string());
+    meta.nested = nested.iter().map(|p| p.strip_prefix(folder).unwrap_or(p).to_string_lossy().to_string()).collect();
+    meta.deferred = deferred.iter().map(|p| p.strip_prefix(folder).unwrap_or(p).to_string_lossy().to_string()).collect();
+    let links = find_hard_links(&files);
+    let to_encrypt: Vec<&PathBuf> = files.iter().filter(|f| !links.contains_key(*f)).collect();
+    let budget = ByteBudget::new(options.memory_budget.unwrap_or_else(budget::default_budget));
+    let stream_threshold = options.stream_threshold.unwrap_or(DEFAULT_STREAM_THRESHOLD);
+    let mut encrypted = match encrypt_files(folder, &to_encrypt, ContentKey::new(&meta, &key), &names.locked_ext, stream_threshold, options, &budget) {
+        Ok(encrypted) => encrypted.into_iter(),
+        Err(failure) => return Err(failure.keep(folder, names, &meta_path, meta)),
+    };
+    let mut skipped: Vec<&PathBuf> = special.iter().collect();
     for file_path in &files {
-        let relative = file_path.strip_prefix(folder).map_err(|e| format!("Path error: {}", e))?;
-        let original_name = file_path.file_name().and_then(|n| n.to_str()).ok_or("Invalid filename")?.to_string();
-        let locked_name = format!("{}{}", original_name, LOCKED_EXT);
-        let plaintext = fs::read(file_path).map_err(|e| format!("Failed to read '{}': {}", file_path.display(), e))?;
-        let encrypted = crypto::encrypt(&key, &plaintext)?;
-        let locked_path = file_path.with_file_name(&locked_name);
-        fs::write(&locked_path, &encrypted).map_err(|e| format!("Failed to write '{}': {}", locked_path.display(), e))?;
-        fs::remove_file(file_path).map_err(|e| format!("Failed to remove original '{}': {}", file_path.display(), e))?;
-        file_metas.push(FileMeta { original_name, locked_name, relative_path: relative.to_string_lossy().to_string() });
-    }
-    let has_recovery = recovery_key.is_some();
-    let meta = FolderMeta { salt: salt.to_vec(), verify_token, files: file_metas.clone(), recovery_key };
-    let meta_json = serde_json::to_string_pretty(&meta).map_err(|e| format!("Metadata serialization error: {}", e))?;
-    fs::write(&meta_path, &meta_json).map_err(|e| format!("Failed to write metadata: {}", e))?;
-    Ok(ProtectedFolder { path: folder_path.to_string(), is_locked: true, file_count: file_metas.len(), has_recovery })
-}
-
-fn decrypt_files(folder: &Path, key: &[u8; 32], files: &[FileMeta]) -> Result<(), String> {
-    for file_meta in files {
-        let locked_path = folder.join(&file_meta.relative_path).with_file_name(&file_meta.locked_name);
-        if !locked_path.exists() { continue; }
-        let encrypted = fs::read(&locked_path).map_err(|e| format!("Failed to read '{}': {}", locked_path.display(), e))?;
-        let plaintext = crypto::decrypt(key, &encrypted)?;
-        let original_path = locked_path.with_file_name(&file_meta.original_name);
-        fs::write(&original_path, &plaintext).map_err(|e| format!("Failed to write '{}': {}", original_path.display(), e))?;
-        fs::remove_file(&locked_path).map_err(|e| format!("Failed to remove '{}': {}", locked_path.display(), e))?;
+        let file_meta = match links.get(file_path) {
+            // A link to a skipped file stays plaintext along with it.
+            Some(primary) if skipped.contains(&primary) => None,
+            Some(primary) => Some(link_file(folder, file_path, primary, &names.locked_ext)?),
+            None => encrypted.next().flatten(),
+        };
+        match file_meta {
+            Some(file_meta) => meta.files.push(file_meta),
+            None => skipped.push(file_path),
+        }
     }
-    Ok(())
+    if meta.files.is_empty() {
+        return Err("Nothing to lock: none of the files could be read".into());
+    }
+    dedup_ciphertext(folder, &mut meta.files)?;
+    meta.ciphertext_root = Some(ciphertext_root(folder, names, &meta.nested)?);
+    write_meta(&meta_path, &meta)?;
+    if !options.show_vault_files {
+        hide_vault_files(folder, &meta_path, &meta);
+        set_hidden_attribute(&reserve_path(folder, names));
+    }
+    let skipped = skipped.iter().map(|p| p.strip_prefix(folder).unwrap_or(p).to_string_lossy().to_string()).collect();
+    Ok(ProtectedFolder { path: folder_path.to_string(), is_locked: true, file_count: meta.files.len(), has_recovery: meta.recovery_key.is_some(), skipped, deferred: meta.deferred, ..Default::default() })
 }
 
-fn read_meta(folder_path: &str) -> Result<(FolderMeta, PathBuf), String> {
-    let folder = Path::new(folder_path);
-    let meta_path = folder.join(META_FILE);
-    if !meta_path.exists() {
-        return Err("Folder is not locked (no .securelock metadata found)".into());
+pub fn lock_file(file_path: &str, password: &str, master_key: Option<&SecretKey>, options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    let file = Path::new(file_path);
+    if !file.is_file() {
+        return Err(Error::new(ErrorCode::NotFound, format!("'{}' is not a valid file", file_path)));
     }
-    let meta_json = fs::read_to_string(&meta_path).map_err(|e| format!("Failed to read metadata: {}", e))?;
-    let meta: FolderMeta = serde_json::from_str(&meta_json).map_err(|e| format!("Invalid metadata: {}", e))?;
-    Ok((meta, meta_path))
+    let meta_path = file_meta_path(file)?;
+    if meta_path.exists() {
+        return Err(already_locked(&meta_path, "File").into());
+    }
+    let dir = file.parent().ok_or("File has no parent directory")?;
+    let (mut meta, key) = new_vault(password, &[], master_key, options)?;
+    let file_meta = encrypt_file(dir, file, ContentKey::new(&meta, &key), LOCKED_EXT, options.stream_threshold.unwrap_or(DEFAULT_STREAM_THRESHOLD), options.retry)?;
+    meta.files.push(file_meta);
+    write_meta(&meta_path, &meta)?;
+    if !options.show_vault_files {
+        hide_vault_files(dir, &meta_path, &meta);
+    }
+    Ok(ProtectedFolder { path: file_path.to_string(), is_locked: true, file_count: 1, has_recovery: meta.recovery_key.is_some(), ..Default::default() })
 }
 
-pub fn unlock_folder(folder_path: &str, password: &str) -> Result<ProtectedFolder, String> {
-    let (meta, meta_path) = read_meta(folder_path)?;
-    let folder = Path::new(folder_path);
-    let salt: [u8; 32] = meta.salt.clone().try_into().map_err(|_| "Invalid salt in metadata")?;
-    let key = crypto::derive_key(password, &salt)?;
-    if !crypto::verify_password(&key, &meta.verify_token) {
-        return Err("Incorrect password".into());
+fn incorrect_password() -> Error {
+    Error::new(ErrorCode::WrongPassword, "Incorrect password")
+}
+
+fn already_locked(meta_path: &Path, what: &str) -> String {
+    if looks_like_meta(meta_path) {
+        format!("{} is already locked", what)
+    } else if fs::read(meta_path).is_ok_and(|data| salvage_bytes(&String::from_utf8_lossy(&data), "salt").is_some()) {
+        // Moving damaged metadata aside would lose the salt rebuild needs.
+        format!("{} is already locked, but its metadata is damaged; rebuild it with the password", what)
+    } else {
+        format!("'{}' is in the way and is not SecureLock metadata; move it elsewhere before locking", meta_path.display())
     }
-    let file_count = meta.files.len();
-    decrypt_files(folder, &key, &meta.files)?;
-    fs::remove_file(&meta_path).map_err(|e| format!("Failed to remove metadata: {}", e))?;
-    Ok(ProtectedFolder { path: folder_path.to_string(), is_locked: false, file_count, has_recovery: false })
 }
 
-pub fn unlock_folder_with_master_key(folder_path: &str, master_key: &[u8; 32]) -> Result<ProtectedFolder, String> {
-    let (meta, meta_path) = read_meta(folder_path)?;
-    let folder = Path::new(folder_path);
-    let wrapped = meta.recovery_key.ok_or("No recovery key found for this folder")?;
-    let folder_key = crypto::unwrap_key(master_key, &wrapped)?;
-    if !crypto::verify_password(&folder_key, &meta.verify_token) {
-        return Err("Master password verification failed".into());
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct MetaProbe {
+    magic: Option<String>,
+    format_version: u32,
+    salt: Vec<u8>,
+    verify_token: Vec<u8>,
+}
+
+// Tells our metadata from an unrelated file that happens to have the same
+// name: it carries META_MAGIC, or was written before the magic was added
+// (an earlier format version, 0 if unversioned) and has a salt and verify token.
+fn is_meta_data(data: &[u8]) -> bool {
+    match serde_json::from_slice::<MetaProbe>(data) {
+        Ok(MetaProbe { magic: Some(magic), .. }) => magic == META_MAGIC,
+        Ok(probe) => probe.format_version < MAGIC_VERSION && !probe.salt.is_empty() && !probe.verify_token.is_empty(),
+        Err(_) => false,
     }
-    let file_count = meta.files.len();
-    decrypt_files(folder, &folder_key, &meta.files)?;
-    fs::remove_file(&meta_path).map_err(|e| format!("Failed to remove metadata: {}", e))?;
-    Ok(ProtectedFolder { path: folder_path.to_string(), is_locked: false, file_count, has_recovery: false })
 }
 
-pub fn has_recovery_key(folder_path: &str) -> bool {
-    if let Ok((meta, _)) = read_meta(folder_path) {
-        return meta.recovery_key.is_some();
+fn looks_like_meta(meta_path: &Path) -> bool {
+    fs::read(meta_path).is_ok_and(|data| is_meta_data(&data))
+}
+
+fn is_vault_dir(dir: &Path, names: &VaultNames) -> bool {
+    looks_like_meta(&dir.join(&names.meta_file)) || looks_like_meta(&dir.join(META_FILE))
+}
+
+// `name.locked` with a `.name.securelock` beside it, as written by `lock_file`.
+fn is_locked_single_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.strip_suffix(LOCKED_EXT))
+        .is_some_and(|original| looks_like_meta(&path.with_file_name(format!(".{}{}", original, META_FILE))))
+}
+
+// Refuses folders whose encryption would break the system or the user's
+// account: filesystem roots, the home directory and anything above it, and
+// OS/program directories.
+pub fn check_lock_target(folder: &Path) -> Result<(), Error> {
+    let folder = folder.canonicalize().map_err(|e| Error::io(&e, format!("Invalid folder '{}': {}", folder.display(), e)))?;
+    if folder.parent().is_none() {
+        return Err(format!("Refusing to lock '{}': it is a filesystem root", folder.display()).into());
     }
-    false
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    if let Some(home) = home.and_then(|h| Path::new(&h).canonicalize().ok()) {
+        if home.starts_with(&folder) {
+            return Err(format!("Refusing to lock '{}': it contains your home directory", folder.display()).into());
+        }
+    }
+    let system_dir = system_dirs().into_iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .find(|dir| folder.starts_with(dir));
+    if let Some(dir) = system_dir {
+        return Err(format!("Refusing to lock '{}': it is inside the system directory '{}'", folder.display(), dir.display()).into());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn system_dirs() -> Vec<PathBuf> {
+    ["SystemRoot", "ProgramFiles", "ProgramFiles(x86)", "ProgramData"]
+        .iter()
+        .filter_map(|var| std::env::var_os(var).map(PathBuf::from))
+        .collect()
+}
+
+#[cfg(not(windows))]
+fn system_dirs() -> Vec<PathBuf> {
+    ["/bin", "/boot", "/dev", "/etc", "/lib", "/lib32", "/lib64", "/proc", "/sbin", "/sys", "/usr",
+     "/System", "/Library", "/Applications"]
+        .iter()
+        .map(PathBuf::from)
+        .collect()
+}
+
+// Builds metadata (with an empty file list) and the matching content key for a
+// new vault, wrapping the key for recovery and binding it to this machine if asked.
+fn new_vault(password: &str, other_passwords: &[&str], master_key: Option<&SecretKey>, options: &LockOptions) -> Result<(FolderMeta, SecretKey), Error> {
+    let machine_id = if options.bind_to_machine { Some(current_machine_id()?) } else { None };
+    let salt = crypto::generate_salt();
+    let kdf = crypto::default_kdf_params(options.kdf);
+    let (kdf, password_key) = if options.kdf_fallback {
+        crypto::derive_key_with_fallback(kdf, password, &salt)?
+    } else {
+        let key = crypto::derive_key_with(&kdf, password, &salt)?;
+        (kdf, key)
+    };
+    let recovery_key = match master_key {
+        Some(mk) => Some(crypto::wrap_key(mk, &password_key, &salt)?),
+        None => None,
+    };
+    let recipients = options.recipients.iter().map(|r| recipient::wrap_key(r, &password_key)).collect::<Result<_, _>>()?;
+    let password_slots = other_passwords.iter().map(|p| PasswordSlot::new(&kdf, p, &password_key)).collect::<Result<_, _>>()?;
+    let machine_binding = machine_id.as_deref().map(|id| crypto::machine_fingerprint(&salt, id));
+    let key = match &machine_id {
+        Some(id) => crypto::bind_key_to_machine(&password_key, id)?,
+        None => password_key,
+    };
+    let verify_token = crypto::create_verify_token(&key)?;
+    let meta = FolderMeta { magic: MetaMagic, format_version: FORMAT_VERSION, salt: salt.to_vec(), verify_token, files: Vec::new(), ciphertext_root: None, recovery_key, recipients, password_slots, cipher: options.cipher, machine_binding, pattern: None, nested: Vec::new(), deferred: Vec::new(), stream_threshold: options.stream_threshold, locked_at: Some(unix_now()), split_manifest: options.split_metadata, kdf: Some(kdf), app_version: Some(APP_VERSION.to_string()), kdf_upgrade: None, pending_rekey: None };
+    Ok((meta, key))
 }
 
-pub fn is_locked(folder_path: &str) -> bool {
-    Path::new(folder_path).join(META_FILE).exists()
+// Why encrypt_files stopped, with the files it had already encrypted and
+// couldn't decrypt back into place.
+struct EncryptFailure {
+    error: Error,
+    locked: Vec<FileMeta>,
 }
 
-pub fn get_locked_file_count(folder_path: &str) -> usize {
-    let meta_path = Path::new(folder_path).join(META_FILE);
-    if let Ok(json) = fs::read_to_string(&meta_path) {
-        if let Ok(meta) = serde_json::from_str::<FolderMeta>(&json) {
-            return meta.files.len();
+impl EncryptFailure {
+    // Adds the files left locked to `meta` and writes it, so their ciphertext
+    // stays recoverable with the password, and returns the error to report.
+    fn keep(self, folder: &Path, names: &VaultNames, meta_path: &Path, mut meta: FolderMeta) -> Error {
+        if self.locked.is_empty() {
+            return self.error;
+        }
+        let count = self.locked.len();
+        meta.files.extend(self.locked);
+        meta.ciphertext_root = ciphertext_root(folder, names, &meta.nested).ok();
+        match write_meta(meta_path, &meta) {
+            Ok(()) => self.error.context(|m| format!("{} ({} files already encrypted couldn't be restored and were left locked)", m, count)),
+            Err(e) => self.error.context(|m| format!("{} ({} files already encrypted couldn't be restored or recorded: {})", m, count, e)),
         }
     }
-    0
 }
 
-pub fn count_files(folder_path: &str) -> usize {
-    WalkDir::new(folder_path)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| {
-            e.file_type().is_file()
-                && !e.file_name().to_str().map(|n| n.starts_with('.')).unwrap_or(false)
-        })
-        .count()
+// Encrypts `files` on a few threads, holding at most `budget` bytes of file
+// contents at once; a streamed file only counts its two chunk buffers. Results
+// come back in input order; None is an unreadable file skipped under
+// UnreadablePolicy::Skip. After the first error no new files are started, the
+// files already encrypted are decrypted back into place and that error is
+// returned.
+fn encrypt_files(folder: &Path, files: &[&PathBuf], key: ContentKey, locked_ext: &str, stream_threshold: u64, options: &LockOptions, budget: &ByteBudget) -> Result<Vec<Option<FileMeta>>, EncryptFailure> {
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1).min(files.len()).max(1);
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new(vec![None; files.len()]);
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= files.len() || failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                let file_path = files[i];
+                // Swapped for a FIFO or the like since the folder was walked;
+                // opening it could block.
+                if fs::symlink_metadata(file_path).is_ok_and(|m| !m.is_file()) {
+                    results.lock().unwrap()[i] = Some(Ok(None));
+                    continue;
+                }
+                let result = match vfs::retry(options.retry, || fs::File::open(file_path).and_then(|f| f.metadata())) {
+                    Ok(metadata) => {
+                        let in_memory = if metadata.len() > stream_threshold { 2 * crypto::STREAM_CHUNK_SIZE as u64 } else { metadata.len() };
+                        let _permit = budget.acquire(in_memory);
+                        encrypt_file(folder, file_path, key, locked_ext, stream_threshold, options.retry).map(Some)
+                    }
+                    Err(_) if options.on_unreadable == UnreadablePolicy::Skip => Ok(None),
+                    Err(e) => Err(Error::io(&e, format!("Failed to read '{}': {}", file_path.display(), e))),
+                };
+                if result.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    let results: Vec<Result<Option<FileMeta>, Error>> = results.into_inner().unwrap().into_iter().flatten().collect();
+    let Some(error) = results.iter().find_map(|r| r.as_ref().err().cloned()) else {
+        return Ok(results.into_iter().map(|r| r.unwrap_or_default()).collect());
+    };
+    // Their originals are already gone.
+    let done: Vec<FileMeta> = results.into_iter().filter_map(|r| r.ok().flatten()).collect();
+    let locked = match decrypt_files(folder, None, key, &done, CollisionPolicy::Quarantine, options.retry, &mut |_, _| Ok(())) {
+        Ok(restored) => restored.failed_entries.iter().map(|&i| done[i].clone()).collect(),
+        Err(_) => done,
+    };
+    Err(EncryptFailure { error, locked })
+}
+
+// The original is only removed if it is unchanged since it was read; a file
+// written to in the meantime is read again, and left alone if it won't settle.
+// Files over `stream_threshold` bytes are encrypted in chunks as they are read.
+fn encrypt_file(folder: &Path, file_path: &Path, key: ContentKey, locked_ext: &str, stream_threshold: u64, retry: RetryPolicy) -> Result<FileMeta, Error> {
+    let mut file_meta = describe_file(folder, file_path, locked_ext, false)?;
+    let locked_path = file_meta.locked_path(folder);
+    // The ciphertext goes under a temporary name and is renamed into place once
+    // complete, so an interrupted lock never leaves a truncated `.locked` file
+    // behind; see clean_interrupted_lock.
+    let temp_path = temp_path(&locked_path);
+    let result = encrypt_unchanged(file_path, &temp_path, key, &mut file_meta, stream_threshold, retry).and_then(|unchanged| {
+        if !unchanged {
+            return Err(Error::new(ErrorCode::Busy, format!("'{}' kept changing while it was being locked; close any program writing to it and try again", file_path.display())));
+        }
+        vfs::retry(retry, || fs::rename(&temp_path, &locked_path)).map_err(|e| Error::io(&e, format!("Failed to write '{}': {}", locked_path.display(), e)))
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    fs::remove_file(file_path).map_err(|e| Error::io(&e, format!("Failed to remove original '{}': {}", file_path.display(), e)))?;
+    Ok(file_meta)
+}
+
+// Encrypts `file_path` into `output`, again if the file changed meanwhile.
+// False if it never held still. A streamed file is only retried on opening.
+fn encrypt_unchanged(file_path: &Path, output: &Path, key: ContentKey, file_meta: &mut FileMeta, stream_threshold: u64, retry: RetryPolicy) -> Result<bool, Error> {
+    for _ in 0..=CHANGED_FILE_RETRIES {
+        let before = file_stamp(file_path)?;
+        let (len, digest) = if before.0 > stream_threshold {
+            file_meta.chunk_size = Some(crypto::STREAM_CHUNK_SIZE as u32);
+            encrypt_streaming(file_path, output, key, file_meta, retry)?
+        } else {
+            file_meta.chunk_size = None;
+            let plaintext = vfs::retry(retry, || fs::read(file_path)).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", file_path.display(), e)))?;
+            let encrypted = key.encrypt(file_meta, &plaintext)?;
+            vfs::retry(retry, || fs::write(output, &encrypted)).map_err(|e| Error::io(&e, format!("Failed to write '{}': {}", output.display(), e)))?;
+            (plaintext.len() as u64, crypto::plaintext_digest(key.key, &plaintext))
+        };
+        if len == before.0 && file_stamp(file_path)? == before {
+            file_meta.plaintext_hash = Some(digest);
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn encrypt_streaming(file_path: &Path, locked_path: &Path, key: ContentKey, file_meta: &FileMeta, retry: RetryPolicy) -> Result<(u64, Vec<u8>), Error> {
+    let input = vfs::retry(retry, || fs::File::open(file_path)).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", file_path.display(), e)))?;
+    let output = vfs::retry(retry, || fs::File::create(locked_path)).map_err(|e| Error::io(&e, format!("Failed to write '{}': {}", locked_path.display(), e)))?;
+    let mut writer = std::io::BufWriter::new(output);
+    let mut hasher = crypto::PlaintextHasher::new(key.key);
+    let len = key.encrypt_stream(file_meta, &mut std::io::BufReader::new(input), &mut writer, &mut |chunk| hasher.update(chunk))
+        .and_then(|len| writer.flush().map(|_| len).map_err(|e| Error::io(&e, format!("Write error: {}", e))))
+        .map_err(|e| e.context(|m| format!("Failed to encrypt '{}': {}", file_path.display(), m)))?;
+    Ok((len, hasher.finalize()))
+}
+
+fn file_stamp(path: &Path) -> Result<(u64, Option<SystemTime>), Error> {
+    let metadata = fs::metadata(path).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", path.display(), e)))?;
+    Ok((metadata.len(), metadata.modified().ok()))
+}
+
+// Records a hard link to an already encrypted file instead of encrypting the
+// same contents twice. The entry is percent-encoded whenever the target path
+// needs it, so both names decode the same way.
+fn link_file(folder: &Path, file_path: &Path, primary: &Path, locked_ext: &str) -> Result<FileMeta, Error> {
+    let primary_rel = primary.strip_prefix(folder).map_err(|e| format!("Path error: {}", e))?;
+    let mut file_meta = describe_file(folder, file_path, locked_ext, primary_rel.to_str().is_none())?;
+    file_meta.hard_link_to = Some(match primary_rel.to_str() {
+        Some(rel) if !file_meta.percent_encoded => rel.to_string(),
+        _ => percent_encode(primary_rel.as_os_str())?,
+    });
+    fs::remove_file(file_path).map_err(|e| Error::io(&e, format!("Failed to remove original '{}': {}", file_path.display(), e)))?;
+    Ok(file_meta)
+}
+
+// Keeps one ciphertext per distinct plaintext: later entries whose checksum
+// matches an earlier one drop their own ciphertext and point at that entry.
+// The checksum is keyed with the folder key, so equal checksums mean equal
+// contents.
+fn dedup_ciphertext(folder: &Path, files: &mut [FileMeta]) -> Result<(), Error> {
+    let mut primaries: HashMap<(&[u8], bool), String> = HashMap::new();
+    let mut duplicates = Vec::new();
+    for (i, file_meta) in files.iter().enumerate() {
+        let Some(hash) = file_meta.plaintext_hash.as_deref().filter(|_| file_meta.has_ciphertext()) else { continue };
+        // Both paths have to decode the same way, so mixed encodings are left alone.
+        match primaries.get(&(hash, file_meta.percent_encoded)) {
+            Some(primary) => duplicates.push((i, primary.clone())),
+            None => { primaries.insert((hash, file_meta.percent_encoded), file_meta.relative_path.clone()); }
+        }
+    }
+    for (i, primary) in duplicates {
+        let locked_path = files[i].locked_path(folder);
+        fs::remove_file(&locked_path).map_err(|e| Error::io(&e, format!("Failed to remove '{}': {}", locked_path.display(), e)))?;
+        files[i].duplicate_of = Some(primary);
+    }
+    Ok(())
+}
+
+fn describe_file(folder: &Path, file_path: &Path, locked_ext: &str, force_percent: bool) -> Result<FileMeta, Error> {
+    let relative = file_path.strip_prefix(folder).map_err(|e| format!("Path error: {}", e))?;
+    let name = file_path.file_name().ok_or("Invalid filename")?;
+    Ok(match (name.to_str(), relative.to_str()) {
+        (Some(original_name), Some(relative_path)) if !force_percent => FileMeta {
+            original_name: original_name.to_string(),
+            locked_name: format!("{}{}", original_name, locked_ext),
+            relative_path: relative_path.to_string(),
+            percent_encoded: false,
+            plaintext_hash: None,
+            hard_link_to: None,
+            duplicate_of: None,
+            chunk_size: None,
+        },
+        _ => {
+            let original_name = percent_encode(name)?;
+            FileMeta {
+                locked_name: format!("{}{}", original_name, percent_encode(OsStr::new(locked_ext))?),
+                original_name,
+                relative_path: percent_encode(relative.as_os_str())?,
+                percent_encoded: true,
+                plaintext_hash: None,
+                hard_link_to: None,
+                duplicate_of: None,
+                chunk_size: None,
+            }
+        }
+    })
+}
+
+// Maps each extra hard link among `files` to the first path seen for the same
+// inode. Done up front because encrypting a file drops its link count.
+#[cfg(unix)]
+fn find_hard_links(files: &[PathBuf]) -> HashMap<PathBuf, PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+    let mut primaries: HashMap<(u64, u64), &PathBuf> = HashMap::new();
+    let mut links = HashMap::new();
+    for file_path in files {
+        let Some(metadata) = fs::metadata(file_path).ok().filter(|m| m.nlink() > 1) else { continue };
+        let primary = *primaries.entry((metadata.dev(), metadata.ino())).or_insert(file_path);
+        if primary != file_path {
+            links.insert(file_path.clone(), primary.clone());
+        }
+    }
+    links
+}
+
+#[cfg(not(unix))]
+fn find_hard_links(_files: &[PathBuf]) -> HashMap<PathBuf, PathBuf> {
+    HashMap::new()
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+// Names that aren't valid UTF-8 are stored percent-encoded so they round-trip
+// byte for byte.
+#[cfg(unix)]
+fn percent_encode(name: &OsStr) -> Result<String, Error> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(name.as_bytes().iter().map(|&b| {
+        if (b.is_ascii_graphic() || b == b' ') && b != b'%' { (b as char).to_string() } else { format!("%{:02X}", b) }
+    }).collect())
+}
+
+#[cfg(not(unix))]
+fn percent_encode(name: &OsStr) -> Result<String, Error> {
+    Err(format!("Unsupported filename '{}'", name.to_string_lossy()))
+}
+
+fn percent_decode(name: &str) -> Vec<u8> {
+    let bytes = name.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(b)) => { out.push(b); i += 3; }
+            (b, _) => { out.push(b); i += 1; }
+        }
+    }
+    out
+}
+
+#[cfg(unix)]
+fn bytes_to_os(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn bytes_to_os(bytes: Vec<u8>) -> OsString {
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Strips Windows verbatim prefixes (`\\?\C:\...`, `\\?\UNC\server\...`) so a folder
+// isn't tracked twice under two spellings; std re-adds them for paths over MAX_PATH.
+pub fn normalize_path(path: &str) -> String {
+    if let Some(rest) = path.strip_prefix(r"\\?\UNC\") {
+        return format!(r"\\{}", rest);
+    }
+    path.strip_prefix(r"\\?\").unwrap_or(path).to_string()
+}
+
+// The first of `tracked` that is `path`, contains it or sits inside it, compared
+// after resolving symlinks and `..` so two spellings of one folder still match.
+// Tracking both would let locking the outer folder encrypt the inner one's files.
+pub fn overlapping_folder<'a>(path: &str, tracked: &'a [String]) -> Option<&'a str> {
+    let resolve = |p: &str| Path::new(&normalize_path(p)).canonicalize().map(|c| PathBuf::from(normalize_path(&c.to_string_lossy())))
+        .unwrap_or_else(|_| PathBuf::from(normalize_path(p)));
+    let path = resolve(path);
+    tracked.iter()
+        .find(|other| {
+            let other = resolve(other);
+            path.starts_with(&other) || other.starts_with(&path)
+        })
+        .map(String::as_str)
+}
+
+fn file_meta_path(file: &Path) -> Result<PathBuf, Error> {
+    let name = file.file_name().and_then(|n| n.to_str()).ok_or("Invalid filename")?;
+    Ok(file.with_file_name(format!(".{}{}", name, META_FILE)))
+}
+
+// Re-encrypts every file under a key derived from `new_password` with a fresh
+// salt, and redoes the verify token and recovery wrap to match. Plaintext only
+// ever exists in memory; new ciphertext is staged next to the old and swapped in
+// once every file has been re-encrypted.
+pub fn rekey_folder(folder_path: &str, names: &VaultNames, old_password: &str, new_password: &str, master_key: Option<&SecretKey>) -> Result<ProtectedFolder, Error> {
+    let (new_meta, meta_path) = stage_rekey(folder_path, names, old_password, new_password, master_key)?;
+    let new_meta = commit_rekey(Path::new(folder_path), names, &meta_path, new_meta)?;
+    Ok(ProtectedFolder { path: folder_path.to_string(), is_locked: true, file_count: new_meta.files.len(), has_recovery: new_meta.recovery_key.is_some(), ..Default::default() })
+}
+
+// The staging half of rekey_folder: re-encrypts the files beside the old ones
+// and records the metadata they belong to as `pending_rekey`, which it
+// returns for commit_rekey.
+fn stage_rekey(folder_path: &str, names: &VaultNames, old_password: &str, new_password: &str, master_key: Option<&SecretKey>) -> Result<(FolderMeta, PathBuf), Error> {
+    let (mut meta, meta_path) = read_meta(folder_path, names)?;
+    let folder = Path::new(folder_path);
+    if let Some(pending) = meta.pending_rekey.take() {
+        // A change that stopped part way; the password it replaced still has
+        // to be given. If it was to `new_password` only the swap is left.
+        folder_key_from_password(&meta, old_password)?;
+        if open_with_password(&pending, new_password)?.is_some() {
+            return Ok((*pending, meta_path));
+        }
+        meta = commit_rekey(folder, names, &meta_path, *pending)?;
+    }
+    let old_key = folder_key_from_password(&meta, old_password)?;
+    check_rekey(&meta, master_key)?;
+    let salt = crypto::generate_salt();
+    let password_key = crypto::derive_key_with(&meta.kdf(), new_password, &salt)?;
+    let material = KeyMaterial::new(&meta, password_key, &salt, master_key)?;
+    let mut staged = Vec::with_capacity(meta.files.len());
+    let old_content_key = ContentKey::new(&meta, &old_key);
+    let new_content_key = ContentKey { key: &material.key, cipher: meta.cipher, per_file: true };
+    let result = stage_rekeyed_files(folder, &meta.files, old_content_key, new_content_key, false, &mut staged, &mut |_, _| {}).and_then(|files| {
+        let new_meta = material.apply(FolderMeta { salt: salt.to_vec(), files, kdf_upgrade: None, ..meta.clone() });
+        // From the first rename on, only the new metadata opens every file.
+        write_meta(&meta_path, &FolderMeta { pending_rekey: Some(Box::new(new_meta.clone())), ..meta })?;
+        Ok(new_meta)
+    });
+    match result {
+        Ok(new_meta) => Ok((new_meta, meta_path)),
+        Err(e) => {
+            for (staged_path, _) in &staged {
+                let _ = fs::remove_file(staged_path);
+            }
+            Err(e)
+        }
+    }
+}
+
+// Renames the staged files still waiting over the old ones, then writes `meta`
+// with its ciphertext root, which can only be taken once they are all in
+// place. Safe to run again on a swap that stopped part way.
+fn commit_rekey(folder: &Path, names: &VaultNames, meta_path: &Path, mut meta: FolderMeta) -> Result<FolderMeta, Error> {
+    for file_meta in meta.files.iter().filter(|f| f.has_ciphertext()) {
+        let locked_path = file_meta.locked_path_on_disk(folder);
+        let staged_path = staged_rekey_path(&locked_path);
+        if staged_path.exists() {
+            fs::rename(&staged_path, &locked_path).map_err(|e| Error::io(&e, format!("Failed to replace '{}': {}", locked_path.display(), e)))?;
+        }
+    }
+    meta.ciphertext_root = Some(ciphertext_root(folder, names, &meta.nested)?);
+    write_meta(meta_path, &meta)?;
+    Ok(meta)
+}
+
+fn check_rekey(meta: &FolderMeta, master_key: Option<&SecretKey>) -> Result<(), Error> {
+    if meta.upgrade_swapping() {
+        return Err(UPGRADE_UNFINISHED.into());
+    }
+    if !meta.password_slots.is_empty() {
+        return Err("Remove the folder's other password slots before changing its password; they can't be carried over to the new key".into());
+    }
+    if meta.recovery_key.is_some() && master_key.is_none() {
+        return Err(Error::new(ErrorCode::MasterLocked, "Unlock the master password first so this folder's recovery key can be updated"));
+    }
+    Ok(())
+}
+
+// Everything in the metadata that hangs off the password key, redone for a new
+// one: the recovery key, recipient wraps, machine binding and verify token.
+struct KeyMaterial {
+    key: SecretKey,
+    recovery_key: Option<Vec<u8>>,
+    recipients: Vec<RecipientKey>,
+    machine_binding: Option<Vec<u8>>,
+    verify_token: Vec<u8>,
+}
+
+impl KeyMaterial {
+    fn new(meta: &FolderMeta, password_key: SecretKey, salt: &[u8], master_key: Option<&SecretKey>) -> Result<Self, Error> {
+        let recovery_key = match master_key {
+            Some(mk) => Some(crypto::wrap_key(mk, &password_key, salt)?),
+            None => None,
+        };
+        let recipients = meta.recipients.iter().map(|r| recipient::wrap_key(&r.recipient, &password_key)).collect::<Result<_, _>>()?;
+        let (key, machine_binding) = match &meta.machine_binding {
+            Some(_) => {
+                let id = current_machine_id()?;
+                (crypto::bind_key_to_machine(&password_key, &id)?, Some(crypto::machine_fingerprint(salt, &id)))
+            }
+            None => (password_key, None),
+        };
+        let verify_token = crypto::create_verify_token(&key)?;
+        Ok(KeyMaterial { key, recovery_key, recipients, machine_binding, verify_token })
+    }
+
+    // `meta` with the new salt and files already in place. The ciphertext root
+    // is left for commit_rekey.
+    fn apply(self, meta: FolderMeta) -> FolderMeta {
+        FolderMeta { format_version: FORMAT_VERSION, verify_token: self.verify_token, ciphertext_root: None, recovery_key: self.recovery_key, recipients: self.recipients, machine_binding: self.machine_binding, ..meta }
+    }
+}
+
+// Re-encrypts a folder's files for stronger key derivation parameters, one
+// file in memory at a time. Like rekey_folder the new ciphertext is staged
+// beside the old; an interrupted upgrade picks up where it stopped when run
+// again with the same parameters, keeping the files it already staged. One
+// interrupted while swapping files in is always finished with its own.
+// `on_progress` receives (files done, total files) while staging.
+pub fn upgrade_folder_kdf(folder_path: &str, names: &VaultNames, password: &str, kdf: &KdfParams, master_key: Option<&SecretKey>, on_progress: &mut dyn FnMut(usize, usize)) -> Result<ProtectedFolder, Error> {
+    let (mut meta, meta_path) = read_meta_to_change(folder_path, names)?;
+    let folder = Path::new(folder_path);
+    let old_key = folder_key_from_password(&meta, password)?;
+    check_rekey(&meta, master_key)?;
+    let mut upgrade = match meta.kdf_upgrade.take() {
+        Some(upgrade) if upgrade.files.is_some() || upgrade.kdf == *kdf => upgrade,
+        _ if meta.kdf() == *kdf => return Err("This folder already uses these key derivation settings".into()),
+        _ => KdfUpgrade { kdf: kdf.clone(), salt: crypto::generate_salt().to_vec(), files: None },
+    };
+    let password_key = crypto::derive_key_with(&upgrade.kdf, password, &upgrade.salt)?;
+    let material = KeyMaterial::new(&meta, password_key, &upgrade.salt, master_key)?;
+    let files = match upgrade.files.take() {
+        Some(files) => files,
+        None => {
+            // Recorded first, so a resumed run derives the same key and can
+            // tell which staged files are already done.
+            meta.kdf_upgrade = Some(upgrade.clone());
+            write_meta(&meta_path, &meta)?;
+            let new_content_key = ContentKey { key: &material.key, cipher: meta.cipher, per_file: true };
+            let files = stage_rekeyed_files(folder, &meta.files, ContentKey::new(&meta, &old_key), new_content_key, true, &mut Vec::new(), on_progress)?;
+            meta.kdf_upgrade = Some(KdfUpgrade { files: Some(files.clone()), ..upgrade.clone() });
+            write_meta(&meta_path, &meta)?;
+            files
+        }
+    };
+    let new_meta = commit_rekey(folder, names, &meta_path, material.apply(FolderMeta { salt: upgrade.salt, kdf: Some(upgrade.kdf), files, kdf_upgrade: None, ..meta }))?;
+    Ok(ProtectedFolder { path: folder_path.to_string(), is_locked: true, file_count: new_meta.files.len(), has_recovery: new_meta.recovery_key.is_some(), deferred: new_meta.deferred, ..Default::default() })
+}
+
+const UPGRADE_UNFINISHED: &str = "A key derivation upgrade of this folder was interrupted part way; run it again to finish";
+
+fn staged_rekey_path(locked_path: &Path) -> PathBuf {
+    let mut staged_name = locked_path.file_name().unwrap_or_default().to_os_string();
+    staged_name.push(REKEY_EXT);
+    locked_path.with_file_name(staged_name)
+}
+
+// Returns the file list with checksums recomputed under the new key. With
+// `resume`, a file already staged under the new key is kept rather than
+// encrypted again.
+fn stage_rekeyed_files(folder: &Path, old_files: &[FileMeta], old_key: ContentKey, new_key: ContentKey, resume: bool, staged: &mut Vec<(PathBuf, PathBuf)>, on_progress: &mut dyn FnMut(usize, usize)) -> Result<Vec<FileMeta>, Error> {
+    let mut files = Vec::with_capacity(old_files.len());
+    let mut new_hashes: HashMap<&str, Vec<u8>> = HashMap::new();
+    let total = old_files.len();
+    for (done, file_meta) in old_files.iter().enumerate() {
+        on_progress(done, total);
+        if let Some(primary) = &file_meta.duplicate_of {
+            files.push(FileMeta { plaintext_hash: new_hashes.get(primary.as_str()).cloned(), ..file_meta.clone() });
+            continue;
+        }
+        if file_meta.hard_link_to.is_some() {
+            files.push(file_meta.clone());
+            continue;
+        }
+        let locked_path = file_meta.locked_path_on_disk(folder);
+        let staged_path = staged_rekey_path(&locked_path);
+        let already_staged = if resume { fs::read(&staged_path).ok().and_then(|e| new_key.decrypt(file_meta, &e).ok()) } else { None };
+        let plaintext = match already_staged {
+            Some(plaintext) => plaintext,
+            None => {
+                let encrypted = fs::read(&locked_path).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", locked_path.display(), e)))?;
+                let plaintext = old_key.decrypt(file_meta, &encrypted)?;
+                file_meta.verify_plaintext(old_key.key, &plaintext)?;
+                let reencrypted = new_key.encrypt(file_meta, &plaintext)?;
+                fs::write(&staged_path, &reencrypted).map_err(|e| Error::io(&e, format!("Failed to write '{}': {}", staged_path.display(), e)))?;
+                plaintext
+            }
+        };
+        let new_hash = crypto::plaintext_digest(new_key.key, &plaintext);
+        new_hashes.insert(&file_meta.relative_path, new_hash.clone());
+        files.push(FileMeta { plaintext_hash: Some(new_hash), ..file_meta.clone() });
+        staged.push((staged_path, locked_path));
+    }
+    on_progress(total, total);
+    Ok(files)
+}
+
+#[derive(Default)]
+struct Restored {
+    paths: Vec<String>,
+    missing: Vec<String>,
+    failed: Vec<FileFailure>,
+    // Indices into the file list of the entries in `failed`.
+    failed_entries: Vec<usize>,
+}
+
+// A file an unlock couldn't restore. It stays locked, and listed in the
+// metadata, so the unlock can be tried again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileFailure {
+    pub path: String,
+    pub error: String,
+    #[serde(default)]
+    pub code: ErrorCode,
+}
+
+// Calls `after_each` with the number of entries handled so far and what has
+// come of them. Entries whose ciphertext is already gone were usually
+// finished by an earlier, interrupted unlock; they count as restored if the
+// plaintext is there and as missing if not. A file that can't be read,
+// decrypted or written is recorded in `failed` and the rest carry on; only an
+// error from `after_each` stops the unlock.
+fn decrypt_files(folder: &Path, output_dir: Option<&Path>, key: ContentKey, files: &[FileMeta], on_conflict: CollisionPolicy, retry: RetryPolicy, after_each: &mut dyn FnMut(usize, &Restored) -> Result<(), Error>) -> Result<Restored, Error> {
+    // Also checked on load; repeated here as nothing may be written outside.
+    check_contained(files)?;
+    let target_root = output_dir.unwrap_or(folder);
+    // A link whose name already points at its target was restored before an interruption.
+    let restored_link = |f: &FileMeta| f.link_target(target_root).is_some_and(|target| same_file(&f.original_path_on_disk(target_root), &target));
+    // Likewise a copy that already holds the expected contents.
+    let restored_copy = |f: &FileMeta| fs::read(f.original_path_on_disk(target_root)).is_ok_and(|data| f.verify_plaintext(key.key, &data).is_ok());
+    if on_conflict == CollisionPolicy::Error {
+        let conflicts: Vec<&str> = files.iter()
+            .filter(|f| f.original_path_on_disk(target_root).exists())
+            .filter(|f| match (&f.hard_link_to, &f.duplicate_of) {
+                (Some(_), _) => !restored_link(f),
+                (_, Some(_)) => !restored_copy(f),
+                _ => f.locked_path_on_disk(folder).exists(),
+            })
+            .map(|f| f.relative_path.as_str())
+            .collect();
+        if !conflicts.is_empty() {
+            return Err(format!("Files already exist and would be overwritten: {}", conflicts.join(", ")).into());
+        }
+    }
+    // The restored path, or None when the entry is missing.
+    let restore = |file_meta: &FileMeta| -> Result<Option<PathBuf>, Error> {
+        let original_path = file_meta.original_path_on_disk(target_root);
+        if let Some(target) = file_meta.link_target(target_root) {
+            if !restored_link(file_meta) {
+                restore_link(&target, &original_path)?;
+            }
+            return Ok(Some(original_path));
+        }
+        // The entry it duplicates comes first, so its plaintext is already out.
+        if let Some(source) = file_meta.copy_source(target_root) {
+            if !restored_copy(file_meta) {
+                let plaintext = vfs::retry(retry, || fs::read(&source)).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", source.display(), e)))?;
+                file_meta.verify_plaintext(key.key, &plaintext)?;
+                write_plaintext(&original_path, &plaintext, output_dir.is_some(), retry)?;
+            }
+            return Ok(Some(original_path));
+        }
+        let locked_path = file_meta.locked_path_on_disk(folder);
+        if !locked_path.exists() {
+            return Ok(original_path.exists().then_some(original_path));
+        }
+        if file_meta.chunk_size.is_some() {
+            let mut input = vfs::retry(retry, || fs::File::open(&locked_path)).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", locked_path.display(), e)))?;
+            decrypt_streaming(&mut input, &original_path, key, file_meta, output_dir.is_some())?;
+        } else {
+            let encrypted = vfs::retry(retry, || fs::read(&locked_path)).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", locked_path.display(), e)))?;
+            let plaintext = key.decrypt(file_meta, &encrypted)?;
+            file_meta.verify_plaintext(key.key, &plaintext)?;
+            write_plaintext(&original_path, &plaintext, output_dir.is_some(), retry)?;
+        }
+        if output_dir.is_none() {
+            fs::remove_file(&locked_path).map_err(|e| Error::io(&e, format!("Failed to remove '{}': {}", locked_path.display(), e)))?;
+        }
+        Ok(Some(original_path))
+    };
+    let mut restored = Restored::default();
+    for (i, file_meta) in files.iter().enumerate() {
+        match restore(file_meta) {
+            Ok(Some(path)) => restored.paths.push(path.to_string_lossy().to_string()),
+            Ok(None) => restored.missing.push(file_meta.relative_path.clone()),
+            Err(error) => {
+                restored.failed.push(FileFailure { path: file_meta.relative_path.clone(), error: error.message, code: error.code });
+                restored.failed_entries.push(i);
+            }
+        }
+        after_each(i + 1, &restored)?;
+    }
+    Ok(restored)
+}
+
+fn write_plaintext(original_path: &Path, plaintext: &[u8], create_parent: bool, retry: RetryPolicy) -> Result<(), Error> {
+    if original_path.exists() {
+        quarantine(original_path)?;
+    }
+    if let Some(parent) = original_path.parent().filter(|_| create_parent) {
+        fs::create_dir_all(parent).map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", parent.display(), e)))?;
+    }
+    vfs::retry(retry, || fs::write(original_path, plaintext)).map_err(|e| Error::io(&e, format!("Failed to write '{}': {}", original_path.display(), e)))
+}
+
+// A streamed file that fails to decrypt or verify partway is removed again, so
+// only the ciphertext remains.
+fn decrypt_streaming(input: &mut dyn std::io::Read, original_path: &Path, key: ContentKey, file_meta: &FileMeta, create_parent: bool) -> Result<(), Error> {
+    if original_path.exists() {
+        quarantine(original_path)?;
+    }
+    if let Some(parent) = original_path.parent().filter(|_| create_parent) {
+        fs::create_dir_all(parent).map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", parent.display(), e)))?;
+    }
+    let output = fs::File::create(original_path).map_err(|e| Error::io(&e, format!("Failed to write '{}': {}", original_path.display(), e)))?;
+    let mut writer = std::io::BufWriter::new(output);
+    let mut hasher = crypto::PlaintextHasher::new(key.key);
+    let result = key.decrypt_stream(file_meta, &mut std::io::BufReader::new(input), &mut writer, &mut |chunk| hasher.update(chunk))
+        .and_then(|_| writer.flush().map_err(|e| Error::io(&e, format!("Failed to write '{}': {}", original_path.display(), e))))
+        .and_then(|_| file_meta.verify_digest(&hasher.finalize()));
+    if result.is_err() {
+        drop(writer);
+        let _ = fs::remove_file(original_path);
+    }
+    result
+}
+
+// Falls back to a copy when the output directory is on another filesystem.
+fn restore_link(target: &Path, link: &Path) -> Result<(), Error> {
+    if link.exists() {
+        quarantine(link)?;
+    }
+    if let Some(parent) = link.parent() {
+        fs::create_dir_all(parent).map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", parent.display(), e)))?;
+    }
+    fs::hard_link(target, link)
+        .or_else(|_| fs::copy(target, link).map(|_| ()))
+        .map_err(|e| Error::io(&e, format!("Failed to restore link '{}': {}", link.display(), e)))
+}
+
+// Moves a file that would be overwritten by unlock to `name.conflict-<unix time>`.
+fn quarantine(path: &Path) -> Result<(), Error> {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let stamp = unix_now();
+    let mut target = path.with_file_name(format!("{}.conflict-{}", name, stamp));
+    let mut n = 1;
+    while target.exists() {
+        target = path.with_file_name(format!("{}.conflict-{}-{}", name, stamp, n));
+        n += 1;
+    }
+    fs::rename(path, &target).map_err(|e| Error::io(&e, format!("Failed to move aside '{}': {}", path.display(), e)))
+}
+
+// The same wording everywhere a folder is expected, so a file or a missing path
+// isn't reported as "not locked" by one call and miscounted by another.
+pub fn check_folder_path(folder_path: &str) -> Result<&Path, Error> {
+    let folder = Path::new(folder_path);
+    match fs::metadata(folder) {
+        Ok(m) if m.is_dir() => Ok(folder),
+        Ok(m) if m.is_file() => Err(format!("'{}' is a file, not a folder", folder_path).into()),
+        Ok(_) => Err(Error::new(ErrorCode::NotFound, format!("'{}' is not a valid directory", folder_path))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::new(ErrorCode::NotFound, format!("'{}' does not exist", folder_path))),
+        Err(e) => Err(Error::io(&e, format!("Cannot access '{}': {}", folder_path, e))),
+    }
+}
+
+fn read_meta(folder_path: &str, names: &VaultNames) -> Result<(FolderMeta, PathBuf), Error> {
+    let folder = check_folder_path(folder_path)?;
+    let meta_path = folder.join(&names.meta_file);
+    if !meta_path.exists() {
+        return Err(format!("Folder is not locked (no {} metadata found)", names.meta_file).into());
+    }
+    Ok((parse_meta(&meta_path)?, meta_path))
+}
+
+// read_meta for callers about to use the folder key or change the vault. A
+// rekey that stopped while swapping files in is finished first, as until then
+// the files are split between the old key and the new. Read-only callers use
+// read_meta and leave it as it is.
+fn read_meta_to_change(folder_path: &str, names: &VaultNames) -> Result<(FolderMeta, PathBuf), Error> {
+    let (mut meta, meta_path) = read_meta(folder_path, names)?;
+    if let Some(pending) = meta.pending_rekey.take() {
+        meta = commit_rekey(Path::new(folder_path), names, &meta_path, *pending)?;
+    }
+    Ok((meta, meta_path))
+}
+
+fn read_file_meta(file_path: &str) -> Result<(FolderMeta, PathBuf, PathBuf), Error> {
+    let file = Path::new(file_path);
+    let meta_path = file_meta_path(file)?;
+    if !meta_path.exists() {
+        return Err("File is not locked (no .securelock metadata found)".into());
+    }
+    let dir = file.parent().ok_or("File has no parent directory")?.to_path_buf();
+    Ok((parse_meta(&meta_path)?, meta_path, dir))
+}
+
+fn manifest_path(meta_path: &Path) -> PathBuf {
+    let mut name = meta_path.file_name().unwrap_or_default().to_os_string();
+    name.push(MANIFEST_EXT);
+    meta_path.with_file_name(name)
+}
+
+fn parse_header(meta_path: &Path) -> Result<FolderMeta, Error> {
+    load_header(&disk(), meta_path)
+}
+
+fn parse_meta(meta_path: &Path) -> Result<FolderMeta, Error> {
+    load_meta(&disk(), meta_path)
+}
+
+// The replacement file keeps the old one's hidden attribute; see hide_vault_files.
+fn write_meta(meta_path: &Path, meta: &FolderMeta) -> Result<(), Error> {
+    let hidden = has_hidden_attribute(meta_path);
+    save_meta(&disk(), meta_path, meta)?;
+    if hidden {
+        set_hidden_attribute(meta_path);
+        if meta.split_manifest {
+            set_hidden_attribute(&manifest_path(meta_path));
+        }
+    }
+    Ok(())
+}
+
+fn load_header(fs: &dyn FileSystem, meta_path: &Path) -> Result<FolderMeta, Error> {
+    let meta_json = fs.read(meta_path).map_err(|e| Error::io(&e, format!("Failed to read metadata: {}", e)))?;
+    if !is_meta_data(&meta_json) {
+        return Err(format!("'{}' {}", meta_path.display(), NOT_A_VAULT).into());
+    }
+    serde_json::from_slice(&meta_json).map_err(|e| Error::new(ErrorCode::Corrupted, format!("Invalid metadata: {}", e)))
+}
+
+// Reads a vault's metadata, and its manifest if it has a separate one.
+pub fn load_meta(fs: &dyn FileSystem, meta_path: &Path) -> Result<FolderMeta, Error> {
+    let mut meta = load_header(fs, meta_path)?;
+    if meta.split_manifest {
+        let path = manifest_path(meta_path);
+        let json = fs.read(&path)
+            .map_err(|e| Error::io(&e, format!("Failed to read file manifest '{}': {} (rebuild can recreate it)", path.display(), e)))?;
+        let manifest: Manifest = serde_json::from_slice(&json).map_err(|e| Error::new(ErrorCode::Corrupted, format!("Invalid file manifest: {} (rebuild can recreate it)", e)))?;
+        meta.files = manifest.files;
+        meta.nested = manifest.nested;
+        meta.deferred = manifest.deferred;
+    }
+    check_contained(&meta.files)?;
+    Ok(meta)
+}
+
+fn check_contained(files: &[FileMeta]) -> Result<(), Error> {
+    match files.iter().find(|f| !f.is_contained()) {
+        Some(bad) => Err(Error::new(ErrorCode::Corrupted, format!("Invalid metadata: entry '{}' points outside the folder", bad.relative_path))),
+        None => Ok(()),
+    }
+}
+
+// Writes a vault's metadata atomically. A split vault's manifest goes first, so
+// a failure in between leaves the old header or a matching pair.
+pub fn save_meta(fs: &dyn FileSystem, meta_path: &Path, meta: &FolderMeta) -> Result<(), Error> {
+    let meta_json = if meta.split_manifest {
+        let manifest = Manifest { files: meta.files.clone(), nested: meta.nested.clone(), deferred: meta.deferred.clone() };
+        let json = serde_json::to_string_pretty(&manifest).map_err(|e| format!("Metadata serialization error: {}", e))?;
+        vfs::write_atomic(fs, &manifest_path(meta_path), json.as_bytes()).map_err(|e| Error::io(&e, format!("Failed to write file manifest: {}", e)))?;
+        // Leaving out `files` entirely makes versions without split support
+        // refuse the header rather than read it as an empty vault.
+        let mut header = serde_json::to_value(meta).map_err(|e| format!("Metadata serialization error: {}", e))?;
+        if let Some(fields) = header.as_object_mut() {
+            fields.remove("files");
+            fields.remove("nested");
+            fields.remove("deferred");
+        }
+        serde_json::to_string_pretty(&header)
+    } else {
+        serde_json::to_string_pretty(meta)
+    }.map_err(|e| format!("Metadata serialization error: {}", e))?;
+    vfs::write_atomic(fs, meta_path, meta_json.as_bytes()).map_err(|e| Error::io(&e, format!("Failed to write metadata: {}", e)))
+}
+
+fn remove_meta(meta_path: &Path, meta: &FolderMeta) -> Result<(), Error> {
+    disk().remove(meta_path).map_err(|e| Error::io(&e, format!("Failed to remove metadata: {}", e)))?;
+    if meta.split_manifest {
+        disk().remove(&manifest_path(meta_path)).map_err(|e| Error::io(&e, format!("Failed to remove file manifest: {}", e)))?;
+    }
+    Ok(())
+}
+
+// vfs::write_atomic on the real disk.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    vfs::write_atomic(&disk(), path, contents)
+}
+
+// The real disk, with metadata reads and writes retried like file contents.
+fn disk() -> Retrying<OsFileSystem> {
+    Retrying { inner: OsFileSystem, policy: RetryPolicy::default() }
+}
+
+// Overwrites a file with random bytes before removing it. Only worth it for
+// small files like metadata; on SSDs and copy-on-write filesystems the old
+// blocks may survive anyway.
+pub fn shred_file(path: &Path) -> std::io::Result<()> {
+    shred_file_checked(path, false).map(|_| ())
+}
+
+// shred_file, optionally checking afterwards that the overwrite can be trusted:
+// the file is read back to see the random bytes landed, and the filesystem is
+// looked up in case it is copy-on-write, where the overwrite went to new blocks
+// and left the old ones alone. Returns why the shredding isn't guaranteed, if
+// that turned out to be so; the file is removed either way.
+pub fn shred_file_checked(path: &Path, verify: bool) -> std::io::Result<Option<String>> {
+    shred_with(path, verify, |path, noise| {
+        let mut file = fs::OpenOptions::new().write(true).open(path)?;
+        file.write_all(noise)?;
+        file.sync_all()
+    })
+}
+
+fn shred_with(path: &Path, verify: bool, overwrite: impl FnOnce(&Path, &[u8]) -> std::io::Result<()>) -> std::io::Result<Option<String>> {
+    use rand::RngCore;
+    let len = fs::metadata(path)?.len() as usize;
+    let mut noise = vec![0u8; len];
+    rand::rngs::OsRng.fill_bytes(&mut noise);
+    overwrite(path, &noise)?;
+    let problem = if !verify {
+        None
+    } else if fs::read(path)? != noise {
+        Some("the random bytes did not read back".to_string())
+    } else {
+        copy_on_write_fs(path).map(|fs_type| format!("it is on a copy-on-write filesystem ({})", fs_type))
+    };
+    fs::remove_file(path)?;
+    Ok(problem)
+}
+
+// The type of the filesystem `path` is on, if it is one of the copy-on-write
+// ones, from the longest matching mount point in /proc/self/mountinfo.
+#[cfg(target_os = "linux")]
+fn copy_on_write_fs(path: &Path) -> Option<String> {
+    const COPY_ON_WRITE: &[&str] = &["btrfs", "zfs", "bcachefs"];
+    let path = fs::canonicalize(path).ok()?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    let mount = mountinfo.lines().filter_map(|line| {
+        let (mount, fs_info) = line.split_once(" - ")?;
+        let mount_point = mount.split(' ').nth(4)?.replace("\\040", " ");
+        Some((PathBuf::from(mount_point), fs_info.split(' ').next()?.to_string()))
+    }).filter(|(mount_point, _)| path.starts_with(mount_point)).max_by_key(|(mount_point, _)| mount_point.as_os_str().len());
+    mount.map(|(_, fs_type)| fs_type).filter(|fs_type| COPY_ON_WRITE.contains(&fs_type.as_str()))
+}
+
+// The same from statfs(2): APFS, and ZFS where OpenZFS is installed. HFS+,
+// FAT and the like overwrite in place.
+#[cfg(target_os = "macos")]
+fn copy_on_write_fs(path: &Path) -> Option<String> {
+    use std::os::unix::ffi::OsStrExt;
+    const COPY_ON_WRITE: &[&str] = &["apfs", "zfs"];
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let fs_type = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) }.to_string_lossy().into_owned();
+    COPY_ON_WRITE.contains(&fs_type.as_str()).then_some(fs_type)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn copy_on_write_fs(_path: &Path) -> Option<String> {
+    None
+}
+
+// Shreds a vault's metadata along with any `.tmp` or moved-aside copies, which
+// hold the same salt and wrapped keys. Ciphertext is left in place: without the
+// metadata it can't be decrypted, and leaving it keeps this fast on big folders.
+// Returns how many files were shredded.
+pub fn shred_metadata(path: &str, names: &VaultNames) -> Result<usize, Error> {
+    let path = Path::new(path);
+    let (dir, prefixes) = if path.is_dir() {
+        (path.to_path_buf(), vec![names.meta_file.clone(), META_FILE.to_string()])
+    } else {
+        let name = path.file_name().and_then(|n| n.to_str()).ok_or("Invalid filename")?;
+        let original = name.strip_suffix(LOCKED_EXT).unwrap_or(name);
+        (path.parent().map(Path::to_path_buf).unwrap_or_default(), vec![format!(".{}{}", original, META_FILE)])
+    };
+    let entries = fs::read_dir(&dir).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", dir.display(), e)))?;
+    let mut shredded = 0;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !prefixes.iter().any(|p| name.starts_with(p.as_str())) || !entry.path().is_file() {
+            continue;
+        }
+        shred_file(&entry.path()).map_err(|e| Error::io(&e, format!("Failed to shred '{}': {}", entry.path().display(), e)))?;
+        shredded += 1;
+    }
+    Ok(shredded)
+}
+
+// Tries the folder's own password, then each slot. Returns the password key
+// (before machine binding) and the folder key, or None for a wrong password.
+fn open_with_password(meta: &FolderMeta, password: &str) -> Result<Option<(SecretKey, SecretKey)>, Error> {
+    let password_key = meta.password_key(password)?;
+    let key = apply_machine_binding(meta, password_key.clone())?;
+    if crypto::verify_password(&key, &meta.verify_token) {
+        return Ok(Some((password_key, key)));
+    }
+    for slot in &meta.password_slots {
+        let slot_key = crypto::derive_key_with(&meta.kdf(), password, &slot.salt)?;
+        let Ok(password_key) = crypto::unwrap_key(&slot_key, &slot.wrapped_key, &slot.salt) else { continue };
+        let key = apply_machine_binding(meta, password_key.clone())?;
+        if crypto::verify_password(&key, &meta.verify_token) {
+            return Ok(Some((password_key, key)));
+        }
+    }
+    Ok(None)
+}
+
+fn folder_key_from_password(meta: &FolderMeta, password: &str) -> Result<SecretKey, Error> {
+    let (_, key) = open_with_password(meta, password)?.ok_or_else(incorrect_password)?;
+    Ok(key)
+}
+
+// Adds `new_password` as another way into the folder; `password` can be the
+// folder's own or any slot's. Returns how many slots there are now.
+pub fn add_password_slot(folder_path: &str, names: &VaultNames, password: &str, new_password: &str) -> Result<usize, Error> {
+    let (mut meta, meta_path) = read_meta_to_change(folder_path, names)?;
+    let (password_key, _) = open_with_password(&meta, password)?.ok_or_else(incorrect_password)?;
+    if open_with_password(&meta, new_password)?.is_some() {
+        return Err("That password already unlocks this folder".into());
+    }
+    meta.password_slots.push(PasswordSlot::new(&meta.kdf(), new_password, &password_key)?);
+    write_meta(&meta_path, &meta)?;
+    Ok(meta.password_slots.len())
+}
+
+// Removes slot `slot` (0-based, in the order they were added). The folder's own
+// password has no slot and can't be removed this way.
+pub fn remove_password_slot(folder_path: &str, names: &VaultNames, password: &str, slot: usize) -> Result<usize, Error> {
+    let (mut meta, meta_path) = read_meta_to_change(folder_path, names)?;
+    open_with_password(&meta, password)?.ok_or_else(incorrect_password)?;
+    if slot >= meta.password_slots.len() {
+        return Err(format!("This folder has no password slot {}", slot).into());
+    }
+    meta.password_slots.remove(slot);
+    write_meta(&meta_path, &meta)?;
+    Ok(meta.password_slots.len())
+}
+
+fn folder_key_from_master(meta: &FolderMeta, master_key: &[u8; 32]) -> Result<SecretKey, Error> {
+    let wrapped = meta.recovery_key.as_ref().ok_or("No recovery key found for this folder")?;
+    let context: &[u8] = if meta.format_version >= BOUND_RECOVERY_VERSION { &meta.salt } else { &[] };
+    let password_key = crypto::unwrap_key(master_key, wrapped, context)?;
+    let key = apply_machine_binding(meta, password_key)?;
+    if !crypto::verify_password(&key, &meta.verify_token) {
+        return Err(Error::new(ErrorCode::WrongPassword, "Master password verification failed"));
+    }
+    Ok(key)
+}
+
+fn folder_key_from_identity(meta: &FolderMeta, identity: &Identity) -> Result<SecretKey, Error> {
+    if meta.recipients.is_empty() {
+        return Err("No recipient keys found for this folder".into());
+    }
+    let password_key = identity.unwrap_key(&meta.recipients).ok_or("This identity does not match any of the folder's recipients")?;
+    let key = apply_machine_binding(meta, password_key)?;
+    if !crypto::verify_password(&key, &meta.verify_token) {
+        return Err("Identity verification failed".into());
+    }
+    Ok(key)
+}
+
+// Re-wraps a recovery key from before BOUND_RECOVERY_VERSION so it is bound to
+// the folder's salt. Returns false if the blob is already in the current format.
+pub fn upgrade_recovery_blob(folder_path: &str, names: &VaultNames, master_key: &[u8; 32]) -> Result<bool, Error> {
+    let (mut meta, meta_path) = read_meta_to_change(folder_path, names)?;
+    let wrapped = meta.recovery_key.as_ref().ok_or("No recovery key found for this folder")?;
+    if meta.format_version >= BOUND_RECOVERY_VERSION {
+        return Ok(false);
+    }
+    // Checks the master key against the folder before anything is rewritten.
+    folder_key_from_master(&meta, master_key)?;
+    let password_key = crypto::unwrap_key(master_key, wrapped, &[])?;
+    meta.recovery_key = Some(crypto::wrap_key(master_key, &password_key, &meta.salt)?);
+    meta.format_version = BOUND_RECOVERY_VERSION;
+    write_meta(&meta_path, &meta)?;
+    Ok(true)
+}
+
+// The wrapped recovery key as base64, for keeping offline. It is useless
+// without the master key, so the export is not a secret in itself.
+pub fn export_recovery_blob(folder_path: &str, names: &VaultNames) -> Result<String, Error> {
+    let meta = read_meta(folder_path, names)?.0.settled();
+    let wrapped = meta.recovery_key.as_ref().ok_or("No recovery key found for this folder")?;
+    Ok(STANDARD.encode(wrapped))
+}
+
+// Puts an exported recovery key back into a folder that has lost its own. The
+// blob only opens in the folder it came from; with `master_key` that is checked
+// before anything is written.
+pub fn import_recovery_blob(folder_path: &str, names: &VaultNames, blob: &str, master_key: Option<&SecretKey>) -> Result<(), Error> {
+    let (mut meta, meta_path) = read_meta_to_change(folder_path, names)?;
+    if meta.recovery_key.is_some() {
+        return Err("This folder already has a recovery key".into());
+    }
+    let wrapped = STANDARD.decode(blob.trim()).map_err(|_| "Not a recovery key export".to_string())?;
+    if wrapped.len() != crypto::WRAPPED_KEY_LEN {
+        return Err("Not a recovery key export".into());
+    }
+    meta.recovery_key = Some(wrapped);
+    if let Some(master_key) = master_key {
+        folder_key_from_master(&meta, master_key).map_err(|_| "This recovery key belongs to another folder or master password".to_string())?;
+    }
+    write_meta(&meta_path, &meta)?;
+    Ok(())
+}
+
+// `on_progress` receives (files done, total files) after each file.
+pub fn unlock_folder(folder_path: &str, names: &VaultNames, password: &str, options: &UnlockOptions, on_progress: &mut dyn FnMut(usize, usize)) -> Result<ProtectedFolder, Error> {
+    let result = folder_key(folder_path, names, password).and_then(|key| unlock_folder_with_key(folder_path, names, &key, options, on_progress));
+    match result {
+        Err(e) if reserve_may_open(&e, folder_path, names) => unlock_hidden(folder_path, names, password, options)?.ok_or(e),
+        result => result,
+    }
+}
+
+// The slow Argon2 half of `unlock_folder`, for callers that want to run or
+// report it separately from the decryption.
+pub fn folder_key(folder_path: &str, names: &VaultNames, password: &str) -> Result<SecretKey, Error> {
+    let (meta, _) = read_meta_to_change(folder_path, names)?;
+    folder_key_from_password(&meta, password)
+}
+
+// The folder key from the master key, for callers that need the key itself.
+pub fn folder_key_with_master(folder_path: &str, names: &VaultNames, master_key: &[u8; 32]) -> Result<SecretKey, Error> {
+    let (meta, _) = read_meta_to_change(folder_path, names)?;
+    folder_key_from_master(&meta, master_key)
+}
+
+// Encrypts one new plaintext file into an already locked folder and adds it to
+// the metadata; nothing else in the vault is touched. `key` is the folder key.
+// Of `options` only the stream threshold and retries apply, and the threshold
+// the folder was locked with is used when none is given.
+pub fn add_file_to_locked(folder_path: &str, names: &VaultNames, file_path: &str, key: &[u8; 32], options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    let (mut meta, meta_path) = read_meta_to_change(folder_path, names)?;
+    if !crypto::verify_password(key, &meta.verify_token) {
+        return Err(incorrect_password());
+    }
+    let folder = Path::new(folder_path);
+    let file = Path::new(file_path);
+    if !file.is_file() {
+        return Err(Error::new(ErrorCode::NotFound, format!("'{}' is not a valid file", file_path)));
+    }
+    let canonical_folder = folder.canonicalize().map_err(|e| Error::io(&e, format!("Invalid folder '{}': {}", folder_path, e)))?;
+    let canonical_file = file.canonicalize().map_err(|e| Error::io(&e, format!("Invalid file '{}': {}", file_path, e)))?;
+    let relative = canonical_file.strip_prefix(&canonical_folder)
+        .map_err(|_| format!("'{}' is not inside the locked folder", file_path))?;
+    let file = folder.join(relative);
+    let is_locked_name = file.file_name().is_some_and(|n| n.to_string_lossy().ends_with(names.locked_ext.as_str()));
+    if is_vault_metadata(&file, names) || is_locked_name {
+        return Err(format!("'{}' is part of the vault itself", file_path).into());
+    }
+    if relative.ancestors().skip(1).any(|dir| !dir.as_os_str().is_empty() && is_vault_dir(&folder.join(dir), names)) {
+        return Err(format!("'{}' is inside a separately locked folder", file_path).into());
+    }
+    if meta.files.iter().any(|f| f.original_path(folder) == file) {
+        return Err(format!("'{}' is already in the vault; unlock the folder to replace it", file_path).into());
+    }
+    let stream_threshold = options.stream_threshold.or(meta.stream_threshold).unwrap_or(DEFAULT_STREAM_THRESHOLD);
+    let file_meta = encrypt_file(folder, &file, ContentKey::new(&meta, key), &names.locked_ext, stream_threshold, options.retry)?;
+    meta.files.push(file_meta);
+    dedup_ciphertext(folder, &mut meta.files)?;
+    meta.ciphertext_root = Some(ciphertext_root(folder, names, &meta.nested)?);
+    let hidden = has_hidden_attribute(&meta_path);
+    write_meta(&meta_path, &meta)?;
+    if hidden {
+        hide_vault_files(folder, &meta_path, &meta);
+    }
+    Ok(ProtectedFolder { path: folder_path.to_string(), is_locked: true, file_count: meta.files.len(), has_recovery: meta.recovery_key.is_some(), ..Default::default() })
+}
+
+// Encrypts the large files a quick lock left behind into the vault. Files that
+// have gone since are dropped from the list and reported as skipped, as are
+// unreadable ones under UnreadablePolicy::Skip, which stay deferred. `key` is
+// the folder key.
+pub fn finish_lock(folder_path: &str, names: &VaultNames, key: &[u8; 32], options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    let (mut meta, meta_path) = read_meta_to_change(folder_path, names)?;
+    if !crypto::verify_password(key, &meta.verify_token) {
+        return Err(incorrect_password());
+    }
+    let folder = Path::new(folder_path);
+    let mut skipped = Vec::new();
+    let mut files = Vec::new();
+    for relative in std::mem::take(&mut meta.deferred) {
+        let contained = Path::new(&relative).components().all(|c| matches!(c, Component::Normal(_)));
+        let file = folder.join(&relative);
+        let in_vault = meta.files.iter().any(|f| f.original_path(folder) == file);
+        if contained && !in_vault && fs::symlink_metadata(&file).is_ok_and(|m| m.is_file()) {
+            files.push((relative, file));
+        } else {
+            skipped.push(relative);
+        }
+    }
+    let to_encrypt: Vec<&PathBuf> = files.iter().map(|(_, file)| file).collect();
+    let budget = ByteBudget::new(options.memory_budget.unwrap_or_else(budget::default_budget));
+    let stream_threshold = options.stream_threshold.or(meta.stream_threshold).unwrap_or(DEFAULT_STREAM_THRESHOLD);
+    let encrypted = match encrypt_files(folder, &to_encrypt, ContentKey::new(&meta, key), &names.locked_ext, stream_threshold, options, &budget) {
+        Ok(encrypted) => encrypted,
+        Err(failure) => {
+            // The files not left locked stay deferred.
+            meta.deferred = files.into_iter()
+                .map(|(relative, _)| relative)
+                .filter(|relative| !failure.locked.iter().any(|f| f.original_path(folder) == folder.join(relative)))
+                .collect();
+            return Err(failure.keep(folder, names, &meta_path, meta));
+        }
+    };
+    for ((relative, _), file_meta) in files.into_iter().zip(encrypted) {
+        match file_meta {
+            Some(file_meta) => meta.files.push(file_meta),
+            None => {
+                skipped.push(relative.clone());
+                meta.deferred.push(relative);
+            }
+        }
+    }
+    dedup_ciphertext(folder, &mut meta.files)?;
+    meta.ciphertext_root = Some(ciphertext_root(folder, names, &meta.nested)?);
+    let hidden = has_hidden_attribute(&meta_path);
+    write_meta(&meta_path, &meta)?;
+    if hidden {
+        hide_vault_files(folder, &meta_path, &meta);
+    }
+    Ok(ProtectedFolder { path: folder_path.to_string(), is_locked: true, file_count: meta.files.len(), has_recovery: meta.recovery_key.is_some(), skipped, deferred: meta.deferred, ..Default::default() })
+}
+
+// Checks the password the way `unlock_folder` would, without touching any files.
+pub fn unlock_dry_run(folder_path: &str, names: &VaultNames, password: &str) -> Result<(), Error> {
+    let (meta, _) = read_meta(folder_path, names)?;
+    folder_key_from_password(&meta.settled(), password).map(|_| ())
+}
+
+// The same check with a wrong password as `Ok(false)`; errors are left for a
+// folder that isn't locked, damaged metadata, or the wrong machine.
+pub fn check_folder_password(folder_path: &str, names: &VaultNames, password: &str) -> Result<bool, Error> {
+    let (meta, _) = read_meta(folder_path, names)?;
+    Ok(open_with_password(&meta.settled(), password)?.is_some())
+}
+
+pub fn unlock_folder_with_key(folder_path: &str, names: &VaultNames, key: &[u8; 32], options: &UnlockOptions, on_progress: &mut dyn FnMut(usize, usize)) -> Result<ProtectedFolder, Error> {
+    let (meta, meta_path) = read_meta_to_change(folder_path, names)?;
+    if !crypto::verify_password(key, &meta.verify_token) {
+        return Err(incorrect_password());
+    }
+    finish_unlock(folder_path, Path::new(folder_path), &meta, &meta_path, key, options, on_progress)
+}
+
+pub fn unlock_folder_with_master_key(folder_path: &str, names: &VaultNames, master_key: &[u8; 32], options: &UnlockOptions, on_progress: &mut dyn FnMut(usize, usize)) -> Result<ProtectedFolder, Error> {
+    let (meta, meta_path) = read_meta_to_change(folder_path, names)?;
+    let key = folder_key_from_master(&meta, master_key)?;
+    finish_unlock(folder_path, Path::new(folder_path), &meta, &meta_path, &key, options, on_progress)
+}
+
+pub fn unlock_folder_with_identity(folder_path: &str, names: &VaultNames, identity: &Identity, options: &UnlockOptions, on_progress: &mut dyn FnMut(usize, usize)) -> Result<ProtectedFolder, Error> {
+    let (meta, meta_path) = read_meta_to_change(folder_path, names)?;
+    let key = folder_key_from_identity(&meta, identity)?;
+    finish_unlock(folder_path, Path::new(folder_path), &meta, &meta_path, &key, options, on_progress)
+}
+
+// With only one file there is nothing partial to report; its failure is the error.
+fn single_file_result(result: Result<ProtectedFolder, Error>) -> Result<ProtectedFolder, Error> {
+    let mut folder = result?;
+    match folder.failed.pop() {
+        Some(failure) => Err(Error::new(failure.code, failure.error)),
+        None => Ok(folder),
+    }
+}
+
+pub fn unlock_file(file_path: &str, password: &str, options: &UnlockOptions) -> Result<ProtectedFolder, Error> {
+    let (meta, meta_path, dir) = read_file_meta(file_path)?;
+    let key = folder_key_from_password(&meta, password)?;
+    single_file_result(finish_unlock(file_path, &dir, &meta, &meta_path, &key, options, &mut |_, _| {}))
+}
+
+pub fn unlock_file_with_master_key(file_path: &str, master_key: &[u8; 32], options: &UnlockOptions) -> Result<ProtectedFolder, Error> {
+    let (meta, meta_path, dir) = read_file_meta(file_path)?;
+    let key = folder_key_from_master(&meta, master_key)?;
+    single_file_result(finish_unlock(file_path, &dir, &meta, &meta_path, &key, options, &mut |_, _| {}))
+}
+
+// In-place unlocks periodically rewrite the metadata with only the files still
+// to go, so an interrupted unlock reports an accurate count and resumes where
+// it stopped. Checkpoints are throttled to keep large vaults from rewriting
+// the whole manifest after every file.
+fn finish_unlock(path: &str, dir: &Path, meta: &FolderMeta, meta_path: &Path, key: &[u8; 32], options: &UnlockOptions, on_progress: &mut dyn FnMut(usize, usize)) -> Result<ProtectedFolder, Error> {
+    if meta.upgrade_swapping() {
+        return Err(UPGRADE_UNFINISHED.into());
+    }
+    let total = meta.files.len();
+    if let Some(output_dir) = &options.output_dir {
+        let output_dir = prepare_output_dir(dir, Path::new(output_dir))?;
+        let restored = decrypt_files(dir, Some(&output_dir), ContentKey::new(meta, key), &meta.files, options.on_conflict, options.retry, &mut |done, _| {
+            on_progress(done, total);
+            Ok(())
+        })?;
+        return Ok(ProtectedFolder { path: path.to_string(), is_locked: true, file_count: total, has_recovery: meta.recovery_key.is_some(), restored: restored.paths, missing: restored.missing, failed: restored.failed, ..Default::default() });
+    }
+    // An upgrade that never got as far as swapping files in is abandoned.
+    if meta.kdf_upgrade.is_some() {
+        for file_meta in meta.files.iter().filter(|f| f.has_ciphertext()) {
+            let _ = fs::remove_file(staged_rekey_path(&file_meta.locked_path_on_disk(dir)));
+        }
+    }
+    // What is still locked: the failures so far and everything not yet reached.
+    let remaining = |done: usize, failed: &[usize]| FolderMeta {
+        files: failed.iter().map(|&i| meta.files[i].clone()).chain(meta.files[done..].iter().cloned()).collect(),
+        ciphertext_root: None,
+        ..meta.clone()
+    };
+    let mut last_checkpoint = Instant::now();
+    let restored = decrypt_files(dir, None, ContentKey::new(meta, key), &meta.files, options.on_conflict, options.retry, &mut |done, restored| {
+        if done < total && last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+            write_meta(meta_path, &remaining(done, &restored.failed_entries))?;
+            last_checkpoint = Instant::now();
+        }
+        on_progress(done, total);
+        Ok(())
+    })?;
+    if restored.failed.is_empty() {
+        remove_meta(meta_path, meta)?;
+    } else {
+        write_meta(meta_path, &remaining(total, &restored.failed_entries))?;
+    }
+    let is_locked = !restored.failed.is_empty();
+    Ok(ProtectedFolder { path: path.to_string(), is_locked, file_count: total, has_recovery: is_locked && meta.recovery_key.is_some(), restored: restored.paths, missing: restored.missing, failed: restored.failed, ..Default::default() })
+}
+
+fn prepare_output_dir(vault_dir: &Path, output_dir: &Path) -> Result<PathBuf, Error> {
+    fs::create_dir_all(output_dir).map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", output_dir.display(), e)))?;
+    let output_dir = output_dir.canonicalize().map_err(|e| Error::io(&e, format!("Invalid output directory '{}': {}", output_dir.display(), e)))?;
+    let vault_dir = vault_dir.canonicalize().map_err(|e| Error::io(&e, format!("Invalid folder '{}': {}", vault_dir.display(), e)))?;
+    if output_dir.starts_with(&vault_dir) {
+        return Err("Output directory must be outside the locked folder".into());
+    }
+    Ok(output_dir)
+}
+
+// Directories made by create_secure_temp_dir start with this, so leftovers
+// from a crashed session can be found again.
+const SECURE_TEMP_PREFIX: &str = "securelock-view-";
+
+// Memory-backed /dev/shm on Linux; elsewhere the OS temp directory, which is
+// cleaned up after rather than trusted to stay off the disk.
+fn secure_temp_root() -> PathBuf {
+    #[cfg(target_os = "linux")]
+    if Path::new("/dev/shm").is_dir() {
+        return PathBuf::from("/dev/shm");
+    }
+    std::env::temp_dir()
+}
+
+// A new directory only the current user can open, for decrypted copies that
+// must not outlive the session; see unlock_to_tmpfs.
+pub fn create_secure_temp_dir() -> Result<PathBuf, Error> {
+    let dir = secure_temp_root().join(format!("{}{:016x}", SECURE_TEMP_PREFIX, rand::random::<u64>()));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&dir).map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", dir.display(), e)))?;
+    Ok(dir)
+}
+
+// Shreds every file in a directory from create_secure_temp_dir, then removes
+// it. Anything else is refused, so a bad path can't take a real folder with it.
+// With `verify`, returns the files whose shredding couldn't be guaranteed and
+// why; see shred_file_checked.
+pub fn remove_secure_temp_dir(dir: &Path, verify: bool) -> Result<Vec<String>, Error> {
+    let ours = dir.parent() == Some(secure_temp_root().as_path())
+        && dir.file_name().is_some_and(|n| n.to_string_lossy().starts_with(SECURE_TEMP_PREFIX));
+    if !ours {
+        return Err(format!("'{}' is not a SecureLock temporary directory", dir.display()).into());
+    }
+    let mut unverified = Vec::new();
+    for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()) {
+        let problem = shred_file_checked(entry.path(), verify).map_err(|e| Error::io(&e, format!("Failed to shred '{}': {}", entry.path().display(), e)))?;
+        if let Some(problem) = problem {
+            unverified.push(format!("'{}' may not be shredded: {}", entry.path().display(), problem));
+        }
+    }
+    fs::remove_dir_all(dir).map_err(|e| Error::io(&e, format!("Failed to remove '{}': {}", dir.display(), e)))?;
+    Ok(unverified)
+}
+
+// Removes what a previous session left behind when it didn't exit cleanly.
+// Directories of other users, or still in use, are skipped.
+pub fn remove_stale_secure_temp_dirs() {
+    for entry in fs::read_dir(secure_temp_root()).into_iter().flatten().flatten() {
+        if entry.file_name().to_string_lossy().starts_with(SECURE_TEMP_PREFIX) {
+            let _ = remove_secure_temp_dir(&entry.path(), false);
+        }
+    }
+}
+
+// Decrypts copies into a fresh directory from create_secure_temp_dir, leaving
+// the folder locked; no plaintext is written to the vault's own disk. The
+// caller removes the directory with remove_secure_temp_dir when done.
+pub fn unlock_to_tmpfs(folder_path: &str, names: &VaultNames, key: &[u8; 32], on_progress: &mut dyn FnMut(usize, usize)) -> Result<(ProtectedFolder, PathBuf), Error> {
+    let dir = create_secure_temp_dir()?;
+    let options = UnlockOptions { output_dir: Some(dir.to_string_lossy().to_string()), ..Default::default() };
+    match unlock_folder_with_key(folder_path, names, key, &options, on_progress) {
+        Ok(folder) => Ok((folder, dir)),
+        Err(e) => {
+            let _ = remove_secure_temp_dir(&dir, false);
+            Err(e)
+        }
+    }
+}
+
+fn current_machine_id() -> Result<String, Error> {
+    machine_uid::get()
+        .map(|id| id.trim().to_string())
+        .map_err(|e| format!("Failed to read machine identifier: {}", e).into())
+}
+
+fn apply_machine_binding(meta: &FolderMeta, key: SecretKey) -> Result<SecretKey, Error> {
+    let Some(fingerprint) = &meta.machine_binding else { return Ok(key) };
+    let id = current_machine_id()?;
+    if crypto::machine_fingerprint(&meta.salt, &id) != *fingerprint {
+        return Err("This folder is bound to a different machine and cannot be unlocked here".into());
+    }
+    crypto::bind_key_to_machine(&key, &id)
+}
+
+fn ciphertext_root(folder: &Path, names: &VaultNames, nested: &[String]) -> Result<Vec<u8>, Error> {
+    Ok(crypto::merkle_root(ciphertext_hashes(folder, names, nested)?))
+}
+
+// Hashes every ciphertext file under the folder, leaving out nested vaults.
+// Paths use `/` whatever the platform, so a vault verifies after moving.
+fn ciphertext_hashes(folder: &Path, names: &VaultNames, nested: &[String]) -> Result<Vec<crypto::MerkleLeaf>, Error> {
+    let nested: Vec<PathBuf> = nested.iter().map(|n| folder.join(n)).collect();
+    let mut leaves = Vec::new();
+    for entry in WalkDir::new(folder).into_iter().filter_entry(|e| !nested.iter().any(|n| e.path() == n)) {
+        let entry = entry.map_err(|e| Error::new(e.io_error().map_or(ErrorCode::Other, error::io_code), format!("Failed to read folder: {}", e)))?;
+        if !entry.file_type().is_file() || !entry.file_name().to_string_lossy().ends_with(names.locked_ext.as_str()) {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(folder).map_err(|e| format!("Path error: {}", e))?;
+        let relative: Vec<&[u8]> = relative.components().map(|c| c.as_os_str().as_encoded_bytes()).collect();
+        let hash = fs::File::open(entry.path())
+            .and_then(|mut f| crypto::ciphertext_hash(&mut f))
+            .map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", entry.path().display(), e)))?;
+        leaves.push((relative.join(&b'/'), hash));
+    }
+    Ok(leaves)
+}
+
+// Whether the ciphertext on disk is exactly what was locked: no file added,
+// removed or altered. Only hashes ciphertext, so it needs no password and is
+// much quicker than decrypting. Someone able to rewrite the metadata can
+// rewrite the root too; decryption still catches that through the verify token
+// and each file's tag.
+pub fn verify_vault_root(folder_path: &str, names: &VaultNames) -> Result<bool, Error> {
+    let (meta, _) = read_meta(folder_path, names)?;
+    let Some(expected) = &meta.ciphertext_root else {
+        return Err("This vault has no integrity root; unlock and lock it again to add one".into());
+    };
+    Ok(ciphertext_root(Path::new(folder_path), names, &meta.nested)? == *expected)
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VaultComparison {
+    // Ciphertext files, by their path in the vault.
+    pub only_in_a: Vec<String>,
+    pub only_in_b: Vec<String>,
+    pub differing: Vec<String>,
+    // The metadata itself differs: another file list, salt, password or
+    // recovery key, or a lock at a different time.
+    pub metadata_differs: bool,
+    pub identical: bool,
+}
+
+// Compares two copies of a locked folder, a synced backup say, without the
+// password: the metadata as a whole and every ciphertext file by hash.
+pub fn compare_vaults(path_a: &str, names_a: &VaultNames, path_b: &str, names_b: &VaultNames) -> Result<VaultComparison, Error> {
+    let (meta_a, _) = read_meta(path_a, names_a)?;
+    let (meta_b, _) = read_meta(path_b, names_b)?;
+    let as_value = |meta: &FolderMeta| serde_json::to_value(meta).map_err(|e| format!("Metadata serialization error: {}", e));
+    let metadata_differs = as_value(&meta_a)? != as_value(&meta_b)?;
+    let a: HashMap<Vec<u8>, [u8; 32]> = ciphertext_hashes(Path::new(path_a), names_a, &meta_a.nested)?.into_iter().collect();
+    let b: HashMap<Vec<u8>, [u8; 32]> = ciphertext_hashes(Path::new(path_b), names_b, &meta_b.nested)?.into_iter().collect();
+    let sorted = |mut paths: Vec<String>| {
+        paths.sort();
+        paths
+    };
+    let display = |path: &Vec<u8>| String::from_utf8_lossy(path).into_owned();
+    let only_in_a = sorted(a.keys().filter(|p| !b.contains_key(*p)).map(display).collect());
+    let only_in_b = sorted(b.keys().filter(|p| !a.contains_key(*p)).map(display).collect());
+    let differing = sorted(a.iter().filter(|(p, hash)| b.get(*p).is_some_and(|other| other != *hash)).map(|(p, _)| display(p)).collect());
+    let identical = !metadata_differs && only_in_a.is_empty() && only_in_b.is_empty() && differing.is_empty();
+    Ok(VaultComparison { only_in_a, only_in_b, differing, metadata_differs, identical })
+}
+
+// `<name><locked_ext>.tmp` files whose plaintext is still beside them: what a
+// lock killed part way through a file leaves. Separately locked subfolders
+// are left to their own vault.
+fn interrupted_ciphertext(folder: &Path, names: &VaultNames) -> Vec<PathBuf> {
+    let suffix = format!("{}.tmp", names.locked_ext);
+    WalkDir::new(folder)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_type().is_dir() || !is_vault_dir(e.path(), names))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy();
+            name.strip_suffix(suffix.as_str()).is_some_and(|original| !original.is_empty() && e.path().with_file_name(original).is_file())
+        })
+        .map(|e| e.into_path())
+        .collect()
+}
+
+// Removes partly written ciphertext left by an interrupted lock. The plaintext
+// it came from was never removed, so nothing is lost and the next lock starts
+// that file over. Returns the removed files, relative to the folder.
+pub fn clean_interrupted_lock(folder_path: &str, names: &VaultNames) -> Result<Vec<String>, Error> {
+    let folder = check_folder_path(folder_path)?;
+    interrupted_ciphertext(folder, names).iter().map(|path| {
+        fs::remove_file(path).map_err(|e| Error::io(&e, format!("Failed to remove '{}': {}", path.display(), e)))?;
+        Ok(path.strip_prefix(folder).unwrap_or(path).to_string_lossy().to_string())
+    }).collect()
+}
+
+// Classifies a folder from what is on disk, so an interrupted lock or unlock
+// can be spotted without the password.
+pub fn diagnose_folder(folder_path: &str, names: &VaultNames) -> Result<FolderDiagnosis, Error> {
+    let folder = check_folder_path(folder_path)?;
+    let leftover_temp_files: Vec<String> = interrupted_ciphertext(folder, names).iter()
+        .map(|p| p.strip_prefix(folder).unwrap_or(p).to_string_lossy().to_string())
+        .collect();
+    let diagnosis = |state, inconsistent_files| FolderDiagnosis { path: folder_path.to_string(), state, inconsistent_files, leftover_temp_files: leftover_temp_files.clone() };
+    if let Ok((meta, _)) = read_meta(folder_path, names) {
+        let inconsistent: Vec<String> = meta.files.iter()
+            .filter(|f| {
+                (f.has_ciphertext() && !f.locked_path_on_disk(folder).exists()) || f.original_path_on_disk(folder).exists()
+            })
+            .map(|f| f.relative_path.clone())
+            .collect();
+        let state = if inconsistent.is_empty() { FolderState::CleanLocked } else { FolderState::PartiallyLocked };
+        return Ok(diagnosis(state, inconsistent));
+    }
+    let orphaned: Vec<String> = WalkDir::new(folder)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && e.file_name().to_string_lossy().ends_with(names.locked_ext.as_str()))
+        .filter_map(|e| e.path().strip_prefix(folder).ok().map(|p| p.to_string_lossy().to_string()))
+        .collect();
+    let state = if orphaned.is_empty() { FolderState::CleanPlaintext } else { FolderState::OrphanedCiphertext };
+    Ok(diagnosis(state, orphaned))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RebuildResult {
+    pub path: String,
+    pub file_count: usize,
+    // Ciphertext that did not decrypt with the password and was left out.
+    pub skipped: Vec<String>,
+}
+
+// Last-resort repair for a folder whose metadata is damaged: writes fresh
+// metadata listing every locked file that decrypts with `password`. The key
+// still needs the folder's salt, so it is salvaged from the damaged metadata
+// (or the temp file of an interrupted write); with no trace of it left the
+// files cannot be recovered. The damaged file is moved aside, not deleted.
+// Hard links, deduplicated copies, the recovery key and the lock pattern are
+// not restored, unless only the manifest of a split vault was lost: its intact
+// header still has the recovery key and pattern, and they carry over.
+pub fn rebuild_metadata(folder_path: &str, names: &VaultNames, password: &str) -> Result<RebuildResult, Error> {
+    let folder = check_folder_path(folder_path)?;
+    let meta_path = folder.join(&names.meta_file);
+    if parse_meta(&meta_path).is_ok() {
+        return Err("Metadata is intact; nothing to rebuild".into());
+    }
+    let mut tmp_name = OsString::from(&names.meta_file);
+    tmp_name.push(".tmp");
+    let damaged: Vec<String> = [meta_path.clone(), folder.join(tmp_name)].iter()
+        .filter_map(|p| fs::read(p).ok())
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .collect();
+    let salt = damaged.iter().find_map(|text| salvage_bytes(text, "salt"))
+        .ok_or("The folder's salt was lost with its metadata; its files cannot be decrypted without it")?;
+    let machine_binding = damaged.iter().find_map(|text| salvage_bytes(text, "machine_binding"));
+    let kdf = damaged.iter().find_map(|text| salvage_kdf(text));
+    let mut meta = FolderMeta { magic: MetaMagic, format_version: FORMAT_VERSION, salt, verify_token: Vec::new(), files: Vec::new(), ciphertext_root: None, recovery_key: None, recipients: Vec::new(), password_slots: Vec::new(), cipher: CipherKind::default(), machine_binding, pattern: None, nested: Vec::new(), deferred: Vec::new(), stream_threshold: None, locked_at: None, split_manifest: false, kdf, app_version: None, kdf_upgrade: None, pending_rekey: None };
+    let key = apply_machine_binding(&meta, meta.password_key(password)?)?;
+    let header = parse_header(&meta_path).ok().filter(|h| h.split_manifest);
+    if let Some(header) = header {
+        if !crypto::verify_password(&key, &header.verify_token) {
+            return Err(incorrect_password());
+        }
+        meta.recovery_key = header.recovery_key;
+        meta.recipients = header.recipients;
+        meta.password_slots = header.password_slots;
+        meta.pattern = header.pattern;
+        meta.locked_at = header.locked_at;
+        meta.app_version = header.app_version;
+        meta.split_manifest = true;
+    }
+
+    let locked_files: Vec<PathBuf> = WalkDir::new(folder)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && e.file_name().to_string_lossy().ends_with(names.locked_ext.as_str()))
+        .map(|e| e.into_path())
+        .collect();
+    // Neither the cipher nor the key scheme is known; whichever combination
+    // opens the first file is used for all of them.
+    let candidates: Vec<ContentKey> = [CipherKind::Aes256Gcm, CipherKind::Aes256GcmSiv].into_iter()
+        .flat_map(|cipher| [true, false].map(|per_file| ContentKey { key: &key, cipher, per_file }))
+        .collect();
+    let mut scheme: Option<ContentKey> = None;
+    let mut skipped = Vec::new();
+    for locked_path in &locked_files {
+        let relative = locked_path.strip_prefix(folder).map_err(|e| format!("Path error: {}", e))?.to_string_lossy().to_string();
+        let encrypted = fs::read(locked_path).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", locked_path.display(), e)))?;
+        let original_path = locked_path.with_file_name(strip_locked_ext(locked_path.file_name().unwrap_or_default(), &names.locked_ext)?);
+        let mut file_meta = describe_file(folder, &original_path, &names.locked_ext, false)?;
+        // Streamed files look like any other, so both layouts are tried.
+        let mut decrypted = None;
+        for chunk_size in [None, Some(crypto::STREAM_CHUNK_SIZE as u32)] {
+            file_meta.chunk_size = chunk_size;
+            decrypted = match scheme {
+                Some(k) => k.decrypt(&file_meta, &encrypted).ok().map(|p| (k, p)),
+                None => candidates.iter().find_map(|k| k.decrypt(&file_meta, &encrypted).ok().map(|p| (*k, p))),
+            };
+            if decrypted.is_some() {
+                break;
+            }
+        }
+        let Some((used, plaintext)) = decrypted else {
+            skipped.push(relative);
+            continue;
+        };
+        scheme = Some(used);
+        file_meta.plaintext_hash = Some(crypto::plaintext_digest(&key, &plaintext));
+        meta.files.push(file_meta);
+    }
+    let Some(scheme) = scheme else {
+        return Err(Error::new(ErrorCode::WrongPassword, "No locked files decrypt with this password"));
+    };
+    meta.cipher = scheme.cipher;
+    if !scheme.per_file {
+        meta.format_version = PER_FILE_KEY_VERSION - 1;
+    }
+    meta.verify_token = crypto::create_verify_token(&key)?;
+    meta.ciphertext_root = Some(ciphertext_root(folder, names, &meta.nested)?);
+    for stale in [meta_path.clone(), manifest_path(&meta_path)] {
+        if stale.exists() {
+            quarantine(&stale)?;
+        }
+    }
+    write_meta(&meta_path, &meta)?;
+    Ok(RebuildResult { path: folder_path.to_string(), file_count: meta.files.len(), skipped })
+}
+
+fn strip_locked_ext(name: &OsStr, locked_ext: &str) -> Result<OsString, Error> {
+    if let Some(name) = name.to_str() {
+        return Ok(OsString::from(name.strip_suffix(locked_ext).unwrap_or(name)));
+    }
+    let encoded = percent_encode(name)?;
+    let ext = percent_encode(OsStr::new(locked_ext))?;
+    Ok(bytes_to_os(percent_decode(encoded.strip_suffix(ext.as_str()).unwrap_or(&encoded))))
+}
+
+// Pulls a byte array field such as `"salt": [1, 2, ...]` out of metadata that
+// no longer parses as a whole.
+fn salvage_bytes(text: &str, field: &str) -> Option<Vec<u8>> {
+    let start = text.find(&format!("\"{}\"", field))?;
+    let rest = &text[start..];
+    let open = rest.find('[')?;
+    let close = rest[open..].find(']')? + open;
+    let bytes: Result<Vec<u8>, _> = rest[open + 1..close]
+        .split(',')
+        .map(|n| n.trim().parse::<u8>())
+        .collect();
+    bytes.ok().filter(|b| !b.is_empty())
+}
+
+// Likewise for the `"kdf": {...}` object.
+fn salvage_kdf(text: &str) -> Option<KdfParams> {
+    let start = text.find("\"kdf\"")?;
+    let rest = &text[start..];
+    let open = rest.find('{')?;
+    let close = rest[open..].find('}')? + open;
+    serde_json::from_str(&rest[open..=close]).ok()
+}
+
+// What a `.slk` archive holds besides the ciphertext: the vault metadata, and
+// the offset and length of each of its files, in the same order.
+#[derive(Serialize, Deserialize)]
+struct ArchiveIndex {
+    meta: FolderMeta,
+    spans: Vec<(u64, u64)>,
+}
+
+// Packs an encrypted copy of the folder into a single `.slk` file that can be
+// moved or backed up as one unit: the magic, each file's ciphertext back to
+// back, a JSON index, and a trailer pointing at the index. The folder itself is
+// left as it is. Hard links and duplicates are stored as separate files.
+pub fn lock_to_archive(folder_path: &str, names: &VaultNames, archive_path: &str, password: &str, master_key: Option<&SecretKey>, options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    let folder = check_folder_path(folder_path)?;
+    if is_locked(folder_path, names) {
+        return Err("Unlock the folder before archiving it".into());
+    }
+    let archive = Path::new(archive_path);
+    if archive.exists() {
+        return Err(format!("'{}' already exists", archive_path).into());
+    }
+    let archive_dir = archive.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if let (Ok(archive_dir), Ok(folder)) = (archive_dir.canonicalize(), folder.canonicalize()) {
+        if archive_dir.starts_with(folder) {
+            return Err("The archive must be outside the folder being archived".into());
+        }
+    }
+    let hidden = options.hidden.clone().unwrap_or_default();
+    hidden.validate()?;
+    let mut nested: Vec<PathBuf> = Vec::new();
+    let mut special: Vec<PathBuf> = Vec::new();
+    let files = lockable_files(folder, names, &hidden, &mut nested, &mut special);
+    if files.is_empty() {
+        return Err("Nothing to archive: the folder has no files to encrypt".into());
+    }
+    let (mut meta, key) = new_vault(password, &[], master_key, options)?;
+    meta.split_manifest = false;
+    meta.nested = nested.iter().map(|p| p.strip_prefix(folder).unwrap_or(p).to_string_lossy().to_string()).collect();
+    let mut tmp_name = archive.file_name().ok_or("Invalid archive name")?.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = archive.with_file_name(tmp_name);
+    let stream_threshold = options.stream_threshold.unwrap_or(DEFAULT_STREAM_THRESHOLD);
+    let result = write_archive(&tmp_path, folder, &files, ContentKey::new(&meta, &key), stream_threshold, options.on_unreadable, &mut meta)
+        .and_then(|skipped| fs::rename(&tmp_path, archive).map(|_| skipped).map_err(|e| Error::io(&e, format!("Failed to write '{}': {}", archive_path, e))));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    let mut skipped = result?;
+    skipped.extend(special.iter().map(|p| p.strip_prefix(folder).unwrap_or(p).to_string_lossy().to_string()));
+    Ok(ProtectedFolder { path: archive_path.to_string(), is_locked: true, file_count: meta.files.len(), has_recovery: meta.recovery_key.is_some(), skipped, ..Default::default() })
+}
+
+// Fills in `meta.files` and returns the files skipped as unreadable.
+fn write_archive(path: &Path, folder: &Path, files: &[PathBuf], key: ContentKey, stream_threshold: u64, on_unreadable: UnreadablePolicy, meta: &mut FolderMeta) -> Result<Vec<String>, Error> {
+    let write_err = |e: std::io::Error| Error::io(&e, format!("Failed to write '{}': {}", path.display(), e));
+    let mut out = std::io::BufWriter::new(fs::File::create(path).map_err(write_err)?);
+    out.write_all(ARCHIVE_MAGIC).map_err(write_err)?;
+    let mut spans = Vec::new();
+    let mut skipped = Vec::new();
+    for file_path in files {
+        let mut file_meta = describe_file(folder, file_path, LOCKED_EXT, false)?;
+        let input = match fs::File::open(file_path).and_then(|f| f.metadata().map(|m| (f, m.len()))) {
+            Ok(input) => input,
+            Err(_) if on_unreadable == UnreadablePolicy::Skip => {
+                skipped.push(file_path.strip_prefix(folder).unwrap_or(file_path).to_string_lossy().to_string());
+                continue;
+            }
+            Err(e) => return Err(Error::io(&e, format!("Failed to read '{}': {}", file_path.display(), e))),
+        };
+        let start = out.stream_position().map_err(write_err)?;
+        let digest = match input {
+            (input, len) if len > stream_threshold => {
+                file_meta.chunk_size = Some(crypto::STREAM_CHUNK_SIZE as u32);
+                let mut hasher = crypto::PlaintextHasher::new(key.key);
+                key.encrypt_stream(&file_meta, &mut std::io::BufReader::new(input), &mut out, &mut |chunk| hasher.update(chunk))
+                    .map_err(|e| e.context(|m| format!("Failed to encrypt '{}': {}", file_path.display(), m)))?;
+                hasher.finalize()
+            }
+            (mut input, len) => {
+                let mut plaintext = Vec::with_capacity(len as usize);
+                input.read_to_end(&mut plaintext).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", file_path.display(), e)))?;
+                out.write_all(&key.encrypt(&file_meta, &plaintext)?).map_err(write_err)?;
+                crypto::plaintext_digest(key.key, &plaintext)
+            }
+        };
+        file_meta.plaintext_hash = Some(digest);
+        spans.push((start, out.stream_position().map_err(write_err)? - start));
+        meta.files.push(file_meta);
+    }
+    if meta.files.is_empty() {
+        return Err("Nothing to archive: none of the files could be read".into());
+    }
+    let index = serde_json::to_vec(&ArchiveIndex { meta: meta.clone(), spans }).map_err(|e| format!("Metadata serialization error: {}", e))?;
+    out.write_all(&index).map_err(write_err)?;
+    out.write_all(&(index.len() as u64).to_le_bytes()).map_err(write_err)?;
+    out.write_all(ARCHIVE_MAGIC).map_err(write_err)?;
+    out.into_inner().map_err(|e| write_err(e.into_error()))?.sync_all().map_err(write_err)?;
+    Ok(skipped)
+}
+
+fn read_archive_index(archive: &mut fs::File) -> Result<ArchiveIndex, Error> {
+    let not_archive = || "Not a SecureLock archive".to_string();
+    let read_err = |e: std::io::Error| Error::io(&e, format!("Failed to read archive: {}", e));
+    let len = archive.metadata().map_err(read_err)?.len();
+    if len < ARCHIVE_MAGIC.len() as u64 + ARCHIVE_TRAILER_LEN {
+        return Err(not_archive().into());
+    }
+    let mut magic = [0u8; 4];
+    archive.read_exact(&mut magic).map_err(read_err)?;
+    let mut trailer = [0u8; ARCHIVE_TRAILER_LEN as usize];
+    archive.seek(SeekFrom::End(-(ARCHIVE_TRAILER_LEN as i64))).map_err(read_err)?;
+    archive.read_exact(&mut trailer).map_err(read_err)?;
+    if &magic != ARCHIVE_MAGIC || &trailer[8..] != ARCHIVE_MAGIC {
+        return Err(not_archive().into());
+    }
+    let index_len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    let index_start = (len - ARCHIVE_TRAILER_LEN).checked_sub(index_len)
+        .filter(|start| *start >= ARCHIVE_MAGIC.len() as u64)
+        .ok_or_else(|| Error::new(ErrorCode::Corrupted, "Archive index is corrupted"))?;
+    let mut index = vec![0u8; index_len as usize];
+    archive.seek(SeekFrom::Start(index_start)).map_err(read_err)?;
+    archive.read_exact(&mut index).map_err(read_err)?;
+    let index: ArchiveIndex = serde_json::from_slice(&index).map_err(|e| Error::new(ErrorCode::Corrupted, format!("Invalid metadata: {}", e)))?;
+    let spans_fit = index.spans.iter().all(|(start, len)| *start >= ARCHIVE_MAGIC.len() as u64 && start.checked_add(*len).is_some_and(|end| end <= index_start));
+    if index.spans.len() != index.meta.files.len() || !spans_fit {
+        return Err(Error::new(ErrorCode::Corrupted, "Archive index is corrupted"));
+    }
+    Ok(index)
+}
+
+// Extracts an archive made by `lock_to_archive` into `dest_path`, which must
+// not exist yet or be empty. If any file fails, what was extracted is removed
+// again.
+pub fn unlock_from_archive(archive_path: &str, dest_path: &str, password: &str, on_progress: &mut dyn FnMut(usize, usize)) -> Result<ProtectedFolder, Error> {
+    let mut archive = fs::File::open(archive_path).map_err(|e| Error::io(&e, format!("Failed to open '{}': {}", archive_path, e)))?;
+    let index = read_archive_index(&mut archive)?;
+    let meta = &index.meta;
+    if let Some(bad) = meta.files.iter().find(|f| !f.is_contained()) {
+        return Err(format!("Archive entry '{}' points outside the destination", bad.relative_path).into());
+    }
+    let key = folder_key_from_password(meta, password)?;
+    let dest = Path::new(dest_path);
+    if dest.exists() && fs::read_dir(dest).map(|mut entries| entries.next().is_some()).unwrap_or(true) {
+        return Err(format!("'{}' must be a new or empty folder", dest_path).into());
+    }
+    let created = !dest.exists();
+    fs::create_dir_all(dest).map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", dest_path, e)))?;
+    let content_key = ContentKey::new(meta, &key);
+    let total = meta.files.len();
+    let mut restored = Vec::with_capacity(total);
+    let mut extract = || -> Result<(), Error> {
+        for (i, (file_meta, (start, len))) in meta.files.iter().zip(&index.spans).enumerate() {
+            archive.seek(SeekFrom::Start(*start)).map_err(|e| Error::io(&e, format!("Failed to read archive: {}", e)))?;
+            let mut input = (&mut archive).take(*len);
+            let original_path = file_meta.original_path(dest);
+            if file_meta.chunk_size.is_some() {
+                decrypt_streaming(&mut input, &original_path, content_key, file_meta, true)?;
+            } else {
+                let mut encrypted = Vec::with_capacity(*len as usize);
+                input.read_to_end(&mut encrypted).map_err(|e| Error::io(&e, format!("Failed to read archive: {}", e)))?;
+                let plaintext = content_key.decrypt(file_meta, &encrypted)?;
+                file_meta.verify_plaintext(content_key.key, &plaintext)?;
+                write_plaintext(&original_path, &plaintext, true, RetryPolicy::default())?;
+            }
+            restored.push(original_path.to_string_lossy().to_string());
+            on_progress(i + 1, total);
+        }
+        Ok(())
+    };
+    if let Err(e) = extract() {
+        clear_dir(dest);
+        if created {
+            let _ = fs::remove_dir(dest);
+        }
+        return Err(e);
+    }
+    Ok(ProtectedFolder { path: dest_path.to_string(), is_locked: false, file_count: total, has_recovery: false, restored, ..Default::default() })
+}
+
+// Extracts a password-protected ZIP (ZipCrypto or AES), as simpler lockers
+// leave them, into `dest_path`, which must be new or empty, and locks it under
+// `password`. If either step fails the extracted plaintext is removed again;
+// the ZIP itself is never touched.
+pub fn import_zip(archive_path: &str, dest_path: &str, names: &VaultNames, zip_password: &str, password: &str, master_key: Option<&SecretKey>, options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    let archive = fs::File::open(archive_path).map_err(|e| Error::io(&e, format!("Failed to open '{}': {}", archive_path, e)))?;
+    let mut zip = zip::ZipArchive::new(std::io::BufReader::new(archive)).map_err(|e| format!("'{}' is not a readable ZIP file: {}", archive_path, e))?;
+    let dest = Path::new(dest_path);
+    if dest.exists() && fs::read_dir(dest).map(|mut entries| entries.next().is_some()).unwrap_or(true) {
+        return Err(format!("'{}' must be a new or empty folder", dest_path).into());
+    }
+    // Checked against the sizes the ZIP declares, before anything is written.
+    if !options.ignore_limits {
+        let mut count = 0;
+        let mut total = 0;
+        for i in 0..zip.len() {
+            let entry = zip.by_index_raw(i).map_err(|e| format!("Failed to read ZIP: {}", e))?;
+            if !entry.is_dir() {
+                count += 1;
+                total += entry.size();
+            }
+        }
+        options.limits.unwrap_or_default().check_totals(count, || total)?;
+    }
+    let created = !dest.exists();
+    fs::create_dir_all(dest).map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", dest_path, e)))?;
+    let result = extract_zip(&mut zip, dest, zip_password)
+        .and_then(|_| lock_folder(dest_path, names, password, master_key, options));
+    if result.is_err() {
+        clear_dir(dest);
+        if created {
+            let _ = fs::remove_dir(dest);
+        }
+    }
+    result
+}
+
+fn extract_zip<R: Read + Seek>(zip: &mut zip::ZipArchive<R>, dest: &Path, password: &str) -> Result<(), Error> {
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index_decrypt(i, password.as_bytes()).map_err(|e| match e {
+            zip::result::ZipError::InvalidPassword => Error::new(ErrorCode::WrongPassword, "Incorrect password for the ZIP file"),
+            e => format!("Failed to read ZIP: {}", e).into(),
+        })?;
+        let relative = entry.enclosed_name().ok_or_else(|| format!("ZIP entry '{}' points outside the folder", entry.name()))?;
+        let path = dest.join(relative);
+        if entry.is_dir() {
+            fs::create_dir_all(&path).map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", path.display(), e)))?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", parent.display(), e)))?;
+        }
+        // create_new, so an entry listed twice can't overwrite the first.
+        let mut out = fs::OpenOptions::new().write(true).create_new(true).open(&path)
+            .map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", path.display(), e)))?;
+        std::io::copy(&mut entry, &mut out).map_err(|e| Error::io(&e, format!("Failed to extract '{}': {}", entry.name(), e)))?;
+    }
+    Ok(())
+}
+
+// Empties `dir` without removing it, on a best-effort basis.
+fn clear_dir(dir: &Path) {
+    for entry in fs::read_dir(dir).into_iter().flatten().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let _ = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+    }
+}
+
+// A vault with a reserve looks the same whether or not a hidden dataset is in
+// it: the reserve is random filler or AES-GCM ciphertext, which can't be told
+// apart, its size is fixed when it is made, and the metadata never mentions it.
+fn reserve_path(folder: &Path, names: &VaultNames) -> PathBuf {
+    folder.join(format!("{}{}", names.meta_file, RESERVE_EXT))
+}
+
+// A reserve's key comes from `kind`'s default settings. Nothing in the reserve
+// records the kind, so unlock_hidden tries the decoy vault's first, then the rest.
+fn reserve_key(kind: KdfKind, password: &str, salt: &[u8]) -> Result<SecretKey, Error> {
+    crypto::derive_key_with(&crypto::default_kdf_params(kind), password, salt)
+}
+
+fn check_reserve_size(capacity: u64) -> Result<(), Error> {
+    if capacity > MAX_RESERVE {
+        return Err(format!("A reserve can be at most {} bytes, not {}", MAX_RESERVE, capacity).into());
+    }
+    Ok(())
+}
+
+// Fills a new reserve with random bytes. An existing one is left alone, since
+// it may hold hidden data and nothing here can tell.
+fn ensure_reserve(folder: &Path, names: &VaultNames, capacity: u64) -> Result<(), Error> {
+    use rand::RngCore;
+    let path = reserve_path(folder, names);
+    if path.exists() {
+        return Ok(());
+    }
+    let mut noise = vec![0u8; (capacity + RESERVE_OVERHEAD) as usize];
+    rand::rngs::OsRng.fill_bytes(&mut noise);
+    write_atomic(&path, &noise).map_err(|e| Error::io(&e, format!("Failed to write reserve: {}", e)))
+}
+
+// The hidden files as (path length u32, path, data length u64, data), after the
+// total length so the zero padding to the reserve's size is ignored.
+fn pack_hidden(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut packed = vec![0u8; 8];
+    for (path, data) in files {
+        packed.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        packed.extend_from_slice(path.as_bytes());
+        packed.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        packed.extend_from_slice(data);
+    }
+    let len = (packed.len() - 8) as u64;
+    packed[..8].copy_from_slice(&len.to_le_bytes());
+    packed
+}
+
+fn unpack_hidden(packed: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let invalid = || Error::new(ErrorCode::Corrupted, "Hidden data is corrupted");
+    let take = |rest: &mut &[u8], n: usize| -> Result<Vec<u8>, Error> {
+        if rest.len() < n {
+            return Err(invalid());
+        }
+        let (head, tail) = rest.split_at(n);
+        *rest = tail;
+        Ok(head.to_vec())
+    };
+    let mut rest = packed;
+    let len = u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap());
+    let mut rest = rest.get(..usize::try_from(len).map_err(|_| invalid())?).ok_or_else(invalid)?;
+    let mut files = Vec::new();
+    while !rest.is_empty() {
+        let path_len = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap()) as usize;
+        let path = String::from_utf8(take(&mut rest, path_len)?).map_err(|_| invalid())?;
+        let relative = Path::new(&path);
+        if relative.components().next().is_none() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("Hidden entry '{}' points outside the folder", path).into());
+        }
+        let data_len = u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap());
+        let data = take(&mut rest, usize::try_from(data_len).map_err(|_| invalid())?)?;
+        files.push((path, data));
+    }
+    Ok(files)
+}
+
+// Moves the folder's files into a new reserve under `password`, then fills the
+// folder with copies of `decoy_source`'s files and locks them normally under
+// `decoy_password`. Afterwards the decoy password opens the decoy files and
+// `password`, through unlock_folder, the real ones. The hidden files are held
+// in memory, so this suits a modest amount of data; if the decoy can't be put
+// in place they are written back from there and the reserve removed.
+pub fn lock_with_decoy(folder_path: &str, names: &VaultNames, decoy_source: &str, decoy_password: &str, password: &str, master_key: Option<&SecretKey>, options: &LockOptions) -> Result<ProtectedFolder, Error> {
+    let folder = check_folder_path(folder_path)?;
+    if !options.allow_system_paths {
+        check_lock_target(folder)?;
+    }
+    let meta_path = folder.join(&names.meta_file);
+    if meta_path.exists() {
+        return Err(already_locked(&meta_path, "Folder").into());
+    }
+    let reserve = reserve_path(folder, names);
+    if reserve.exists() {
+        return Err("The folder already has a reserve, which may hold hidden data; unlock or remove it first".into());
+    }
+    if decoy_password == password {
+        return Err("The decoy password must differ from the real one".into());
+    }
+    if let Some(capacity) = options.reserve {
+        check_reserve_size(capacity)?;
+    }
+    let decoy = check_folder_path(decoy_source)?;
+    if overlapping_folder(decoy_source, &[folder_path.to_string()]).is_some() {
+        return Err("The decoy files must come from outside the folder".into());
+    }
+    let decoy_files: Vec<PathBuf> = WalkDir::new(decoy).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()).map(|e| e.into_path()).collect();
+    if decoy_files.is_empty() {
+        return Err("The decoy folder has no files to lock".into());
+    }
+    let decoy_targets: Vec<PathBuf> = decoy_files.iter().map(|source| folder.join(source.strip_prefix(decoy).unwrap_or(source))).collect();
+    let hidden = options.hidden.clone().unwrap_or_default();
+    hidden.validate()?;
+    let mut nested = Vec::new();
+    let files = lockable_files(folder, names, &hidden, &mut nested, &mut Vec::new());
+    if !nested.is_empty() {
+        return Err("A folder containing separately locked vaults can't have a decoy".into());
+    }
+    // Hidden or excluded files stay where they are, and a decoy copy mustn't
+    // replace one.
+    if let Some(taken) = decoy_targets.iter().find(|target| target.exists() && !files.contains(target)) {
+        return Err(format!("The decoy file '{}' would replace a file that stays in the folder", taken.display()).into());
+    }
+    let mut entries = Vec::with_capacity(files.len());
+    for file in &files {
+        let relative = file.strip_prefix(folder).unwrap_or(file).to_string_lossy().replace('\\', "/");
+        let data = fs::read(file).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", file.display(), e)))?;
+        entries.push((relative, data));
+    }
+    let mut packed = pack_hidden(&entries);
+    entries.iter_mut().for_each(|(_, data)| data.zeroize());
+    let capacity = options.reserve.unwrap_or_else(|| (packed.len() as u64).next_power_of_two().max(MIN_RESERVE));
+    if packed.len() as u64 > capacity.min(MAX_RESERVE) {
+        packed.zeroize();
+        return Err(format!("The folder's files ({} bytes packed) don't fit in a {}-byte reserve", packed.len(), capacity.min(MAX_RESERVE)).into());
+    }
+    packed.resize(capacity as usize, 0);
+    let salt = crypto::generate_salt();
+    let result = reserve_key(options.kdf, password, &salt)
+        .and_then(|key| crypto::encrypt(&key, &packed))
+        .and_then(|sealed| write_atomic(&reserve, &[salt.as_slice(), &sealed].concat()).map_err(|e| Error::io(&e, format!("Failed to write reserve: {}", e))))
+        .and_then(|_| {
+            replace_with_decoy(folder, &files, &decoy_files, &decoy_targets)
+                .and_then(|_| lock_folder(folder_path, names, decoy_password, master_key, options))
+                .map_err(|e| undo_decoy(folder_path, names, &packed, &decoy_targets, options.retry, e))
+        });
+    packed.zeroize();
+    result
+}
+
+// The real files now live only in the reserve; no empty directories are left
+// behind to hint at them.
+fn replace_with_decoy(folder: &Path, files: &[PathBuf], decoy_files: &[PathBuf], decoy_targets: &[PathBuf]) -> Result<(), Error> {
+    for file in files {
+        fs::remove_file(file).map_err(|e| Error::io(&e, format!("Failed to remove '{}': {}", file.display(), e)))?;
+    }
+    remove_empty_dirs(folder, files);
+    for (source, target) in decoy_files.iter().zip(decoy_targets) {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::io(&e, format!("Failed to create '{}': {}", parent.display(), e)))?;
+        }
+        fs::copy(source, target).map_err(|e| Error::io(&e, format!("Failed to copy decoy file '{}': {}", source.display(), e)))?;
+    }
+    Ok(())
+}
+
+// Takes out whatever of the decoy got in, all of it copies, and writes the real
+// files back from `packed`. The reserve goes only once they are all back.
+fn undo_decoy(folder_path: &str, names: &VaultNames, packed: &[u8], decoy_targets: &[PathBuf], retry: RetryPolicy, error: Error) -> Error {
+    let folder = Path::new(folder_path);
+    if let Ok((meta, meta_path)) = read_meta(folder_path, names) {
+        for file in meta.files.iter().filter(|f| f.has_ciphertext()) {
+            let _ = fs::remove_file(file.locked_path_on_disk(folder));
+        }
+        let _ = remove_meta(&meta_path, &meta);
+    }
+    let _ = clean_interrupted_lock(folder_path, names);
+    for target in decoy_targets {
+        let _ = fs::remove_file(target);
+    }
+    remove_empty_dirs(folder, decoy_targets);
+    let restored = unpack_hidden(packed).and_then(|mut files| {
+        let written = files.iter().try_for_each(|(relative, data)| write_plaintext(&folder.join(relative), data, true, retry));
+        files.iter_mut().for_each(|(_, data)| data.zeroize());
+        written
+    });
+    match restored {
+        Ok(()) => {
+            let _ = disk().remove(&reserve_path(folder, names));
+            error
+        }
+        Err(e) => error.context(|m| format!("{}; not every file could be put back ({}), but all of them are in the reserve, which the real password opens", m, e)),
+    }
+}
+
+// Removes the directories under `folder` that held `files` and are now empty,
+// deepest first.
+fn remove_empty_dirs(folder: &Path, files: &[PathBuf]) {
+    let mut dirs: Vec<&Path> = files.iter().flat_map(|f| f.ancestors().skip(1)).filter(|d| d.starts_with(folder) && *d != folder).collect();
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    dirs.dedup();
+    for dir in dirs {
+        let _ = fs::remove_dir(dir);
+    }
+}
+
+// Whether an unlock that failed with `error` should try the reserve: there is
+// one, and the password was wrong for the vault or there is no vault (its decoy
+// is unlocked).
+pub fn reserve_may_open(error: &Error, folder_path: &str, names: &VaultNames) -> bool {
+    reserve_path(Path::new(folder_path), names).is_file()
+        && (error.code == ErrorCode::WrongPassword || !is_locked(folder_path, names))
+}
+
+// Opens the folder's reserve with `password`; None when there is no reserve or
+// the password doesn't open it. The hidden files are restored in place of the
+// decoy vault, which is removed, or copied out to `options.output_dir`.
+pub fn unlock_hidden(folder_path: &str, names: &VaultNames, password: &str, options: &UnlockOptions) -> Result<Option<ProtectedFolder>, Error> {
+    let folder = check_folder_path(folder_path)?;
+    let reserve = reserve_path(folder, names);
+    let Ok(contents) = disk().read(&reserve) else { return Ok(None) };
+    if contents.len() < RESERVE_SALT_LEN {
+        return Ok(None);
+    }
+    let (salt, sealed) = contents.split_at(RESERVE_SALT_LEN);
+    let first = read_meta(folder_path, names).ok().and_then(|(meta, _)| meta.kdf).map(|kdf| kdf.algorithm).unwrap_or_default();
+    let others = [KdfKind::Argon2id, KdfKind::Pbkdf2HmacSha256].into_iter().filter(|kind| *kind != first);
+    let mut opened = None;
+    for kind in std::iter::once(first).chain(others) {
+        let key = reserve_key(kind, password, salt)?;
+        if let Ok(packed) = crypto::decrypt(&key, sealed) {
+            opened = Some(packed);
+            break;
+        }
+    }
+    let Some(mut packed) = opened else { return Ok(None) };
+    let files = unpack_hidden(&packed);
+    packed.zeroize();
+    let mut files = files?;
+    let target = match &options.output_dir {
+        Some(dir) => prepare_output_dir(folder, Path::new(dir))?,
+        None => folder.to_path_buf(),
+    };
+    let mut restored = Vec::with_capacity(files.len());
+    for (relative, data) in &mut files {
+        let path = target.join(&*relative);
+        write_plaintext(&path, data, true, options.retry)?;
+        data.zeroize();
+        restored.push(path.to_string_lossy().to_string());
+    }
+    if options.output_dir.is_none() {
+        if let Ok((meta, meta_path)) = read_meta(folder_path, names) {
+            for file in meta.files.iter().filter(|f| f.has_ciphertext()) {
+                let _ = fs::remove_file(file.locked_path_on_disk(folder));
+            }
+            remove_meta(&meta_path, &meta)?;
+        }
+        disk().remove(&reserve).map_err(|e| Error::io(&e, format!("Failed to remove reserve: {}", e)))?;
+    }
+    Ok(Some(ProtectedFolder { path: folder_path.to_string(), is_locked: options.output_dir.is_some(), file_count: files.len(), has_recovery: false, restored, ..Default::default() }))
+}
+
+pub fn list_locked_files(folder_path: &str, names: &VaultNames) -> Result<Vec<LockedFile>, Error> {
+    let folder = Path::new(folder_path);
+    let (meta, _) = read_meta(folder_path, names)?;
+    let sizes: HashMap<&str, u64> = meta.files.iter()
+        .filter(|f| f.has_ciphertext())
+        .filter_map(|f| {
+            let len = fs::metadata(f.locked_path_on_disk(folder)).ok()?.len();
+            Some((f.relative_path.as_str(), crypto::plaintext_len(len, f.chunk_size.map(|c| c as usize))))
+        })
+        .collect();
+    Ok(meta.files.iter().map(|f| {
+        let source = f.hard_link_to.as_deref().or(f.duplicate_of.as_deref()).unwrap_or(&f.relative_path);
+        let path = f.original_path(folder);
+        LockedFile {
+            path: path.strip_prefix(folder).unwrap_or(&path).to_string_lossy().to_string(),
+            size: sizes.get(source).copied(),
+        }
+    }).collect())
+}
+
+// Decrypts one file of a locked folder into memory, for previews; nothing is
+// written and the folder stays locked. `file` is relative to the folder, as
+// list_locked_files gives it, and `key` is the folder key.
+pub fn decrypt_file_to_bytes(folder_path: &str, names: &VaultNames, file: &str, key: &[u8; 32], retry: RetryPolicy) -> Result<Vec<u8>, Error> {
+    let folder = Path::new(folder_path);
+    let (meta, _) = read_meta_to_change(folder_path, names)?;
+    if !crypto::verify_password(key, &meta.verify_token) {
+        return Err(incorrect_password());
+    }
+    let wanted = folder.join(file);
+    let entry = meta.files.iter().find(|f| f.original_path(folder) == wanted).ok_or_else(|| format!("'{}' is not in the vault", file))?;
+    // Links and copies decrypt from the entry holding their ciphertext; the
+    // chain is bounded in case the metadata loops.
+    let mut source = entry;
+    for _ in 0..meta.files.len() {
+        let Some(next) = source.hard_link_to.as_deref().or(source.duplicate_of.as_deref()) else { break };
+        source = meta.files.iter().find(|f| f.relative_path == next).ok_or_else(|| format!("The ciphertext for '{}' is missing from the metadata", file))?;
+    }
+    if !source.has_ciphertext() {
+        return Err(format!("The ciphertext for '{}' is missing from the metadata", file).into());
+    }
+    let locked_path = source.locked_path_on_disk(folder);
+    let encrypted = vfs::retry(retry, || fs::read(&locked_path)).map_err(|e| Error::io(&e, format!("Failed to read '{}': {}", locked_path.display(), e)))?;
+    let content_key = ContentKey::new(&meta, key);
+    let plaintext = content_key.decrypt(source, &encrypted)?;
+    source.verify_plaintext(key, &plaintext)?;
+    Ok(plaintext)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// A stored timestamp as it is safe to show: slightly ahead of `now` is clamped
+// to it, further ahead is withheld and flagged rather than shown as is.
+fn displayed_timestamp(stamp: Option<u64>, now: u64) -> (Option<u64>, bool) {
+    match stamp {
+        Some(t) if t > now.saturating_add(CLOCK_SKEW_TOLERANCE) => (None, true),
+        Some(t) => (Some(t.min(now)), false),
+        None => (None, false),
+    }
+}
+
+pub fn folder_info(folder_path: &str, names: &VaultNames) -> Result<FolderInfo, Error> {
+    let (meta, _) = read_meta(folder_path, names)?;
+    let (locked_at, locked_in_future) = displayed_timestamp(meta.locked_at, unix_now());
+    Ok(FolderInfo {
+        path: folder_path.to_string(),
+        format_version: meta.format_version,
+        cipher: meta.cipher,
+        kdf: meta.kdf(),
+        salt_len: meta.salt.len(),
+        file_count: meta.files.len(),
+        has_recovery: meta.recovery_key.is_some(),
+        recipients: meta.recipients.into_iter().map(|r| r.recipient).collect(),
+        password_slots: meta.password_slots.len(),
+        machine_bound: meta.machine_binding.is_some(),
+        pattern: meta.pattern,
+        nested: meta.nested,
+        locked_at,
+        locked_in_future,
+        app_version: meta.app_version,
+    })
+}
+
+pub fn has_recovery_key(folder_path: &str, names: &VaultNames) -> bool {
+    if let Ok((meta, _)) = read_meta(folder_path, names) {
+        return meta.recovery_key.is_some();
+    }
+    false
+}
+
+// A folder only counts as locked if its metadata file really is ours.
+pub fn is_locked(folder_path: &str, names: &VaultNames) -> bool {
+    check_folder_path(folder_path).is_ok_and(|folder| looks_like_meta(&folder.join(&names.meta_file)))
+}
+
+pub fn get_locked_file_count(folder_path: &str, names: &VaultNames) -> usize {
+    read_meta(folder_path, names).map(|(meta, _)| meta.files.len()).unwrap_or(0)
+}
+
+// How many files locking the folder with `hidden` would encrypt; 0 for
+// anything that isn't a folder.
+pub fn count_files(folder_path: &str, names: &VaultNames, hidden: &HiddenPolicy) -> usize {
+    match check_folder_path(folder_path) {
+        Ok(folder) => lockable_files(folder, names, hidden, &mut Vec::new(), &mut Vec::new()).len(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmp() -> tempfile::TempDir {
+        tempfile::tempdir().unwrap()
+    }
+
+    fn lock(path: &str, password: &str) -> Result<ProtectedFolder, Error> {
+        lock_folder(path, &VaultNames::default(), password, None, &LockOptions::default())
+    }
+
+    fn unlock(path: &str, password: &str) -> Result<ProtectedFolder, Error> {
+        unlock_folder(path, &VaultNames::default(), password, &UnlockOptions::default(), &mut |_, _| {})
+    }
+
+    fn meta_in(dir: &Path) -> FolderMeta {
+        read_meta(dir.to_str().unwrap(), &VaultNames::default()).unwrap().0
+    }
+
+    fn save_meta_in(dir: &Path, meta: &FolderMeta) {
+        fs::write(dir.join(META_FILE), serde_json::to_string_pretty(meta).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn machine_binding_refuses_another_machine() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        let options = LockOptions { bind_to_machine: true, cipher: CipherKind::Aes256GcmSiv, ..Default::default() };
+        lock_folder(p, &VaultNames::default(), "pw", None, &options).unwrap();
+        let bound = meta_in(d.path());
+        assert!(bound.machine_binding.is_some());
+
+        // The same metadata as written on a machine with another ID.
+        let mut elsewhere = bound.clone();
+        elsewhere.machine_binding = Some(crypto::machine_fingerprint(&bound.salt, "some-other-machine"));
+        save_meta_in(d.path(), &elsewhere);
+        let err = unlock(p, "pw").unwrap_err();
+        assert!(err.message.contains("bound to a different machine"), "{}", err);
+        assert!(!d.path().join("a.txt").exists());
+
+        save_meta_in(d.path(), &bound);
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn diagnose_classifies_each_state() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        let state = || diagnose_folder(p, &names).unwrap();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        fs::write(d.path().join("b.txt"), b"world").unwrap();
+        assert!(matches!(state().state, FolderState::CleanPlaintext));
+        lock(p, "pw").unwrap();
+        assert!(matches!(state().state, FolderState::CleanLocked));
+
+        // Plaintext back beside its ciphertext, as after an interrupted unlock.
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        let diagnosis = state();
+        assert!(matches!(diagnosis.state, FolderState::PartiallyLocked));
+        assert_eq!(diagnosis.inconsistent_files, vec!["a.txt"]);
+        fs::remove_file(d.path().join("a.txt")).unwrap();
+
+        // Ciphertext the metadata lists has gone.
+        fs::rename(d.path().join("b.txt.locked"), d.path().join("b.bak")).unwrap();
+        assert_eq!(state().inconsistent_files, vec!["b.txt"]);
+        fs::rename(d.path().join("b.bak"), d.path().join("b.txt.locked")).unwrap();
+
+        // Half-written ciphertext beside the plaintext it came from.
+        fs::write(d.path().join("c.txt"), b"new").unwrap();
+        fs::write(temp_path(&d.path().join("c.txt.locked")), b"partial").unwrap();
+        assert_eq!(state().leftover_temp_files, vec!["c.txt.locked.tmp"]);
+        clean_interrupted_lock(p, &names).unwrap();
+        assert!(state().leftover_temp_files.is_empty());
+        fs::remove_file(d.path().join("c.txt")).unwrap();
+
+        fs::remove_file(d.path().join(META_FILE)).unwrap();
+        let diagnosis = state();
+        assert!(matches!(diagnosis.state, FolderState::OrphanedCiphertext));
+        assert_eq!(diagnosis.inconsistent_files.len(), 2);
+    }
+
+    #[test]
+    fn change_password_swaps_the_key() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        lock(p, "old").unwrap();
+        rekey_folder(p, &VaultNames::default(), "old", "new", None).unwrap();
+        assert!(unlock(p, "old").is_err());
+        unlock(p, "new").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read_dir(d.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn single_file_round_trip() {
+        let d = tmp();
+        let f = d.path().join("a.txt");
+        let fp = f.to_str().unwrap();
+        fs::write(&f, b"hello").unwrap();
+        let master_key = crypto::derive_key("master", &crypto::generate_salt()).unwrap();
+        lock_file(fp, "pw", Some(&master_key), &LockOptions::default()).unwrap();
+        assert!(!f.exists());
+        assert!(d.path().join("a.txt.locked").exists());
+        assert!(unlock_file(fp, "wrong", &UnlockOptions::default()).is_err());
+        unlock_file(fp, "pw", &UnlockOptions::default()).unwrap();
+        assert_eq!(fs::read(&f).unwrap(), b"hello");
+        assert_eq!(fs::read_dir(d.path()).unwrap().count(), 1);
+
+        // The recovery key opens it without the password.
+        lock_file(fp, "pw", Some(&master_key), &LockOptions::default()).unwrap();
+        unlock_file_with_master_key(fp, &master_key, &UnlockOptions::default()).unwrap();
+        assert_eq!(fs::read(&f).unwrap(), b"hello");
+        assert_eq!(fs::read_dir(d.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn unlock_keeps_a_conflicting_file() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        lock(p, "pw").unwrap();
+        fs::write(d.path().join("a.txt"), b"new").unwrap();
+        let refuse = UnlockOptions { on_conflict: CollisionPolicy::Error, ..Default::default() };
+        assert!(unlock_folder(p, &VaultNames::default(), "pw", &refuse, &mut |_, _| {}).is_err());
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"new");
+
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"hello");
+        let names: Vec<String> = fs::read_dir(d.path()).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        let conflict = names.iter().find(|n| n.starts_with("a.txt.conflict-")).unwrap();
+        assert_eq!(fs::read(d.path().join(conflict)).unwrap(), b"new");
+    }
+
+    #[test]
+    fn custom_vault_names_round_trip() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        let names = VaultNames { locked_ext: ".enc".into(), meta_file: ".vault".into() };
+        names.validate().unwrap();
+        lock_folder(p, &names, "pw", None, &LockOptions::default()).unwrap();
+        assert!(d.path().join("a.txt.enc").exists() && d.path().join(".vault").exists());
+        assert!(is_locked(p, &names) && !is_locked(p, &VaultNames::default()));
+        unlock_folder(p, &names, "pw", &UnlockOptions::default(), &mut |_, _| {}).unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read_dir(d.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn files_named_like_the_metadata_are_still_locked() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames { locked_ext: ".enc".into(), meta_file: ".vault".into() };
+        let user_files = [".securelock-notes.txt", ".securelock.bak", ".vault-old", "x.securelock"];
+        for name in user_files {
+            fs::write(d.path().join(name), name).unwrap();
+        }
+        for name in [".vault", ".vault.tmp", ".vault.manifest", ".vault.manifest.tmp", ".vault.reserve", ".securelock", ".a.txt.securelock"] {
+            assert!(is_vault_metadata(&d.path().join(name), &names), "{}", name);
+        }
+        lock_folder(p, &names, "pw", None, &LockOptions { hidden: Some(HiddenPolicy::None), ..Default::default() }).unwrap();
+        let mut locked: Vec<String> = parse_meta(&d.path().join(".vault")).unwrap().files.iter().map(|f| f.relative_path.clone()).collect();
+        locked.sort();
+        assert_eq!(locked, user_files);
+        unlock_folder(p, &names, "pw", &UnlockOptions::default(), &mut |_, _| {}).unwrap();
+        for name in user_files {
+            assert_eq!(fs::read(d.path().join(name)).unwrap(), name.as_bytes());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_round_trip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let sub = d.path().join(OsStr::from_bytes(b"d\xff%ir"));
+        let name = OsStr::from_bytes(b"f\xfe\x80 %41.txt");
+        fs::create_dir(&sub).unwrap();
+        fs::write(sub.join(name), b"hi").unwrap();
+        fs::write(d.path().join("ok.txt"), b"ok").unwrap();
+        lock(p, "pw").unwrap();
+        assert!(!sub.join(name).exists());
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(sub.join(name)).unwrap(), b"hi");
+        assert_eq!(fs::read_dir(&sub).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn verbatim_prefixes_are_stripped() {
+        assert_eq!(normalize_path(r"\\?\C:\x"), r"C:\x");
+        assert_eq!(normalize_path(r"\\?\UNC\srv\s"), r"\\srv\s");
+    }
+
+    #[test]
+    fn lock_matching_leaves_other_files_plain() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("a.pem"), b"key").unwrap();
+        fs::write(d.path().join("sub/b.pem"), b"key2").unwrap();
+        fs::write(d.path().join("notes.txt"), b"plain").unwrap();
+        assert!(lock_matching(p, &names, "*.key", "pw", None, &LockOptions::default()).is_err());
+        let locked = lock_matching(p, &names, "*.pem", "pw", None, &LockOptions::default()).unwrap();
+        assert_eq!(locked.file_count, 2);
+        assert!(!d.path().join("a.pem").exists() && !d.path().join("sub/b.pem").exists());
+        assert_eq!(fs::read(d.path().join("notes.txt")).unwrap(), b"plain");
+        assert_eq!(meta_in(d.path()).pattern.as_deref(), Some("*.pem"));
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.pem")).unwrap(), b"key");
+        assert_eq!(fs::read(d.path().join("sub/b.pem")).unwrap(), b"key2");
+        assert_eq!(fs::read(d.path().join("notes.txt")).unwrap(), b"plain");
+    }
+
+    #[test]
+    fn plaintext_hash_catches_a_bad_decrypt() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        lock(p, "pw").unwrap();
+        let mut meta = meta_in(d.path());
+        let good = meta.files[0].plaintext_hash.clone();
+        assert_eq!(good.as_ref().unwrap().len(), 32);
+        meta.files[0].plaintext_hash.as_mut().unwrap()[0] ^= 1;
+        save_meta_in(d.path(), &meta);
+        let result = unlock(p, "pw").unwrap();
+        assert!(result.is_locked && result.failed[0].error.contains("Integrity check failed"), "{:?}", result.failed);
+        assert!(!d.path().join("a.txt").exists());
+
+        // Hashes are keyed, so a password change recomputes them.
+        meta.files[0].plaintext_hash = good;
+        save_meta_in(d.path(), &meta);
+        rekey_folder(p, &VaultNames::default(), "pw", "pw2", None).unwrap();
+        unlock(p, "pw2").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn unlock_to_output_dir_leaves_folder_locked() {
+        let d = tmp();
+        let out = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("sub/b.txt"), b"world").unwrap();
+        lock(p, "pw").unwrap();
+        let inside = UnlockOptions { output_dir: Some(d.path().join("x").to_str().unwrap().into()), ..Default::default() };
+        assert!(unlock_folder(p, &names, "pw", &inside, &mut |_, _| {}).is_err());
+
+        let target = out.path().join("copy");
+        let options = UnlockOptions { output_dir: Some(target.to_str().unwrap().into()), ..Default::default() };
+        assert!(unlock_folder(p, &names, "pw", &options, &mut |_, _| {}).unwrap().is_locked);
+        assert_eq!(fs::read(target.join("sub/b.txt")).unwrap(), b"world");
+        assert!(is_locked(p, &names));
+        assert!(d.path().join("sub/b.txt.locked").exists() && !d.path().join("sub/b.txt").exists());
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("sub/b.txt")).unwrap(), b"world");
+    }
+
+    #[test]
+    fn folder_info_describes_the_lock() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        fs::write(d.path().join("b.txt"), b"hello").unwrap();
+        assert!(folder_info(p, &names).is_err());
+        let master_key = crypto::derive_key("master", &crypto::generate_salt()).unwrap();
+        let options = LockOptions { cipher: CipherKind::Aes256GcmSiv, ..Default::default() };
+        lock_folder(p, &names, "pw", Some(&master_key), &options).unwrap();
+        let info = folder_info(p, &names).unwrap();
+        assert_eq!(info.format_version, FORMAT_VERSION);
+        assert_eq!(info.cipher, CipherKind::Aes256GcmSiv);
+        assert_eq!(info.kdf, crypto::kdf_params());
+        assert_eq!(info.salt_len, 32);
+        assert_eq!(info.file_count, 2);
+        assert!(info.has_recovery && !info.machine_bound && info.pattern.is_none());
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(!json.contains("verify") && !json.contains("recovery_key"));
+    }
+
+    #[test]
+    fn interrupted_meta_write_keeps_the_old_metadata() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        lock(p, "pw").unwrap();
+        let meta_path = d.path().join(META_FILE);
+        let temp = d.path().join(format!("{}.tmp", META_FILE));
+        let before = fs::read(&meta_path).unwrap();
+        assert!(!temp.exists());
+
+        // A writer that dies before the rename: the temp file can't be written.
+        fs::create_dir(&temp).unwrap();
+        assert!(write_atomic(&meta_path, b"{truncated").is_err());
+        assert_eq!(fs::read(&meta_path).unwrap(), before);
+        fs::remove_dir(&temp).unwrap();
+
+        // A stale temp file from an earlier crash is replaced.
+        fs::write(&temp, b"{trunc").unwrap();
+        write_atomic(&meta_path, &before).unwrap();
+        assert!(!temp.exists());
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn interrupted_unlock_resumes() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        for i in 0..5 {
+            fs::write(d.path().join(format!("f{}.txt", i)), format!("data{}", i)).unwrap();
+        }
+        lock(p, "pw").unwrap();
+        let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            unlock_folder(p, &VaultNames::default(), "pw", &UnlockOptions::default(), &mut |done, _| if done == 2 { panic!("power cut") })
+        }));
+        assert!(interrupted.is_err());
+        assert!(is_locked(p, &VaultNames::default()));
+
+        // Files already restored aren't decrypted again, so nothing collides.
+        let refuse = UnlockOptions { on_conflict: CollisionPolicy::Error, ..Default::default() };
+        let mut seen = Vec::new();
+        let result = unlock_folder(p, &VaultNames::default(), "pw", &refuse, &mut |done, total| seen.push((done, total))).unwrap();
+        assert_eq!(result.file_count, 5);
+        assert_eq!(seen, (1..=5).map(|i| (i, 5)).collect::<Vec<_>>());
+        for i in 0..5 {
+            assert_eq!(fs::read_to_string(d.path().join(format!("f{}.txt", i))).unwrap(), format!("data{}", i));
+        }
+        assert_eq!(fs::read_dir(d.path()).unwrap().count(), 5);
+    }
+
+    #[test]
+    fn nothing_to_lock_is_refused() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let err = lock(p, "pw").unwrap_err();
+        assert!(err.message.contains("Nothing to lock"), "{}", err);
+        fs::write(d.path().join(".hidden"), b"x").unwrap();
+        assert!(lock(p, "pw").is_err());
+        assert!(!is_locked(p, &VaultNames::default()));
+        assert_eq!(fs::read(d.path().join(".hidden")).unwrap(), b"x");
+    }
+
+    #[test]
+    fn recovery_blob_only_opens_its_own_folder() {
+        let master_key = crypto::derive_key("master", &crypto::generate_salt()).unwrap();
+        let names = VaultNames::default();
+        let (a, b) = (tmp(), tmp());
+        for d in [&a, &b] {
+            fs::write(d.path().join("x.txt"), b"x").unwrap();
+            lock_folder(d.path().to_str().unwrap(), &names, "pw", Some(&master_key), &LockOptions::default()).unwrap();
+        }
+        let mut meta_b = meta_in(b.path());
+        meta_b.recovery_key = meta_in(a.path()).recovery_key;
+        save_meta_in(b.path(), &meta_b);
+        assert!(unlock_folder_with_master_key(b.path().to_str().unwrap(), &names, &master_key, &UnlockOptions::default(), &mut |_, _| {}).is_err());
+
+        // Metadata from before versioning used an unbound blob and still recovers.
+        let mut meta_a = meta_in(a.path());
+        let password_key = crypto::derive_key("pw", &meta_a.salt).unwrap();
+        meta_a.format_version = 0;
+        meta_a.files[0].plaintext_hash = None;
+        meta_a.recovery_key = Some(crypto::wrap_key(&master_key, &password_key, b"").unwrap());
+        fs::write(a.path().join("x.txt.locked"), crypto::encrypt(&password_key, b"x").unwrap()).unwrap();
+        save_meta_in(a.path(), &meta_a);
+        unlock_folder_with_master_key(a.path().to_str().unwrap(), &names, &master_key, &UnlockOptions::default(), &mut |_, _| {}).unwrap();
+        assert_eq!(fs::read(a.path().join("x.txt")).unwrap(), b"x");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_are_restored() {
+        use std::os::unix::fs::MetadataExt;
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("a.bin"), b"shared").unwrap();
+        fs::hard_link(d.path().join("a.bin"), d.path().join("sub/b.bin")).unwrap();
+        fs::write(d.path().join("c.txt"), b"alone").unwrap();
+        lock(p, "pw").unwrap();
+        let locked = WalkDir::new(d.path()).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_name().to_string_lossy().ends_with(".locked")).count();
+        assert_eq!(locked, 2);
+        assert_eq!(diagnose_folder(p, &VaultNames::default()).unwrap().state, FolderState::CleanLocked);
+
+        // A copy-out can't link back into the folder, so it gets the content.
+        rekey_folder(p, &VaultNames::default(), "pw", "pw2", None).unwrap();
+        let out = tmp();
+        let options = UnlockOptions { output_dir: Some(out.path().to_str().unwrap().into()), ..Default::default() };
+        unlock_folder(p, &VaultNames::default(), "pw2", &options, &mut |_, _| {}).unwrap();
+        assert_eq!(fs::read(out.path().join("sub/b.bin")).unwrap(), b"shared");
+
+        unlock(p, "pw2").unwrap();
+        let (a, b) = (fs::metadata(d.path().join("a.bin")).unwrap(), fs::metadata(d.path().join("sub/b.bin")).unwrap());
+        assert_eq!(a.ino(), b.ino());
+        assert_eq!(a.nlink(), 2);
+        assert_eq!(fs::read(d.path().join("sub/b.bin")).unwrap(), b"shared");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn system_and_home_directories_are_refused() {
+        assert!(check_lock_target(Path::new("/")).unwrap_err().message.contains("root"));
+        let home = std::env::var("HOME").unwrap();
+        assert!(check_lock_target(Path::new(&home)).unwrap_err().message.contains("home"));
+        assert!(check_lock_target(Path::new("/usr/bin")).is_err());
+        assert!(check_lock_target(Path::new("/etc")).is_err());
+        check_lock_target(tmp().path()).unwrap();
+        let err = lock("/", "pw").unwrap_err();
+        assert!(err.message.contains("Refusing"), "{}", err);
+    }
+
+    #[test]
+    fn rebuild_metadata_from_ciphertext() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("a.txt"), b"alpha").unwrap();
+        fs::write(d.path().join("sub/b.txt"), b"beta").unwrap();
+        lock_folder(p, &names, "pw", None, &LockOptions { cipher: CipherKind::Aes256GcmSiv, ..Default::default() }).unwrap();
+        assert!(rebuild_metadata(p, &names, "pw").unwrap_err().message.contains("intact"));
+        // A stray file that doesn't decrypt is left out.
+        fs::write(d.path().join("junk.locked"), b"not ciphertext at all, really").unwrap();
+        // Truncate the metadata just after the salt.
+        let meta = fs::read_to_string(d.path().join(META_FILE)).unwrap();
+        let cut = meta.find("verify_token").unwrap();
+        fs::write(d.path().join(META_FILE), &meta[..cut]).unwrap();
+        assert!(rebuild_metadata(p, &names, "wrong").is_err());
+        let rebuilt = rebuild_metadata(p, &names, "pw").unwrap();
+        assert_eq!(rebuilt.file_count, 2);
+        assert_eq!(rebuilt.skipped, vec!["junk.locked".to_string()]);
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"alpha");
+        assert_eq!(fs::read(d.path().join("sub/b.txt")).unwrap(), b"beta");
+
+        // With the metadata gone entirely there is no salt to derive the key from.
+        let e = tmp();
+        let ep = e.path().to_str().unwrap();
+        fs::write(e.path().join("x"), b"x").unwrap();
+        lock(ep, "pw").unwrap();
+        fs::remove_file(e.path().join(META_FILE)).unwrap();
+        assert!(rebuild_metadata(ep, &names, "pw").unwrap_err().message.contains("salt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_files_follow_the_policy() {
+        use std::os::unix::fs::PermissionsExt;
+        for policy in [UnreadablePolicy::Abort, UnreadablePolicy::Skip] {
+            let d = tmp();
+            let p = d.path().to_str().unwrap();
+            fs::write(d.path().join("a.txt"), b"a").unwrap();
+            fs::write(d.path().join("secret.txt"), b"s").unwrap();
+            fs::set_permissions(d.path().join("secret.txt"), fs::Permissions::from_mode(0o000)).unwrap();
+            if fs::read(d.path().join("secret.txt")).is_ok() {
+                // Running as root, where permissions aren't enforced.
+                return;
+            }
+            let options = LockOptions { on_unreadable: policy, ..Default::default() };
+            let result = lock_folder(p, &VaultNames::default(), "pw", None, &options);
+            match policy {
+                UnreadablePolicy::Abort => {
+                    assert!(result.unwrap_err().message.contains("secret.txt"));
+                    assert!(!is_locked(p, &VaultNames::default()));
+                    assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"a");
+                }
+                UnreadablePolicy::Skip => {
+                    let locked = result.unwrap();
+                    assert_eq!((locked.file_count, locked.skipped), (1, vec!["secret.txt".to_string()]));
+                    assert!(d.path().join("secret.txt").exists());
+                    unlock(p, "pw").unwrap();
+                    assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"a");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn aborted_lock_restores_finished_files() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names: Vec<String> = (0..8).map(|i| format!("{}.txt", i)).collect();
+        for name in &names {
+            fs::write(d.path().join(name), name.as_bytes()).unwrap();
+        }
+        // The ciphertext of 5.txt can't be renamed into place, after the
+        // other workers have encrypted (and removed) some of the rest.
+        fs::create_dir(d.path().join("5.txt.locked")).unwrap();
+        let e = lock(p, "pw").unwrap_err();
+        assert!(e.message.contains("5.txt.locked"), "{}", e);
+        assert!(!is_locked(p, &VaultNames::default()));
+        for name in &names {
+            assert_eq!(fs::read(d.path().join(name)).unwrap(), name.as_bytes(), "{}", name);
+            assert!(!d.path().join(format!("{}.locked", name)).is_file(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn nested_vaults_are_left_intact() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let child = d.path().join("child");
+        let single = d.path().join("single.txt");
+        fs::create_dir(&child).unwrap();
+        fs::write(child.join("c.txt"), b"child").unwrap();
+        fs::write(&single, b"single").unwrap();
+        fs::write(d.path().join("p.txt"), b"parent").unwrap();
+        lock(child.to_str().unwrap(), "child-pw").unwrap();
+        lock_file(single.to_str().unwrap(), "file-pw", None, &LockOptions::default()).unwrap();
+        let child_meta = fs::read(child.join(META_FILE)).unwrap();
+        let child_cipher = fs::read(child.join("c.txt.locked")).unwrap();
+
+        assert_eq!(lock(p, "parent-pw").unwrap().file_count, 1);
+        let mut nested = folder_info(p, &VaultNames::default()).unwrap().nested;
+        nested.sort();
+        assert_eq!(nested, vec!["child".to_string(), "single.txt.locked".to_string()]);
+        assert_eq!(fs::read(child.join(META_FILE)).unwrap(), child_meta);
+        assert_eq!(fs::read(child.join("c.txt.locked")).unwrap(), child_cipher);
+
+        unlock(p, "parent-pw").unwrap();
+        unlock(child.to_str().unwrap(), "child-pw").unwrap();
+        unlock_file(single.to_str().unwrap(), "file-pw", &UnlockOptions::default()).unwrap();
+        assert_eq!(fs::read(child.join("c.txt")).unwrap(), b"child");
+        assert_eq!(fs::read(&single).unwrap(), b"single");
+        assert_eq!(fs::read(d.path().join("p.txt")).unwrap(), b"parent");
+    }
+
+    #[test]
+    fn files_are_encrypted_under_their_own_subkeys() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        fs::write(d.path().join("a.txt"), b"same").unwrap();
+        fs::write(d.path().join("b.txt"), b"sane").unwrap();
+        lock(p, "pw").unwrap();
+        let key = crypto::derive_key("pw", &meta_in(d.path()).salt).unwrap();
+        let a = fs::read(d.path().join("a.txt.locked")).unwrap();
+        assert!(crypto::decrypt(&key, &a).is_err());
+        let (key_a, key_b) = (crypto::derive_file_key(&key, b"a.txt").unwrap(), crypto::derive_file_key(&key, b"b.txt").unwrap());
+        assert_ne!(*key_a, *key_b);
+        assert_eq!(crypto::decrypt(&key_a, &a).unwrap(), b"same");
+        assert!(crypto::decrypt(&key_b, &a).is_err());
+        rekey_folder(p, &VaultNames::default(), "pw", "pw2", None).unwrap();
+        unlock(p, "pw2").unwrap();
+        assert_eq!(fs::read(d.path().join("b.txt")).unwrap(), b"sane");
+
+        // Version 2 vaults used the folder key directly and still unlock.
+        let salt = crypto::generate_salt();
+        let key = crypto::derive_key("pw", &salt).unwrap();
+        fs::write(d.path().join("a.txt.locked"), crypto::encrypt(&key, b"old").unwrap()).unwrap();
+        fs::remove_file(d.path().join("a.txt")).unwrap();
+        let legacy = serde_json::json!({
+            "format_version": 2, "salt": salt.to_vec(), "verify_token": crypto::create_verify_token(&key).unwrap(),
+            "files": [{"original_name": "a.txt", "locked_name": "a.txt.locked", "relative_path": "a.txt"}],
+        });
+        fs::write(d.path().join(META_FILE), legacy.to_string()).unwrap();
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn file_changing_during_lock_is_caught() {
+        use std::io::Write;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let busy = d.path().join("busy.log");
+        fs::write(&busy, vec![7u8; 32 << 20]).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (busy, stop) = (busy.clone(), stop.clone());
+            std::thread::spawn(move || {
+                let mut file = fs::OpenOptions::new().append(true).open(&busy).unwrap();
+                while !stop.load(Ordering::Relaxed) {
+                    file.write_all(b"x").unwrap();
+                    std::thread::sleep(std::time::Duration::from_micros(200));
+                }
+            })
+        };
+        let err = lock(p, "pw").unwrap_err();
+        stop.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        assert!(err.message.contains("kept changing"), "{}", err);
+        assert!(!d.path().join("busy.log.locked").exists());
+        assert!(fs::metadata(&busy).unwrap().len() > 32 << 20);
+    }
+
+    #[test]
+    fn dry_run_unlock_leaves_the_folder_locked() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        fs::write(d.path().join("a.txt"), b"a").unwrap();
+        lock(p, "pw").unwrap();
+        let snapshot = || {
+            let mut names: Vec<_> = fs::read_dir(d.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+            names.sort();
+            (names, fs::read(d.path().join(META_FILE)).unwrap())
+        };
+        let before = snapshot();
+        unlock_dry_run(p, &VaultNames::default(), "pw").unwrap();
+        assert_eq!(snapshot(), before);
+        assert!(unlock_dry_run(p, &VaultNames::default(), "nope").unwrap_err().message.contains("Incorrect"));
+        assert_eq!(snapshot(), before);
+    }
+
+    #[test]
+    fn lock_within_a_small_memory_budget() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        for i in 0..12 {
+            fs::write(d.path().join(format!("f{}", i)), vec![i as u8; 4096]).unwrap();
+        }
+        let options = LockOptions { memory_budget: Some(5000), ..Default::default() };
+        assert_eq!(lock_folder(p, &VaultNames::default(), "pw", None, &options).unwrap().file_count, 12);
+        unlock(p, "pw").unwrap();
+        for i in 0..12 {
+            assert_eq!(fs::read(d.path().join(format!("f{}", i))).unwrap(), vec![i as u8; 4096]);
+        }
+    }
+
+    #[test]
+    fn identical_files_share_one_ciphertext() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        for name in ["a.png", "b.png", "sub/c.png"] {
+            fs::write(d.path().join(name), b"same bytes").unwrap();
+        }
+        fs::write(d.path().join("other"), b"different").unwrap();
+        lock(p, "pw").unwrap();
+        let blobs = WalkDir::new(d.path()).into_iter().flatten().filter(|e| e.file_name().to_string_lossy().ends_with(".locked")).count();
+        assert_eq!(blobs, 2);
+        assert_eq!(meta_in(d.path()).files.iter().filter(|f| f.duplicate_of.is_some()).count(), 2);
+        assert_eq!(get_locked_file_count(p, &names), 4);
+        assert_eq!(diagnose_folder(p, &names).unwrap().state, FolderState::CleanLocked);
+
+        let out = tmp();
+        let options = UnlockOptions { output_dir: Some(out.path().join("x").to_str().unwrap().into()), ..Default::default() };
+        unlock_folder(p, &names, "pw", &options, &mut |_, _| {}).unwrap();
+        assert_eq!(fs::read(out.path().join("x/sub/c.png")).unwrap(), b"same bytes");
+
+        rekey_folder(p, &names, "pw", "pw2", None).unwrap();
+        unlock(p, "pw2").unwrap();
+        for name in ["a.png", "b.png", "sub/c.png"] {
+            assert_eq!(fs::read(d.path().join(name)).unwrap(), b"same bytes", "{}", name);
+        }
+        assert_eq!(fs::read(d.path().join("other")).unwrap(), b"different");
+        assert!(!d.path().join(META_FILE).exists());
+    }
+
+    #[test]
+    fn hidden_policies_select_the_right_files() {
+        let names = VaultNames::default();
+        let setup = || {
+            let d = tmp();
+            fs::create_dir(d.path().join("logs")).unwrap();
+            for name in ["a.txt", ".env", "logs/x.log", "b.bak"] {
+                fs::write(d.path().join(name), name).unwrap();
+            }
+            d
+        };
+        let locked = |policy: Option<HiddenPolicy>| {
+            let d = setup();
+            let p = d.path().to_str().unwrap();
+            let counted = count_files(p, &names, &policy.clone().unwrap_or_default());
+            lock_folder(p, &names, "pw", None, &LockOptions { hidden: policy, ..Default::default() }).unwrap();
+            let mut files: Vec<String> = meta_in(d.path()).files.iter().map(|f| f.relative_path.clone()).collect();
+            files.sort();
+            assert_eq!(counted, files.len());
+            unlock(p, "pw").unwrap();
+            files
+        };
+        assert_eq!(locked(None), ["a.txt", "b.bak", "logs/x.log"]);
+        assert_eq!(locked(Some(HiddenPolicy::Unix)), ["a.txt", "b.bak", "logs/x.log"]);
+        assert_eq!(locked(Some(HiddenPolicy::None)), [".env", "a.txt", "b.bak", "logs/x.log"]);
+        #[cfg(not(windows))]
+        assert_eq!(locked(Some(HiddenPolicy::WindowsAttribute)), [".env", "a.txt", "b.bak", "logs/x.log"]);
+        assert_eq!(locked(Some(HiddenPolicy::Exclude(vec!["*.bak".into(), "logs/*".into()]))), [".env", "a.txt"]);
+        assert!(HiddenPolicy::Exclude(vec!["[".into()]).validate().is_err());
+
+        // Vault metadata is never picked up, even with nothing hidden.
+        let d = setup();
+        fs::write(d.path().join("solo"), b"s").unwrap();
+        lock_file(d.path().join("solo").to_str().unwrap(), "pw", None, &LockOptions::default()).unwrap();
+        assert_eq!(count_files(d.path().to_str().unwrap(), &names, &HiddenPolicy::None), 4);
+    }
+
+    #[test]
+    fn nfc_and_nfd_names_resolve_to_the_same_file() {
+        let (nfc, nfd) = ("caf\u{e9}", "cafe\u{301}");
+        let names = VaultNames::default();
+        // Locked under one form, synced to a machine that renamed everything to the other.
+        for (locked_as, synced_as) in [(nfc, nfd), (nfd, nfc)] {
+            let d = tmp();
+            let p = d.path().to_str().unwrap();
+            fs::create_dir(d.path().join(locked_as)).unwrap();
+            fs::write(d.path().join(locked_as).join(format!("{}.txt", locked_as)), b"menu").unwrap();
+            fs::write(d.path().join(format!("{}.md", locked_as)), b"notes").unwrap();
+            lock(p, "pw").unwrap();
+            fs::rename(d.path().join(locked_as).join(format!("{}.txt.locked", locked_as)), d.path().join(locked_as).join(format!("{}.txt.locked", synced_as))).unwrap();
+            fs::rename(d.path().join(locked_as), d.path().join(synced_as)).unwrap();
+            fs::rename(d.path().join(format!("{}.md.locked", locked_as)), d.path().join(format!("{}.md.locked", synced_as))).unwrap();
+
+            assert_eq!(diagnose_folder(p, &names).unwrap().state, FolderState::CleanLocked);
+            rekey_folder(p, &names, "pw", "pw2", None).unwrap();
+            unlock(p, "pw2").unwrap();
+            assert_eq!(fs::read(d.path().join(synced_as).join(format!("{}.txt", locked_as))).unwrap(), b"menu");
+            assert_eq!(fs::read(d.path().join(format!("{}.md", locked_as))).unwrap(), b"notes");
+            assert!(!d.path().join(locked_as).exists());
+            let leftovers = WalkDir::new(d.path()).into_iter().flatten().filter(|e| e.file_name().to_string_lossy().ends_with(".locked")).count();
+            assert_eq!(leftovers, 0);
+        }
+    }
+
+    #[test]
+    fn split_metadata_round_trip() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a"), b"a").unwrap();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("sub/b"), b"b").unwrap();
+        let master_key = crypto::derive_key("master", &[1u8; 32]).unwrap();
+        lock_folder(p, &names, "pw", Some(&master_key), &LockOptions { split_metadata: true, ..Default::default() }).unwrap();
+        let header = fs::read_to_string(d.path().join(META_FILE)).unwrap();
+        assert!(!header.contains("\"files\"") && header.contains("split_manifest"), "{}", header);
+        assert!(d.path().join(".securelock.manifest").exists());
+        assert_eq!(get_locked_file_count(p, &names), 2);
+        assert!(check_folder_password(p, &names, "pw").unwrap());
+
+        // Lost manifest: refused with a pointer to rebuild, and rebuild keeps the recovery key.
+        fs::remove_file(d.path().join(".securelock.manifest")).unwrap();
+        assert!(unlock(p, "pw").unwrap_err().message.contains("rebuild"));
+        assert!(rebuild_metadata(p, &names, "wrong").is_err());
+        assert_eq!(rebuild_metadata(p, &names, "pw").unwrap().file_count, 2);
+        assert!(d.path().join(".securelock.manifest").exists());
+        assert!(has_recovery_key(p, &names));
+        unlock_folder_with_master_key(p, &names, &master_key, &UnlockOptions::default(), &mut |_, _| {}).unwrap();
+        assert_eq!(fs::read(d.path().join("sub/b")).unwrap(), b"b");
+        assert!(!d.path().join(".securelock.manifest").exists());
+        assert!(!d.path().join(META_FILE).exists());
+    }
+
+    #[test]
+    fn list_locked_files_without_password() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("sub/b.txt"), vec![7u8; 3000]).unwrap();
+        fs::write(d.path().join("sub/copy.txt"), b"hello").unwrap();
+        lock(p, "pw").unwrap();
+        let mut listed: Vec<(String, Option<u64>)> = list_locked_files(p, &names).unwrap().into_iter().map(|f| (f.path, f.size)).collect();
+        listed.sort();
+        let sep = std::path::MAIN_SEPARATOR;
+        assert_eq!(listed, vec![
+            ("a.txt".to_string(), Some(5)),
+            (format!("sub{}b.txt", sep), Some(3000)),
+            (format!("sub{}copy.txt", sep), Some(5)),
+        ]);
+        // A missing ciphertext leaves the size unknown; a subfolder is not a vault.
+        fs::remove_file(d.path().join("sub/b.txt.locked")).unwrap();
+        let b = list_locked_files(p, &names).unwrap().into_iter().find(|f| f.path.ends_with("b.txt")).unwrap();
+        assert_eq!(b.size, None);
+        assert!(list_locked_files(d.path().join("sub").to_str().unwrap(), &names).is_err());
+    }
+
+    #[test]
+    fn streamed_and_in_memory_files_round_trip() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        let chunk = crypto::STREAM_CHUNK_SIZE;
+        let pattern = |n: usize, seed: u8| (0..n).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect::<Vec<u8>>();
+        let files = [("small", pattern(10, 1)), ("edge", pattern(chunk + 1, 2)), ("exact", pattern(2 * chunk, 3)), ("big", pattern(3 * chunk + 5, 4)), ("big-copy", pattern(3 * chunk + 5, 4))];
+        for (name, data) in &files {
+            fs::write(d.path().join(name), data).unwrap();
+        }
+        let opts = LockOptions { stream_threshold: Some(chunk as u64), ..Default::default() };
+        lock_folder(p, &names, "pw", None, &opts).unwrap();
+        let meta: serde_json::Value = serde_json::from_str(&fs::read_to_string(d.path().join(META_FILE)).unwrap()).unwrap();
+        let chunked = meta["files"].as_array().unwrap().iter().filter(|f| f.get("chunk_size").is_some()).count();
+        assert_eq!(chunked, 4, "{:?}", meta["files"]);
+        let mut sizes: Vec<(String, Option<u64>)> = list_locked_files(p, &names).unwrap().into_iter().map(|f| (f.path, f.size)).collect();
+        sizes.sort();
+        let mut expected: Vec<(String, Option<u64>)> = files.iter().map(|(n, data)| (n.to_string(), Some(data.len() as u64))).collect();
+        expected.sort();
+        assert_eq!(sizes, expected);
+
+        // Rekeying keeps the layout.
+        rekey_folder(p, &names, "pw", "pw2", None).unwrap();
+        unlock(p, "pw2").unwrap();
+        for (name, data) in &files {
+            assert_eq!(&fs::read(d.path().join(name)).unwrap(), data, "{}", name);
+        }
+
+        // A truncated stream fails and leaves no partial plaintext behind.
+        lock_folder(p, &names, "pw", None, &opts).unwrap();
+        let primary = ["big", "big-copy"].into_iter().find(|n| d.path().join(format!("{}.locked", n)).exists()).unwrap();
+        let locked = d.path().join(format!("{}.locked", primary));
+        let f = fs::OpenOptions::new().write(true).open(&locked).unwrap();
+        f.set_len(7 + 3 * (chunk as u64 + 16)).unwrap();
+        drop(f);
+        assert_eq!(unlock(p, "pw").unwrap().failed.len(), 2);
+        assert!(!d.path().join(primary).exists());
+        assert!(locked.exists());
+    }
+
+    #[test]
+    fn rebuild_finds_streamed_files() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        let data: Vec<u8> = (0..(3 << 20) + 9).map(|i| (i % 251) as u8).collect();
+        fs::write(d.path().join("big"), &data).unwrap();
+        fs::write(d.path().join("small"), b"s").unwrap();
+        lock_folder(p, &names, "pw", None, &LockOptions { stream_threshold: Some(1 << 20), ..Default::default() }).unwrap();
+        let meta = fs::read_to_string(d.path().join(META_FILE)).unwrap();
+        let cut = meta.find("\"files\"").unwrap();
+        fs::write(d.path().join(META_FILE), &meta[..cut]).unwrap();
+        let rebuilt = rebuild_metadata(p, &names, "pw").unwrap();
+        assert_eq!(rebuilt.file_count, 2, "{:?}", rebuilt.skipped);
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("big")).unwrap(), data);
+    }
+
+    #[test]
+    fn bogus_metadata_is_not_a_vault() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a"), b"a").unwrap();
+        fs::write(d.path().join(META_FILE), b"[core]\n  editor = vim\n").unwrap();
+        assert!(!is_locked(p, &names));
+        let err = lock(p, "pw").unwrap_err();
+        assert!(err.message.contains("not SecureLock metadata"), "{}", err);
+        fs::write(d.path().join(META_FILE), b"{\"salt\": [], \"verify_token\": []}").unwrap();
+        assert!(!is_locked(p, &names));
+
+        // A subfolder with an unrelated .securelock is locked along with the rest.
+        fs::remove_file(d.path().join(META_FILE)).unwrap();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("sub").join(META_FILE), b"not ours").unwrap();
+        fs::write(d.path().join("sub/b"), b"b").unwrap();
+        lock(p, "pw").unwrap();
+        assert!(is_locked(p, &names));
+        assert!(d.path().join("sub/b.locked").exists());
+
+        // Damaged but still ours: recognised, so it can be rebuilt.
+        let meta = fs::read_to_string(d.path().join(META_FILE)).unwrap();
+        fs::write(d.path().join(META_FILE), &meta[..meta.len() / 2]).unwrap();
+        assert!(!is_locked(p, &names));
+        let err = lock(p, "pw").unwrap_err();
+        assert!(err.message.contains("rebuild"), "{}", err);
+    }
+
+    #[test]
+    fn archive_round_trip() {
+        let d = tmp();
+        let names = VaultNames::default();
+        let src = d.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        fs::write(src.join("sub/b.bin"), vec![9u8; (2 << 20) + 3]).unwrap();
+        fs::write(src.join("empty"), b"").unwrap();
+        let src_s = src.to_str().unwrap();
+        let archive = d.path().join("src.slk");
+        let archive_s = archive.to_str().unwrap();
+        let opts = LockOptions { stream_threshold: Some(1 << 20), ..Default::default() };
+        assert_eq!(lock_to_archive(src_s, &names, archive_s, "pw", None, &opts).unwrap().file_count, 3);
+        assert!(src.join("a.txt").exists() && !is_locked(src_s, &names));
+        // Never over an existing archive, nor inside the folder being archived.
+        assert!(lock_to_archive(src_s, &names, archive_s, "pw", None, &opts).is_err());
+        assert!(lock_to_archive(src_s, &names, src.join("x.slk").to_str().unwrap(), "pw", None, &opts).is_err());
+
+        let out = d.path().join("out");
+        assert!(unlock_from_archive(archive_s, out.to_str().unwrap(), "nope", &mut |_, _| {}).is_err());
+        let mut progress = Vec::new();
+        unlock_from_archive(archive_s, out.to_str().unwrap(), "pw", &mut |done, total| progress.push((done, total))).unwrap();
+        assert_eq!(progress.last(), Some(&(3, 3)));
+        assert_eq!(fs::read(out.join("a.txt")).unwrap(), b"alpha");
+        assert_eq!(fs::read(out.join("sub/b.bin")).unwrap(), fs::read(src.join("sub/b.bin")).unwrap());
+        assert_eq!(fs::read(out.join("empty")).unwrap(), b"");
+        assert!(unlock_from_archive(archive_s, out.to_str().unwrap(), "pw", &mut |_, _| {}).is_err());
+
+        // A damaged file leaves nothing behind, not even the files before it.
+        let mut bytes = fs::read(&archive).unwrap();
+        let mid = bytes.len() / 2;
+        bytes[mid] ^= 1;
+        let damaged = d.path().join("damaged.slk");
+        fs::write(&damaged, &bytes).unwrap();
+        let out4 = d.path().join("out4");
+        assert!(unlock_from_archive(damaged.to_str().unwrap(), out4.to_str().unwrap(), "pw", &mut |_, _| {}).is_err());
+        assert!(!out4.exists());
+        fs::create_dir(&out4).unwrap();
+        assert!(unlock_from_archive(damaged.to_str().unwrap(), out4.to_str().unwrap(), "pw", &mut |_, _| {}).is_err());
+        assert_eq!(fs::read_dir(&out4).unwrap().count(), 0);
+
+        // A tampered index can't send files outside the destination.
+        let mut bytes = fs::read(&archive).unwrap();
+        let needle = b"\"relative_path\":\"a.txt\"";
+        let at = bytes.windows(needle.len()).rposition(|w| w == needle).unwrap();
+        bytes.splice(at..at + needle.len(), b"\"relative_path\":\"../aa.txt\"".iter().copied());
+        let len_at = bytes.len() - 12;
+        let index_len = u64::from_le_bytes(bytes[len_at..len_at + 8].try_into().unwrap()) + 4;
+        bytes[len_at..len_at + 8].copy_from_slice(&index_len.to_le_bytes());
+        let evil = d.path().join("evil.slk");
+        fs::write(&evil, &bytes).unwrap();
+        let err = unlock_from_archive(evil.to_str().unwrap(), d.path().join("out2").to_str().unwrap(), "pw", &mut |_, _| {}).unwrap_err();
+        assert!(err.message.contains("outside"), "{}", err);
+        assert!(!d.path().join("aa.txt").exists());
+        fs::write(&evil, b"SLK1 nonsense").unwrap();
+        assert!(unlock_from_archive(evil.to_str().unwrap(), d.path().join("out3").to_str().unwrap(), "pw", &mut |_, _| {}).is_err());
+    }
+
+    #[test]
+    fn pbkdf2_vault_round_trip() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"alpha").unwrap();
+        let options = LockOptions { kdf: crypto::KdfKind::Pbkdf2HmacSha256, ..Default::default() };
+        lock_folder(p, &names, "pw", None, &options).unwrap();
+        let meta = fs::read_to_string(d.path().join(META_FILE)).unwrap();
+        assert!(meta.contains("pbkdf2_hmac_sha256"));
+        assert_eq!(folder_info(p, &names).unwrap().kdf, crypto::default_kdf_params(crypto::KdfKind::Pbkdf2HmacSha256));
+        assert!(!check_folder_password(p, &names, "wrong").unwrap());
+        assert!(check_folder_password(p, &names, "pw").unwrap());
+        // Truncated metadata still yields the KDF, so rebuild derives the same key.
+        let cut = meta.find("verify_token").unwrap();
+        fs::write(d.path().join(META_FILE), &meta[..cut]).unwrap();
+        assert_eq!(rebuild_metadata(p, &names, "pw").unwrap().file_count, 1);
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"alpha");
+
+        // Metadata without a kdf field is Argon2id at the old defaults.
+        lock(p, "pw").unwrap();
+        let mut legacy: serde_json::Value = serde_json::from_str(&fs::read_to_string(d.path().join(META_FILE)).unwrap()).unwrap();
+        legacy.as_object_mut().unwrap().remove("kdf");
+        fs::write(d.path().join(META_FILE), legacy.to_string()).unwrap();
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"alpha");
+    }
+
+    #[test]
+    fn age_recipient_recovery() {
+        use age::secrecy::ExposeSecret;
+        let d = tmp();
+        let vault = d.path().join("vault");
+        fs::create_dir(&vault).unwrap();
+        let p = vault.to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(vault.join("a.txt"), b"alpha").unwrap();
+        let id = age::x25519::Identity::generate();
+        let other = age::x25519::Identity::generate();
+        let id_file = d.path().join("key.txt");
+        fs::write(&id_file, format!("# created: today\n{}\n", id.to_string().expose_secret())).unwrap();
+        let bad = LockOptions { recipients: vec!["age1nope".into()], ..Default::default() };
+        assert!(lock_folder(p, &names, "pw", None, &bad).unwrap_err().message.contains("not an age recipient"));
+        assert!(vault.join("a.txt").exists());
+        let options = LockOptions { recipients: vec![other.to_public().to_string(), id.to_public().to_string()], ..Default::default() };
+        lock_folder(p, &names, "pw", None, &options).unwrap();
+        assert_eq!(folder_info(p, &names).unwrap().recipients.len(), 2);
+
+        // A password change re-wraps for the same recipients.
+        rekey_folder(p, &names, "pw", "pw2", None).unwrap();
+        let stranger = d.path().join("stranger.txt");
+        fs::write(&stranger, age::x25519::Identity::generate().to_string().expose_secret()).unwrap();
+        let identity = Identity::from_file(stranger.to_str().unwrap()).unwrap();
+        assert!(unlock_folder_with_identity(p, &names, &identity, &Default::default(), &mut |_, _| {}).unwrap_err().message.contains("does not match"));
+        let identity = Identity::from_file(id_file.to_str().unwrap()).unwrap();
+        unlock_folder_with_identity(p, &names, &identity, &Default::default(), &mut |_, _| {}).unwrap();
+        assert_eq!(fs::read(vault.join("a.txt")).unwrap(), b"alpha");
+
+        // Without recipients there is nothing to try.
+        lock(p, "pw").unwrap();
+        assert!(unlock_folder_with_identity(p, &names, &identity, &Default::default(), &mut |_, _| {}).unwrap_err().message.contains("No recipient keys"));
+        fs::write(&stranger, "not a key").unwrap();
+        assert!(Identity::from_file(stranger.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn future_lock_time_is_flagged() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"alpha").unwrap();
+        lock(p, "pw").unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let info = folder_info(p, &names).unwrap();
+        assert!(info.locked_at.unwrap() <= now && info.locked_at.unwrap() + 60 > now);
+        assert!(!info.locked_in_future);
+        let meta_path = d.path().join(META_FILE);
+        let set_locked_at = |value: serde_json::Value| {
+            let mut meta: serde_json::Value = serde_json::from_str(&fs::read_to_string(&meta_path).unwrap()).unwrap();
+            meta["locked_at"] = value;
+            fs::write(&meta_path, meta.to_string()).unwrap();
+        };
+        // A day ahead: withheld and flagged, not shown verbatim.
+        set_locked_at((now + 86_400).into());
+        let info = folder_info(p, &names).unwrap();
+        assert_eq!(info.locked_at, None);
+        assert!(info.locked_in_future);
+        // A minute ahead is ordinary clock drift and reads as now.
+        set_locked_at((now + 60).into());
+        let info = folder_info(p, &names).unwrap();
+        assert!(info.locked_at.unwrap() <= now + 1 && !info.locked_in_future);
+        set_locked_at(serde_json::Value::Null);
+        let info = folder_info(p, &names).unwrap();
+        assert!(info.locked_at.is_none() && !info.locked_in_future);
+        unlock(p, "pw").unwrap();
+    }
+
+    #[test]
+    fn any_password_slot_unlocks() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"alpha").unwrap();
+        lock_folder_with_passwords(p, &names, "alice", &["bob"], None, &Default::default()).unwrap();
+        assert_eq!(folder_info(p, &names).unwrap().password_slots, 1);
+        assert!(check_folder_password(p, &names, "alice").unwrap());
+        assert!(check_folder_password(p, &names, "bob").unwrap());
+        assert!(!check_folder_password(p, &names, "mallory").unwrap());
+        assert!(unlock(p, "mallory").unwrap_err().message.contains("Incorrect password"));
+        for pw in ["alice", "bob"] {
+            unlock(p, pw).unwrap();
+            assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"alpha");
+            lock_folder_with_passwords(p, &names, "alice", &["bob"], None, &Default::default()).unwrap();
+        }
+        // Any password can manage slots; duplicates are refused.
+        assert!(add_password_slot(p, &names, "mallory", "carol").is_err());
+        assert!(add_password_slot(p, &names, "bob", "alice").unwrap_err().message.contains("already unlocks"));
+        assert_eq!(add_password_slot(p, &names, "bob", "carol").unwrap(), 2);
+        assert!(check_folder_password(p, &names, "carol").unwrap());
+        assert!(rekey_folder(p, &names, "alice", "new", None).unwrap_err().message.contains("password slots"));
+        assert!(remove_password_slot(p, &names, "carol", 5).is_err());
+        assert_eq!(remove_password_slot(p, &names, "carol", 0).unwrap(), 1);
+        assert!(!check_folder_password(p, &names, "bob").unwrap());
+        unlock(p, "carol").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"alpha");
+    }
+
+    #[test]
+    fn unlock_reports_restored_and_missing() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("a.txt"), b"alpha").unwrap();
+        fs::write(d.path().join("sub/b.txt"), b"beta").unwrap();
+        fs::write(d.path().join("sub/dup.txt"), b"alpha").unwrap();
+        fs::write(d.path().join("gone.txt"), b"gone").unwrap();
+        let locked = lock(p, "pw").unwrap();
+        assert!(locked.restored.is_empty() && locked.missing.is_empty());
+
+        // Copying out reports the paths under the output directory.
+        let out = tmp();
+        let r = unlock_folder(p, &names, "pw", &UnlockOptions { output_dir: Some(out.path().to_str().unwrap().into()), ..Default::default() }, &mut |_, _| {}).unwrap();
+        let out_root = out.path().canonicalize().unwrap();
+        let mut restored: Vec<_> = r.restored.iter().map(PathBuf::from).collect();
+        restored.sort();
+        let mut expected: Vec<_> = ["a.txt", "gone.txt", "sub/b.txt", "sub/dup.txt"].iter().map(|f| out_root.join(f)).collect();
+        expected.sort();
+        assert_eq!(restored, expected);
+        assert!(restored.iter().all(|f| f.is_absolute() && f.exists()));
+
+        let gone = fs::read_dir(d.path()).unwrap().map(|e| e.unwrap().path())
+            .find(|f| f.file_name().unwrap().to_string_lossy().starts_with("gone.txt")).unwrap();
+        fs::remove_file(gone).unwrap();
+        let r = unlock(p, "pw").unwrap();
+        assert_eq!(r.missing, vec!["gone.txt".to_string()]);
+        let mut restored: Vec<_> = r.restored.iter().map(PathBuf::from).collect();
+        restored.sort();
+        let mut expected: Vec<_> = ["a.txt", "sub/b.txt", "sub/dup.txt"].iter().map(|f| d.path().join(f)).collect();
+        expected.sort();
+        assert_eq!(restored, expected);
+        assert!(restored.iter().all(|f| f.exists()));
+        assert!(!d.path().join("gone.txt").exists());
+    }
+
+    #[test]
+    fn decoy_vault() {
+        let d = tmp();
+        let names = VaultNames::default();
+        let real = d.path().join("real");
+        let decoy = d.path().join("decoy");
+        let plain = d.path().join("plain");
+        for dir in [&real, &decoy, &plain] {
+            fs::create_dir(dir).unwrap();
+        }
+        fs::create_dir(real.join("secret")).unwrap();
+        fs::write(real.join("secret/plans.txt"), b"the real plans").unwrap();
+        fs::write(real.join("diary.txt"), b"dear diary").unwrap();
+        fs::write(decoy.join("recipes.txt"), b"pancakes").unwrap();
+        fs::write(plain.join("recipes.txt"), b"pancakes").unwrap();
+        let rp = real.to_str().unwrap();
+        let options = LockOptions { reserve: Some(4096), ..Default::default() };
+        let listing = |dir: &Path| {
+            let mut names: Vec<String> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+            names.sort();
+            names
+        };
+        assert!(lock_with_decoy(rp, &names, decoy.to_str().unwrap(), "same", "same", None, &options).is_err());
+        // A decoy that can't be locked leaves the real files as they were.
+        let refused = LockOptions { limits: Some(LockLimits { max_files: Some(0), max_bytes: None }), ..options.clone() };
+        assert!(lock_with_decoy(rp, &names, decoy.to_str().unwrap(), "decoy-pw", "real-pw", None, &refused).unwrap_err().message.contains(OVER_LIMIT));
+        assert_eq!(listing(&real), vec!["diary.txt", "secret"]);
+        assert_eq!(fs::read(real.join("secret/plans.txt")).unwrap(), b"the real plans");
+        // Nor may a decoy file take the place of one that isn't moved.
+        fs::write(decoy.join(".env"), b"decoy").unwrap();
+        fs::write(real.join(".env"), b"real").unwrap();
+        assert!(lock_with_decoy(rp, &names, decoy.to_str().unwrap(), "decoy-pw", "real-pw", None, &options).unwrap_err().message.contains("would replace"));
+        fs::remove_file(decoy.join(".env")).unwrap();
+        fs::remove_file(real.join(".env")).unwrap();
+        let huge = LockOptions { reserve: Some(u64::MAX), ..Default::default() };
+        assert!(lock_with_decoy(rp, &names, decoy.to_str().unwrap(), "decoy-pw", "real-pw", None, &huge).unwrap_err().message.contains("at most"));
+        assert!(lock_folder(plain.to_str().unwrap(), &names, "pw", None, &huge).unwrap_err().message.contains("at most"));
+        assert!(real.join("diary.txt").exists() && !reserve_path(&real, &names).exists());
+        lock_with_decoy(rp, &names, decoy.to_str().unwrap(), "decoy-pw", "real-pw", None, &options).unwrap();
+        // An ordinary vault with a reserve looks the same from outside.
+        lock_folder(plain.to_str().unwrap(), &names, "pw", None, &options).unwrap();
+        assert_eq!(listing(&real), listing(&plain));
+        assert_eq!(fs::metadata(reserve_path(&real, &names)).unwrap().len(), fs::metadata(reserve_path(&plain, &names)).unwrap().len());
+        let keys = |dir: &Path| {
+            let meta: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.join(META_FILE)).unwrap()).unwrap();
+            let mut keys: Vec<String> = meta.as_object().unwrap().keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys(&real), keys(&plain));
+        let meta = fs::read_to_string(real.join(META_FILE)).unwrap();
+        assert!(!meta.contains("plans") && !meta.contains("diary") && !meta.contains("reserve"));
+        assert!(unlock(rp, "wrong").unwrap_err().message.contains("Incorrect password"));
+        assert!(unlock(plain.to_str().unwrap(), "wrong").unwrap_err().message.contains("Incorrect password"));
+
+        // The decoy password shows only the decoy files.
+        assert_eq!(unlock(rp, "decoy-pw").unwrap().file_count, 1);
+        assert_eq!(listing(&real), vec![".securelock.reserve", "recipes.txt"]);
+        assert_eq!(fs::read(real.join("recipes.txt")).unwrap(), b"pancakes");
+        lock(rp, "decoy-pw").unwrap();
+
+        // The real password restores only the real files.
+        let r = unlock(rp, "real-pw").unwrap();
+        assert_eq!((r.file_count, r.restored.len()), (2, 2));
+        assert_eq!(listing(&real), vec!["diary.txt", "secret"]);
+        assert_eq!(fs::read(real.join("secret/plans.txt")).unwrap(), b"the real plans");
+        assert_eq!(fs::read(real.join("diary.txt")).unwrap(), b"dear diary");
+        // Filler never opens.
+        assert!(unlock_hidden(plain.to_str().unwrap(), &names, "pw", &Default::default()).unwrap().is_none());
+
+        // The reserve's key is derived with the KDF the lock asked for, and
+        // still found once the decoy vault that records it is gone.
+        let fips = d.path().join("fips");
+        fs::create_dir(&fips).unwrap();
+        fs::write(fips.join("diary.txt"), b"dear diary").unwrap();
+        let fp = fips.to_str().unwrap();
+        let pbkdf2 = LockOptions { kdf: KdfKind::Pbkdf2HmacSha256, ..options.clone() };
+        lock_with_decoy(fp, &names, decoy.to_str().unwrap(), "decoy-pw", "real-pw", None, &pbkdf2).unwrap();
+        let contents = fs::read(reserve_path(&fips, &names)).unwrap();
+        let (salt, sealed) = contents.split_at(RESERVE_SALT_LEN);
+        assert!(crypto::decrypt(&reserve_key(KdfKind::Pbkdf2HmacSha256, "real-pw", salt).unwrap(), sealed).is_ok());
+        unlock(fp, "decoy-pw").unwrap();
+        assert_eq!(unlock(fp, "real-pw").unwrap().file_count, 1);
+        assert_eq!(fs::read(fips.join("diary.txt")).unwrap(), b"dear diary");
+    }
+
+    // Runs itself again in a child whose address space leaves room for about
+    // 40 MiB more, so Argon2's 64 MiB can't be allocated but 32 MiB can.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn kdf_out_of_memory() {
+        if std::env::var_os("SECURELOCK_OOM_CHILD").is_none() {
+            let out = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "folder::tests::kdf_out_of_memory", "--test-threads=1"])
+                .env("SECURELOCK_OOM_CHILD", "1")
+                .output()
+                .unwrap();
+            assert!(out.status.success(), "{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+            assert!(String::from_utf8_lossy(&out.stdout).contains("1 passed"));
+            return;
+        }
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"tiny device").unwrap();
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let vm_kib: u64 = status.lines().find(|l| l.starts_with("VmSize:")).unwrap().split_whitespace().nth(1).unwrap().parse().unwrap();
+        let limit = libc::rlimit { rlim_cur: (vm_kib + 40 * 1024) * 1024, rlim_max: libc::RLIM_INFINITY };
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) }, 0);
+
+        let e = crypto::derive_key("pw", &[1; 32]).err().unwrap();
+        assert!(e.message.starts_with(crypto::KDF_OUT_OF_MEMORY), "{}", e);
+        assert_eq!(e.code, ErrorCode::OutOfMemory);
+        let e = lock(p, "pw").unwrap_err();
+        assert!(e.message.contains("Not enough memory for key derivation (64 MiB needed)"), "{}", e);
+        assert!(!d.path().join(META_FILE).exists());
+
+        lock_folder(p, &names, "pw", None, &LockOptions { kdf_fallback: true, ..Default::default() }).unwrap();
+        let kdf = folder_info(p, &names).unwrap().kdf;
+        assert_eq!((kdf.memory_kib, kdf.iterations), (32768, 4));
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"tiny device");
+        // PBKDF2 needs no working memory, so there's nothing to fall back from.
+        let pbkdf2 = crypto::default_kdf_params(KdfKind::Pbkdf2HmacSha256);
+        assert_eq!(crypto::derive_key_with_fallback(pbkdf2.clone(), "pw", &[1; 32]).unwrap().0, pbkdf2);
+    }
+
+    #[test]
+    fn vault_root_catches_changed_ciphertext() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        fs::write(d.path().join("a.txt"), b"a").unwrap();
+        fs::write(d.path().join("b.txt"), b"b").unwrap();
+        fs::write(d.path().join("sub/c.txt"), b"c").unwrap();
+        let child = d.path().join("child");
+        fs::create_dir(&child).unwrap();
+        fs::write(child.join("n.txt"), b"nested").unwrap();
+        lock(child.to_str().unwrap(), "child-pw").unwrap();
+        lock(p, "pw").unwrap();
+        assert!(verify_vault_root(p, &names).unwrap());
+        // Nested vaults are theirs to check.
+        fs::write(child.join("extra.locked"), b"x").unwrap();
+        assert!(verify_vault_root(p, &names).unwrap());
+
+        // An added file is caught, and so is its removal again putting things right.
+        fs::write(d.path().join("sub/new.txt.locked"), b"junk").unwrap();
+        assert!(!verify_vault_root(p, &names).unwrap());
+        fs::remove_file(d.path().join("sub/new.txt.locked")).unwrap();
+        assert!(verify_vault_root(p, &names).unwrap());
+        // A removed file.
+        let b = fs::read(d.path().join("b.txt.locked")).unwrap();
+        fs::remove_file(d.path().join("b.txt.locked")).unwrap();
+        assert!(!verify_vault_root(p, &names).unwrap());
+        // A renamed one.
+        fs::write(d.path().join("sub/b.txt.locked"), &b).unwrap();
+        assert!(!verify_vault_root(p, &names).unwrap());
+        fs::rename(d.path().join("sub/b.txt.locked"), d.path().join("b.txt.locked")).unwrap();
+        assert!(verify_vault_root(p, &names).unwrap());
+        // An altered one.
+        let mut a = fs::read(d.path().join("a.txt.locked")).unwrap();
+        a[20] ^= 1;
+        fs::write(d.path().join("a.txt.locked"), &a).unwrap();
+        assert!(!verify_vault_root(p, &names).unwrap());
+        a[20] ^= 1;
+        fs::write(d.path().join("a.txt.locked"), &a).unwrap();
+
+        // Adding a file to the vault and rekeying keep the root current.
+        fs::write(d.path().join("late.txt"), b"late").unwrap();
+        let key = folder_key(p, &names, "pw").unwrap();
+        add_file_to_locked(p, &names, d.path().join("late.txt").to_str().unwrap(), &key, &LockOptions::default()).unwrap();
+        assert!(verify_vault_root(p, &names).unwrap());
+        // A copy of a file already in the vault shares its ciphertext.
+        fs::write(d.path().join("late copy.txt"), b"late").unwrap();
+        add_file_to_locked(p, &names, d.path().join("late copy.txt").to_str().unwrap(), &key, &LockOptions::default()).unwrap();
+        assert!(!d.path().join("late copy.txt.locked").exists());
+        assert!(verify_vault_root(p, &names).unwrap());
+        rekey_folder(p, &names, "pw", "pw2", None).unwrap();
+        assert!(verify_vault_root(p, &names).unwrap());
+
+        // Metadata without a root says so rather than passing.
+        let mut meta: serde_json::Value = serde_json::from_str(&fs::read_to_string(d.path().join(META_FILE)).unwrap()).unwrap();
+        meta.as_object_mut().unwrap().remove("ciphertext_root");
+        fs::write(d.path().join(META_FILE), meta.to_string()).unwrap();
+        assert!(verify_vault_root(p, &names).unwrap_err().message.contains("no integrity root"));
+        unlock(p, "pw2").unwrap();
+        assert_eq!(fs::read(d.path().join("late.txt")).unwrap(), b"late");
+        assert_eq!(fs::read(d.path().join("late copy.txt")).unwrap(), b"late");
+    }
+
+    #[test]
+    fn compare_vaults_reports_differences() {
+        let d = tmp();
+        let names = VaultNames::default();
+        let a = d.path().join("a");
+        let b = d.path().join("b");
+        fs::create_dir_all(a.join("sub")).unwrap();
+        fs::write(a.join("one.txt"), b"one").unwrap();
+        fs::write(a.join("sub/two.txt"), b"two").unwrap();
+        let (pa, pb) = (a.to_str().unwrap(), b.to_str().unwrap());
+        lock(pa, "pw").unwrap();
+        for entry in WalkDir::new(&a) {
+            let entry = entry.unwrap();
+            let target = b.join(entry.path().strip_prefix(&a).unwrap());
+            if entry.file_type().is_dir() {
+                fs::create_dir_all(&target).unwrap();
+            } else {
+                fs::copy(entry.path(), &target).unwrap();
+            }
+        }
+        let diff = compare_vaults(pa, &names, pb, &names).unwrap();
+        assert!(diff.identical && !diff.metadata_differs);
+
+        // A stray file, a missing one and a corrupted one in the copy.
+        fs::write(b.join("extra.txt.locked"), b"stray").unwrap();
+        fs::remove_file(b.join("one.txt.locked")).unwrap();
+        let mut two = fs::read(b.join("sub/two.txt.locked")).unwrap();
+        two[15] ^= 0x80;
+        fs::write(b.join("sub/two.txt.locked"), &two).unwrap();
+        let diff = compare_vaults(pa, &names, pb, &names).unwrap();
+        assert!(!diff.identical && !diff.metadata_differs);
+        assert_eq!(diff.only_in_a, vec!["one.txt.locked"]);
+        assert_eq!(diff.only_in_b, vec!["extra.txt.locked"]);
+        assert_eq!(diff.differing, vec!["sub/two.txt.locked"]);
+
+        // The same files locked separately are a different vault.
+        let c = d.path().join("c");
+        fs::create_dir_all(c.join("sub")).unwrap();
+        fs::write(c.join("one.txt"), b"one").unwrap();
+        fs::write(c.join("sub/two.txt"), b"two").unwrap();
+        lock(c.to_str().unwrap(), "pw").unwrap();
+        let diff = compare_vaults(pa, &names, c.to_str().unwrap(), &names).unwrap();
+        assert!(diff.metadata_differs);
+        assert_eq!(diff.differing.len(), 2);
+        assert!(compare_vaults(pa, &names, d.path().to_str().unwrap(), &names).unwrap_err().message.contains("not locked"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_are_skipped() {
+        use std::os::unix::fs::FileTypeExt;
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"a").unwrap();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        assert!(std::process::Command::new("mkfifo").arg(d.path().join("sub/pipe")).status().unwrap().success());
+        let _socket = std::os::unix::net::UnixListener::bind(d.path().join("sock")).unwrap();
+        assert_eq!(count_files(p, &names, &Default::default()), 1);
+
+        let out = tmp();
+        let archive = out.path().join("a.slk");
+        let r = lock_to_archive(p, &names, archive.to_str().unwrap(), "pw", None, &Default::default()).unwrap();
+        assert_eq!(r.file_count, 1);
+        let mut skipped = r.skipped.clone();
+        skipped.sort();
+        assert_eq!(skipped, vec!["sock", "sub/pipe"]);
+
+        let r = lock(p, "pw").unwrap();
+        assert_eq!(r.file_count, 1);
+        let mut skipped = r.skipped.clone();
+        skipped.sort();
+        assert_eq!(skipped, vec!["sock", "sub/pipe"]);
+        assert!(fs::symlink_metadata(d.path().join("sub/pipe")).unwrap().file_type().is_fifo());
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"a");
+
+        // Left out by a pattern lock like any other non-matching file.
+        assert!(lock_matching(p, &names, "*.txt", "pw", None, &Default::default()).unwrap().skipped.is_empty());
+    }
+
+    #[test]
+    fn metadata_write_failures_leave_the_old_metadata() {
+        use std::io::ErrorKind;
+        use vfs::{MemoryFileSystem, Op};
+        let d = tmp();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        lock_folder(d.path().to_str().unwrap(), &VaultNames::default(), "pw", None, &LockOptions { split_metadata: true, ..Default::default() }).unwrap();
+        let mut meta = load_meta(&OsFileSystem, &d.path().join(META_FILE)).unwrap();
+        assert!(meta.split_manifest);
+
+        let mem = MemoryFileSystem::new();
+        let path = Path::new("/vault/.securelock");
+        save_meta(&mem, path, &meta).unwrap();
+        let before = mem.files();
+        assert_eq!(before.len(), 2);
+
+        // Disk full while writing the manifest: nothing changes, no temp files.
+        let files = meta.files.clone();
+        meta.files.clear();
+        mem.fail(Op::Write, Path::new("/vault/.securelock.manifest.tmp"), ErrorKind::StorageFull);
+        let err = save_meta(&mem, path, &meta).unwrap_err();
+        assert_eq!(err.code, ErrorCode::DiskFull);
+        assert_eq!(mem.files(), before);
+        assert_eq!(load_meta(&mem, path).unwrap().files.len(), files.len());
+
+        // A header write that dies halfway leaves the old header readable.
+        mem.fail_partway(Path::new("/vault/.securelock.tmp"), 10, ErrorKind::StorageFull);
+        assert!(save_meta(&mem, path, &meta).is_err());
+        assert_eq!(mem.files(), before);
+        assert!(load_meta(&mem, path).is_ok());
+
+        // Permission denied on the final rename.
+        mem.fail(Op::Rename, path, ErrorKind::PermissionDenied);
+        let err = save_meta(&mem, path, &meta).unwrap_err();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+        assert_eq!(mem.files(), before);
+
+        save_meta(&mem, path, &meta).unwrap();
+        assert!(load_meta(&mem, path).unwrap().files.is_empty());
+        mem.fail(Op::Read, path, ErrorKind::PermissionDenied);
+        assert!(load_meta(&mem, path).unwrap_err().message.contains("Permission denied"));
+    }
+
+    #[test]
+    fn quick_lock_then_finish() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("doc.txt"), b"small").unwrap();
+        fs::create_dir(d.path().join("media")).unwrap();
+        fs::write(d.path().join("media/video.mp4"), vec![7u8; 5000]).unwrap();
+        fs::write(d.path().join("big.bin"), vec![9u8; 3000]).unwrap();
+        let opts = LockOptions { quick_lock_limit: Some(1024), split_metadata: true, stream_threshold: Some(4096), ..Default::default() };
+        let r = lock_folder(p, &names, "pw", None, &opts).unwrap();
+        assert_eq!(r.file_count, 1);
+        let mut deferred = r.deferred.clone();
+        deferred.sort();
+        assert_eq!(deferred, vec!["big.bin".to_string(), "media/video.mp4".to_string()]);
+        assert!(d.path().join("media/video.mp4").exists());
+        assert!(!d.path().join("doc.txt").exists());
+        assert!(verify_vault_root(p, &names).unwrap());
+        // Everything over the limit: nothing to lock.
+        let only_big = tmp();
+        fs::write(only_big.path().join("x"), vec![0u8; 4000]).unwrap();
+        assert!(lock_folder(only_big.path().to_str().unwrap(), &names, "pw", None, &opts).unwrap_err().message.contains("quick-lock"));
+
+        // A deferred file that has gone since is skipped.
+        fs::remove_file(d.path().join("big.bin")).unwrap();
+        let key = folder_key(p, &names, "pw").unwrap();
+        assert!(finish_lock(p, &names, &[1u8; 32], &Default::default()).is_err());
+        let r = finish_lock(p, &names, &key, &Default::default()).unwrap();
+        assert_eq!(r.file_count, 2);
+        assert_eq!(r.skipped, vec!["big.bin".to_string()]);
+        assert!(r.deferred.is_empty());
+        assert!(!d.path().join("media/video.mp4").exists());
+        assert!(verify_vault_root(p, &names).unwrap());
+        // Under the threshold the folder was locked with, not the default.
+        assert!(meta_in(d.path()).files.iter().any(|f| f.relative_path == "media/video.mp4" && f.chunk_size.is_some()));
+        // Finishing again is a no-op.
+        assert_eq!(finish_lock(p, &names, &key, &Default::default()).unwrap().file_count, 2);
+
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("media/video.mp4")).unwrap(), vec![7u8; 5000]);
+        assert_eq!(fs::read(d.path().join("doc.txt")).unwrap(), b"small");
+    }
+
+    #[test]
+    fn unlock_continues_past_a_corrupt_file() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(d.path().join(name), name.as_bytes()).unwrap();
+        }
+        lock(p, "pw").unwrap();
+        let locked = d.path().join("b.txt.locked");
+        let mut data = fs::read(&locked).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&locked, &data).unwrap();
+
+        let r = unlock(p, "pw").unwrap();
+        assert!(r.is_locked);
+        assert_eq!(r.failed.len(), 1);
+        assert_eq!(r.failed[0].path, "b.txt");
+        assert_eq!(r.restored.len(), 2);
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"a.txt");
+        assert_eq!(fs::read(d.path().join("c.txt")).unwrap(), b"c.txt");
+        assert!(locked.exists() && !d.path().join("b.txt").exists());
+        assert!(is_locked(p, &names));
+        assert_eq!(list_locked_files(p, &names).unwrap().len(), 1);
+
+        // Once the ciphertext is repaired the unlock finishes and the metadata goes.
+        data[last] ^= 1;
+        fs::write(&locked, &data).unwrap();
+        let r = unlock(p, "pw").unwrap();
+        assert!(!r.is_locked && r.failed.is_empty());
+        assert_eq!(fs::read(d.path().join("b.txt")).unwrap(), b"b.txt");
+        assert!(!is_locked(p, &names));
+    }
+
+    #[test]
+    fn interrupted_lock_leftovers_are_cleaned() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        fs::write(d.path().join("a.txt.locked.tmp"), b"trunc").unwrap();
+        // No original beside it: not ours to remove.
+        fs::write(d.path().join("b.locked.tmp"), b"keep").unwrap();
+        assert_eq!(diagnose_folder(p, &names).unwrap().leftover_temp_files, vec!["a.txt.locked.tmp".to_string()]);
+        assert_eq!(clean_interrupted_lock(p, &names).unwrap(), vec!["a.txt.locked.tmp".to_string()]);
+        assert!(!d.path().join("a.txt.locked.tmp").exists());
+
+        // Locking again clears it too, rather than encrypting it.
+        fs::write(d.path().join("a.txt.locked.tmp"), b"trunc").unwrap();
+        assert_eq!(lock(p, "pw").unwrap().file_count, 2);
+        assert!(!d.path().join("a.txt.locked.tmp").exists());
+        assert!(!d.path().join("a.txt.locked.tmp.locked").exists());
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(d.path().join("b.locked.tmp")).unwrap(), b"keep");
+    }
+
+    #[test]
+    fn recovery_blob_export_import() {
+        let names = VaultNames::default();
+        let master_key = crypto::derive_key("master", &crypto::generate_salt()).unwrap();
+        let (a, b) = (tmp(), tmp());
+        for d in [&a, &b] {
+            fs::write(d.path().join("x.txt"), b"x").unwrap();
+            lock_folder(d.path().to_str().unwrap(), &names, "pw", Some(&master_key), &Default::default()).unwrap();
+        }
+        let (pa, pb) = (a.path().to_str().unwrap(), b.path().to_str().unwrap());
+        let blob_a = export_recovery_blob(pa, &names).unwrap();
+        let blob_b = export_recovery_blob(pb, &names).unwrap();
+        assert!(import_recovery_blob(pa, &names, &blob_a, None).unwrap_err().message.contains("already"));
+
+        // Lose a's recovery key, then put it back from the backup.
+        let mut meta = meta_in(a.path());
+        meta.recovery_key = None;
+        save_meta_in(a.path(), &meta);
+        assert!(!has_recovery_key(pa, &names));
+        assert!(export_recovery_blob(pa, &names).is_err());
+        assert!(import_recovery_blob(pa, &names, "not base64!", None).is_err());
+        assert!(import_recovery_blob(pa, &names, &blob_b, Some(&master_key)).unwrap_err().message.contains("another folder"));
+        assert!(!has_recovery_key(pa, &names));
+        import_recovery_blob(pa, &names, &format!("{}\n", blob_a), Some(&master_key)).unwrap();
+        assert_eq!(export_recovery_blob(pa, &names).unwrap(), blob_a);
+        unlock_folder_with_master_key(pa, &names, &master_key, &Default::default(), &mut |_, _| {}).unwrap();
+        assert_eq!(fs::read(a.path().join("x.txt")).unwrap(), b"x");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn vault_files_are_hidden_on_windows() {
+        use std::os::windows::fs::MetadataExt;
+        let hidden = |p: &Path| fs::metadata(p).unwrap().file_attributes() & 0x2 != 0;
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"a").unwrap();
+        lock(p, "pw").unwrap();
+        assert!(hidden(&d.path().join(META_FILE)));
+        assert!(hidden(&d.path().join("a.txt.locked")));
+        // Rewriting the metadata keeps it hidden.
+        add_password_slot(p, &names, "pw", "other").unwrap();
+        assert!(hidden(&d.path().join(META_FILE)));
+        unlock(p, "pw").unwrap();
+        assert!(!hidden(&d.path().join("a.txt")));
+        lock_folder(p, &names, "pw", None, &LockOptions { show_vault_files: true, ..Default::default() }).unwrap();
+        assert!(!hidden(&d.path().join(META_FILE)));
+        assert!(!hidden(&d.path().join("a.txt.locked")));
+    }
+
+    #[test]
+    fn folder_records_app_version() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"a").unwrap();
+        lock(p, "pw").unwrap();
+        assert!(!APP_VERSION.is_empty());
+        assert_eq!(folder_info(p, &names).unwrap().app_version.as_deref(), Some(APP_VERSION));
+        // Older metadata has none.
+        let mut meta: serde_json::Value = serde_json::from_str(&fs::read_to_string(d.path().join(META_FILE)).unwrap()).unwrap();
+        meta.as_object_mut().unwrap().remove("app_version");
+        fs::write(d.path().join(META_FILE), meta.to_string()).unwrap();
+        assert_eq!(folder_info(p, &names).unwrap().app_version, None);
+        unlock(p, "pw").unwrap();
+    }
+
+    #[test]
+    fn interrupted_password_change_is_finished() {
+        let names = VaultNames::default();
+        let staged_files = |dir: &Path| fs::read_dir(dir).unwrap().flatten().filter(|e| e.file_name().to_string_lossy().ends_with(REKEY_EXT)).count();
+        for retry in [false, true] {
+            let d = tmp();
+            let p = d.path().to_str().unwrap();
+            for (name, contents) in [("a.txt", "alpha"), ("b.txt", "beta"), ("c.txt", "gamma")] {
+                fs::write(d.path().join(name), contents).unwrap();
+            }
+            lock(p, "old").unwrap();
+            let (pending, _) = stage_rekey(p, &names, "old", "new", None).unwrap();
+            // Stopped after the first file was swapped in, before the new
+            // metadata was written.
+            let first = pending.files[0].locked_path_on_disk(d.path());
+            fs::rename(staged_rekey_path(&first), &first).unwrap();
+            assert!(parse_meta(&d.path().join(META_FILE)).unwrap().pending_rekey.is_some());
+            assert_eq!(staged_files(d.path()), 2);
+            // Read-only calls leave it as it is.
+            let meta_before = fs::read(d.path().join(META_FILE)).unwrap();
+            folder_info(p, &names).unwrap();
+            list_locked_files(p, &names).unwrap();
+            diagnose_folder(p, &names).unwrap();
+            let _ = verify_vault_root(p, &names);
+            assert!(check_folder_password(p, &names, "new").unwrap() && !check_folder_password(p, &names, "old").unwrap());
+            assert_eq!(fs::read(d.path().join(META_FILE)).unwrap(), meta_before);
+            assert_eq!(staged_files(d.path()), 2);
+
+            if retry {
+                // Resuming still needs the password being replaced.
+                assert!(rekey_folder(p, &names, "wrong", "new", None).unwrap_err().message.contains("Incorrect password"));
+                rekey_folder(p, &names, "old", "new", None).unwrap();
+            } else {
+                assert!(unlock(p, "old").unwrap_err().message.contains("Incorrect password"));
+            }
+            assert!(parse_meta(&d.path().join(META_FILE)).unwrap().pending_rekey.is_none());
+            assert_eq!(staged_files(d.path()), 0);
+            assert!(verify_vault_root(p, &names).unwrap());
+            // Once finished, the new password alone doesn't make a change succeed.
+            assert!(rekey_folder(p, &names, "old", "new", None).unwrap_err().message.contains("Incorrect password"));
+            unlock(p, "new").unwrap();
+            for (name, contents) in [("a.txt", "alpha"), ("b.txt", "beta"), ("c.txt", "gamma")] {
+                assert_eq!(fs::read_to_string(d.path().join(name)).unwrap(), contents);
+            }
+        }
+    }
+
+    #[test]
+    fn upgrade_folder_kdf_resumes() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.path().join("a.txt"), b"aaa").unwrap();
+        fs::write(d.path().join("b.txt"), b"bbb").unwrap();
+        fs::write(d.path().join("c.txt"), b"aaa").unwrap();
+        let opts = LockOptions { kdf: KdfKind::Pbkdf2HmacSha256, ..Default::default() };
+        lock_folder(p, &names, "pw", None, &opts).unwrap();
+        let target = crypto::kdf_params();
+        assert!(upgrade_folder_kdf(p, &names, "bad", &target, None, &mut |_, _| {}).is_err());
+        let primaries = |dir: &Path| -> Vec<String> { meta_in(dir).files.iter().filter(|f| f.duplicate_of.is_none()).map(|f| f.locked_name.clone()).collect() };
+
+        // Corrupt the last file staged so the first run stops part way.
+        let locked = primaries(d.path());
+        assert_eq!(locked.len(), 2);
+        let b_locked = d.path().join(&locked[1]);
+        let a_staged = d.path().join(format!("{}{}", locked[0], REKEY_EXT));
+        let good = fs::read(&b_locked).unwrap();
+        let mut bad = good.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        fs::write(&b_locked, &bad).unwrap();
+        assert!(upgrade_folder_kdf(p, &names, "pw", &target, None, &mut |_, _| {}).is_err());
+        let staged_a = fs::read(&a_staged).unwrap();
+        // Still opens the old way meanwhile.
+        assert!(check_folder_password(p, &names, "pw").unwrap());
+
+        // The second run keeps what the first one staged.
+        fs::write(&b_locked, &good).unwrap();
+        let mut progress = Vec::new();
+        let r = upgrade_folder_kdf(p, &names, "pw", &target, None, &mut |done, total| progress.push((done, total))).unwrap();
+        assert_eq!(r.file_count, 3);
+        assert_eq!(progress.last(), Some(&(3, 3)));
+        assert_eq!(fs::read(d.path().join(&locked[0])).unwrap(), staged_a);
+        assert!(!a_staged.exists());
+        assert_eq!(folder_info(p, &names).unwrap().kdf, target);
+        assert!(verify_vault_root(p, &names).unwrap());
+        assert!(upgrade_folder_kdf(p, &names, "pw", &target, None, &mut |_, _| {}).unwrap_err().message.contains("already"));
+        unlock(p, "pw").unwrap();
+        for (name, contents) in [("a.txt", b"aaa"), ("b.txt", b"bbb"), ("c.txt", b"aaa")] {
+            assert_eq!(&fs::read(d.path().join(name)).unwrap(), contents);
+        }
+
+        // Recovery keys are rewrapped; an abandoned upgrade is cleaned up by unlock.
+        let master_key = crypto::derive_key("master", &crypto::generate_salt()).unwrap();
+        lock_folder(p, &names, "pw", Some(&master_key), &opts).unwrap();
+        assert!(upgrade_folder_kdf(p, &names, "pw", &target, None, &mut |_, _| {}).unwrap_err().message.contains("master"));
+        let locked = primaries(d.path());
+        let a_staged = d.path().join(format!("{}{}", locked[0], REKEY_EXT));
+        fs::write(d.path().join(&locked[1]), b"garbage").unwrap();
+        assert!(upgrade_folder_kdf(p, &names, "pw", &target, Some(&master_key), &mut |_, _| {}).is_err());
+        assert!(a_staged.exists());
+        let r = unlock_folder_with_master_key(p, &names, &master_key, &Default::default(), &mut |_, _| {}).unwrap();
+        assert!(!r.failed.is_empty());
+        assert!(!a_staged.exists());
+    }
+
+    #[test]
+    fn metadata_on_a_flaky_share_is_retried() {
+        use std::io::ErrorKind;
+        use vfs::{MemoryFileSystem, Op};
+        let d = tmp();
+        fs::write(d.path().join("a.txt"), b"hello").unwrap();
+        let retry = RetryPolicy { retries: 3, backoff_ms: 0 };
+        lock_folder(d.path().to_str().unwrap(), &VaultNames::default(), "pw", None, &LockOptions { retry, ..Default::default() }).unwrap();
+        let meta = load_meta(&OsFileSystem, &d.path().join(META_FILE)).unwrap();
+
+        let share = Retrying { inner: MemoryFileSystem::new(), policy: retry };
+        let path = Path::new("/share/.securelock");
+        share.inner.fail(Op::Write, Path::new("/share/.securelock.tmp"), ErrorKind::TimedOut);
+        share.inner.fail(Op::Rename, path, ErrorKind::ConnectionReset);
+        save_meta(&share, path, &meta).unwrap();
+        share.inner.fail(Op::Read, path, ErrorKind::TimedOut);
+        assert_eq!(load_meta(&share, path).unwrap().files.len(), meta.files.len());
+        for _ in 0..4 {
+            share.inner.fail(Op::Read, path, ErrorKind::TimedOut);
+        }
+        assert!(load_meta(&share, path).is_err());
+    }
+
+    #[test]
+    fn lock_limits_refuse_before_anything_is_encrypted() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        for i in 0..5 {
+            fs::write(d.path().join(format!("{}.txt", i)), vec![0u8; 1000]).unwrap();
+        }
+        let files = LockOptions { limits: Some(LockLimits { max_files: Some(4), max_bytes: None }), ..Default::default() };
+        let err = lock_folder(p, &names, "pw", None, &files).unwrap_err();
+        assert_eq!(err.code, ErrorCode::OverLimit, "{}", err);
+        assert!(!is_locked(p, &names));
+        assert!(d.path().join("0.txt").exists());
+        let bytes = LockOptions { limits: Some(LockLimits { max_files: None, max_bytes: Some(4999) }), ..Default::default() };
+        assert!(lock_folder(p, &names, "pw", None, &bytes).unwrap_err().message.contains(OVER_LIMIT));
+        let ignored = LockOptions { ignore_limits: true, ..bytes };
+        lock_folder(p, &names, "pw", None, &ignored).unwrap();
+    }
+
+    #[test]
+    fn paths_outside_the_folder_are_refused() {
+        let outer = tmp();
+        let d = outer.path().join("vault");
+        fs::create_dir(&d).unwrap();
+        let p = d.to_str().unwrap();
+        let names = VaultNames::default();
+        fs::write(d.join("a.txt"), b"alpha").unwrap();
+        let master_key = crypto::derive_key("master", &crypto::generate_salt()).unwrap();
+        lock_folder(p, &names, "pw", Some(&master_key), &Default::default()).unwrap();
+        let meta_path = d.join(META_FILE);
+        let original = fs::read_to_string(&meta_path).unwrap();
+        for (field, value) in [("relative_path", "../escape.txt"), ("relative_path", "/tmp/escape.txt"), ("original_name", "../escape.txt")] {
+            let mut json: serde_json::Value = serde_json::from_str(&original).unwrap();
+            json["files"][0][field] = value.into();
+            fs::write(&meta_path, json.to_string()).unwrap();
+            let err = unlock(p, "pw").unwrap_err();
+            assert!(err.message.contains("points outside the folder"), "{}", err);
+            assert_eq!(err.code, ErrorCode::Corrupted);
+            assert!(unlock_folder_with_master_key(p, &names, &master_key, &Default::default(), &mut |_, _| {}).is_err());
+            assert!(!outer.path().join("escape.txt").exists());
+        }
+        // Percent-encoding doesn't smuggle a separator past the check.
+        let mut json: serde_json::Value = serde_json::from_str(&original).unwrap();
+        json["files"][0]["relative_path"] = "..%2Fescape.txt".into();
+        json["files"][0]["percent_encoded"] = true.into();
+        fs::write(&meta_path, json.to_string()).unwrap();
+        assert!(unlock(p, "pw").is_err());
+        fs::write(&meta_path, &original).unwrap();
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.join("a.txt")).unwrap(), b"alpha");
+    }
+
+    #[test]
+    fn metadata_must_carry_the_magic() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        let meta_path = d.path().join(META_FILE);
+        // Some other tool's JSON under the same name.
+        fs::write(&meta_path, br#"{"salt": "abc", "files": []}"#).unwrap();
+        assert!(!is_locked(p, &names));
+        let err = load_meta(&OsFileSystem, &meta_path).unwrap_err();
+        assert!(err.message.contains(NOT_A_VAULT), "{}", err);
+        assert!(unlock(p, "pw").unwrap_err().message.contains(NOT_A_VAULT));
+        fs::remove_file(&meta_path).unwrap();
+
+        fs::write(d.path().join("a.txt"), b"alpha").unwrap();
+        lock(p, "pw").unwrap();
+        let text = fs::read_to_string(&meta_path).unwrap();
+        assert!(text.trim_start().trim_start_matches('{').trim_start().starts_with("\"magic\": \"securelock-vault\""), "{}", text);
+        // A wrong magic is refused even with everything else in place.
+        fs::write(&meta_path, text.replace("securelock-vault", "other-vault")).unwrap();
+        assert!(!is_locked(p, &names));
+        assert!(load_meta(&OsFileSystem, &meta_path).unwrap_err().message.contains(NOT_A_VAULT));
+        // So is a current version without it.
+        let mut json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        json.as_object_mut().unwrap().remove("magic");
+        fs::write(&meta_path, json.to_string()).unwrap();
+        assert!(!is_locked(p, &names));
+        assert!(load_meta(&OsFileSystem, &meta_path).is_err());
+        // Metadata from before the magic still opens, and gains it when saved.
+        json["format_version"] = (MAGIC_VERSION - 1).into();
+        fs::write(&meta_path, json.to_string()).unwrap();
+        assert!(is_locked(p, &names));
+        let meta = load_meta(&OsFileSystem, &meta_path).unwrap();
+        save_meta(&OsFileSystem, &meta_path, &meta).unwrap();
+        assert!(fs::read_to_string(&meta_path).unwrap().contains("securelock-vault"));
+        unlock(p, "pw").unwrap();
+        assert_eq!(fs::read(d.path().join("a.txt")).unwrap(), b"alpha");
+    }
+
+    #[test]
+    fn decrypt_a_single_file_in_memory() {
+        let d = tmp();
+        let p = d.path().to_str().unwrap();
+        let names = VaultNames::default();
+        fs::create_dir(d.path().join("sub")).unwrap();
+        let big: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(d.path().join("sub/img.png"), b"\x89PNG fake").unwrap();
+        fs::write(d.path().join("copy.png"), b"\x89PNG fake").unwrap();
+        fs::write(d.path().join("big.bin"), &big).unwrap();
+        lock_folder(p, &names, "pw", None, &LockOptions { stream_threshold: Some(1 << 20), ..Default::default() }).unwrap();
+        let key = folder_key(p, &names, "pw").unwrap();
+        let listing = || WalkDir::new(d.path()).sort_by_file_name().into_iter().map(|e| e.unwrap().into_path()).collect::<Vec<_>>();
+        let before = listing();
+        assert_eq!(decrypt_file_to_bytes(p, &names, "sub/img.png", &key, RetryPolicy::default()).unwrap(), b"\x89PNG fake");
+        assert_eq!(decrypt_file_to_bytes(p, &names, "copy.png", &key, RetryPolicy::default()).unwrap(), b"\x89PNG fake");
+        assert_eq!(decrypt_file_to_bytes(p, &names, "big.bin", &key, RetryPolicy::default()).unwrap(), big);
+        assert!(decrypt_file_to_bytes(p, &names, "nope.txt", &key, RetryPolicy::default()).is_err());
+        assert!(decrypt_file_to_bytes(p, &names, "big.bin", &[7u8; 32], RetryPolicy::default()).is_err());
+        // Nothing is written to disk.
+        assert_eq!(listing(), before);
+        assert!(is_locked(p, &names));
+    }
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])], options: zip::write::SimpleFileOptions) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, contents) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn import_password_zip() {
+        use zip::unstable::write::FileOptionsExt;
+        use zip::write::SimpleFileOptions;
+        let dir = tmp();
+        let names = VaultNames::default();
+        let opts = LockOptions::default();
+        let zip_path = dir.path().join("old.zip");
+        let z = zip_path.to_str().unwrap();
+        write_zip(&zip_path, &[("a.txt", b"alpha"), ("sub/b.txt", b"beta")], SimpleFileOptions::default().with_deprecated_encryption(b"zpw"));
+        let dest = dir.path().join("new");
+        let d = dest.to_str().unwrap();
+        // Wrong ZIP password: nothing left behind.
+        let e = import_zip(z, d, &names, "nope", "pw", None, &opts).unwrap_err();
+        assert!(e.message.contains("Incorrect password") || e.message.contains("extract"), "{}", e);
+        assert!(!dest.exists());
+        // Over the limits: refused before writing.
+        let tight = LockOptions { limits: Some(LockLimits { max_files: Some(1), max_bytes: None }), ..Default::default() };
+        assert!(import_zip(z, d, &names, "zpw", "pw", None, &tight).unwrap_err().message.contains(OVER_LIMIT));
+        assert!(!dest.exists());
+        assert_eq!(import_zip(z, d, &names, "zpw", "pw", None, &opts).unwrap().file_count, 2);
+        assert!(is_locked(d, &names));
+        assert!(!dest.join("a.txt").exists() && !dest.join("sub/b.txt").exists());
+        unlock(d, "pw").unwrap();
+        assert_eq!(fs::read(dest.join("a.txt")).unwrap(), b"alpha");
+        assert_eq!(fs::read(dest.join("sub/b.txt")).unwrap(), b"beta");
+        // Not into a folder with files in it.
+        assert!(import_zip(z, d, &names, "zpw", "pw", None, &opts).unwrap_err().message.contains("new or empty"));
+
+        // Entries escaping the destination are refused.
+        let evil = dir.path().join("evil.zip");
+        write_zip(&evil, &[("../escape.txt", b"x")], SimpleFileOptions::default());
+        let d2 = dir.path().join("new2");
+        assert!(import_zip(evil.to_str().unwrap(), d2.to_str().unwrap(), &names, "zpw", "pw", None, &opts).unwrap_err().message.contains("outside"));
+        assert!(!dir.path().join("escape.txt").exists() && !d2.exists());
+
+        // AES.
+        let aes = dir.path().join("aes.zip");
+        write_zip(&aes, &[("c.txt", b"gamma")], SimpleFileOptions::default().with_aes_encryption(zip::AesMode::Aes256, "zpw"));
+        let d3 = dir.path().join("new3");
+        assert!(import_zip(aes.to_str().unwrap(), d3.to_str().unwrap(), &names, "bad", "pw", None, &opts).unwrap_err().message.contains("Incorrect password"));
+        import_zip(aes.to_str().unwrap(), d3.to_str().unwrap(), &names, "zpw", "pw", None, &opts).unwrap();
+        unlock(d3.to_str().unwrap(), "pw").unwrap();
+        assert_eq!(fs::read(d3.join("c.txt")).unwrap(), b"gamma");
+    }
+
+    #[test]
+    fn import_zip_applies_the_default_limits() {
+        let dir = tmp();
+        let zip_path = dir.path().join("huge.zip");
+        let entries: Vec<(String, &[u8])> = (0..26).map(|i| (format!("{}.bin", i), &b"x"[..])).collect();
+        let entries: Vec<(&str, &[u8])> = entries.iter().map(|(n, c)| (n.as_str(), *c)).collect();
+        write_zip(&zip_path, &entries, zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored));
+        // Claim just under 4 GiB per entry in the central directory, over 100 GiB in all.
+        let mut bytes = fs::read(&zip_path).unwrap();
+        let mut at = 0;
+        while let Some(found) = bytes[at..].windows(4).position(|w| w == b"PK\x01\x02") {
+            at += found;
+            bytes[at + 24..at + 28].copy_from_slice(&0xFFFF_FFFEu32.to_le_bytes());
+            at += 4;
+        }
+        fs::write(&zip_path, &bytes).unwrap();
+        let dest = dir.path().join("new");
+        let err = import_zip(zip_path.to_str().unwrap(), dest.to_str().unwrap(), &VaultNames::default(), "", "pw", None, &LockOptions::default()).unwrap_err();
+        assert!(err.message.contains(OVER_LIMIT), "{}", err);
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn shred_verification_flags_an_overwrite_that_did_not_land() {
+        let dir = tmp();
+        let file = dir.path().join("plain.txt");
+        fs::write(&file, b"top secret").unwrap();
+        // Stands in for a cache or filesystem that drops the write.
+        let problem = shred_with(&file, true, |_, _| Ok(())).unwrap();
+        assert!(problem.unwrap().contains("did not read back"));
+        assert!(!file.exists());
+
+        // Without verification nothing is reported.
+        fs::write(&file, b"top secret").unwrap();
+        assert_eq!(shred_with(&file, false, |_, _| Ok(())).unwrap(), None);
+        fs::write(&file, b"top secret").unwrap();
+        if let Some(problem) = shred_file_checked(&file, true).unwrap() {
+            assert!(problem.contains("copy-on-write"), "{}", problem);
+        }
+        assert!(!file.exists());
+    }
 }
@@ -0,0 +1,50 @@
+use crate::error::{Error, ErrorCode};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use zeroize::Zeroize;
+
+const SERVICE: &str = "securelock";
+const ACCOUNT: &str = "master-key";
+
+// Somewhere outside the config file to keep the master key between sessions.
+pub trait KeyStore: Send + Sync {
+    fn store(&self, secret: &[u8]) -> Result<(), Error>;
+    fn load(&self) -> Result<Option<Vec<u8>>, Error>;
+    fn clear(&self) -> Result<(), Error>;
+}
+
+// macOS Keychain, Windows Credential Manager or the Secret Service on Linux.
+pub struct OsKeychain;
+
+impl OsKeychain {
+    fn entry() -> Result<keyring::Entry, Error> {
+        keyring::Entry::new(SERVICE, ACCOUNT).map_err(|e| format!("Keychain unavailable: {}", e).into())
+    }
+}
+
+impl KeyStore for OsKeychain {
+    fn store(&self, secret: &[u8]) -> Result<(), Error> {
+        let mut encoded = STANDARD.encode(secret);
+        let result = Self::entry()?.set_password(&encoded);
+        encoded.zeroize();
+        result.map_err(|e| format!("Failed to save to keychain: {}", e).into())
+    }
+
+    fn load(&self) -> Result<Option<Vec<u8>>, Error> {
+        match Self::entry()?.get_password() {
+            Ok(mut encoded) => {
+                let decoded = STANDARD.decode(&encoded);
+                encoded.zeroize();
+                decoded.map(Some).map_err(|_| Error::new(ErrorCode::Corrupted, "Keychain entry is corrupted"))
+            }
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(format!("Failed to read from keychain: {}", e).into()),
+        }
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        match Self::entry()?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(format!("Failed to remove from keychain: {}", e).into()),
+        }
+    }
+}
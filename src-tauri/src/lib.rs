@@ -0,0 +1,10 @@
+pub mod breach;
+pub mod budget;
+pub mod crypto;
+pub mod error;
+pub mod fido;
+pub mod folder;
+pub mod keychain;
+pub mod recipient;
+pub mod secret;
+pub mod vfs;
@@ -4,19 +4,57 @@
 )]
 
 mod commands;
-mod crypto;
-mod folder;
+mod events;
 
-use commands::AppState;
+use commands::{AppState, TrayGate};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tauri::{
     CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem,
 };
 
+const CONFIG_DIR_ENV: &str = "SECURELOCK_CONFIG_DIR";
+const PORTABLE_DIR: &str = "securelock-data";
+const BREACH_FILTER_RESOURCE: &str = "resources/breached-passwords.bloom";
+
+// `--config-dir <dir>` wins over `--portable` (config next to the executable),
+// which wins over the SECURELOCK_CONFIG_DIR environment variable.
+fn config_dir_override() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    let mut portable = false;
+    while let Some(arg) = args.next() {
+        if let Some(dir) = arg.strip_prefix("--config-dir=") {
+            return Some(PathBuf::from(dir));
+        }
+        match arg.as_str() {
+            "--config-dir" => return args.next().map(PathBuf::from),
+            "--portable" => portable = true,
+            _ => {}
+        }
+    }
+    if portable {
+        let exe = std::env::current_exe().ok()?;
+        return exe.parent().map(|dir| dir.join(PORTABLE_DIR));
+    }
+    std::env::var_os(CONFIG_DIR_ENV).map(PathBuf::from)
+}
+
+fn ensure_writable(dir: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(dir)
+        .map_err(|e| format!("Cannot create config directory '{}': {}", dir.display(), e))?;
+    let probe = dir.join(".securelock-write-test");
+    std::fs::write(&probe, b"")
+        .map_err(|e| format!("Config directory '{}' is not writable: {}", dir.display(), e))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
 fn main() {
     let tray_menu = SystemTrayMenu::new()
         .add_item(CustomMenuItem::new("show".to_string(), "Show SecureLock"))
         .add_native_item(SystemTrayMenuItem::Separator)
         .add_item(CustomMenuItem::new("lock_all".to_string(), "Lock All Folders"))
+        .add_item(CustomMenuItem::new("cancel_lock_all".to_string(), "Stop Locking"))
         .add_native_item(SystemTrayMenuItem::Separator)
         .add_item(CustomMenuItem::new("quit".to_string(), "Quit"));
 
@@ -45,13 +83,32 @@ fn main() {
                         let _ = window.set_focus();
                     }
                 }
+                "lock_all" | "cancel_lock_all" if app.state::<AppState>().tray_gate() == TrayGate::NeedsMaster => {
+                    if let Some(window) = app.get_window("main") {
+                        let _ = window.show();
+                        let _ = window.set_focus();
+                        let _ = window.emit("tray-master-required", id.as_str());
+                    }
+                }
                 "lock_all" => {
                     if let Some(window) = app.get_window("main") {
                         let _ = window.emit("tray-lock-all", ());
                     }
                 }
+                "cancel_lock_all" => {
+                    app.state::<AppState>().cancel_lock_all();
+                }
                 "quit" => {
-                    std::process::exit(0);
+                    let busy = app.state::<AppState>().operations_in_progress();
+                    if busy.is_empty() {
+                        app.state::<AppState>().close_temp_unlocks();
+                        std::process::exit(0);
+                    }
+                    if let Some(window) = app.get_window("main") {
+                        let _ = window.show();
+                        let _ = window.set_focus();
+                        let _ = window.emit("quit-blocked", busy);
+                    }
                 }
                 _ => {}
             },
@@ -61,32 +118,134 @@ fn main() {
             if let tauri::WindowEvent::CloseRequested { api, .. } = event.event() {
                 event.window().hide().unwrap();
                 api.prevent_close();
+                let app = event.window().app_handle();
+                std::thread::spawn(move || commands::lock_on_hide(&app, &app.state::<AppState>()));
             }
         })
         .setup(|app| {
-            let config_dir = app
-                .path_resolver()
-                .app_config_dir()
-                .expect("Failed to get config dir");
-            std::fs::create_dir_all(&config_dir).ok();
+            let config_dir = match config_dir_override() {
+                Some(dir) => {
+                    ensure_writable(&dir)?;
+                    dir
+                }
+                None => {
+                    let dir = app
+                        .path_resolver()
+                        .app_config_dir()
+                        .expect("Failed to get config dir");
+                    std::fs::create_dir_all(&dir).ok();
+                    dir
+                }
+            };
             let config_path = config_dir.join("config.json").to_string_lossy().to_string();
-            app.manage(AppState::new(config_path));
+            let mut state = AppState::new(config_path);
+            state.bundled_breach_filter = app.path_resolver().resolve_resource(BREACH_FILTER_RESOURCE);
+            app.manage(state);
+            commands::clean_interrupted_locks(&app.state::<AppState>());
+            let handle = app.handle();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(Duration::from_secs(1));
+                commands::tick_timed_unlocks(&handle, &handle.state::<AppState>());
+            });
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             commands::get_folders,
             commands::add_folder,
+            commands::add_folders,
             commands::remove_folder,
             commands::lock_folder,
+            commands::lock_with_decoy,
+            commands::add_password_slot,
+            commands::remove_password_slot,
+            commands::lock_matching,
             commands::unlock_folder,
+            commands::unlock_dry_run,
+            commands::unlock_folder_timed,
+            commands::unlock_to_tmpfs,
+            commands::relock_tmpfs,
+            commands::check_folder_password,
+            commands::add_file_to_locked,
+            commands::finish_lock,
+            commands::rekey_folder,
+            commands::change_folder_password,
+            commands::upgrade_folder_kdf,
+            commands::lock_file,
+            commands::unlock_file,
+            commands::recover_file,
+            commands::preview_lock_all,
             commands::lock_all,
+            commands::cancel_lock_all,
             commands::setup_master_password,
+            commands::prepare_master_password,
+            commands::commit_master_password,
+            commands::abort_master_password,
             commands::verify_master_password,
+            commands::enroll_hardware_key,
+            commands::has_hardware_key,
             commands::has_master_password,
             commands::is_master_unlocked,
+            commands::store_master_in_keychain,
+            commands::load_master_from_keychain,
+            commands::lock_master,
+            commands::list_session_keys,
+            commands::clear_session_keys,
+            commands::panic_wipe,
+            commands::is_master_only,
             commands::check_recovery_key,
+            commands::recovery_available,
             commands::recover_folder,
+            commands::recover_folder_with_identity,
+            commands::recover_all,
+            commands::unlock_all_with_master,
+            commands::upgrade_recovery_blobs,
+            commands::export_recovery_blob,
+            commands::import_recovery_blob,
+            commands::diagnose_folder,
+            commands::verify_vault_root,
+            commands::compare_vaults,
+            commands::rebuild_metadata,
+            commands::crypto_self_test,
+            commands::measure_kdf_cost,
+            commands::get_config_error,
+            commands::get_base_dir,
+            commands::set_base_dir,
+            commands::get_stream_threshold,
+            commands::set_stream_threshold,
+            commands::get_lock_limits,
+            commands::set_lock_limits,
+            commands::get_tray_requires_master,
+            commands::set_tray_requires_master,
+            commands::get_lock_on_hide,
+            commands::set_lock_on_hide,
+            commands::get_verify_shred,
+            commands::set_verify_shred,
+            commands::folder_info,
+            commands::list_locked_files,
+            commands::decrypt_file_to_bytes,
+            commands::lock_to_archive,
+            commands::unlock_from_archive,
+            commands::import_external_locker,
+            commands::set_folder_names,
+            commands::set_folder_label,
+            commands::set_folder_note,
+            commands::set_folder_pin,
+            commands::has_folder_pin,
+            commands::verify_folder_pin,
+            commands::set_hidden_policy,
         ])
-        .run(tauri::generate_context!())
-        .expect("Error running SecureLock");
+        .build(tauri::generate_context!())
+        .expect("Error building SecureLock")
+        .run(|app, event| {
+            // Don't let the process exit underneath a lock/unlock that is mid-write.
+            match event {
+                tauri::RunEvent::ExitRequested { api, .. } => {
+                    if !app.state::<AppState>().operations_in_progress().is_empty() {
+                        api.prevent_exit();
+                    }
+                }
+                tauri::RunEvent::Exit => app.state::<AppState>().close_temp_unlocks(),
+                _ => {}
+            }
+        });
 }
@@ -0,0 +1,101 @@
+use crate::error::Error;
+use crate::secret::SecretKey;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::str::FromStr;
+use zeroize::Zeroize;
+
+const KEY_LEN: usize = 32;
+
+// A folder's password key wrapped for one age recipient, so the matching age
+// key file or SSH private key can recover the folder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipientKey {
+    // As given at lock time; kept so a password change can re-wrap for it.
+    pub recipient: String,
+    pub wrapped_key: Vec<u8>,
+}
+
+enum Recipient {
+    X25519(age::x25519::Recipient),
+    Ssh(age::ssh::Recipient),
+}
+
+// `age1...` keys first, then SSH public keys (ssh-ed25519 / ssh-rsa lines).
+fn parse_recipient(recipient: &str) -> Result<Recipient, Error> {
+    let recipient = recipient.trim();
+    if let Ok(r) = age::x25519::Recipient::from_str(recipient) {
+        return Ok(Recipient::X25519(r));
+    }
+    age::ssh::Recipient::from_str(recipient)
+        .map(Recipient::Ssh)
+        .map_err(|_| format!("'{}' is not an age recipient or SSH public key", recipient).into())
+}
+
+// Lets callers reject a typo before any file is touched.
+pub fn validate_recipients(recipients: &[String]) -> Result<(), Error> {
+    recipients.iter().try_for_each(|r| parse_recipient(r).map(|_| ()))
+}
+
+pub fn wrap_key(recipient: &str, key: &[u8; KEY_LEN]) -> Result<RecipientKey, Error> {
+    let wrapped = match parse_recipient(recipient)? {
+        Recipient::X25519(r) => age::encrypt(&r, key),
+        Recipient::Ssh(r) => age::encrypt(&r, key),
+    };
+    let wrapped_key = wrapped.map_err(|e| format!("Failed to wrap key for '{}': {}", recipient.trim(), e))?;
+    Ok(RecipientKey { recipient: recipient.trim().to_string(), wrapped_key })
+}
+
+pub enum Identity {
+    X25519(Vec<age::x25519::Identity>),
+    Ssh(age::ssh::Identity),
+}
+
+impl Identity {
+    // An age identity file (AGE-SECRET-KEY-1 lines, `#` comments allowed) or an
+    // OpenSSH private key without a passphrase.
+    pub fn from_file(path: &str) -> Result<Self, Error> {
+        let mut text = fs::read_to_string(path).map_err(|e| Error::io(&e, format!("Failed to read identity file: {}", e)))?;
+        let identity = Self::parse(&text, path);
+        text.zeroize();
+        identity
+    }
+
+    fn parse(text: &str, path: &str) -> Result<Self, Error> {
+        if text.contains("-----BEGIN") {
+            let key = age::ssh::Identity::from_buffer(text.as_bytes(), Some(path.to_string()))
+                .map_err(|e| format!("Invalid SSH private key: {}", e))?;
+            return match key {
+                age::ssh::Identity::Unencrypted(_) => Ok(Identity::Ssh(key)),
+                age::ssh::Identity::Encrypted(_) => Err("Passphrase-protected SSH keys are not supported; use a copy without a passphrase".into()),
+                age::ssh::Identity::Unsupported(_) => Err("Unsupported SSH key type".into()),
+            };
+        }
+        let keys = text.lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| age::x25519::Identity::from_str(l).map_err(|_| "Identity file contains a line that is not an age secret key".to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if keys.is_empty() {
+            return Err("No age secret key found in the identity file".into());
+        }
+        Ok(Identity::X25519(keys))
+    }
+
+    // The key from the first entry this identity can open.
+    pub fn unwrap_key(&self, wrapped: &[RecipientKey]) -> Option<SecretKey> {
+        wrapped.iter().find_map(|entry| {
+            let mut bytes = match self {
+                Identity::X25519(keys) => keys.iter().find_map(|k| age::decrypt(k, &entry.wrapped_key).ok())?,
+                Identity::Ssh(key) => age::decrypt(key, &entry.wrapped_key).ok()?,
+            };
+            let key = (bytes.len() == KEY_LEN).then(|| {
+                let mut key = SecretKey::zeroed();
+                key.as_mut_bytes().copy_from_slice(&bytes);
+                key
+            });
+            bytes.zeroize();
+            key
+        })
+    }
+}
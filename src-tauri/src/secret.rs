@@ -0,0 +1,91 @@
+use std::ops::Deref;
+use std::ptr::NonNull;
+use zeroize::Zeroize;
+
+const KEY_LEN: usize = 32;
+
+// A 32-byte key on pages of its own between guard pages, pinned in RAM so it
+// never reaches swap, and zeroized on drop. Locks are per page and not
+// counted, so keys can't share a page: dropping one would unpin the others.
+// If the OS refuses the lock (RLIMIT_MEMLOCK, say) the key still works, unpinned.
+pub struct SecretKey {
+    bytes: NonNull<[u8; KEY_LEN]>,
+}
+
+// The allocation is owned like a Box and only reached through &self / &mut self.
+unsafe impl Send for SecretKey {}
+unsafe impl Sync for SecretKey {}
+
+impl SecretKey {
+    pub fn zeroed() -> Self {
+        let bytes = unsafe { memsec::malloc::<[u8; KEY_LEN]>() }
+            .unwrap_or_else(|| std::alloc::handle_alloc_error(std::alloc::Layout::new::<[u8; KEY_LEN]>()));
+        unsafe { bytes.as_ptr().write([0; KEY_LEN]) };
+        SecretKey { bytes }
+    }
+
+    pub fn as_mut_bytes(&mut self) -> &mut [u8; KEY_LEN] {
+        unsafe { self.bytes.as_mut() }
+    }
+
+    // The zeroizing half of drop; split out so tests can look at the bytes
+    // afterwards, which the freed pages don't allow.
+    fn wipe(&mut self) {
+        self.as_mut_bytes().zeroize();
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = [u8; KEY_LEN];
+
+    fn deref(&self) -> &[u8; KEY_LEN] {
+        unsafe { self.bytes.as_ref() }
+    }
+}
+
+impl Clone for SecretKey {
+    fn clone(&self) -> Self {
+        let mut secret = Self::zeroed();
+        secret.as_mut_bytes().copy_from_slice(&self[..]);
+        secret
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.wipe();
+        // Unlocks the key's own pages only.
+        unsafe { memsec::free(self.bytes) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wipe_zeroizes_and_leaves_clones_alone() {
+        let mut key = SecretKey::zeroed();
+        key.as_mut_bytes().fill(0xAA);
+        let copy = key.clone();
+        key.wipe();
+        assert_eq!(*key, [0; KEY_LEN]);
+        // The clone has its own allocation and keeps the key.
+        assert_eq!(*copy, [0xAA; KEY_LEN]);
+        assert_ne!(copy.bytes.as_ptr(), key.bytes.as_ptr());
+    }
+
+    #[test]
+    fn keys_never_share_a_page() {
+        let keys: Vec<SecretKey> = (0..8).map(|_| SecretKey::zeroed()).collect();
+        // 4 KiB is the smallest page size in use, so distinct pages of any size
+        // give distinct numbers here.
+        let mut pages: Vec<usize> = keys.iter().map(|k| k.bytes.as_ptr() as usize / 4096).collect();
+        pages.sort();
+        pages.dedup();
+        assert_eq!(pages.len(), keys.len());
+        drop(keys);
+        let key = SecretKey::zeroed();
+        assert_eq!(*key, [0; KEY_LEN]);
+    }
+}
@@ -0,0 +1,316 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use walkdir::WalkDir;
+
+// The file operations vault metadata goes through (header, manifest, reserve),
+// so the same code can run against memory and be made to fail at a chosen
+// step. File contents don't: they are streamed in chunks, compared by size and
+// mtime, hard-linked and walked with per-directory filters, none of which this
+// models, so folder.rs reads and writes them with std::fs and vfs::retry.
+pub trait FileSystem: Send + Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    // Creates or truncates; the contents are on disk once it returns.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    fn exists(&self, path: &Path) -> bool;
+    // Every regular file under `root`, in no particular order.
+    fn walk(&self, root: &Path) -> io::Result<Vec<PathBuf>>;
+}
+
+// The real disk.
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        file.write_all(contents)?;
+        file.sync_all()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn walk(&self, root: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in WalkDir::new(root) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                files.push(entry.into_path());
+            }
+        }
+        Ok(files)
+    }
+}
+
+// How often an operation failing with a transient error is tried again, for
+// folders on network mounts. The wait doubles after each attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { retries: 3, backoff_ms: 200 }
+    }
+}
+
+// Errors an SMB or NFS mount throws now and then that are worth trying again;
+// anything else (missing files, permissions, a full disk) fails straight away.
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::NotConnected)
+}
+
+pub fn retry<T>(policy: RetryPolicy, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = Duration::from_millis(policy.backoff_ms);
+    let mut retries = 0;
+    loop {
+        match op() {
+            Err(e) if is_transient(&e) && retries < policy.retries => {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Another FileSystem with every operation retried under `policy`.
+pub struct Retrying<F> {
+    pub inner: F,
+    pub policy: RetryPolicy,
+}
+
+impl<F: FileSystem> FileSystem for Retrying<F> {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        retry(self.policy, || self.inner.read(path))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        retry(self.policy, || self.inner.write(path, contents))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        retry(self.policy, || self.inner.rename(from, to))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        retry(self.policy, || self.inner.remove(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn walk(&self, root: &Path) -> io::Result<Vec<PathBuf>> {
+        retry(self.policy, || self.inner.walk(root))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    Rename,
+    Remove,
+}
+
+struct Fault {
+    op: Op,
+    path: PathBuf,
+    kind: ErrorKind,
+    // For writes: how much lands before the failure.
+    written: usize,
+}
+
+// Files kept in memory, for tests. Failures queued with `fail` or
+// `fail_partway` fire once each.
+#[derive(Default)]
+pub struct MemoryFileSystem {
+    files: Mutex<BTreeMap<PathBuf, Vec<u8>>>,
+    faults: Mutex<Vec<Fault>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The next `op` on `path` fails with `kind` (e.g. StorageFull, PermissionDenied).
+    // A rename is matched on its destination. Queue it more than once for a
+    // fault that outlasts a few retries.
+    pub fn fail(&self, op: Op, path: &Path, kind: ErrorKind) {
+        self.faults.lock().unwrap().push(Fault { op, path: path.to_path_buf(), kind, written: 0 });
+    }
+
+    // The next write to `path` stores its first `written` bytes, then fails.
+    pub fn fail_partway(&self, path: &Path, written: usize, kind: ErrorKind) {
+        self.faults.lock().unwrap().push(Fault { op: Op::Write, path: path.to_path_buf(), kind, written });
+    }
+
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.files.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn take_fault(&self, op: Op, path: &Path) -> Option<Fault> {
+        let mut faults = self.faults.lock().unwrap();
+        let index = faults.iter().position(|f| f.op == op && f.path == path)?;
+        Some(faults.remove(index))
+    }
+}
+
+// Worded like the OS messages for the same failures; error::io_code goes by
+// the kind.
+fn fault_error(kind: ErrorKind) -> io::Error {
+    let message = match kind {
+        ErrorKind::StorageFull => "No space left on device",
+        ErrorKind::PermissionDenied => "Permission denied",
+        ErrorKind::NotFound => "No such file or directory",
+        ErrorKind::TimedOut => "Connection timed out",
+        _ => "Injected I/O failure",
+    };
+    io::Error::new(kind, message)
+}
+
+fn not_found() -> io::Error {
+    fault_error(ErrorKind::NotFound)
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        if let Some(fault) = self.take_fault(Op::Read, path) {
+            return Err(fault_error(fault.kind));
+        }
+        self.files.lock().unwrap().get(path).cloned().ok_or_else(not_found)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if let Some(fault) = self.take_fault(Op::Write, path) {
+            let partial = contents[..fault.written.min(contents.len())].to_vec();
+            self.files.lock().unwrap().insert(path.to_path_buf(), partial);
+            return Err(fault_error(fault.kind));
+        }
+        self.files.lock().unwrap().insert(path.to_path_buf(), contents.to_vec());
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if let Some(fault) = self.take_fault(Op::Rename, to) {
+            return Err(fault_error(fault.kind));
+        }
+        let mut files = self.files.lock().unwrap();
+        let contents = files.remove(from).ok_or_else(not_found)?;
+        files.insert(to.to_path_buf(), contents);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        if let Some(fault) = self.take_fault(Op::Remove, path) {
+            return Err(fault_error(fault.kind));
+        }
+        self.files.lock().unwrap().remove(path).map(|_| ()).ok_or_else(not_found)
+    }
+
+    // Directories only exist implicitly, as the parents of files.
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().keys().any(|p| p.starts_with(path))
+    }
+
+    fn walk(&self, root: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self.files.lock().unwrap().keys().filter(|p| p.starts_with(root)).cloned().collect())
+    }
+}
+
+// Writes to a sibling temp file and renames it over `path`, so a crash leaves
+// either the old contents or the new, never a truncated file.
+pub fn write_atomic(fs: &dyn FileSystem, path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = fs.write(&tmp_path, contents).and_then(|_| fs.rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs.remove(&tmp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_atomic_writes_keep_the_old_contents() {
+        let mem = MemoryFileSystem::new();
+        let path = Path::new("/vault/file");
+        let tmp_path = Path::new("/vault/file.tmp");
+        write_atomic(&mem, path, b"old").unwrap();
+
+        mem.fail(Op::Write, tmp_path, ErrorKind::StorageFull);
+        assert_eq!(write_atomic(&mem, path, b"new").unwrap_err().kind(), ErrorKind::StorageFull);
+        mem.fail_partway(tmp_path, 1, ErrorKind::StorageFull);
+        assert!(write_atomic(&mem, path, b"new").is_err());
+        mem.fail(Op::Rename, path, ErrorKind::PermissionDenied);
+        assert_eq!(write_atomic(&mem, path, b"new").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(mem.files(), vec![path.to_path_buf()]);
+        assert_eq!(mem.read(path).unwrap(), b"old");
+
+        // Each fault fires once.
+        write_atomic(&mem, path, b"new").unwrap();
+        assert_eq!(mem.read(path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn transient_faults_are_retried() {
+        let policy = RetryPolicy { retries: 3, backoff_ms: 0 };
+        let fs = Retrying { inner: MemoryFileSystem::new(), policy };
+        let path = Path::new("/share/file");
+        fs.inner.fail(Op::Write, path, ErrorKind::TimedOut);
+        fs.write(path, b"data").unwrap();
+        fs.inner.fail(Op::Read, path, ErrorKind::TimedOut);
+        fs.inner.fail(Op::Read, path, ErrorKind::ConnectionReset);
+        assert_eq!(fs.read(path).unwrap(), b"data");
+        // One more fault than there are retries outlasts them.
+        for _ in 0..4 {
+            fs.inner.fail(Op::Read, path, ErrorKind::TimedOut);
+        }
+        assert_eq!(fs.read(path).unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(fs.read(path).is_ok());
+        // Not transient: fails at once, leaving the second fault queued.
+        fs.inner.fail(Op::Read, path, ErrorKind::PermissionDenied);
+        fs.inner.fail(Op::Read, path, ErrorKind::PermissionDenied);
+        assert!(fs.read(path).is_err());
+        assert!(fs.read(path).is_err());
+        assert!(fs.read(path).is_ok());
+
+        let mut calls = 0;
+        let result = retry(policy, || {
+            calls += 1;
+            if calls < 3 { Err(io::Error::from(ErrorKind::Interrupted)) } else { Ok(calls) }
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert!(is_transient(&io::Error::from(ErrorKind::WouldBlock)));
+        assert!(!is_transient(&io::Error::from(ErrorKind::NotFound)));
+    }
+}
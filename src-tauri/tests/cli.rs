@@ -0,0 +1,86 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+// Runs the CLI with `stdin` piped in.
+fn cli(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_securelock-cli"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn status(path: &str) -> String {
+    stdout(&cli(&["status", path], ""))
+}
+
+fn folder_with_file() -> (tempfile::TempDir, String) {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), b"hello").unwrap();
+    let path = dir.path().to_str().unwrap().to_string();
+    (dir, path)
+}
+
+fn contents(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn lock_status_unlock_with_password_on_stdin() {
+    let (dir, path) = folder_with_file();
+    assert_eq!(status(&path), format!("{}: unlocked\n", path));
+
+    let locked = cli(&["lock", &path, "--password-stdin"], "pw\n");
+    assert!(locked.status.success(), "{}", String::from_utf8_lossy(&locked.stderr));
+    assert!(stdout(&locked).starts_with(&format!("Locked {} (1 files)", path)));
+    assert_eq!(contents(dir.path()), vec![".securelock", "a.txt.locked"]);
+    assert_eq!(status(&path), format!("{}: locked\n", path));
+
+    let wrong = cli(&["unlock", &path], "nope\n");
+    assert_eq!(wrong.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&wrong.stderr).starts_with("Error: "));
+
+    let unlocked = cli(&["unlock", &path], "pw\n");
+    assert!(unlocked.status.success());
+    assert_eq!(fs::read(dir.path().join("a.txt")).unwrap(), b"hello");
+    assert_eq!(status(&path), format!("{}: unlocked\n", path));
+}
+
+#[test]
+fn password_from_keyfile() {
+    let (dir, path) = folder_with_file();
+    let keys = tempfile::tempdir().unwrap();
+    let keyfile = keys.path().join("key");
+    fs::write(&keyfile, "secret\n").unwrap();
+    let keyfile = keyfile.to_str().unwrap();
+    assert!(cli(&["lock", &path, "--keyfile", keyfile], "").status.success());
+    assert!(!dir.path().join("a.txt").exists());
+    // The trailing newline is not part of the password.
+    assert!(cli(&["unlock", &path], "secret\n").status.success());
+    assert_eq!(fs::read(dir.path().join("a.txt")).unwrap(), b"hello");
+}
+
+#[test]
+fn passwords_on_the_command_line_are_refused() {
+    let (dir, path) = folder_with_file();
+    let output = cli(&["lock", &path, "--password", "pw"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not accepted on the command line"));
+    assert_eq!(contents(dir.path()), vec!["a.txt"]);
+
+    let empty = cli(&["lock", &path], "\n");
+    assert_eq!(empty.status.code(), Some(1));
+    assert_eq!(contents(dir.path()), vec!["a.txt"]);
+}
@@ -6,6 +6,7 @@ const { listen } = window.__TAURI__.event;
 const folderListEl = document.getElementById("folder-list");
 const emptyStateEl = document.getElementById("empty-state");
 const btnAdd = document.getElementById("btn-add");
+const btnImport = document.getElementById("btn-import");
 const modalOverlay = document.getElementById("modal-overlay");
 const modalTitle = document.getElementById("modal-title");
 const modalDesc = document.getElementById("modal-desc");
@@ -25,9 +26,12 @@ const btnSettings = document.getElementById("btn-settings");
 const forgotPassword = document.getElementById("forgot-password");
 const btnForgot = document.getElementById("btn-forgot");
 
-let currentAction = null; // { type: 'lock'|'unlock'|'lock_all'|'setup_master'|'verify_master'|'recover', path?: string }
+let currentAction = null; // { type: 'lock'|'unlock'|'lock_all'|'setup_master'|'verify_master'|'recover', path?: string, then?: string }
 let masterPasswordConfigured = false;
 let masterSessionUnlocked = false;
+let masterOnly = false; // folder passwords turned off by policy; see is_master_only
+let folderLabels = {}; // path -> label, as last rendered
+let folderNotes = {}; // path -> note, as last rendered
 
 // ── Load folders on startup ──
 async function loadFolders() {
@@ -44,6 +48,14 @@ async function checkMasterState() {
   try {
     masterPasswordConfigured = await invoke("has_master_password");
     masterSessionUnlocked = await invoke("is_master_unlocked");
+    masterOnly = await invoke("is_master_only");
+    if (masterPasswordConfigured && !masterSessionUnlocked) {
+      try {
+        masterSessionUnlocked = await invoke("load_master_from_keychain");
+      } catch (e) {
+        console.error("Failed to load master key from keychain:", e);
+      }
+    }
     updateSettingsIcon();
     if (!masterPasswordConfigured) {
       setupBanner.classList.remove("hidden");
@@ -78,10 +90,12 @@ function renderFolders(folders) {
 
   emptyStateEl.style.display = "none";
   folderListEl.style.display = "flex";
+  folderLabels = Object.fromEntries(folders.map((f) => [f.path, f.label || ""]));
+  folderNotes = Object.fromEntries(folders.map((f) => [f.path, f.note || ""]));
 
   folderListEl.innerHTML = folders
     .map((f) => {
-      const name = f.path.split(/[\\/]/).pop();
+      const name = f.label || f.path.split(/[\\/]/).pop();
       const lockIcon = f.is_locked
         ? `<svg width="22" height="22" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2">
              <rect x="3" y="11" width="18" height="11" rx="2" ry="2"/><path d="M7 11V7a5 5 0 0 1 10 0v4"/>
@@ -98,23 +112,39 @@ function renderFolders(folders) {
            </span>`
         : "";
 
-      const actionBtn = f.is_locked
+      const actionBtn = f.problem
+        ? ""
+        : f.is_locked
         ? `<button class="btn btn-sm btn-primary" onclick="promptUnlock('${escPath(f.path)}')">Unlock</button>`
         : `<button class="btn btn-sm btn-secondary" onclick="promptLock('${escPath(f.path)}')">Lock</button>`;
+      const contentsBtn = f.is_locked
+        ? `<button class="btn btn-sm btn-secondary" onclick="showContents('${escPath(f.path)}')">Contents</button>`
+        : "";
+      const passwordBtn = f.is_locked && !f.problem
+        ? `<button class="btn btn-sm btn-secondary" onclick="promptChangePassword('${escPath(f.path)}')">Password</button>`
+        : "";
 
       return `
         <div class="folder-card">
           <div class="folder-icon ${f.is_locked ? "locked" : "unlocked"}">${lockIcon}</div>
           <div class="folder-info">
             <div class="folder-path" title="${escHtml(f.path)}">${escHtml(name)}</div>
+            ${f.note ? `<div class="folder-note" title="${escHtml(f.note)}">${escHtml(f.note)}</div>` : ""}
             <div class="folder-meta">
-              <span class="status-badge ${f.is_locked ? "locked" : "unlocked"}">${f.is_locked ? "Locked" : "Unlocked"}</span>
+              ${f.problem
+                ? `<span class="status-badge unavailable">Unavailable</span><span>${escHtml(f.problem)}</span>`
+                : `<span class="status-badge ${f.is_locked ? "locked" : "unlocked"}">${f.is_locked ? "Locked" : "Unlocked"}</span>
               ${recoveryBadge}
-              <span>${f.file_count} file${f.file_count !== 1 ? "s" : ""}</span>
+              <span>${f.file_count} file${f.file_count !== 1 ? "s" : ""}</span>`}
             </div>
           </div>
           <div class="folder-actions">
             ${actionBtn}
+            ${contentsBtn}
+            ${passwordBtn}
+            <button class="btn btn-sm btn-secondary" onclick="renameFolder('${escPath(f.path)}')">Rename</button>
+            <button class="btn btn-sm btn-secondary" onclick="editFolderNote('${escPath(f.path)}')">Note</button>
+            <button class="btn btn-sm btn-secondary" onclick="setFolderPin('${escPath(f.path)}')">PIN</button>
             <button class="btn btn-sm btn-danger" onclick="removeFolder('${escPath(f.path)}')">Remove</button>
           </div>
         </div>`;
@@ -124,17 +154,42 @@ function renderFolders(folders) {
 
 // ── Add folder ──
 btnAdd.addEventListener("click", async () => {
-  const selected = await open({ directory: true, multiple: false });
-  if (!selected) return;
+  const selected = await open({ directory: true, multiple: true });
+  if (!selected || selected.length === 0) return;
 
   try {
-    await invoke("add_folder", { path: selected });
+    const results = await invoke("add_folders", { paths: selected });
     await loadFolders();
+    const skipped = results.filter((r) => r.status !== "added");
+    if (skipped.length > 0) {
+      const reasons = {
+        already_present: "already in the list",
+        overlaps: "inside or containing a folder already in the list",
+        invalid: "not a valid directory",
+      };
+      const lines = skipped.map((r) => `${r.path}: ${reasons[r.status]}`);
+      alert("Some folders were not added:\n" + lines.join("\n"));
+    }
   } catch (e) {
-    alert("Error: " + e);
+    alert("Error: " + errorText(e));
   }
 });
 
+// ── Import from another locker ──
+// The ZIP's password is asked for first, then the new SecureLock password.
+btnImport.addEventListener("click", async () => {
+  const archive = await open({ filters: [{ name: "ZIP archive", extensions: ["zip"] }] });
+  if (!archive) return;
+  const dest = await open({ directory: true, title: "Choose a new or empty folder to import into" });
+  if (!dest) return;
+  showModal(
+    "Import ZIP",
+    "Enter the ZIP file's password.",
+    { type: "import_zip_password", archive, dest },
+    false
+  );
+});
+
 // ── Modal helpers ──
 function showModal(title, desc, action, showConfirmField) {
   modalTitle.textContent = title;
@@ -161,6 +216,8 @@ function showModal(title, desc, action, showConfirmField) {
 
 function hideModal() {
   modalOverlay.classList.add("hidden");
+  // A master password prepared but never committed is dropped.
+  if (currentAction && currentAction.type === "setup_master") invoke("abort_master_password");
   currentAction = null;
   modalPassword.value = "";
   modalConfirm.value = "";
@@ -178,6 +235,17 @@ window.promptLock = function (path) {
 };
 
 window.promptUnlock = async function (path) {
+  // Under the master-only policy every unlock is a recovery.
+  if (masterOnly) {
+    showModal(
+      "Unlock Folder",
+      "Folder passwords are turned off here. Enter your master password to unlock this folder.",
+      { type: "recover", path },
+      false
+    );
+    if (masterSessionUnlocked) doRecover(path);
+    return;
+  }
   showModal(
     "Unlock Folder",
     "Enter your password to decrypt files.",
@@ -195,12 +263,86 @@ window.promptUnlock = async function (path) {
   }
 };
 
+// The current password is checked first, then the new one is asked for.
+window.promptChangePassword = function (path) {
+  showModal(
+    "Change Password",
+    "Enter the folder's current password.",
+    { type: "change_password_check", path },
+    false
+  );
+};
+
 window.removeFolder = async function (path) {
   try {
     await invoke("remove_folder", { path });
     await loadFolders();
   } catch (e) {
-    alert("Error: " + e);
+    alert("Error: " + errorText(e));
+  }
+};
+
+// ── Folder labels ──
+window.renameFolder = async function (path) {
+  const label = prompt("Label for this folder (leave empty to show its name):", folderLabels[path] || "");
+  if (label === null) return;
+  try {
+    await invoke("set_folder_label", { path, label });
+    await loadFolders();
+  } catch (e) {
+    alert("Error: " + errorText(e));
+  }
+};
+
+// ── Folder notes ──
+// Kept in the app config as plain text; not part of the encrypted folder.
+window.editFolderNote = async function (path) {
+  const note = prompt("Note for this folder (leave empty to remove it):", folderNotes[path] || "");
+  if (note === null) return;
+  try {
+    await invoke("set_folder_note", { path, note });
+    await loadFolders();
+  } catch (e) {
+    alert("Error: " + errorText(e));
+  }
+};
+
+// ── Folder PINs ──
+// A UI speed bump only; the PIN doesn't protect the files themselves.
+window.setFolderPin = async function (path) {
+  try {
+    let currentPin = null;
+    if (await invoke("has_folder_pin", { path })) {
+      currentPin = prompt("Current PIN for this folder:");
+      if (currentPin === null) return;
+    }
+    const pin = prompt(
+      "PIN to ask for before showing this folder's contents (leave empty for none).\n" +
+      "This only guards the app's view of the folder; it does not encrypt anything."
+    );
+    if (pin === null) return;
+    await invoke("set_folder_pin", { path, pin, currentPin });
+  } catch (e) {
+    alert("Error: " + errorText(e));
+  }
+};
+
+// ── Locked folder contents ──
+window.showContents = async function (path) {
+  try {
+    if (await invoke("has_folder_pin", { path })) {
+      const pin = prompt("PIN for this folder:");
+      if (pin === null) return;
+      if (!(await invoke("verify_folder_pin", { path, pin }))) {
+        alert("Incorrect PIN");
+        return;
+      }
+    }
+    const files = await invoke("list_locked_files", { path });
+    const lines = files.map((f) => f.size === null ? f.path : `${f.path} (${formatSize(f.size)})`);
+    alert(`${files.length} locked file${files.length !== 1 ? "s" : ""}:\n` + lines.join("\n"));
+  } catch (e) {
+    alert("Error: " + errorText(e));
   }
 };
 
@@ -230,9 +372,8 @@ async function doRecover(path) {
   try {
     await invoke("recover_folder", { path });
     hideModal();
-    await loadFolders();
   } catch (e) {
-    showError(e);
+    showError(errorText(e));
   } finally {
     btnConfirm.disabled = false;
     btnConfirm.textContent = "Confirm";
@@ -240,7 +381,7 @@ async function doRecover(path) {
 }
 
 // ── Settings button ──
-btnSettings.addEventListener("click", () => {
+btnSettings.addEventListener("click", async () => {
   if (!masterPasswordConfigured) {
     showModal(
       "Set Up Master Password",
@@ -256,15 +397,58 @@ btnSettings.addEventListener("click", () => {
       false
     );
   } else {
-    showModal(
-      "Unlock Master Password",
-      "Master password is already active for this session. Re-enter to verify.",
-      { type: "verify_master" },
-      false
-    );
+    const hardwareKey = await invoke("has_hardware_key").catch(() => false);
+    if (!hardwareKey) {
+      const remember = confirm(
+        "Master password is active for this session.\n\n" +
+        "Remember it in the system keychain so recovery is unlocked automatically at launch? " +
+        "Anyone who can sign in to this computer account could then recover your folders."
+      );
+      if (remember) {
+        invoke("store_master_in_keychain").catch((e) => alert("Error: " + errorText(e)));
+      } else if (confirm("Also require a FIDO2 security key to unlock the master password?")) {
+        showModal(
+          "Enroll Security Key",
+          "Enter your master password, then touch your security key each time it blinks.",
+          { type: "enroll_hardware_key" },
+          false
+        );
+        return;
+      }
+    }
+    await toggleTrayRequirement();
+    await toggleLockOnHide();
   }
 });
 
+async function toggleTrayRequirement() {
+  try {
+    const required = await invoke("get_tray_requires_master");
+    const change = confirm(
+      required
+        ? "Tray actions (Lock All, Stop Locking) currently ask for the master password when it isn't unlocked.\n\nStop asking?"
+        : "Require the master password for tray actions (Lock All, Stop Locking) when it isn't unlocked?"
+    );
+    if (change) await invoke("set_tray_requires_master", { enabled: !required });
+  } catch (e) {
+    alert("Error: " + errorText(e));
+  }
+}
+
+async function toggleLockOnHide() {
+  try {
+    const enabled = await invoke("get_lock_on_hide");
+    const change = confirm(
+      enabled
+        ? "Closing the window to the tray currently re-locks the folders unlocked since this was turned on and locks the master password.\n\nStop doing that?"
+        : "Re-lock folders and lock the master password whenever the window is closed to the tray?\n\nThis applies to folders unlocked from now on."
+    );
+    if (change) await invoke("set_lock_on_hide", { enabled: !enabled });
+  } catch (e) {
+    alert("Error: " + errorText(e));
+  }
+}
+
 // ── Setup banner ──
 btnSetupMaster.addEventListener("click", () => {
   setupBanner.classList.add("hidden");
@@ -292,7 +476,7 @@ btnConfirm.addEventListener("click", async () => {
   }
 
   // Validation for actions requiring confirmation
-  if (currentAction.type === "lock" || currentAction.type === "lock_all" || currentAction.type === "setup_master") {
+  if (currentAction.type === "lock" || currentAction.type === "lock_all" || currentAction.type === "setup_master" || currentAction.type === "change_password" || currentAction.type === "import") {
     if (password.length < 4) {
       showError("Password must be at least 4 characters");
       return;
@@ -307,15 +491,73 @@ btnConfirm.addEventListener("click", async () => {
   btnConfirm.disabled = true;
   btnConfirm.innerHTML = '<span class="spinner"></span> Working...';
 
+  const action = currentAction;
   try {
     if (currentAction.type === "lock") {
-      await invoke("lock_folder", { path: currentAction.path, password });
+      try {
+        await invoke("lock_folder", { path: currentAction.path, password });
+      } catch (e) {
+        // A folder over the size limits is only locked once confirmed.
+        if (!e || e.code !== "over_limit" || !confirm(e.message + "\n\nLock it anyway?")) throw e;
+        await invoke("lock_folder", { path: currentAction.path, password, options: { ignore_limits: true } });
+      }
     } else if (currentAction.type === "unlock") {
-      await invoke("unlock_folder", { path: currentAction.path, password });
+      const result = await invoke("unlock_folder", { path: currentAction.path, password });
+      if (result.failed && result.failed.length > 0) {
+        const lines = result.failed.map((f) => `${f.path}: ${f.error}`);
+        alert("These files could not be restored and are still locked:\n" + lines.join("\n"));
+      }
+    } else if (currentAction.type === "change_password_check") {
+      if (!(await invoke("check_folder_password", { path: action.path, password }))) {
+        showError("Incorrect password");
+        return;
+      }
+      showModal(
+        "Change Password",
+        "Enter a new password. The files are re-encrypted without being unlocked.",
+        { type: "change_password", path: action.path, oldPassword: password },
+        true
+      );
+      return;
+    } else if (currentAction.type === "change_password") {
+      await invoke("change_folder_password", { path: action.path, oldPassword: action.oldPassword, newPassword: password });
+    } else if (currentAction.type === "import_zip_password") {
+      showModal(
+        "Import ZIP",
+        "Enter a password to lock the imported folder with.",
+        { type: "import", archive: action.archive, dest: action.dest, archivePassword: password },
+        true
+      );
+      return;
+    } else if (currentAction.type === "import") {
+      const args = { archive: action.archive, dest: action.dest, archivePassword: action.archivePassword, password };
+      try {
+        await invoke("import_external_locker", args);
+      } catch (e) {
+        if (!e || e.code !== "over_limit" || !confirm(e.message + "\n\nImport it anyway?")) throw e;
+        await invoke("import_external_locker", { ...args, options: { ignore_limits: true } });
+      }
+      await loadFolders();
     } else if (currentAction.type === "lock_all") {
-      await invoke("lock_all", { password });
+      const results = await invoke("lock_all", { password });
+      const failed = results.filter((r) => r.status === "failed");
+      const cancelled = results.filter((r) => r.status === "cancelled");
+      if (failed.length > 0 || cancelled.length > 0) {
+        const lines = failed.map((r) => `${r.path}: ${r.message}`);
+        if (cancelled.length > 0) lines.push(`Stopped before ${cancelled.length} folder${cancelled.length !== 1 ? "s" : ""}.`);
+        alert("Some folders were not locked:\n" + lines.join("\n"));
+      }
     } else if (currentAction.type === "setup_master") {
-      await invoke("setup_master_password", { password });
+      // Nothing is saved until commit, so a breached password can still be swapped.
+      const setup = await invoke("prepare_master_password", { password });
+      if (setup.breached && !confirm("This master password appears in a list of breached passwords. Use it anyway?")) {
+        await invoke("abort_master_password");
+        modalPassword.value = "";
+        modalConfirm.value = "";
+        updateStrength("");
+        return;
+      }
+      await invoke("commit_master_password", { confirmation: modalConfirm.value });
       masterPasswordConfigured = true;
       masterSessionUnlocked = true;
       updateSettingsIcon();
@@ -323,6 +565,9 @@ btnConfirm.addEventListener("click", async () => {
       await invoke("verify_master_password", { password });
       masterSessionUnlocked = true;
       updateSettingsIcon();
+    } else if (currentAction.type === "enroll_hardware_key") {
+      await invoke("enroll_hardware_key", { password });
+      alert("Security key enrolled. Unlocking the master password now needs it too.");
     } else if (currentAction.type === "recover") {
       await invoke("verify_master_password", { password });
       masterSessionUnlocked = true;
@@ -331,16 +576,23 @@ btnConfirm.addEventListener("click", async () => {
     }
 
     hideModal();
-    await loadFolders();
+    if (action.then) runTrayAction(action.then);
   } catch (e) {
-    showError(e);
+    showError(errorText(e));
   } finally {
     btnConfirm.disabled = false;
     btnConfirm.textContent = "Confirm";
   }
 });
 
-btnCancel.addEventListener("click", hideModal);
+// While Lock All runs, Cancel stops it after the current folder instead.
+btnCancel.addEventListener("click", () => {
+  if (currentAction && currentAction.type === "lock_all" && btnConfirm.disabled) {
+    invoke("cancel_lock_all");
+    return;
+  }
+  hideModal();
+});
 
 modalOverlay.addEventListener("click", (e) => {
   if (e.target === modalOverlay) hideModal();
@@ -404,19 +656,121 @@ function updateStrength(pw) {
   strengthLabel.style.color = level.color;
 }
 
+// Commands reject with { code, message_key, message }; `code` is stable
+// (e.g. "wrong_password", "disk_full") for anything that needs to branch on it.
+function errorText(e) {
+  return e && e.message ? e.message : String(e);
+}
+
 function showError(msg) {
   modalError.textContent = msg;
   modalError.classList.remove("hidden");
 }
 
 // ── Tray "Lock All" event ──
-listen("tray-lock-all", () => {
+listen("tray-lock-all", () => startLockAll());
+
+async function startLockAll() {
+  let pending = [];
+  try {
+    pending = await invoke("preview_lock_all");
+  } catch (e) {
+    console.error("Failed to preview lock all:", e);
+  }
+  if (pending.length === 0) {
+    alert("All folders are already locked.");
+    return;
+  }
+  const names = pending.map((f) => `${f.path.split(/[\\/]/).pop()} (${f.file_count} file${f.file_count !== 1 ? "s" : ""})`);
   showModal(
     "Lock All Folders",
-    "Enter a password to lock all unlocked folders.",
+    `Enter a password to lock ${pending.length} unlocked folder${pending.length !== 1 ? "s" : ""}: ${names.join(", ")}.`,
     { type: "lock_all" },
     true
   );
+}
+
+// ── Tray actions gated on the master password ──
+// The backend holds them back until the master password is unlocked; once it
+// is, the action carries on from here.
+listen("tray-master-required", (event) => {
+  if (!masterPasswordConfigured) {
+    alert("Tray actions require the master password, but none is set up.");
+    return;
+  }
+  showModal(
+    "Unlock Master Password",
+    "Tray actions require the master password. Enter it to continue.",
+    { type: "verify_master", then: event.payload },
+    false
+  );
+});
+
+function runTrayAction(id) {
+  if (id === "lock_all") {
+    startLockAll();
+  } else if (id === "cancel_lock_all") {
+    invoke("cancel_lock_all");
+  }
+}
+
+// ── Backend state changes ──
+// Folder transitions carry a path; master password transitions don't.
+listen("state-changed", (event) => {
+  if (event.payload.path) {
+    loadFolders();
+  } else {
+    // The folder list is encrypted under the master key, so it changes too.
+    checkMasterState();
+    loadFolders();
+  }
+});
+
+// ── Key derivation ──
+// Argon2 can take a few seconds on slow machines; say so instead of looking stuck.
+listen("key-derivation", (event) => {
+  if (btnConfirm.disabled) {
+    btnConfirm.innerHTML = event.payload.done
+      ? '<span class="spinner"></span> Working...'
+      : '<span class="spinner"></span> Deriving key...';
+  }
+});
+
+// ── Locked without recovery ──
+// Lock All can lock many folders this way in one go; they're reported together.
+let unrecoverable = [];
+listen("no-recovery-warning", (event) => {
+  unrecoverable.push(event.payload.path);
+  if (unrecoverable.length > 1) return;
+  setTimeout(() => {
+    alert(
+      "The master password isn't unlocked, so these folders were locked without a recovery key. " +
+      "If you forget their password they can't be recovered:\n" + unrecoverable.join("\n") +
+      "\n\nUnlock the master password and lock them again to add one."
+    );
+    unrecoverable = [];
+  }, 500);
+});
+
+// ── Left open on hide ──
+// Sent while the window is hidden, so the warning waits until it is back.
+let leftUnlocked = [];
+listen("left-unlocked", (event) => {
+  leftUnlocked = event.payload.paths;
+});
+window.addEventListener("focus", () => {
+  if (leftUnlocked.length === 0) return;
+  const paths = leftUnlocked;
+  leftUnlocked = [];
+  alert(
+    "These were left unlocked when the window was hidden, as no key was kept to lock them again:\n" +
+    paths.join("\n") + "\n\nLock them with their password."
+  );
+});
+
+// ── Quit while busy ──
+listen("quit-blocked", (event) => {
+  alert("SecureLock can't quit while these folders are being processed:\n" + event.payload.join("\n") + "\n\nTry again once they finish.");
 });
 
 // ── Helpers ──
@@ -426,6 +780,16 @@ function escHtml(str) {
   return div.innerHTML;
 }
 
+function formatSize(bytes) {
+  const units = ["B", "KB", "MB", "GB", "TB"];
+  let i = 0;
+  while (bytes >= 1024 && i < units.length - 1) {
+    bytes /= 1024;
+    i++;
+  }
+  return `${i === 0 ? bytes : bytes.toFixed(1)} ${units[i]}`;
+}
+
 function escPath(str) {
   return str.replace(/\\/g, "\\\\").replace(/'/g, "\\'");
 }
@@ -433,3 +797,6 @@ function escPath(str) {
 // ── Init ──
 checkMasterState();
 loadFolders();
+invoke("get_config_error").then((error) => {
+  if (error) alert(error);
+});